@@ -2,5 +2,96 @@
 // License: MIT OR Apache-2.0
 
 fn main() -> anyhow::Result<()> {
-    Ok(wdk_build::configure_wdk_binary_build()?)
+    let config = wdk_build::Config::from_env_auto()?;
+
+    #[cfg(feature = "wpp-tracing")]
+    wpp::build(&config)?;
+
+    Ok(config.configure_binary_build()?)
+}
+
+/// Build steps of the `wpp-tracing` feature: run the WPP preprocessor on
+/// `src/wpp.c`, and compile it with the header it generates into a static
+/// library linked into the driver.
+#[cfg(feature = "wpp-tracing")]
+mod wpp {
+    use std::{
+        env,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use anyhow::{ensure, Context};
+
+    /// C source with the `TraceEvents` calls
+    const SOURCE: &str = "src/wpp.c";
+
+    /// Run `tracewpp.exe` on [`SOURCE`], which generates `wpp.tmh` in
+    /// `OUT_DIR`, then compile [`SOURCE`] for kernel mode with the include
+    /// paths and definitions of `config`.
+    pub fn build(config: &wdk_build::Config) -> anyhow::Result<()> {
+        println!("cargo::rerun-if-changed={SOURCE}");
+        println!("cargo::rerun-if-env-changed=WDKContentRoot");
+
+        let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+        let wdk_content_root = PathBuf::from(
+            env::var("WDKContentRoot")
+                .context("WDKContentRoot is not set, build from an eWDK developer prompt")?,
+        );
+        let config_dir = wpp_config_dir(&wdk_content_root)?;
+
+        // The eWDK developer prompt has the WDK tools, tracewpp.exe included,
+        // in its PATH. -km selects the kernel-mode templates, and -func declares
+        // the trace message function called in SOURCE.
+        let status = Command::new("tracewpp.exe")
+            .arg("-km")
+            .arg(format!("-cfgdir:{}", config_dir.display()))
+            .arg(format!("-odir:{}", out_dir.display()))
+            .arg("-func:TraceEvents(LEVEL,FLAGS,MSG,...)")
+            .arg(SOURCE)
+            .status()
+            .context("Failed to run tracewpp.exe")?;
+        ensure!(status.success(), "tracewpp.exe failed: {status}");
+
+        let mut build = cc::Build::new();
+        build
+            .file(SOURCE)
+            .include(&out_dir)
+            .includes(config.get_include_paths()?)
+            // The message formats are only kept in the debug information,
+            // where tracepdb.exe finds them
+            .debug(true)
+            .flag("/kernel");
+        for (name, value) in config.get_preprocessor_definitions_iter() {
+            build.define(&name, value.as_deref());
+        }
+        build.try_compile("echo_wpp")?;
+
+        Ok(())
+    }
+
+    /// `WppConfig\Rev1` directory of the most recent WDK version installed
+    /// under `wdk_content_root`, with the templates and the configuration of
+    /// `tracewpp.exe`
+    fn wpp_config_dir(wdk_content_root: &Path) -> anyhow::Result<PathBuf> {
+        let bin_dir = wdk_content_root.join("bin");
+        bin_dir
+            .read_dir()
+            .with_context(|| format!("Failed to read {}", bin_dir.display()))?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let version = entry
+                    .file_name()
+                    .to_str()?
+                    .split('.')
+                    .map(str::parse)
+                    .collect::<Result<Vec<u32>, _>>()
+                    .ok()?;
+                let config_dir = entry.path().join("WppConfig").join("Rev1");
+                config_dir.is_dir().then_some((version, config_dir))
+            })
+            .max()
+            .map(|(_, config_dir)| config_dir)
+            .with_context(|| format!("No WppConfig directory found in {}", bin_dir.display()))
+    }
 }
@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Log which callbacks of the default queue run at the same time with the
+//! `callback-trace` feature, to observe the serialization the framework
+//! provides.
+//!
+//! By default, the queue has no synchronization scope, so the framework calls
+//! its I/O callbacks, the cancel routine of its requests and the timer
+//! whenever they are due, possibly at the same time on different processors,
+//! and the queue lock is the only thing keeping them from corrupting the queue
+//! context. With the `queue-serialization` feature, the queue uses
+//! `WdfSynchronizationScopeQueue` and the timer `AutomaticSerialization`, so
+//! the framework calls at most one of them at a time, and an overlap is an
+//! error.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::log::{log_error, log_info};
+
+/// Number of tracked queue callbacks currently running
+pub struct CallbackTracker {
+    running: AtomicU32,
+}
+
+impl CallbackTracker {
+    /// Tracker with no callback running
+    pub const fn new() -> Self {
+        Self {
+            running: AtomicU32::new(0),
+        }
+    }
+
+    /// Record that `callback` started running, logging the other callbacks
+    /// running at the same time, and return a guard that records that it
+    /// returned when dropped.
+    pub fn enter(&self, callback: &'static str) -> CallbackGuard<'_> {
+        let others = self.running.fetch_add(1, Ordering::AcqRel);
+        if others == 0 {
+            log_info!("{callback} entered, no other queue callback running");
+        } else if cfg!(feature = "queue-serialization") {
+            log_error!(
+                "{callback} entered while {others} other queue callbacks are running, despite \
+                 WdfSynchronizationScopeQueue"
+            );
+        } else {
+            log_info!(
+                "{callback} entered while {others} other queue callbacks are running, the queue \
+                 lock serializes them"
+            );
+        }
+
+        CallbackGuard {
+            tracker: self,
+            callback,
+        }
+    }
+}
+
+/// Returned by [`CallbackTracker::enter`] for the duration of a callback
+#[must_use = "the callback is recorded as returned as soon as the guard is dropped"]
+pub struct CallbackGuard<'a> {
+    tracker: &'a CallbackTracker,
+    callback: &'static str,
+}
+
+impl Drop for CallbackGuard<'_> {
+    fn drop(&mut self) {
+        let others = self.tracker.running.fetch_sub(1, Ordering::AcqRel) - 1;
+        log_info!(
+            "{} returned, {others} other queue callbacks still running",
+            self.callback
+        );
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! The completion ownership protocol between the timer (or DPC) completing the
+//! current request and its cancel routine.
+//!
+//! Both can run at the same time on different processors, and exactly one of
+//! them must complete the request. Each request keeps a cancel completion
+//! ownership count, initialized to [`INITIAL_OWNERSHIP_COUNT`]:
+//!
+//! * the cancel routine decrements it with [`on_cancel`],
+//! * the timer first increments it with [`on_timer_fire`], so the request stays
+//!   valid while it tries to unmark it cancelable, and then reports the result
+//!   of that with [`on_unmark_cancelable`].
+//!
+//! Whoever brings the count to zero owns the request and completes it. The
+//! functions in this module only make that decision, and have no dependency on
+//! WDF: `queue.rs` translates the returned actions into WDF calls.
+
+use core::sync::atomic::{AtomicI32, Ordering};
+
+/// Value of the cancel completion ownership count when a request is marked
+/// cancelable
+pub const INITIAL_OWNERSHIP_COUNT: i32 = 1;
+
+/// What the cancel routine has to do with the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelAction {
+    /// The cancel routine owns the request: clear it from the queue context
+    /// and complete it with `STATUS_CANCELLED`.
+    CompleteCancelled,
+    /// The timer owns the request: set the status of the current request to
+    /// `STATUS_CANCELLED`, the timer completes it with that status.
+    MarkCancelled,
+}
+
+/// What the timer has to do with the current request when it fires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerAction {
+    /// The timer holds a reference to the request: unmark it cancelable and
+    /// pass the result to [`on_unmark_cancelable`].
+    UnmarkCancelable,
+    /// The cancel routine has already claimed the request and completes it,
+    /// the timer must leave it alone.
+    Leave,
+}
+
+/// What the timer has to do with the current request once it has tried to
+/// unmark it cancelable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmarkAction {
+    /// The timer owns the request: clear it from the queue context and
+    /// complete it with the status of the current request.
+    Complete,
+    /// The cancel routine owns the request and completes it.
+    Leave,
+}
+
+/// The cancel routine of the request has been called.
+///
+/// # Arguments:
+///
+/// * `count` - the cancel completion ownership count of the request.
+///
+/// # Return value:
+///
+/// * [`CancelAction`] - what the cancel routine has to do with the request
+pub fn on_cancel(count: &AtomicI32) -> CancelAction {
+    if decrement_ownership_count(count) {
+        CancelAction::CompleteCancelled
+    } else {
+        CancelAction::MarkCancelled
+    }
+}
+
+/// The timer has fired while the request is the current request.
+///
+/// # Arguments:
+///
+/// * `count` - the cancel completion ownership count of the request.
+///
+/// # Return value:
+///
+/// * [`TimerAction`] - what the timer has to do with the request
+pub fn on_timer_fire(count: &AtomicI32) -> TimerAction {
+    // See comments in interlocked_increment_floor as to why <= 1 is failure
+    if interlocked_increment_gtzero(count) > 1 {
+        TimerAction::UnmarkCancelable
+    } else {
+        // What has happened is that the cancel routine has executed and has
+        // already claimed cancel ownership of the request, but has not yet
+        // acquired the queue context lock and cleared the current request.
+        TimerAction::Leave
+    }
+}
+
+/// The timer has tried to unmark the request cancelable after
+/// [`on_timer_fire`] returned [`TimerAction::UnmarkCancelable`].
+///
+/// # Arguments:
+///
+/// * `count` - the cancel completion ownership count of the request.
+/// * `cancelled` - whether unmarking failed with `STATUS_CANCELLED`, because
+///   the cancel routine has been or is about to be called.
+///
+/// # Return value:
+///
+/// * [`UnmarkAction`] - what the timer has to do with the request
+pub fn on_unmark_cancelable(count: &AtomicI32, cancelled: bool) -> UnmarkAction {
+    if cancelled {
+        // Race the cancel routine for ownership, releasing the reference taken
+        // by on_timer_fire.
+        if decrement_ownership_count(count) {
+            UnmarkAction::Complete
+        } else {
+            UnmarkAction::Leave
+        }
+    } else {
+        // Since we successfully removed the cancel routine (and we are not
+        // currently racing with it), there is no need to check the result of
+        // the decrement.
+        //
+        // 2 is the initial count plus the increment of on_timer_fire
+        count.fetch_sub(INITIAL_OWNERSHIP_COUNT + 1, Ordering::SeqCst);
+        UnmarkAction::Complete
+    }
+}
+
+/// Decrements the cancel ownership count for the request.  When the count
+/// reaches zero ownership has been acquired.
+///
+/// # Arguments:
+///
+/// * `count` - the cancel completion ownership count of the request.
+///
+/// # Return value:
+///
+/// * TRUE if the caller can complete the request, FALSE otherwise
+fn decrement_ownership_count(count: &AtomicI32) -> bool {
+    count.fetch_sub(1, Ordering::SeqCst) - 1 == 0
+}
+
+/// This routine will interlock increment a value only if the current value
+/// is greater then the floor value.
+///
+/// The volatile keyword on the Target pointer is absolutely required, otherwise
+/// the compiler might rearrange pointer dereferences and that cannot happen.
+///
+/// # Arguments:
+///
+/// * `target` - the  value that will be pontetially incrmented
+/// * `floor` - the value in which the Target value must be greater then if it
+///   is to be incremented
+///
+/// # Return value:
+///
+/// The current value of Target.  To detect failure, the return value will be
+/// <= Floor + 1.  It is +1 because we cannot increment from the Floor value
+/// itself, so Floor+1 cannot be a successful return value.
+fn interlocked_increment_floor(target: &AtomicI32, floor: i32) -> i32 {
+    let mut current_value = target.load(Ordering::SeqCst);
+    loop {
+        if current_value <= floor {
+            return current_value;
+        }
+
+        // currentValue will be the value that used to be Target if the exchange
+        // was made or its current value if the exchange was not made.
+        //
+        match target.compare_exchange(
+            current_value,
+            current_value + 1,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            // If oldValue == currentValue, then no one updated Target in between
+            // the deref at the top and the InterlockecCompareExchange afterward
+            // and we have successfully incremented the value and can exit the loop.
+            Ok(_) => break,
+            Err(v) => current_value = v,
+        }
+    }
+
+    current_value + 1
+}
+
+/// Increment the value only if it is currently > 0.
+///
+/// # Arguments:
+///
+/// * `target` - the value to be incremented
+///
+/// # Return value:
+///
+/// Upon success, a value > 0.  Upon failure, a value <= 0.
+fn interlocked_increment_gtzero(target: &AtomicI32) -> i32 {
+    interlocked_increment_floor(target, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    #[test]
+    fn cancel_before_timer_completes_cancelled() {
+        let count = AtomicI32::new(INITIAL_OWNERSHIP_COUNT);
+
+        assert_eq!(on_cancel(&count), CancelAction::CompleteCancelled);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        // The timer fires before the cancel routine has cleared the request
+        assert_eq!(on_timer_fire(&count), TimerAction::Leave);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn timer_unmarking_cancelable_completes() {
+        let count = AtomicI32::new(INITIAL_OWNERSHIP_COUNT);
+
+        assert_eq!(on_timer_fire(&count), TimerAction::UnmarkCancelable);
+        assert_eq!(count.load(Ordering::SeqCst), INITIAL_OWNERSHIP_COUNT + 1);
+
+        assert_eq!(on_unmark_cancelable(&count, false), UnmarkAction::Complete);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn cancel_while_timer_holds_request_leaves_it_to_timer() {
+        let count = AtomicI32::new(INITIAL_OWNERSHIP_COUNT);
+
+        assert_eq!(on_timer_fire(&count), TimerAction::UnmarkCancelable);
+        assert_eq!(on_cancel(&count), CancelAction::MarkCancelled);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        assert_eq!(on_unmark_cancelable(&count, true), UnmarkAction::Complete);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn cancel_after_failed_unmark_completes_cancelled() {
+        let count = AtomicI32::new(INITIAL_OWNERSHIP_COUNT);
+
+        assert_eq!(on_timer_fire(&count), TimerAction::UnmarkCancelable);
+        // Unmarking fails because the cancel routine is about to be called
+        assert_eq!(on_unmark_cancelable(&count, true), UnmarkAction::Leave);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        assert_eq!(on_cancel(&count), CancelAction::CompleteCancelled);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn increment_floor_only_increments_above_floor() {
+        let count = AtomicI32::new(0);
+        assert_eq!(interlocked_increment_floor(&count, 0), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        let count = AtomicI32::new(-1);
+        assert_eq!(interlocked_increment_gtzero(&count), -1);
+        assert_eq!(count.load(Ordering::SeqCst), -1);
+
+        let count = AtomicI32::new(1);
+        assert_eq!(interlocked_increment_floor(&count, 0), 2);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn racing_timer_and_cancel_complete_once() {
+        for _ in 0..1000 {
+            let count = AtomicI32::new(INITIAL_OWNERSHIP_COUNT);
+            // Whether the request still has a cancel routine, which either the
+            // timer unmarking it or the I/O manager cancelling it clears
+            let cancelable = AtomicBool::new(true);
+
+            let (timer_completes, cancel_completes) = std::thread::scope(|scope| {
+                let timer = scope.spawn(|| match on_timer_fire(&count) {
+                    TimerAction::UnmarkCancelable => {
+                        let cancelled = !cancelable.swap(false, Ordering::SeqCst);
+                        on_unmark_cancelable(&count, cancelled) == UnmarkAction::Complete
+                    }
+                    TimerAction::Leave => false,
+                });
+                let cancel = scope.spawn(|| {
+                    cancelable.swap(false, Ordering::SeqCst)
+                        && on_cancel(&count) == CancelAction::CompleteCancelled
+                });
+                (timer.join().unwrap(), cancel.join().unwrap())
+            });
+
+            assert!(
+                timer_completes != cancel_completes,
+                "exactly one of the timer and the cancel routine must complete the request"
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_increment_floor_counts_each_increment_and_stops_at_floor() {
+        const THREADS: i32 = 8;
+        const INCREMENTS: i32 = 1000;
+
+        // Above the floor, every increment succeeds, each with its own value
+        let count = AtomicI32::new(1);
+        let mut values = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..INCREMENTS)
+                            .map(|_| interlocked_increment_gtzero(&count))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 1 + THREADS * INCREMENTS);
+        values.sort_unstable();
+        assert_eq!(values, (2..=1 + THREADS * INCREMENTS).collect::<Vec<_>>());
+
+        // Once the count drops to the floor, no increment succeeds, so the
+        // count stays there
+        for _ in 0..100 {
+            let count = AtomicI32::new(1);
+            std::thread::scope(|scope| {
+                for _ in 0..THREADS {
+                    scope.spawn(|| {
+                        for _ in 0..INCREMENTS / 10 {
+                            let value = interlocked_increment_gtzero(&count);
+                            assert!(value <= 0 || value >= 2, "incremented from the floor");
+                        }
+                    });
+                }
+                scope.spawn(|| count.store(0, Ordering::SeqCst));
+            });
+            assert_eq!(count.load(Ordering::SeqCst), 0);
+        }
+    }
+}
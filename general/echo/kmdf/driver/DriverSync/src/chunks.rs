@@ -0,0 +1,111 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Splitting a copy into chunks at successive offsets, with the `chunked-read`
+//! feature.
+//!
+//! The read path copies each [`Chunk`] of the stored data to the same offset
+//! of the request memory with its own `WdfMemoryCopyFromBuffer` call, the way
+//! a response is assembled from several source regions, e.g. the fragments of
+//! a scatter/gather list. Like `request_state`, this module has no dependency
+//! on WDF: it only computes the offsets and lengths.
+
+/// Part of a copy: `length` bytes at `offset` from the start of both the
+/// source and the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Offset of the chunk, in bytes
+    pub offset: usize,
+    /// Length of the chunk, in bytes
+    pub length: usize,
+}
+
+/// Iterator over the [`Chunk`]s of a copy, see [`chunks`]
+pub struct Chunks {
+    length: usize,
+    chunk_size: usize,
+    offset: usize,
+}
+
+/// Split a copy of `length` bytes into chunks of `chunk_size` bytes, in order.
+/// The last chunk is shorter when `length` is not a multiple of `chunk_size`,
+/// and there is none when `length` is 0.
+///
+/// # Arguments:
+///
+/// * `length` - Number of bytes to copy.
+/// * `chunk_size` - Longest chunk, in bytes. A size of 0 is treated as 1, so
+///   that the chunks always cover the copy.
+pub const fn chunks(length: usize, chunk_size: usize) -> Chunks {
+    Chunks {
+        length,
+        chunk_size: if chunk_size == 0 { 1 } else { chunk_size },
+        offset: 0,
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        if self.offset >= self.length {
+            return None;
+        }
+
+        let chunk = Chunk {
+            offset: self.offset,
+            length: self.chunk_size.min(self.length - self.offset),
+        };
+        self.offset += chunk.length;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(length: usize, chunk_size: usize) -> Vec<(usize, usize)> {
+        chunks(length, chunk_size)
+            .map(|chunk| (chunk.offset, chunk.length))
+            .collect()
+    }
+
+    #[test]
+    fn empty_copy_has_no_chunk() {
+        assert_eq!(collect(0, 1024), []);
+        assert_eq!(collect(0, 0), []);
+    }
+
+    #[test]
+    fn exact_multiple_has_full_chunks() {
+        assert_eq!(collect(1024, 1024), [(0, 1024)]);
+        assert_eq!(collect(3072, 1024), [(0, 1024), (1024, 1024), (2048, 1024)]);
+    }
+
+    #[test]
+    fn remainder_is_last_chunk() {
+        assert_eq!(collect(1, 1024), [(0, 1)]);
+        assert_eq!(collect(2500, 1024), [(0, 1024), (1024, 1024), (2048, 452)]);
+    }
+
+    #[test]
+    fn zero_chunk_size_is_one() {
+        assert_eq!(collect(3, 0), [(0, 1), (1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn chunks_cover_copy_in_order() {
+        for length in 0..64 {
+            for chunk_size in 1..16 {
+                let mut offset = 0;
+                for chunk in chunks(length, chunk_size) {
+                    assert_eq!(chunk.offset, offset);
+                    assert!(chunk.length > 0 && chunk.length <= chunk_size);
+                    offset += chunk.length;
+                }
+                assert_eq!(offset, length, "{length} bytes in chunks of {chunk_size}");
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Tunables of the driver, read once in `DriverEntry` from the `Parameters`
+//! subkey of its service key, e.g.
+//! `HKLM\SYSTEM\CurrentControlSet\Services\ECHO_2\Parameters`.
+//!
+//! Every value is an optional `REG_DWORD`. A missing value leaves its default,
+//! which is how the driver behaves without any configuration, and a value out
+//! of its range is logged and ignored:
+//!
+//! * `TimerPeriodMs` - period of the timer completing the requests, 100 to
+//!   60000 ms, 10000 by default
+//! * `MaxWriteLength` - longest write accepted, 1 byte to 1 MiB, 40 KiB by
+//!   default
+//! * `PoolTag` - tag of the buffers the driver allocates, any value but 0
+//! * `DispatchType` - dispatch type of the default queue, 1 for sequential and
+//!   2 for parallel, as in `WDF_IO_QUEUE_DISPATCH_TYPE`. Parallel is only
+//!   accepted with the `parallel-queue` feature, which is its default.
+//! * `CompletionDelayMs` - with the `one-shot-timer` feature, how long after a
+//!   request arrives the timer fires to complete it, 1 to 60000 ms, 1000 by
+//!   default
+
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ULONG,
+    WDFDRIVER,
+    WDFOBJECT,
+    WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_IO_QUEUE_DISPATCH_TYPE,
+};
+
+use crate::{
+    driver_get_context,
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    paged_code::paged_code_checked,
+    wdf_registry::RegistryKey,
+};
+
+/// `REG_DWORD` value of the `Parameters` key, with the range of values accepted
+struct Parameter {
+    name: &'static str,
+    min: ULONG,
+    max: ULONG,
+}
+
+const TIMER_PERIOD_MS: Parameter = Parameter {
+    name: "TimerPeriodMs",
+    min: 100,
+    max: 60 * 1000,
+};
+
+const MAX_WRITE_LENGTH: Parameter = Parameter {
+    name: "MaxWriteLength",
+    min: 1,
+    max: 1024 * 1024,
+};
+
+const POOL_TAG: Parameter = Parameter {
+    name: "PoolTag",
+    min: 1,
+    max: ULONG::MAX,
+};
+
+// WdfIoQueueDispatchSequential is 1 and WdfIoQueueDispatchParallel 2
+const DISPATCH_TYPE: Parameter = Parameter {
+    name: "DispatchType",
+    min: 1,
+    max: if cfg!(feature = "parallel-queue") {
+        2
+    } else {
+        1
+    },
+};
+
+#[cfg(feature = "one-shot-timer")]
+const COMPLETION_DELAY_MS: Parameter = Parameter {
+    name: "CompletionDelayMs",
+    min: 1,
+    max: 60 * 1000,
+};
+
+/// Configuration of the driver, stored in the context of the driver object.
+/// It does not change once `DriverEntry` has read it.
+#[derive(Clone, Copy)]
+pub struct DriverConfig {
+    /// Period of the timer in ms
+    pub timer_period_ms: u32,
+    /// Longest write accepted, in bytes
+    pub max_write_length: usize,
+    /// Tag of the buffers the driver allocates
+    pub pool_tag: ULONG,
+    /// Dispatch type of the default queue
+    pub dispatch_type: WDF_IO_QUEUE_DISPATCH_TYPE,
+    /// Delay in ms from the arrival of a request to the timer firing, with the
+    /// `one-shot-timer` feature
+    #[cfg(feature = "one-shot-timer")]
+    pub completion_delay_ms: u32,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            timer_period_ms: 1000 * 10,
+            max_write_length: 1024 * 40,
+            pool_tag: 's' as ULONG,
+            dispatch_type: if cfg!(feature = "parallel-queue") {
+                _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel
+            } else {
+                _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential
+            },
+            #[cfg(feature = "one-shot-timer")]
+            completion_delay_ms: 1000,
+        }
+    }
+}
+
+impl DriverConfig {
+    /// Read the configuration from the `Parameters` key of `driver`, falling
+    /// back to the default of any value that is missing or invalid. Called by
+    /// `DriverEntry` at `PASSIVE_LEVEL`.
+    #[link_section = "PAGE"]
+    pub fn read(driver: WDFDRIVER) -> Self {
+        paged_code_checked!();
+
+        let mut config = Self::default();
+
+        let key = match RegistryKey::open_driver_parameters(driver) {
+            Ok(key) => key,
+            Err(nt_status) => {
+                log_info!(
+                    "Parameters key not opened {}, using the default configuration",
+                    NtStatus(nt_status)
+                );
+                return config;
+            }
+        };
+
+        if let Some(value) = query(&key, &TIMER_PERIOD_MS) {
+            config.timer_period_ms = value;
+        }
+        if let Some(value) = query(&key, &MAX_WRITE_LENGTH) {
+            config.max_write_length = value as usize;
+        }
+        if let Some(value) = query(&key, &POOL_TAG) {
+            config.pool_tag = value;
+        }
+        if let Some(value) = query(&key, &DISPATCH_TYPE) {
+            config.dispatch_type = if value == 2 {
+                _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel
+            } else {
+                _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential
+            };
+        }
+        #[cfg(feature = "one-shot-timer")]
+        if let Some(value) = query(&key, &COMPLETION_DELAY_MS) {
+            config.completion_delay_ms = value;
+        }
+
+        config
+    }
+
+    /// Configuration read by `DriverEntry`, from the context of the driver
+    /// object. Can be called at any IRQL once `DriverEntry` has returned.
+    pub fn current() -> Self {
+        // SAFETY: WdfGetDriver can be called at any IRQL, and returns the
+        // driver object created by DriverEntry
+        let driver = unsafe { call_unsafe_wdf_function_binding!(WdfGetDriver) };
+        match unsafe { driver_get_context(driver as WDFOBJECT) } {
+            // SAFETY: The context is only written by DriverEntry, before any
+            // device is added
+            Some(driver_context) => unsafe { (*driver_context).config },
+            None => {
+                log_error!("Driver {driver:?} has no DriverContext");
+                Self::default()
+            }
+        }
+    }
+
+    /// Shortest delay the timer halves its period down to, in ms, with the
+    /// `adaptive-timer` feature
+    #[cfg(feature = "adaptive-timer")]
+    pub const fn min_timer_period_ms(&self) -> u32 {
+        self.timer_period_ms / 16
+    }
+}
+
+/// Read `parameter` from `key`, if it is set and within its range.
+fn query(key: &RegistryKey, parameter: &Parameter) -> Option<ULONG> {
+    let value = key.query_ulong(parameter.name).ok()?;
+    if !(parameter.min..=parameter.max).contains(&value) {
+        log_error!(
+            "{} {value} is not within {}..={}, using the default",
+            parameter.name,
+            parameter.min,
+            parameter.max
+        );
+        return None;
+    }
+
+    log_info!("{} is {value}", parameter.name);
+    Some(value)
+}
@@ -1,36 +1,117 @@
 // Copyright (c) Microsoft Corporation.
 // License: MIT OR Apache-2.0
 
-use wdk::{nt_success, paged_code, println};
+#[cfg(any(feature = "forward-writes", feature = "timer-watchdog"))]
+use core::str::FromStr;
+#[cfg(feature = "fault-injection")]
+use core::sync::atomic::AtomicI32;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use wdk::nt_success;
+#[cfg(feature = "timer-watchdog")]
+use wdk_sys::ntddk::KeQueryUnbiasedInterruptTime;
+#[cfg(feature = "named-device")]
+use wdk_sys::STATUS_OBJECT_NAME_COLLISION;
+#[cfg(any(
+    feature = "named-device",
+    feature = "idle-power-policy",
+    feature = "timer-watchdog"
+))]
+use wdk_sys::ULONG;
+#[cfg(feature = "forward-writes")]
+use wdk_sys::UNICODE_STRING;
+#[cfg(feature = "forward-writes")]
+use wdk_sys::WDFSTRING;
 use wdk_sys::{
     call_unsafe_wdf_function_binding,
-    APC_LEVEL,
     NTSTATUS,
+    STATUS_DELETE_PENDING,
+    STATUS_INVALID_DEVICE_STATE,
     STATUS_SUCCESS,
+    WDFCMRESLIST,
     WDFDEVICE,
     WDFDEVICE_INIT,
+    WDFFILEOBJECT,
     WDFOBJECT,
     WDFQUEUE,
+    WDFREQUEST,
+    WDF_FILEOBJECT_CONFIG,
     WDF_NO_HANDLE,
-    WDF_OBJECT_ATTRIBUTES,
+    WDF_NO_OBJECT_ATTRIBUTES,
     WDF_PNPPOWER_EVENT_CALLBACKS,
-    _WDF_EXECUTION_LEVEL,
-    _WDF_SYNCHRONIZATION_SCOPE,
+    WDF_POWER_DEVICE_STATE,
+    _WDF_DEVICE_IO_TYPE,
+    _WDF_FILEOBJECT_CLASS,
+    _WDF_TRI_STATE,
 };
+#[cfg(feature = "idle-power-policy")]
+use wdk_sys::{_POWER_ACTION, _WDF_POWER_DEVICE_STATE, WDF_POWER_POLICY_EVENT_CALLBACKS};
+#[cfg(feature = "restricted-access")]
+use wdk_sys::{FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN};
+#[cfg(any(feature = "forward-writes", feature = "timer-watchdog"))]
+use wdk_sys::{KEY_QUERY_VALUE, PLUGPLAY_REGKEY_DEVICE, WDFKEY};
 
+#[cfg(feature = "latency-stats")]
+use crate::latency::LatencyStats;
+#[cfg(feature = "method-neither")]
+use crate::queue::echo_evt_io_in_caller_context;
+#[cfg(feature = "transform")]
+use crate::transform::AtomicTransform;
+#[cfg(any(feature = "forward-writes", feature = "timer-watchdog"))]
+use crate::unicode_string::OwnedUnicodeString;
+#[cfg(feature = "idle-power-policy")]
+use crate::wdf_device::{assign_s0_idle_settings, assign_sx_wake_settings};
+#[cfg(feature = "purge-on-surprise-removal")]
+use crate::wdf_queue::Queue;
+#[cfg(feature = "timer-watchdog")]
+use crate::WATCHDOG_THRESHOLD_VALUE_NAME;
 use crate::{
+    driver::echo_create_version_string,
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    paged_code::paged_code_checked,
     queue::echo_queue_initialize,
     queue_get_context,
-    wdf_object_context::wdf_get_context_type_info,
+    teardown::tear_down,
+    wdf_device::create_device_interface,
+    wdf_object_attributes::{allocate_context, ObjectAttributes},
     wdf_object_get_device_context,
+    wdf_object_get_device_stats_context,
+    wdf_structure_size::wdf_structure_size,
     DeviceContext,
-    KeGetCurrentIrql,
+    DeviceStatsContext,
+    Request,
+    RequestContext,
     GUID_DEVINTERFACE_ECHO,
-    WDF_DEVICE_CONTEXT_TYPE_INFO,
-    WDF_OBJECT_ATTRIBUTES_SIZE,
-    WDF_PNPPOWER_EVENT_CALLBACKS_SIZE,
-    WDF_REQUEST_CONTEXT_TYPE_INFO,
 };
+#[cfg(feature = "forward-writes")]
+use crate::{unicode_string, wdf_io_target::IoTarget, FORWARD_TARGET_VALUE_NAME};
+#[cfg(feature = "restricted-access")]
+use crate::{wdf_device::assign_sddl, ECHO_DEVICE_SDDL};
+#[cfg(feature = "named-device")]
+use crate::{
+    wdf_device::{assign_name, create_symbolic_link},
+    ECHO_DEVICE_NAME,
+};
+
+extern crate alloc;
+
+use alloc::format;
+#[cfg(feature = "forward-writes")]
+use alloc::string::String;
+
+/// Time the device stays idle in D0 before the framework powers it down, with
+/// the `idle-power-policy` feature
+#[cfg(feature = "idle-power-policy")]
+const IDLE_TIMEOUT_MS: ULONG = 5000;
+
+/// Most reads the default queue holds at once until
+/// `IOCTL_ECHO_SET_MAX_PENDING` changes it, with the `pending-limit` feature
+#[cfg(feature = "pending-limit")]
+const DEFAULT_MAX_PENDING_REQUESTS: u32 = 64;
+
+/// Instance number given to the next device created by `echo_device_create`
+static NEXT_INSTANCE: AtomicU32 = AtomicU32::new(0);
 
 /// Worker routine called to create a device and its software resources.
 ///
@@ -42,19 +123,27 @@
 ///
 /// # Return value:
 ///
-/// * `NTSTATUS`
+/// * `Ok(())` on success,
+/// * `Err(NTSTATUS)` - the status of the first step that failed.
 #[link_section = "PAGE"]
-pub fn echo_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
-    paged_code!();
+pub fn echo_device_create(mut device_init: &mut WDFDEVICE_INIT) -> Result<(), NTSTATUS> {
+    paged_code_checked!();
 
     // Register pnp/power callbacks so that we can start and stop the timer as the
-    // device gets started and stopped.
+    // device gets started and stopped. The PrepareHardware and D0 callbacks only
+    // log the transitions, so they can be followed in the debugger.
     let mut pnp_power_callbacks = WDF_PNPPOWER_EVENT_CALLBACKS {
-        Size: WDF_PNPPOWER_EVENT_CALLBACKS_SIZE,
+        Size: wdf_structure_size!(WDF_PNPPOWER_EVENT_CALLBACKS),
+        EvtDevicePrepareHardware: Some(echo_evt_device_prepare_hardware),
+        EvtDeviceD0Entry: Some(echo_evt_device_d0_entry),
+        EvtDeviceD0Exit: Some(echo_evt_device_d0_exit),
         EvtDeviceSelfManagedIoInit: Some(echo_evt_device_self_managed_io_start),
         EvtDeviceSelfManagedIoSuspend: Some(echo_evt_device_self_managed_io_suspend),
         // Function used for both Init and Restart Callbacks
         EvtDeviceSelfManagedIoRestart: Some(echo_evt_device_self_managed_io_start),
+        EvtDeviceSelfManagedIoFlush: Some(echo_evt_device_self_managed_io_flush),
+        EvtDeviceSelfManagedIoCleanup: Some(echo_evt_device_self_managed_io_cleanup),
+        EvtDeviceSurpriseRemoval: Some(echo_evt_device_surprise_removal),
         ..WDF_PNPPOWER_EVENT_CALLBACKS::default()
     };
 
@@ -68,14 +157,76 @@ pub fn echo_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
         );
     };
 
-    let mut attributes = WDF_OBJECT_ATTRIBUTES {
-        Size: WDF_OBJECT_ATTRIBUTES_SIZE,
-        ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
-        SynchronizationScope: _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
-        ContextTypeInfo: wdf_get_context_type_info!(RequestContext),
-        ..WDF_OBJECT_ATTRIBUTES::default()
+    // With the `idle-power-policy` feature, register the power policy callbacks
+    // that log when the device is armed and disarmed to wake the system. The
+    // idle and wake settings themselves are assigned once the device is
+    // created.
+    #[cfg(feature = "idle-power-policy")]
+    {
+        let mut power_policy_callbacks = WDF_POWER_POLICY_EVENT_CALLBACKS {
+            Size: wdf_structure_size!(WDF_POWER_POLICY_EVENT_CALLBACKS),
+            EvtDeviceArmWakeFromSx: Some(echo_evt_device_arm_wake_from_sx),
+            EvtDeviceDisarmWakeFromSx: Some(echo_evt_device_disarm_wake_from_sx),
+            EvtDeviceWakeFromSxTriggered: Some(echo_evt_device_wake_from_sx_triggered),
+            ..WDF_POWER_POLICY_EVENT_CALLBACKS::default()
+        };
+
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceInitSetPowerPolicyEventCallbacks,
+                device_init,
+                &mut power_policy_callbacks
+            );
+        };
+    }
+
+    // Register the file object callbacks, so that the device can count the
+    // handles opened on it. No context is needed for the file objects.
+    let mut file_object_config = WDF_FILEOBJECT_CONFIG {
+        Size: wdf_structure_size!(WDF_FILEOBJECT_CONFIG),
+        EvtDeviceFileCreate: Some(echo_evt_device_file_create),
+        EvtFileClose: Some(echo_evt_file_close),
+        AutoForwardCleanupClose: _WDF_TRI_STATE::WdfUseDefault,
+        FileObjectClass: _WDF_FILEOBJECT_CLASS::WdfFileObjectWdfCannotUseFsContexts,
+        ..WDF_FILEOBJECT_CONFIG::default()
+    };
+
+    unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitSetFileObjectConfig,
+            device_init,
+            &mut file_object_config,
+            WDF_NO_OBJECT_ATTRIBUTES
+        );
+    };
+
+    // Select how the I/O manager passes the buffers of read and write requests to
+    // the driver. Buffered I/O is the framework default, direct I/O is selected
+    // with the `direct-io` feature. This has to be done before the device is
+    // created.
+    let io_type = if cfg!(feature = "direct-io") {
+        _WDF_DEVICE_IO_TYPE::WdfDeviceIoDirect
+    } else {
+        _WDF_DEVICE_IO_TYPE::WdfDeviceIoBuffered
+    };
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfDeviceInitSetIoType, device_init, io_type);
     };
 
+    // With the `method-neither` feature, see requests in the context of the
+    // thread that sent them, where the buffers of METHOD_NEITHER control codes
+    // can be locked, before they are queued
+    #[cfg(feature = "method-neither")]
+    unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitSetIoInCallerContextCallback,
+            device_init,
+            Some(echo_evt_io_in_caller_context)
+        );
+    };
+
+    let mut attributes = ObjectAttributes::new().context::<RequestContext>().build();
+
     unsafe {
         call_unsafe_wdf_function_binding!(
             WdfDeviceInitSetRequestAttributes,
@@ -84,16 +235,56 @@ pub fn echo_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
         );
     };
 
-    let mut attributes = WDF_OBJECT_ATTRIBUTES {
-        Size: WDF_OBJECT_ATTRIBUTES_SIZE,
-        ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
-        SynchronizationScope: _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
-        ContextTypeInfo: wdf_get_context_type_info!(DeviceContext),
-        ..WDF_OBJECT_ATTRIBUTES::default()
-    };
+    // The instance number is taken before the device is created, since it is
+    // also part of the device object name with the `named-device` feature.
+    let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+
+    // With the `named-device` feature, name the device object so that a
+    // symbolic link can point to it. Each instance gets its own name, since
+    // WdfDeviceCreate fails if the name is already taken.
+    #[cfg(feature = "named-device")]
+    assign_name(
+        device_init,
+        &format!("\\Device\\{ECHO_DEVICE_NAME}{instance}"),
+    )
+    .map_err(|nt_status| {
+        log_error!("WdfDeviceInitAssignName failed {}", NtStatus(nt_status));
+        nt_status
+    })?;
+
+    // With the `restricted-access` feature, only let the system and the
+    // administrators open the device. FILE_DEVICE_SECURE_OPEN makes the I/O
+    // manager check the security descriptor for opens of any path below the
+    // device name too, e.g. \\.\RustEcho\anything, which would bypass it
+    // otherwise.
+    #[cfg(feature = "restricted-access")]
+    {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceInitSetDeviceType,
+                device_init,
+                FILE_DEVICE_UNKNOWN
+            );
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceInitSetCharacteristics,
+                device_init,
+                FILE_DEVICE_SECURE_OPEN,
+                u8::from(true)
+            );
+        };
+        assign_sddl(device_init, ECHO_DEVICE_SDDL).map_err(|nt_status| {
+            log_error!(
+                "WdfDeviceInitAssignSDDLString failed {}",
+                NtStatus(nt_status)
+            );
+            nt_status
+        })?;
+    }
+
+    let mut attributes = ObjectAttributes::new().context::<DeviceContext>().build();
 
     let mut device = WDF_NO_HANDLE as WDFDEVICE;
-    let mut nt_status = unsafe {
+    let nt_status = unsafe {
         call_unsafe_wdf_function_binding!(
             WdfDeviceCreate,
             (core::ptr::addr_of_mut!(device_init)).cast(),
@@ -102,33 +293,476 @@ pub fn echo_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
         )
     };
 
-    if nt_success(nt_status) {
-        // Get the device context and initialize it. WdfObjectGet_DEVICE_CONTEXT is an
-        // inline function generated by WDF_DECLARE_CONTEXT_TYPE macro in the
-        // device.h header file. This function will do the type checking and return
-        // the device context. If you pass a wrong object  handle
-        // it will return NULL and assert if run under framework verifier mode.
-        let device_context: *mut DeviceContext =
-            unsafe { wdf_object_get_device_context(device as WDFOBJECT) };
-        unsafe { (*device_context).private_device_data = 0 };
-
-        // Create a device interface so that application can find and talk
-        // to us.
-        nt_status = unsafe {
-            call_unsafe_wdf_function_binding!(
-                WdfDeviceCreateDeviceInterface,
-                device,
-                &GUID_DEVINTERFACE_ECHO,
-                core::ptr::null_mut(),
-            )
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceCreate failed {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    // From here on, the device exists, and a failure is returned from
+    // EvtDriverDeviceAdd like any other. The framework then deletes the device,
+    // and with it everything parented to it. The device must not be deleted
+    // here with WdfObjectDelete, which the framework would do a second time.
+    echo_device_initialize(device, instance).map_err(|nt_status| {
+        log_error!(
+            "Initializing device {device:?} failed {}, the framework deletes it",
+            NtStatus(nt_status)
+        );
+        nt_status
+    })
+}
+
+/// Initialize the contexts of `device`, just created by `echo_device_create`,
+/// and create its software resources: its device interface, symbolic link,
+/// I/O target and queues.
+///
+/// Every object created here is parented to the device, directly or through
+/// its queues, and the device interface and symbolic link are removed along
+/// with it. If a step fails, the ones before it are not undone here: the
+/// error is returned to the framework, which deletes the device and all of
+/// them with it, so that nothing is leaked and nothing is deleted twice. This
+/// only works as long as no step creates a resource outside of the object
+/// tree of the device, e.g. a pool allocation that is not a `WDFMEMORY`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to the device created by `echo_device_create`.
+/// * `instance` - Instance number of the device.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success,
+/// * `Err(NTSTATUS)` - the status of the first step that failed.
+#[link_section = "PAGE"]
+fn echo_device_initialize(device: WDFDEVICE, instance: u32) -> Result<(), NTSTATUS> {
+    paged_code_checked!();
+
+    // Get the device context and initialize it. WdfObjectGet_DEVICE_CONTEXT is an
+    // inline function generated by WDF_DECLARE_CONTEXT_TYPE macro in the
+    // device.h header file. This function will do the type checking and return
+    // the device context. If you pass a wrong object  handle
+    // it will return None and assert if run under framework verifier mode.
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
+    unsafe {
+        (*device_context).private_device_data = 0;
+        (*device_context).instance = instance;
+        (*device_context).manual_queue = core::ptr::null_mut();
+        (*device_context).open_count = AtomicU32::new(0);
+        (*device_context).shutting_down = AtomicBool::new(false);
+        (*device_context).removed = AtomicBool::new(false);
+        #[cfg(feature = "sequence-numbers")]
+        (*device_context).sequence_number = AtomicU64::new(0);
+        #[cfg(feature = "fault-injection")]
+        (*device_context).injected_status = AtomicI32::new(STATUS_SUCCESS);
+        #[cfg(feature = "latency-stats")]
+        (*device_context).latency_stats = LatencyStats::new();
+        #[cfg(feature = "memory-pressure")]
+        (*device_context).failing_allocations = AtomicU32::new(0);
+        #[cfg(feature = "pending-limit")]
+        (*device_context).max_pending_requests = AtomicU32::new(DEFAULT_MAX_PENDING_REQUESTS);
+        #[cfg(feature = "transform")]
+        (*device_context).transform = AtomicTransform::new();
+    };
+
+    // Attach the second context of the device, which WdfDeviceCreate did not
+    // allocate. It is retrieved by its own type, like the DeviceContext.
+    let stats_context =
+        allocate_context::<DeviceStatsContext>(device as WDFOBJECT).map_err(|nt_status| {
+            log_error!("WdfObjectAllocateContext failed {}", NtStatus(nt_status));
+            nt_status
+        })?;
+    unsafe {
+        (*stats_context).reads = AtomicU64::new(0);
+        (*stats_context).bytes_read = AtomicU64::new(0);
+        (*stats_context).writes = AtomicU64::new(0);
+        (*stats_context).bytes_written = AtomicU64::new(0);
+    };
+
+    // Keep the version string of the driver for IOCTL_ECHO_GET_WDF_VERSION,
+    // which cannot retrieve it at the IRQL it is dispatched at. The string
+    // object is deleted with the device.
+    let mut attributes = ObjectAttributes::new().parent(device as WDFOBJECT).build();
+    let version_string = echo_create_version_string(Some(&mut attributes))?;
+    unsafe { (*device_context).version_string = version_string };
+
+    // With the `idle-power-policy` feature, let the device idle in D3 and be
+    // powered back up when a request arrives. Waking the system is optional,
+    // since a root-enumerated device cannot do it.
+    #[cfg(feature = "idle-power-policy")]
+    {
+        assign_s0_idle_settings(device, IDLE_TIMEOUT_MS).map_err(|nt_status| {
+            log_error!(
+                "WdfDeviceAssignS0IdleSettings failed {}",
+                NtStatus(nt_status)
+            );
+            nt_status
+        })?;
+
+        if let Err(nt_status) = assign_sx_wake_settings(device) {
+            log_info!(
+                "WdfDeviceAssignSxWakeSettings failed {}, the device cannot wake the system",
+                NtStatus(nt_status)
+            );
+        }
+    }
+
+    // Create a device interface so that application can find and talk
+    // to us. The reference string is appended to the interface's symbolic
+    // link, so each instance of the device can be told apart when several
+    // are installed.
+    create_device_interface(
+        device,
+        &GUID_DEVINTERFACE_ECHO,
+        Some(&format!("Echo{instance}")),
+    )
+    .map_err(|nt_status| {
+        log_error!(
+            "WdfDeviceCreateDeviceInterface failed {}",
+            NtStatus(nt_status)
+        );
+        nt_status
+    })?;
+
+    #[cfg(feature = "named-device")]
+    echo_create_symbolic_link(device, instance)?;
+
+    // With the `forward-writes` feature, open the device the writes are
+    // forwarded to. The device still starts without it, it just does not
+    // forward anything.
+    #[cfg(feature = "forward-writes")]
+    {
+        let forward_target = echo_query_forward_target(device).and_then(|name| {
+            IoTarget::open_by_name(device, &name)
+                .map_err(|nt_status| {
+                    log_error!(
+                        "Opening forward target {name} failed {}",
+                        NtStatus(nt_status)
+                    );
+                })
+                .ok()
+        });
+        unsafe { (*device_context).forward_target = forward_target };
+    }
+
+    // With the `timer-watchdog` feature, read how long the timer may go without
+    // firing, before the queue and its watchdog are created
+    #[cfg(feature = "timer-watchdog")]
+    unsafe {
+        (*device_context).watchdog_threshold_ms = echo_query_watchdog_threshold(device);
+    }
+
+    // Initialize the I/O Package and any Queues
+    unsafe { echo_queue_initialize(device) }
+}
+
+/// Read the `ForwardTarget` value of the device hardware key, with the
+/// `forward-writes` feature. It names the device that the writes are forwarded
+/// to, e.g. `\Device\RustEcho1` or `\DosDevices\RustEcho`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * The name of the target device, or `None` if the value is not set, in which
+///   case nothing is forwarded.
+#[cfg(feature = "forward-writes")]
+#[link_section = "PAGE"]
+fn echo_query_forward_target(device: WDFDEVICE) -> Option<String> {
+    paged_code_checked!();
+
+    let value_name = OwnedUnicodeString::from_str(FORWARD_TARGET_VALUE_NAME).ok()?;
+
+    let mut key: WDFKEY = core::ptr::null_mut();
+    // SAFETY: `device` is a valid device created by `echo_device_create`
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceOpenRegistryKey,
+            device,
+            PLUGPLAY_REGKEY_DEVICE,
+            KEY_QUERY_VALUE,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut key,
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceOpenRegistryKey failed {}", NtStatus(nt_status));
+        return None;
+    }
+
+    let mut string: WDFSTRING = core::ptr::null_mut();
+    // SAFETY: The string is not parented to anything, and is deleted below
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfStringCreate,
+            core::ptr::null_mut(),
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut string
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("WdfStringCreate failed {}", NtStatus(nt_status));
+        // SAFETY: `key` was opened above and is not used after being closed
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRegistryClose, key);
+        };
+        return None;
+    }
+
+    // SAFETY: `key` and `string` were created above, and `value_name` outlives
+    // the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRegistryQueryString,
+            key,
+            value_name.as_unicode_string(),
+            string
+        )
+    };
+    let name = if nt_success(nt_status) {
+        let mut us = UNICODE_STRING::default();
+        // SAFETY: `string` was filled in above
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfStringGetUnicodeString, string, &mut us);
         };
+        // SAFETY: `us` describes the buffer of `string`, which is only deleted
+        // after the name is copied out of it.
+        Some(unsafe { unicode_string::to_string(&us) })
+    } else {
+        log_info!(
+            "{FORWARD_TARGET_VALUE_NAME} not read {}, not forwarding writes",
+            NtStatus(nt_status)
+        );
+        None
+    };
+
+    // SAFETY: `string` was created above and is not used after being deleted
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfObjectDelete, string as WDFOBJECT);
+    };
+    // SAFETY: `key` was opened above and is not used after being closed
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfRegistryClose, key);
+    };
+
+    name.filter(|name| !name.is_empty())
+}
 
-        if nt_success(nt_status) {
-            // Initialize the I/O Package and any Queues
-            nt_status = unsafe { echo_queue_initialize(device) };
+/// Read the `WatchdogThresholdMs` value of the device hardware key, with the
+/// `timer-watchdog` feature.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * The threshold of the watchdog in ms, or 0 if the value is not set, in
+///   which case the watchdog uses its default threshold.
+#[cfg(feature = "timer-watchdog")]
+#[link_section = "PAGE"]
+fn echo_query_watchdog_threshold(device: WDFDEVICE) -> ULONG {
+    paged_code_checked!();
+
+    let Ok(value_name) = OwnedUnicodeString::from_str(WATCHDOG_THRESHOLD_VALUE_NAME) else {
+        return 0;
+    };
+
+    let mut key: WDFKEY = core::ptr::null_mut();
+    // SAFETY: `device` is a valid device created by `echo_device_create`
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceOpenRegistryKey,
+            device,
+            PLUGPLAY_REGKEY_DEVICE,
+            KEY_QUERY_VALUE,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut key,
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceOpenRegistryKey failed {}", NtStatus(nt_status));
+        return 0;
+    }
+
+    let mut threshold_ms: ULONG = 0;
+    // SAFETY: `key` was opened above, and `value_name` outlives the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRegistryQueryULong,
+            key,
+            value_name.as_unicode_string(),
+            &mut threshold_ms
+        )
+    };
+    if nt_success(nt_status) {
+        log_info!("{WATCHDOG_THRESHOLD_VALUE_NAME} is {threshold_ms} ms");
+    } else {
+        log_info!(
+            "{WATCHDOG_THRESHOLD_VALUE_NAME} not read {}, using the default threshold",
+            NtStatus(nt_status)
+        );
+        threshold_ms = 0;
+    }
+
+    // SAFETY: `key` was opened above and is not used after being closed
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfRegistryClose, key);
+    };
+
+    threshold_ms
+}
+
+/// Create a symbolic link to the named device object of `device`, with the
+/// `named-device` feature, so that applications can open it without looking up
+/// its device interface.
+///
+/// The first device gets `\DosDevices\RustEcho`, i.e. `\\.\RustEcho`. While
+/// that link exists, the other devices fall back to a link named after their
+/// device object, e.g. `\\.\RustEcho1`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object, named with `assign_name`.
+/// * `instance` - Instance number of the device, part of its device object
+///   name.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success,
+/// * `Err(NTSTATUS)` - the status of `WdfDeviceCreateSymbolicLink`.
+#[cfg(feature = "named-device")]
+#[link_section = "PAGE"]
+fn echo_create_symbolic_link(device: WDFDEVICE, instance: ULONG) -> Result<(), NTSTATUS> {
+    paged_code_checked!();
+
+    let symbolic_link_name = format!("\\DosDevices\\{ECHO_DEVICE_NAME}");
+    let symbolic_link_name = match create_symbolic_link(device, &symbolic_link_name) {
+        Ok(()) => Ok(symbolic_link_name),
+        // Another device already has the short name
+        Err(STATUS_OBJECT_NAME_COLLISION) => {
+            let symbolic_link_name = format!("\\DosDevices\\{ECHO_DEVICE_NAME}{instance}");
+            create_symbolic_link(device, &symbolic_link_name).map(|()| symbolic_link_name)
         }
+        Err(nt_status) => Err(nt_status),
+    }
+    .map_err(|nt_status| {
+        log_error!("WdfDeviceCreateSymbolicLink failed {}", NtStatus(nt_status));
+        nt_status
+    })?;
+
+    log_info!("Created symbolic link {symbolic_link_name}");
+    Ok(())
+}
+
+/// This event is called by the Framework after the device has been started
+/// by the `PnP` manager and before it enters D0 for the first time. A driver
+/// for real hardware would map the resources assigned to it here; the echo
+/// device has none.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+/// * `_resources_raw` - Handle to the list of raw hardware resources.
+/// * `_resources_translated` - Handle to the list of translated hardware
+///   resources.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - Failures will result in the device stack being torn down.
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_prepare_hardware(
+    device: WDFDEVICE,
+    _resources_raw: WDFCMRESLIST,
+    _resources_translated: WDFCMRESLIST,
+) -> NTSTATUS {
+    paged_code_checked!();
+
+    log_info!("EchoEvtDevicePrepareHardware device {device:?}");
+
+    STATUS_SUCCESS
+}
+
+/// This event is called by the Framework every time the device enters D0,
+/// both when it is started and when it resumes from a low power state.
+///
+/// This function is not marked pageable because it is in the device power up
+/// path, see `echo_evt_device_self_managed_io_start`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+/// * `previous_state` - Power state the device is leaving.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - Failures will result in the device stack being torn down.
+extern "C" fn echo_evt_device_d0_entry(
+    device: WDFDEVICE,
+    previous_state: WDF_POWER_DEVICE_STATE,
+) -> NTSTATUS {
+    log_info!("EchoEvtDeviceD0Entry device {device:?}, previous state {previous_state:?}");
+
+    #[cfg(feature = "idle-power-policy")]
+    if echo_is_idle_transition(device, previous_state) {
+        log_info!("EchoEvtDeviceD0Entry device {device:?} is active again, a request arrived");
+    }
+
+    STATUS_SUCCESS
+}
+
+/// This event is called by the Framework every time the device leaves D0,
+/// when it is going to sleep, or is stopped or removed.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+/// * `target_state` - Power state the device is entering.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - Failures will result in the device stack being torn down.
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_d0_exit(
+    device: WDFDEVICE,
+    target_state: WDF_POWER_DEVICE_STATE,
+) -> NTSTATUS {
+    paged_code_checked!();
+
+    log_info!("EchoEvtDeviceD0Exit device {device:?}, target state {target_state:?}");
+
+    #[cfg(feature = "idle-power-policy")]
+    if echo_is_idle_transition(device, target_state) {
+        log_info!("EchoEvtDeviceD0Exit device {device:?} is idle, powering down");
     }
-    nt_status
+
+    STATUS_SUCCESS
+}
+
+/// Whether the device is entering or leaving D0 on its own, with the
+/// `idle-power-policy` feature: powering down after being idle for
+/// `IDLE_TIMEOUT_MS`, or back up for a request. The system is not changing
+/// power state then, and the device is neither being started for the first
+/// time nor removed, which are also transitions to and from `D3Final`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+/// * `dx_state` - Low power state the device is leaving or entering.
+///
+/// # Return value:
+///
+/// * `true` for an idle transition, `false` otherwise.
+#[cfg(feature = "idle-power-policy")]
+fn echo_is_idle_transition(device: WDFDEVICE, dx_state: WDF_POWER_DEVICE_STATE) -> bool {
+    let system_power_action =
+        unsafe { call_unsafe_wdf_function_binding!(WdfDeviceGetSystemPowerAction, device) };
+    system_power_action == _POWER_ACTION::PowerActionNone
+        && dx_state != _WDF_POWER_DEVICE_STATE::WdfPowerDeviceD3Final
 }
 
 /// This event is called by the Framework when the device is started
@@ -152,13 +786,16 @@ extern "C" fn echo_evt_device_self_managed_io_start(device: WDFDEVICE) -> NTSTAT
     // into low power state.
     let queue: WDFQUEUE;
 
-    println!("--> EchoEvtDeviceSelfManagedIoInit");
+    log_info!("--> EchoEvtDeviceSelfManagedIoInit");
 
     unsafe {
         queue = call_unsafe_wdf_function_binding!(WdfDeviceGetDefaultQueue, device);
     };
 
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return STATUS_INVALID_DEVICE_STATE;
+    };
 
     // Restart the queue and the periodic timer. We stopped them before going
     // into low power state.
@@ -166,9 +803,26 @@ extern "C" fn echo_evt_device_self_managed_io_start(device: WDFDEVICE) -> NTSTAT
 
     let due_time: i64 = -(100) * (10000);
 
+    // With the `adaptive-timer` feature, the timer starts itself again each
+    // time it fires, until the device is suspended
+    #[cfg(feature = "adaptive-timer")]
+    unsafe {
+        (*queue_context).timer_running.store(true, Ordering::SeqCst);
+    }
+
     let _ = unsafe { (*queue_context).timer.start(due_time) };
 
-    println!("<-- EchoEvtDeviceSelfManagedIoInit");
+    // With the `timer-watchdog` feature, watch the timer again. The time the
+    // device was suspended does not count as the timer being stuck.
+    #[cfg(feature = "timer-watchdog")]
+    unsafe {
+        (*queue_context)
+            .watchdog
+            .feed(KeQueryUnbiasedInterruptTime());
+        let _ = (*queue_context).watchdog_timer.start(due_time);
+    }
+
+    log_info!("<-- EchoEvtDeviceSelfManagedIoInit");
 
     STATUS_SUCCESS
 }
@@ -187,9 +841,9 @@ extern "C" fn echo_evt_device_self_managed_io_start(device: WDFDEVICE) -> NTSTAT
 ///   the device stack will be torn down.
 #[link_section = "PAGE"]
 unsafe extern "C" fn echo_evt_device_self_managed_io_suspend(device: WDFDEVICE) -> NTSTATUS {
-    paged_code!();
+    paged_code_checked!();
 
-    println!("--> EchoEvtDeviceSelfManagedIoSuspend");
+    log_info!("--> EchoEvtDeviceSelfManagedIoSuspend");
 
     // Before we stop the timer we should make sure there are no outstanding
     // i/o. We need to do that because framework cannot suspend the device
@@ -201,16 +855,331 @@ extern "C" fn echo_evt_device_self_managed_io_start(device: WDFDEVICE) -> NTSTAT
     // because it's pretty easy to do. We will restart the queue when the
     // device is restarted.
     let queue = unsafe { call_unsafe_wdf_function_binding!(WdfDeviceGetDefaultQueue, device) };
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        // This callback is not allowed to fail
+        log_error!("Queue {queue:?} has no QueueContext");
+        return STATUS_SUCCESS;
+    };
 
     unsafe {
         call_unsafe_wdf_function_binding!(WdfIoQueueStopSynchronously, queue);
         // Stop the watchdog timer and wait for DPC to run to completion if it's already
         // fired.
+        #[cfg(feature = "adaptive-timer")]
+        (*queue_context)
+            .timer_running
+            .store(false, Ordering::SeqCst);
+        // With the `timer-watchdog` feature, stop watching the timer before
+        // stopping it
+        #[cfg(feature = "timer-watchdog")]
+        let _ = (*queue_context).watchdog_timer.stop(true);
         let _ = (*queue_context).timer.stop(true);
+        // With the `adaptive-timer` feature, a callback that was running may
+        // have started the timer again before seeing it stopped. Any callback
+        // that could has returned by now, so stopping it once more is enough.
+        #[cfg(feature = "adaptive-timer")]
+        let _ = (*queue_context).timer.stop(false);
+        // With the `one-shot-timer` feature, a request arriving after the
+        // restart must arm the timer again
+        #[cfg(feature = "one-shot-timer")]
+        (*queue_context).timer_armed.store(false, Ordering::SeqCst);
+        // With the `dpc-completion` feature, also wait for a queued DPC
+        #[cfg(feature = "dpc-completion")]
+        let _ = (*queue_context).dpc.cancel(true);
+        // With the `wait-lock` feature, also wait for the work item the timer may
+        // have queued
+        #[cfg(feature = "wait-lock")]
+        (*queue_context).work_item.flush();
     };
 
-    println!("<-- EchoEvtDeviceSelfManagedIoSuspend");
+    log_info!("<-- EchoEvtDeviceSelfManagedIoSuspend");
 
     STATUS_SUCCESS
 }
+
+/// This event is called by the Framework when the device is removed,
+/// including surprise removal, after its queues have been purged. It is the
+/// last chance to reject new file objects before the device goes away.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_self_managed_io_flush(device: WDFDEVICE) {
+    paged_code_checked!();
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return;
+    };
+
+    // The queues have already been purged, so the framework fails any new
+    // read or write. Creates are not queued, so they are rejected in
+    // echo_evt_device_file_create instead.
+    let open_count = unsafe {
+        (*device_context)
+            .shutting_down
+            .store(true, Ordering::Release);
+        (*device_context).open_count.load(Ordering::Acquire)
+    };
+
+    log_info!("EchoEvtDeviceSelfManagedIoFlush device {device:?}, {open_count} handles still open");
+
+    // The statistics are in the second context of the device, found by its type
+    if let Some(stats_context) = unsafe { wdf_object_get_device_stats_context(device as WDFOBJECT) }
+    {
+        let (reads, bytes_read, writes, bytes_written) = unsafe {
+            (
+                (*stats_context).reads.load(Ordering::Relaxed),
+                (*stats_context).bytes_read.load(Ordering::Relaxed),
+                (*stats_context).writes.load(Ordering::Relaxed),
+                (*stats_context).bytes_written.load(Ordering::Relaxed),
+            )
+        };
+        log_info!(
+            "EchoEvtDeviceSelfManagedIoFlush device {device:?}, {reads} reads of {bytes_read} \
+             bytes, {writes} writes of {bytes_written} bytes"
+        );
+    }
+}
+
+/// This event is called by the Framework when the device is removed, after
+/// `echo_evt_device_self_managed_io_flush`, and before its queues are deleted.
+/// It is the last callback that can use the handle of the timer.
+///
+/// The timer of the default queue was already stopped by
+/// `echo_evt_device_self_managed_io_suspend`. It is marked as torn down, so
+/// that it returns right away if it is started again anyway, and stopped once
+/// more, waiting for a running callback to return, so that none is left
+/// running when the queue context is dropped. See the [`Drop`] implementation
+/// of `QueueContext` for the ordering the teardown relies on.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_self_managed_io_cleanup(device: WDFDEVICE) {
+    paged_code_checked!();
+
+    log_info!("--> EchoEvtDeviceSelfManagedIoCleanup");
+
+    let queue = unsafe { call_unsafe_wdf_function_binding!(WdfDeviceGetDefaultQueue, device) };
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
+
+    unsafe {
+        tear_down(
+            &(*queue_context).torn_down,
+            &[
+                #[cfg(feature = "timer-watchdog")]
+                &(*queue_context).watchdog_timer,
+                &(*queue_context).timer,
+            ],
+        );
+    }
+
+    log_info!("<-- EchoEvtDeviceSelfManagedIoCleanup");
+}
+
+/// This event is called by the Framework when the device has been removed
+/// without warning, e.g. unplugged, before it is powered down
+/// (`IRP_MN_SURPRISE_REMOVAL`).
+///
+/// The device is marked as removed, so that the reads, writes and control
+/// requests presented from now on, while the application still has handles
+/// open, fail with `STATUS_DEVICE_REMOVED` instead of being held for a device
+/// that is gone. The requests the driver already holds are still completed by
+/// the timer until the device is suspended.
+///
+/// With the `purge-on-surprise-removal` feature, both queues are also purged,
+/// so that the requests the driver holds are cancelled right away instead of
+/// being left to the timer, and the writes waiting in the manual queue are
+/// cancelled by the framework. The number of requests each queue held is
+/// logged.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_surprise_removal(device: WDFDEVICE) {
+    paged_code_checked!();
+
+    log_info!("--> EchoEvtDeviceSurpriseRemoval device {device:?}");
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return;
+    };
+
+    // Marked before any purge, so that a request presented while the queues
+    // are purged is failed rather than held
+    unsafe {
+        (*device_context).removed.store(true, Ordering::Release);
+    }
+
+    #[cfg(feature = "purge-on-surprise-removal")]
+    {
+        // The default queue goes first, so that it no longer forwards writes
+        // to the manual queue once that one is purged
+        let default_queue =
+            unsafe { call_unsafe_wdf_function_binding!(WdfDeviceGetDefaultQueue, device) };
+        let manual_queue = unsafe { (*device_context).manual_queue };
+
+        for (name, queue) in [("default", default_queue), ("manual", manual_queue)] {
+            // SAFETY: Both queues are children of the device, which is not
+            // deleted before this callback returns.
+            let queue = unsafe { Queue::from_raw(queue) };
+            let counts = queue.request_counts();
+
+            // The timer keeps running until the device is suspended, so a
+            // request it is completing, and that can no longer be cancelled,
+            // still completes while the purge waits for it
+            queue.purge_synchronously();
+
+            log_info!(
+                "EchoEvtDeviceSurpriseRemoval purged the {name} queue, which held {} queued and \
+                 {} driver-owned requests",
+                counts.queued,
+                counts.driver_owned
+            );
+        }
+    }
+
+    log_info!("<-- EchoEvtDeviceSurpriseRemoval");
+}
+
+/// This event is called by the Framework with the `idle-power-policy` feature,
+/// when the system is about to enter a sleep state and the device is armed to
+/// wake it. A driver for real hardware would enable the wake signal of the
+/// hardware here.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - A failure leaves the device disarmed.
+#[cfg(feature = "idle-power-policy")]
+extern "C" fn echo_evt_device_arm_wake_from_sx(device: WDFDEVICE) -> NTSTATUS {
+    log_info!("EchoEvtDeviceArmWakeFromSx device {device:?}");
+
+    STATUS_SUCCESS
+}
+
+/// This event is called by the Framework with the `idle-power-policy` feature,
+/// when the system has returned to S0 and the device is no longer armed to wake
+/// it.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "idle-power-policy")]
+extern "C" fn echo_evt_device_disarm_wake_from_sx(device: WDFDEVICE) {
+    log_info!("EchoEvtDeviceDisarmWakeFromSx device {device:?}");
+}
+
+/// This event is called by the Framework with the `idle-power-policy` feature,
+/// when the device woke the system from a sleep state.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "idle-power-policy")]
+extern "C" fn echo_evt_device_wake_from_sx_triggered(device: WDFDEVICE) {
+    log_info!("EchoEvtDeviceWakeFromSxTriggered device {device:?}");
+}
+
+/// This event is called by the Framework when an application opens a handle
+/// to the device (`IRP_MJ_CREATE`). The request must be completed here, with
+/// a failure status to refuse the open.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+/// * `request` - Handle to the framework request object of the create.
+/// * `file_object` - Handle to the framework file object being created.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_device_file_create(
+    device: WDFDEVICE,
+    request: WDFREQUEST,
+    file_object: WDFFILEOBJECT,
+) {
+    paged_code_checked!();
+
+    // SAFETY: The framework hands ownership of the create request to this
+    // callback
+    let request = unsafe { Request::from_raw(request) };
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        request.complete(STATUS_INVALID_DEVICE_STATE);
+        return;
+    };
+
+    if unsafe { (*device_context).shutting_down.load(Ordering::Acquire) } {
+        log_info!("EchoEvtDeviceFileCreate file {file_object:?} rejected, device is being removed");
+        request.complete(STATUS_DELETE_PENDING);
+        return;
+    }
+
+    let open_count = unsafe { (*device_context).open_count.fetch_add(1, Ordering::AcqRel) } + 1;
+    log_info!("EchoEvtDeviceFileCreate file {file_object:?}, {open_count} handles open");
+
+    request.complete(STATUS_SUCCESS);
+}
+
+/// This event is called by the Framework when the last reference to a file
+/// object opened by `echo_evt_device_file_create` is released
+/// (`IRP_MJ_CLOSE`), after all of its requests have been completed.
+///
+/// # Arguments:
+///
+/// * `file_object` - Handle to the framework file object being closed.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_file_close(file_object: WDFFILEOBJECT) {
+    paged_code_checked!();
+
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfFileObjectGetDevice, file_object) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return;
+    };
+
+    let open_count = unsafe { (*device_context).open_count.fetch_sub(1, Ordering::AcqRel) } - 1;
+    log_info!("EchoEvtFileClose file {file_object:?}, {open_count} handles open");
+}
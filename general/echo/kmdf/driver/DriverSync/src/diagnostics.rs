@@ -0,0 +1,35 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! State of the default queue, returned to applications by
+//! `IOCTL_ECHO_GET_QUEUE_STATE` with the `queue-diagnostics` feature.
+//!
+//! The cancel completion ownership count and the state of the current request
+//! otherwise only show in the debugger. Returning them lets a test see the
+//! cancel protocol at work from user mode, e.g. that a read held for the timer
+//! has the initial count, and that the queue is idle again once it has been
+//! cancelled. The state is read under the queue context lock, so it is
+//! consistent, but may have changed by the time the application sees it.
+//!
+//! The feature is only meant for debug builds, and the driver does not build
+//! with it in release.
+
+/// State of the default queue returned by `IOCTL_ECHO_GET_QUEUE_STATE`. The
+/// layout is shared with the applications reading it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EchoQueueState {
+    /// Number of requests waiting for the timer: 0 or 1 for the current
+    /// request of the sequential queue, or the number of pending requests with
+    /// the `parallel-queue` feature
+    pub pending_requests: u32,
+    /// `RequestState` of the current request of the sequential queue, always
+    /// 0 (`Idle`) with the `parallel-queue` feature
+    pub current_request_state: u32,
+    /// Cancel completion ownership count of the current request, or of the
+    /// first pending request with the `parallel-queue` feature. 0 when no
+    /// request is waiting.
+    pub ownership_count: i32,
+    /// Status the timer completes the current request with
+    pub current_status: i32,
+}
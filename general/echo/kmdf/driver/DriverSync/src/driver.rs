@@ -1,11 +1,11 @@
 // Copyright (c) Microsoft Corporation.
 // License: MIT OR Apache-2.0
 
-use wdk::{nt_success, paged_code, println};
+use wdk::nt_success;
+#[cfg(feature = "heap-free")]
+use wdk_sys::ntddk::DbgPrint;
 use wdk_sys::{
     call_unsafe_wdf_function_binding,
-    ntddk::KeGetCurrentIrql,
-    APC_LEVEL,
     DRIVER_OBJECT,
     NTSTATUS,
     PCUNICODE_STRING,
@@ -16,17 +16,25 @@
     WDFDRIVER,
     WDFOBJECT,
     WDFSTRING,
-    WDF_DRIVER_CONFIG,
     WDF_DRIVER_VERSION_AVAILABLE_PARAMS,
     WDF_NO_HANDLE,
     WDF_NO_OBJECT_ATTRIBUTES,
+    WDF_OBJECT_ATTRIBUTES,
 };
 
-use crate::{device, WDF_DRIVER_CONFIG_SIZE, WDF_DRIVER_VERSION_AVAILABLE_PARAMS_SIZE};
-
-extern crate alloc;
-
-use alloc::{slice, string::String};
+use crate::{
+    config,
+    device,
+    driver_get_context,
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    paged_code::paged_code_checked,
+    unicode_string,
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_structure_size::wdf_structure_size,
+    DriverContext,
+};
 
 /// `DriverEntry` initializes the driver and is the first routine called by the
 /// system after the driver is loaded. `DriverEntry` specifies the other entry
@@ -53,34 +61,112 @@ extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
     registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
-    let mut driver_config = WDF_DRIVER_CONFIG {
-        Size: WDF_DRIVER_CONFIG_SIZE,
-        EvtDriverDeviceAdd: Some(echo_evt_device_add),
-        ..WDF_DRIVER_CONFIG::default()
-    };
-    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+    #[cfg(feature = "log-etw")]
+    crate::log::initialize();
+
+    // The driver works the same without the events, so failing to register
+    // their provider is only logged
+    #[cfg(feature = "etw-events")]
+    if let Err(nt_status) = crate::etw_events::ECHO_EVENTS.register() {
+        log_error!("Error: EtwRegister failed {}", NtStatus(nt_status));
+    }
+
+    // WPP_INIT_TRACING must be called before WdfDriverCreate
+    #[cfg(feature = "wpp-tracing")]
+    // SAFETY: These are the arguments of DriverEntry
+    unsafe {
+        crate::wpp::initialize(core::ptr::from_mut(driver), registry_path);
+    }
+
+    let mut driver_config = DriverConfig::new(Some(echo_evt_device_add))
+        .unload(Some(echo_evt_driver_unload))
+        .build();
+    let mut attributes = ObjectAttributes::new().context::<DriverContext>().build();
+    let mut driver_handle = WDF_NO_HANDLE as WDFDRIVER;
 
     let nt_status = unsafe {
         call_unsafe_wdf_function_binding!(
             WdfDriverCreate,
             driver as PDRIVER_OBJECT,
             registry_path,
-            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut attributes,
             &mut driver_config,
-            driver_handle_output,
+            &mut driver_handle,
         )
     };
 
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_driver_entry(nt_status);
+
     if !nt_success(nt_status) {
-        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        log_error!("Error: WdfDriverCreate failed {}", NtStatus(nt_status));
+        #[cfg(feature = "wpp-tracing")]
+        // SAFETY: The driver object is the one WPP was initialized with
+        unsafe {
+            crate::wpp::cleanup(driver);
+        }
+        #[cfg(feature = "etw-events")]
+        crate::etw_events::ECHO_EVENTS.unregister();
+        #[cfg(feature = "log-etw")]
+        crate::log::uninitialize();
         return nt_status;
     }
 
+    // Read the tunables before any device is added, so that every device sees
+    // the same configuration
+    let Some(driver_context) = (unsafe { driver_get_context(driver_handle as WDFOBJECT) }) else {
+        log_error!("Driver {driver_handle:?} has no DriverContext");
+        return nt_status;
+    };
+    unsafe { (*driver_context).config = config::DriverConfig::read(driver_handle) };
+
     echo_print_driver_version();
 
     nt_status
 }
 
+/// `EvtDriverUnload` is called by the framework before the driver is unloaded.
+/// With the `log-etw` logging backend, it unregisters the ETW provider, and
+/// with the `etw-events` feature, the provider of the request events. With the
+/// `wpp-tracing` feature, it cleans up WPP.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+#[link_section = "PAGE"]
+extern "C" fn echo_evt_driver_unload(_driver: WDFDRIVER) {
+    paged_code_checked!();
+
+    // Every deferred request has been completed with its device, and the
+    // reference on it released
+    #[cfg(debug_assertions)]
+    debug_assert_eq!(
+        crate::wdf_object_reference::outstanding_references(),
+        0,
+        "WDF object references leaked"
+    );
+
+    #[cfg(feature = "etw-events")]
+    crate::etw_events::ECHO_EVENTS.unregister();
+
+    #[cfg(feature = "wpp-tracing")]
+    {
+        crate::wpp::trace_driver_unload();
+        let driver = unsafe { (*wdk_sys::WdfDriverGlobals).Driver };
+        // SAFETY: WDF returns the driver object passed to DriverEntry, which WPP
+        // was initialized with, and EvtDriverUnload runs at PASSIVE_LEVEL
+        unsafe {
+            crate::wpp::cleanup(call_unsafe_wdf_function_binding!(
+                WdfDriverWdmGetDriverObject,
+                driver
+            ));
+        }
+    }
+
+    #[cfg(feature = "log-etw")]
+    crate::log::uninitialize();
+}
+
 /// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
 /// call from the `PnP` manager. We create and initialize a device object to
 /// represent a new instance of the device.
@@ -96,9 +182,9 @@ extern "system" fn driver_entry(
 ///   * `NTSTATUS`
 #[link_section = "PAGE"]
 extern "C" fn echo_evt_device_add(_driver: WDFDRIVER, device_init: PWDFDEVICE_INIT) -> NTSTATUS {
-    paged_code!();
+    paged_code_checked!();
 
-    println!("Enter  EchoEvtDeviceAdd");
+    log_info!("Enter  EchoEvtDeviceAdd");
 
     let device_init =
         // SAFETY: WDF should always be providing a pointer that is properly aligned, dereferencable per https://doc.rust-lang.org/std/ptr/index.html#safety, and initialized. For the lifetime of the resulting reference, the pointed-to memory is never accessed through any other pointer.
@@ -107,13 +193,28 @@ extern "C" fn echo_evt_device_add(_driver: WDFDRIVER, device_init: PWDFDEVICE_IN
             .as_mut()
             .expect("WDF should never provide a null pointer for device_init")
     };
-    device::echo_device_create(device_init)
+
+    // Convert the result back to the NTSTATUS the framework expects
+    let nt_status = match device::echo_device_create(device_init) {
+        Ok(()) => STATUS_SUCCESS,
+        Err(nt_status) => nt_status,
+    };
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_device_add(nt_status);
+
+    nt_status
 }
 
 /// This routine shows how to retrieve framework version string and
 /// also how to find out to which version of framework library the
 /// client driver is bound to.
 ///
+/// With the `heap-free` feature, it does not allocate from the heap: the
+/// version is copied to an array on the stack rather than a `String`, and
+/// printed with `DbgPrint` rather than the logging macros. The `WDFSTRING`
+/// itself is still allocated by the framework.
+///
 /// # Arguments:
 ///
 /// # Return value:
@@ -123,45 +224,40 @@ extern "C" fn echo_evt_device_add(_driver: WDFDRIVER, device_init: PWDFDEVICE_IN
 fn echo_print_driver_version() -> NTSTATUS {
     // 1) Retreive version string and print that in the debugger.
     //
-    let mut string: WDFSTRING = core::ptr::null_mut();
     let mut us: UNICODE_STRING = UNICODE_STRING::default();
-    let mut nt_status = unsafe {
-        call_unsafe_wdf_function_binding!(
-            WdfStringCreate,
-            core::ptr::null_mut(),
-            WDF_NO_OBJECT_ATTRIBUTES,
-            &mut string
-        )
-    };
-    if !nt_success(nt_status) {
-        println!("Error: WdfStringCreate failed {nt_status:#010X}");
-        return nt_status;
-    }
-
-    let driver = unsafe { (*wdk_sys::WdfDriverGlobals).Driver };
-    nt_status = unsafe {
-        call_unsafe_wdf_function_binding!(WdfDriverRetrieveVersionString, driver, string)
+    let string = match echo_create_version_string(None) {
+        Ok(string) => string,
+        Err(nt_status) => return nt_status,
     };
-    if !nt_success(nt_status) {
-        // No need to worry about delete the string object because
-        // by default it's parented to the driver and it will be
-        // deleted when the driverobject is deleted when the DriverEntry
-        // returns a failure status.
-        //
-        println!("Error: WdfDriverRetrieveVersionString failed {nt_status:#010X}");
-        return nt_status;
-    }
 
     unsafe {
         call_unsafe_wdf_function_binding!(WdfStringGetUnicodeString, string, &mut us);
     };
-    let driver_version = String::from_utf16_lossy(unsafe {
-        slice::from_raw_parts(
-            us.Buffer,
-            us.Length as usize / core::mem::size_of_val(&(*us.Buffer)),
-        )
-    });
-    println!("Echo Sample {driver_version}");
+
+    #[cfg(not(feature = "heap-free"))]
+    {
+        // SAFETY: `us` describes the buffer of `string`, which is only deleted
+        // after the version is copied out of it.
+        let driver_version = unsafe { unicode_string::to_string(&us) };
+        log_info!("Echo Sample {driver_version}");
+    }
+
+    // The logging macros format their message on the heap, so the version is
+    // copied to an array on the stack and printed with DbgPrint instead
+    #[cfg(feature = "heap-free")]
+    {
+        // SAFETY: `us` describes the buffer of `string`, which is only deleted
+        // after the version is copied out of it.
+        let driver_version: [u16; VERSION_STRING_CAPACITY] =
+            unsafe { unicode_string::to_fixed_buffer(&us) };
+        // SAFETY: The format string and `driver_version` are both
+        // null-terminated, and the `%ws` conversion consumes exactly the one
+        // argument passed. `%ws` requires PASSIVE_LEVEL, which is the IRQL of
+        // DriverEntry.
+        unsafe {
+            DbgPrint(c"Echo Sample %ws\n".as_ptr(), driver_version.as_ptr());
+        }
+    }
 
     unsafe {
         call_unsafe_wdf_function_binding!(WdfObjectDelete, string as WDFOBJECT);
@@ -169,19 +265,95 @@ fn echo_print_driver_version() -> NTSTATUS {
 
     // 2) Find out to which version of framework this driver is bound to.
     //
+    let driver = unsafe { (*wdk_sys::WdfDriverGlobals).Driver };
     let mut ver = WDF_DRIVER_VERSION_AVAILABLE_PARAMS {
-        Size: WDF_DRIVER_VERSION_AVAILABLE_PARAMS_SIZE,
+        Size: wdf_structure_size!(WDF_DRIVER_VERSION_AVAILABLE_PARAMS),
         MajorVersion: 1,
         MinorVersion: 0,
     };
 
-    if unsafe { call_unsafe_wdf_function_binding!(WdfDriverIsVersionAvailable, driver, &mut ver) }
-        > 0
-    {
-        println!("Yes, framework version is 1.0");
+    let version_available =
+        unsafe { call_unsafe_wdf_function_binding!(WdfDriverIsVersionAvailable, driver, &mut ver) }
+            > 0;
+
+    #[cfg(not(feature = "heap-free"))]
+    if version_available {
+        log_info!("Yes, framework version is 1.0");
     } else {
-        println!("No, framework version is not 1.0");
+        log_info!("No, framework version is not 1.0");
+    }
+
+    #[cfg(feature = "heap-free")]
+    {
+        let message = if version_available {
+            c"Yes, framework version is 1.0\n"
+        } else {
+            c"No, framework version is not 1.0\n"
+        };
+        // SAFETY: `message` is null-terminated and has no conversion
+        // specifications
+        unsafe {
+            DbgPrint(message.as_ptr());
+        }
     }
 
     STATUS_SUCCESS
 }
+
+/// Capacity of the array the version string of the driver is copied to with
+/// the `heap-free` feature, in UTF-16 code units including the null terminator
+#[cfg(feature = "heap-free")]
+const VERSION_STRING_CAPACITY: usize = 128;
+
+/// Create a string object holding the version string of the driver, as
+/// retrieved by `WdfDriverRetrieveVersionString`. Its content can then be read
+/// with `WdfStringGetUnicodeString`, which unlike creating it, can be called up
+/// to `DISPATCH_LEVEL`.
+///
+/// # Arguments:
+///
+/// * `attributes` - Attributes of the string object, e.g. to parent it to a
+///   device. Without them, it is parented to the driver.
+///
+/// # Return value:
+///
+/// * `Ok(WDFSTRING)` - the string object, which the caller must delete with
+///   `WdfObjectDelete` unless its parent is deleted first,
+/// * `Err(NTSTATUS)` - the status of the call that failed.
+#[link_section = "PAGE"]
+pub fn echo_create_version_string(
+    attributes: Option<&mut WDF_OBJECT_ATTRIBUTES>,
+) -> Result<WDFSTRING, NTSTATUS> {
+    paged_code_checked!();
+
+    let mut string: WDFSTRING = core::ptr::null_mut();
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfStringCreate,
+            core::ptr::null_mut(),
+            attributes.map_or(WDF_NO_OBJECT_ATTRIBUTES, core::ptr::from_mut),
+            &mut string
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("Error: WdfStringCreate failed {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    let driver = unsafe { (*wdk_sys::WdfDriverGlobals).Driver };
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfDriverRetrieveVersionString, driver, string)
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "Error: WdfDriverRetrieveVersionString failed {}",
+            NtStatus(nt_status)
+        );
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfObjectDelete, string as WDFOBJECT);
+        };
+        return Err(nt_status);
+    }
+
+    Ok(string)
+}
@@ -0,0 +1,244 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Structured ETW events for the lifetime of each request, with the
+//! `etw-events` feature.
+//!
+//! Unlike the `log-etw` logging backend, which writes formatted strings, the
+//! [`ECHO_EVENTS`] provider writes one event with binary fields when a request
+//! is presented to the driver, when it is completed and when it is cancelled,
+//! so they can be matched by request handle and timed in Windows Performance
+//! Analyzer without parsing any text:
+//!
+//! * `RequestStart` (id 1, start opcode): `Request` (u64), `MajorFunction`
+//!   (u32), `IoControlCode` (u32, 0 for reads and writes) and `Length` (u64)
+//! * `RequestComplete` (id 2, stop opcode): `Request` (u64), `Status` (i32) and
+//!   `Information` (u64)
+//! * `RequestCancel` (id 3, info opcode): `Request` (u64)
+//!
+//! The provider has no manifest, so the fields show up undecoded, in this
+//! order, in the payload of the events. Requests that the framework completes
+//! itself, e.g. writes cancelled while they wait in the manual queue, have no
+//! `RequestComplete` event.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use wdk::nt_success;
+use wdk_sys::{
+    ntddk::{EtwRegister, EtwUnregister, EtwWrite},
+    EVENT_DATA_DESCRIPTOR,
+    EVENT_DESCRIPTOR,
+    GUID,
+    IRP_MJ_DEVICE_CONTROL,
+    IRP_MJ_READ,
+    IRP_MJ_WRITE,
+    NTSTATUS,
+    REGHANDLE,
+    UCHAR,
+    ULONG,
+    ULONGLONG,
+    WDFREQUEST,
+};
+
+/// Provider of the request events.
+// {3F0B6D21-8C4A-4E57-9B12-6A7D5E0C4F93}
+pub const ECHO_EVENTS_PROVIDER: GUID = GUID {
+    Data1: 0x3F0B_6D21u32,
+    Data2: 0x8C4Au16,
+    Data3: 0x4E57u16,
+    Data4: [
+        0x9Bu8, 0x12u8, 0x6Au8, 0x7Du8, 0x5Eu8, 0x0Cu8, 0x4Fu8, 0x93u8,
+    ],
+};
+
+/// `TRACE_LEVEL_INFORMATION` from evntrace.h
+const TRACE_LEVEL_INFORMATION: UCHAR = 4;
+
+/// `WINEVENT_OPCODE_INFO` from winmeta.h
+const OPCODE_INFO: UCHAR = 0;
+
+/// `WINEVENT_OPCODE_START` from winmeta.h
+const OPCODE_START: UCHAR = 1;
+
+/// `WINEVENT_OPCODE_STOP` from winmeta.h
+const OPCODE_STOP: UCHAR = 2;
+
+/// Descriptor of an information-level event with no channel, task or keyword
+const fn event_descriptor(id: u16, opcode: UCHAR) -> EVENT_DESCRIPTOR {
+    EVENT_DESCRIPTOR {
+        Id: id,
+        Version: 0,
+        Channel: 0,
+        Level: TRACE_LEVEL_INFORMATION,
+        Opcode: opcode,
+        Task: 0,
+        Keyword: 0,
+    }
+}
+
+/// A request was presented to the driver
+const REQUEST_START: EVENT_DESCRIPTOR = event_descriptor(1, OPCODE_START);
+
+/// The driver completed a request
+const REQUEST_COMPLETE: EVENT_DESCRIPTOR = event_descriptor(2, OPCODE_STOP);
+
+/// The cancel routine of a request was called
+const REQUEST_CANCEL: EVENT_DESCRIPTOR = event_descriptor(3, OPCODE_INFO);
+
+/// ETW provider, registered with `EtwRegister` for as long as the driver is
+/// loaded.
+///
+/// Events written while it is not registered are dropped, so the driver works
+/// the same when the registration fails.
+pub struct EventProvider {
+    /// Registration handle, 0 while unregistered
+    reg_handle: AtomicU64,
+}
+
+impl EventProvider {
+    /// Provider that is not registered yet
+    const fn new() -> Self {
+        Self {
+            reg_handle: AtomicU64::new(0),
+        }
+    }
+
+    /// Register the [`EventProvider`] as [`ECHO_EVENTS_PROVIDER`]. Must be
+    /// called from `DriverEntry` at `PASSIVE_LEVEL`, and balanced with
+    /// [`EventProvider::unregister`] when the driver unloads or fails to load.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider could not be
+    /// registered. The error variant will contain a [`NTSTATUS`] of the
+    /// failure. Full error documentation is available in the [EtwRegister Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdm/nf-wdm-etwregister#return-value)
+    pub fn register(&self) -> Result<(), NTSTATUS> {
+        let mut reg_handle: REGHANDLE = 0;
+
+        // SAFETY: `ECHO_EVENTS_PROVIDER` and `reg_handle` are valid for the whole
+        // call, and no enable callback is registered.
+        let nt_status = unsafe {
+            EtwRegister(
+                &ECHO_EVENTS_PROVIDER,
+                None,
+                core::ptr::null_mut(),
+                &mut reg_handle,
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        self.reg_handle.store(reg_handle, Ordering::Release);
+        Ok(())
+    }
+
+    /// Unregister the [`EventProvider`], if it is registered. Must be called
+    /// at `PASSIVE_LEVEL`, once no more events can be written.
+    pub fn unregister(&self) {
+        let reg_handle = self.reg_handle.swap(0, Ordering::AcqRel);
+        if reg_handle != 0 {
+            // SAFETY: `reg_handle` was returned by `EtwRegister` and, having been
+            // swapped out, cannot be used for any further writes.
+            unsafe {
+                EtwUnregister(reg_handle);
+            }
+        }
+    }
+
+    /// Write the event described by `descriptor`, with `data` as its fields.
+    /// Can be called at any IRQL.
+    fn write(&self, descriptor: &EVENT_DESCRIPTOR, data: &[EVENT_DATA_DESCRIPTOR]) {
+        let reg_handle = self.reg_handle.load(Ordering::Acquire);
+        if reg_handle == 0 {
+            return;
+        }
+
+        let Ok(count) = ULONG::try_from(data.len()) else {
+            return;
+        };
+
+        // SAFETY: `reg_handle` was returned by `EtwRegister`, and `descriptor`
+        // and `data`, as well as the fields `data` points to, outlive the call,
+        // which only reads them.
+        unsafe {
+            EtwWrite(
+                reg_handle,
+                descriptor,
+                core::ptr::null(),
+                count,
+                data.as_ptr().cast_mut(),
+            );
+        }
+    }
+}
+
+/// Provider of the request events, registered in `DriverEntry` and
+/// unregistered in `EvtDriverUnload`
+pub static ECHO_EVENTS: EventProvider = EventProvider::new();
+
+/// Kind of request reported by [`request_start`]
+#[derive(Clone, Copy)]
+pub enum RequestKind {
+    Read,
+    Write,
+    /// Device control request, with its I/O control code
+    DeviceControl(ULONG),
+}
+
+/// Field of an event pointing to `value`, which must outlive the write
+fn field<T>(value: &T) -> EVENT_DATA_DESCRIPTOR {
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the fields of the events are at most 8 bytes"
+    )]
+    let size = core::mem::size_of::<T>() as ULONG;
+
+    EVENT_DATA_DESCRIPTOR {
+        Ptr: core::ptr::from_ref(value) as ULONGLONG,
+        Size: size,
+        ..EVENT_DATA_DESCRIPTOR::default()
+    }
+}
+
+/// Write a `RequestStart` event for `request`, of `kind` and `length` bytes.
+/// Called by the queue callbacks as soon as the request is presented.
+pub fn request_start(request: WDFREQUEST, kind: RequestKind, length: usize) {
+    let request = request as ULONGLONG;
+    let (major_function, io_control_code) = match kind {
+        RequestKind::Read => (IRP_MJ_READ, 0),
+        RequestKind::Write => (IRP_MJ_WRITE, 0),
+        RequestKind::DeviceControl(io_control_code) => (IRP_MJ_DEVICE_CONTROL, io_control_code),
+    };
+    let length = length as ULONGLONG;
+
+    ECHO_EVENTS.write(
+        &REQUEST_START,
+        &[
+            field(&request),
+            field(&major_function),
+            field(&io_control_code),
+            field(&length),
+        ],
+    );
+}
+
+/// Write a `RequestComplete` event for `request`, completed with `nt_status`
+/// and `information`. Called by [`crate::wdf_request::Request`] right before
+/// it completes the request.
+pub fn request_complete(request: WDFREQUEST, nt_status: NTSTATUS, information: ULONGLONG) {
+    let request = request as ULONGLONG;
+
+    ECHO_EVENTS.write(
+        &REQUEST_COMPLETE,
+        &[field(&request), field(&nt_status), field(&information)],
+    );
+}
+
+/// Write a `RequestCancel` event for `request`. Called by the cancel routine
+/// before it decides who completes the request.
+pub fn request_cancel(request: WDFREQUEST) {
+    let request = request as ULONGLONG;
+
+    ECHO_EVENTS.write(&REQUEST_CANCEL, &[field(&request)]);
+}
@@ -0,0 +1,117 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Measure how long requests stay in the driver, with the `latency-stats`
+//! feature.
+//!
+//! Reads and writes are stamped with the performance counter when they arrive,
+//! in their `RequestContext`, and the time until the timer completes them is
+//! accumulated in the [`LatencyStats`] of the device. The statistics are kept
+//! in atomics rather than under a lock, since requests of different queues are
+//! completed concurrently, so a [`LatencyStats::snapshot`] taken while a
+//! request is being recorded may count it in some fields and not in others.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use wdk_sys::{ntddk::KeQueryPerformanceCounter, LARGE_INTEGER};
+
+/// Latency statistics returned by `IOCTL_ECHO_GET_LATENCY_STATS`, in
+/// microseconds. The layout is shared with the applications reading them, and
+/// all the fields are 0 until a request has been completed.
+#[repr(C)]
+pub struct EchoLatencyStats {
+    /// Number of requests completed by the timer
+    pub count: u64,
+    /// Shortest time a request stayed in the driver
+    pub min_us: u64,
+    /// Longest time a request stayed in the driver
+    pub max_us: u64,
+    /// Average time a request stayed in the driver
+    pub average_us: u64,
+}
+
+/// Current value of the performance counter, in ticks. It can be read at any
+/// IRQL, and is never 0 once the system has started.
+pub fn timestamp() -> u64 {
+    // SAFETY: KeQueryPerformanceCounter can be called at any IRQL, and the
+    // frequency is optional
+    let counter = unsafe { KeQueryPerformanceCounter(core::ptr::null_mut()) };
+    // SAFETY: Every field of LARGE_INTEGER is a view of the same 64 bits
+    let counter = unsafe { counter.QuadPart };
+    counter.unsigned_abs()
+}
+
+/// Frequency of the performance counter, in ticks per second
+fn frequency() -> u64 {
+    let mut frequency = LARGE_INTEGER::default();
+    // SAFETY: KeQueryPerformanceCounter can be called at any IRQL, and writes
+    // the frequency to a valid LARGE_INTEGER
+    unsafe { KeQueryPerformanceCounter(&mut frequency) };
+    // SAFETY: Every field of LARGE_INTEGER is a view of the same 64 bits
+    unsafe { frequency.QuadPart }.unsigned_abs()
+}
+
+/// Convert `ticks` of the performance counter running at `frequency` to
+/// microseconds, saturating if they do not fit.
+fn ticks_to_us(ticks: u64, frequency: u64) -> u64 {
+    if frequency == 0 {
+        return 0;
+    }
+    u64::try_from(u128::from(ticks) * 1_000_000 / u128::from(frequency)).unwrap_or(u64::MAX)
+}
+
+/// Time requests stayed in the driver, in performance counter ticks.
+pub struct LatencyStats {
+    count: AtomicU64,
+    total_ticks: AtomicU64,
+    min_ticks: AtomicU64,
+    max_ticks: AtomicU64,
+}
+
+impl LatencyStats {
+    /// Statistics of no request
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_ticks: AtomicU64::new(0),
+            min_ticks: AtomicU64::new(u64::MAX),
+            max_ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a request that arrived at `arrival_time`, a [`timestamp`], and is
+    /// being completed now. A request that was never stamped, with an arrival
+    /// time of 0, is not recorded.
+    pub fn record(&self, arrival_time: u64) {
+        if arrival_time == 0 {
+            return;
+        }
+
+        let ticks = timestamp().saturating_sub(arrival_time);
+        self.total_ticks.fetch_add(ticks, Ordering::Relaxed);
+        self.min_ticks.fetch_min(ticks, Ordering::Relaxed);
+        self.max_ticks.fetch_max(ticks, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Copy of the statistics recorded so far, converted to microseconds
+    pub fn snapshot(&self) -> EchoLatencyStats {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return EchoLatencyStats {
+                count: 0,
+                min_us: 0,
+                max_us: 0,
+                average_us: 0,
+            };
+        }
+
+        let frequency = frequency();
+        EchoLatencyStats {
+            count,
+            min_us: ticks_to_us(self.min_ticks.load(Ordering::Relaxed), frequency),
+            max_us: ticks_to_us(self.max_ticks.load(Ordering::Relaxed), frequency),
+            average_us: ticks_to_us(self.total_ticks.load(Ordering::Relaxed) / count, frequency),
+        }
+    }
+}
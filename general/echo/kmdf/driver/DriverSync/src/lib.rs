@@ -14,6 +14,95 @@
 //!    made cancellable by the call `WdfRequestMarkCancelable`. This
 //!    allows the test program to cancel the request and exit instantly.
 //!
+//!    Write requests are instead forwarded with `WdfRequestForwardToIoQueue`
+//!    to a second, manual queue, where they wait until the timer DPC retrieves
+//!    them with `WdfIoQueueRetrieveNextRequest`. While a request is in the
+//!    manual queue, the framework takes care of cancelling it.
+//!
+//!    The period of the timer, the longest write, the tag of the buffers and
+//!    the dispatch type of the default queue can be changed with values of the
+//!    `Parameters` key of the service, read in `DriverEntry` into the
+//!    `DriverConfig` kept in the context of the driver object.
+//!
+//!    The device uses buffered I/O by default. With the `direct-io` feature,
+//!    it uses direct I/O instead, and the read and write callbacks map the MDL
+//!    of the request rather than copying through its `WDFMEMORY`.
+//!
+//!    With the `sequence-numbers` feature, each written buffer is stamped
+//!    with an increasing 64-bit sequence number, which is echoed in front of
+//!    the data, so that requests completed out of order or not at all can be
+//!    spotted by the application.
+//!
+//!    With the `partial-reads` feature, a read shorter than the data of the
+//!    last write returns the part that fits and leaves the rest for the next
+//!    reads, which continue from an offset kept in the queue context under its
+//!    lock. The buffer is released once reads have drained it.
+//!
+//!    With the `chunked-read` feature, the data returned by a read is copied
+//!    to the request memory in chunks, each to its own offset, with one
+//!    `WdfMemoryCopyFromBuffer` call per chunk, the way a response is
+//!    assembled from several source regions.
+//!
+//!    With the `heap-free` feature, `echo_print_driver_version` prints the
+//!    version of the driver without allocating from the heap: the version
+//!    string is copied to a fixed-size array on the stack and printed with
+//!    `DbgPrint`, instead of being converted to a `String` and logged.
+//!
+//!    With the `adaptive-timer` feature, the timer is not periodic: it starts
+//!    itself again each time it fires. When no request arrived since it last
+//!    fired, the delay is halved, down to a minimum, and when a request
+//!    arrives, the delay is reset to the full period and the timer restarted
+//!    with it. Each adjustment is logged.
+//!
+//!    With the `one-shot-timer` feature, the timer is not periodic either: it
+//!    is armed to fire once, `CompletionDelayMs` after a read or write arrives
+//!    while it is not armed already, so that a request waits for a delay that
+//!    can be configured rather than until the next tick of a 10 second timer.
+//!
+//!    When the device is surprise-removed, `EvtDeviceSurpriseRemoval` marks
+//!    it as removed in its context, and the read, write and device control
+//!    callbacks fail the requests presented after that with
+//!    `STATUS_DEVICE_REMOVED`, rather than holding them for a device that is
+//!    gone.
+//!
+//!    With the `purge-on-surprise-removal` feature, both queues are purged
+//!    with `WdfIoQueuePurgeSynchronously` when the device is surprise-removed,
+//!    e.g. unplugged while an application still has requests outstanding.
+//!    The cancel routines of the requests the driver holds are called right
+//!    away, and the number of requests each queue held is logged.
+//!
+//!    With the `etw-events` feature, the driver registers an ETW provider in
+//!    `DriverEntry`, and writes an event with the handle of each request when
+//!    it is presented, completed and cancelled, so that the life of requests
+//!    can be followed in Windows Performance Analyzer. The provider is
+//!    unregistered in `EvtDriverUnload`.
+//!
+//!    With the `wpp-tracing` feature, each callback also traces a message with
+//!    WPP. WPP is a preprocessor for C sources, so the messages are C functions
+//!    in `wpp.c`, which the build script runs through `tracewpp.exe` and
+//!    compiles into the driver, and which `wpp` wraps in safe functions.
+//!
+//!    With the `idle-power-policy` feature, the driver uses its role of power
+//!    policy owner: the device is powered down to D3 once it has been idle
+//!    for a few seconds, and powered back up by the framework when a request
+//!    arrives in its power-managed queues. Each of these transitions is
+//!    logged from the D0 callbacks.
+//!
+//!    The device has a second context, `DeviceStatsContext`, which counts the
+//!    reads and writes. WdfDeviceCreate only allocates the `DeviceContext`,
+//!    so it is attached afterwards with `WdfObjectAllocateContext`, and each
+//!    context is retrieved by its own type.
+//!
+//!    The device is found through its device interface. With the
+//!    `named-device` feature, its device object is also named and given a
+//!    symbolic link, so that applications can open it as `\\.\RustEcho`.
+//!
+//!    With the `restricted-access` feature, the device object is given the
+//!    security descriptor `ECHO_DEVICE_SDDL` with
+//!    `WdfDeviceInitAssignSDDLString`, so that only the system and the
+//!    administrators can open it, and `FILE_DEVICE_SECURE_OPEN`, so that the
+//!    check also applies below its name.
+//!
 //!    This rather complicated set of events is designed to demonstrate
 //!    the driver frameworks synchronization of access to a device driver
 //!    data structure, and a pointer which can be a proxy for device hardware
@@ -22,11 +111,55 @@
 //!    This common data structure, or resource is accessed by new request
 //!    events arriving, the DPC that completes it, and cancel processing.
 //!
+//!    The deferred request is referenced with `WdfObjectReference` while it
+//!    waits, and the reference is only released after it has been completed,
+//!    so its handle stays valid for the timer and the cancel routine racing
+//!    to complete it. Debug builds check that every reference is released.
+//!
+//!    The queue releases what its context holds in its `EvtCleanupCallback`,
+//!    when it is deleted. With the `destroy-callback` feature, it also holds a
+//!    reference on itself, which it must release there, and has an
+//!    `EvtDestroyCallback`, which the framework only calls once no references
+//!    are left, and which checks that the cleanup callback ran first.
+//!
+//!    Which of the timer and the cancel routine completes the current request
+//!    is decided by a cancel completion ownership count, in `cancel_protocol`.
+//!    The queue also keeps the state of its current request, `Idle`,
+//!    `Pending`, `Completing` or `Cancelled`, in `request_state`, and debug
+//!    builds check that each change of state is one the protocol allows.
+//!
 //!    Notice the lack of specific lock/unlock operations.
 //!
+//!    By default, the queue has no synchronization scope and the timer is
+//!    created without `AutomaticSerialization`, so the framework does not
+//!    serialize anything: the queue callbacks, the cancel routine and the
+//!    timer can run at the same time, and synchronize through the queue lock.
+//!    With the `queue-serialization` feature, the queue uses
+//!    `WdfSynchronizationScopeQueue` and the timer `AutomaticSerialization`,
+//!    and the framework calls at most one of them at a time. With the
+//!    `callback-trace` feature, entering and leaving each of them is logged
+//!    with the number of others running, so both behaviors can be observed.
+//!    The queue lock is kept either way, since
+//!    not every callback touching the queue context is covered by the
+//!    synchronization scope, e.g. the DPC of the `dpc-completion` feature.
+//!
 //!    Even though this example utilizes a serial queue, a parallel queue
 //!    would not need any additional explicit synchronization, just a
-//!    strategy for managing multiple requests outstanding.
+//!    strategy for managing multiple requests outstanding. The
+//!    `parallel-queue` feature shows one: the pending reads are kept in a
+//!    `WDFCOLLECTION`, and the queue context buffer is only accessed under
+//!    its spinlock, since reads and writes can now run at the same time.
+//!
+//!    The queue context is protected by a spinlock, which raises the IRQL to
+//!    `DISPATCH_LEVEL` while it is held, since the timer completes requests at
+//!    `DISPATCH_LEVEL`. With the `wait-lock` feature, it is protected by a
+//!    wait lock instead, which keeps the IRQL at `PASSIVE_LEVEL`: the queue is
+//!    created with the passive execution level, so that its callbacks and the
+//!    cancel routines of its requests are called at `PASSIVE_LEVEL`, and the
+//!    timer queues a work item that completes the requests at `PASSIVE_LEVEL`
+//!    instead of completing them itself. A wait lock suits code that may have
+//!    to wait or touch pageable memory while holding the lock, while a
+//!    spinlock suits short sections that must also run at `DISPATCH_LEVEL`.
 
 #![no_std]
 #![deny(clippy::all)]
@@ -35,11 +168,109 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::missing_safety_doc)]
 
+#[cfg(feature = "callback-trace")]
+mod callback_tracker;
+mod cancel_protocol;
+#[cfg(feature = "chunked-read")]
+mod chunks;
+mod config;
 mod device;
+#[cfg(feature = "queue-diagnostics")]
+mod diagnostics;
 mod driver;
+#[cfg(feature = "etw-events")]
+mod etw_events;
+#[cfg(feature = "latency-stats")]
+mod latency;
+mod log;
+#[cfg(feature = "direct-io")]
+mod mdl;
+mod nt_assert;
+mod nt_status;
+mod paged_code;
+#[cfg(all(not(test), any(feature = "panic-bugcheck", feature = "panic-log")))]
+mod panic;
 mod queue;
+#[cfg(not(feature = "parallel-queue"))]
+mod request_state;
+#[cfg(feature = "ring-buffer")]
+mod ring;
+mod teardown;
+mod transfer_length;
+#[cfg(feature = "transform")]
+mod transform;
+mod unicode_string;
+#[cfg(feature = "timer-watchdog")]
+mod watchdog;
+#[cfg(feature = "parallel-queue")]
+mod wdf_collection;
+mod wdf_device;
+#[cfg(feature = "dpc-completion")]
+mod wdf_dpc;
+mod wdf_driver_config;
+mod wdf_io_queue_config;
+#[cfg(feature = "forward-writes")]
+mod wdf_io_target;
+#[cfg(any(not(feature = "ring-buffer"), feature = "forward-writes"))]
+#[cfg_attr(
+    feature = "ring-buffer",
+    allow(
+        dead_code,
+        reason = "only forwarded writes use it with the ring buffer"
+    )
+)]
+mod wdf_memory;
+mod wdf_object_attributes;
+mod wdf_object_reference;
+#[cfg(any(feature = "purge-on-surprise-removal", feature = "timer-watchdog"))]
+#[cfg_attr(
+    not(feature = "purge-on-surprise-removal"),
+    allow(dead_code, reason = "the watchdog only counts the requests of queues")
+)]
+mod wdf_queue;
+mod wdf_registry;
+mod wdf_request;
+#[cfg(not(feature = "wait-lock"))]
+mod wdf_spin_lock;
+mod wdf_structure_size;
+mod wdf_timer;
+#[cfg(feature = "method-neither")]
+mod wdf_user_buffer;
+#[cfg(feature = "wait-lock")]
+mod wdf_wait_lock;
+#[cfg(feature = "wait-lock")]
+mod wdf_work_item;
+#[cfg(feature = "wpp-tracing")]
+mod wpp;
 
-#[cfg(not(test))]
+// A wait lock cannot be acquired from the DPC
+#[cfg(all(feature = "wait-lock", feature = "dpc-completion"))]
+compile_error!("The `wait-lock` and `dpc-completion` features are mutually exclusive");
+
+// The timer cannot be serialized with a queue at PASSIVE_LEVEL while it keeps
+// firing at DISPATCH_LEVEL
+#[cfg(all(feature = "queue-serialization", feature = "wait-lock"))]
+compile_error!("The `queue-serialization` and `wait-lock` features are mutually exclusive");
+
+// Both re-arm the timer, with different delays
+#[cfg(all(feature = "adaptive-timer", feature = "one-shot-timer"))]
+compile_error!("The `adaptive-timer` and `one-shot-timer` features are mutually exclusive");
+
+// Direct I/O copies to the mapped MDL rather than with WdfMemoryCopyFromBuffer
+#[cfg(all(feature = "chunked-read", feature = "direct-io"))]
+compile_error!("The `chunked-read` and `direct-io` features are mutually exclusive");
+
+// The ring already drains the data of the writes as it is read
+#[cfg(all(feature = "partial-reads", feature = "ring-buffer"))]
+compile_error!("The `partial-reads` and `ring-buffer` features are mutually exclusive");
+
+// The diagnostic control code exposes internal state, and is not meant to ship
+#[cfg(all(feature = "queue-diagnostics", not(debug_assertions)))]
+compile_error!("The `queue-diagnostics` feature is only available in debug builds");
+
+// The panic handler of wdk_panic is only linked without a panic policy feature,
+// which provide their own
+#[cfg(not(any(test, feature = "panic-bugcheck", feature = "panic-log")))]
 extern crate wdk_panic;
 
 use wdk::wdf;
@@ -47,25 +278,34 @@
 use wdk_alloc::WdkAllocator;
 use wdk_sys::{
     call_unsafe_wdf_function_binding,
-    ntddk::KeGetCurrentIrql,
     GUID,
     NTSTATUS,
-    PVOID,
     ULONG,
     WDFOBJECT,
+    WDFQUEUE,
     WDFREQUEST,
-    WDF_DRIVER_CONFIG,
-    WDF_DRIVER_VERSION_AVAILABLE_PARAMS,
-    WDF_IO_QUEUE_CONFIG,
-    WDF_OBJECT_ATTRIBUTES,
+    WDFSTRING,
     WDF_OBJECT_CONTEXT_TYPE_INFO,
-    WDF_PNPPOWER_EVENT_CALLBACKS,
-    WDF_TIMER_CONFIG,
 };
 mod wdf_object_context;
-use core::sync::atomic::AtomicI32;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64};
 
-use wdf_object_context::{wdf_declare_context_type, wdf_declare_context_type_with_name};
+use wdf_object_context::{
+    wdf_declare_context_type,
+    wdf_declare_context_type_with_name,
+    wdf_declare_context_type_with_name_and_drop,
+};
+use wdf_request::Request;
+#[cfg(not(feature = "wait-lock"))]
+use wdf_spin_lock::SpinLockExt;
+
+/// Lock protecting the queue context: a spinlock, or a wait lock with the
+/// `wait-lock` feature. Both are acquired with `lock`, which returns a guard
+/// releasing them on drop.
+#[cfg(not(feature = "wait-lock"))]
+type QueueLock = wdf::SpinLock;
+#[cfg(feature = "wait-lock")]
+type QueueLock = wdf_wait_lock::WaitLock;
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -81,139 +321,251 @@
     ],
 };
 
+// Base name of the device object and of its symbolic link with the
+// `named-device` feature. The first device can be opened as \\.\RustEcho.
+#[cfg(feature = "named-device")]
+const ECHO_DEVICE_NAME: &str = "RustEcho";
+
+// Security descriptor of the device object with the `restricted-access`
+// feature: generic all access for the system and the built-in administrators,
+// and none for anyone else. This is SDDL_DEVOBJ_SYS_ALL_ADM_ALL of wdmsec.h.
+#[cfg(feature = "restricted-access")]
+const ECHO_DEVICE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+// Value of the device hardware key naming the device that writes are
+// forwarded to with the `forward-writes` feature, e.g. \Device\RustEcho1.
+#[cfg(feature = "forward-writes")]
+const FORWARD_TARGET_VALUE_NAME: &str = "ForwardTarget";
+
+// Value of the device hardware key setting how long, in ms, the timer may go
+// without firing while requests wait for it before the watchdog of the
+// `timer-watchdog` feature bug checks. Only thresholds longer than the default,
+// twice the timer period, are used.
+#[cfg(feature = "timer-watchdog")]
+const WATCHDOG_THRESHOLD_VALUE_NAME: &str = "WatchdogThresholdMs";
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS). The
+// output buffer receives the version string of the driver, in UTF-16 and
+// without a terminating null.
+const IOCTL_ECHO_GET_WDF_VERSION: ULONG = 0x0022_2004;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `fault-injection` feature. The input buffer holds the NTSTATUS that the
+// next read or write is completed with.
+#[cfg(feature = "fault-injection")]
+const IOCTL_ECHO_INJECT_FAULT: ULONG = 0x0022_2000;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `latency-stats` feature. The output buffer receives the
+// `EchoLatencyStats` of the device.
+#[cfg(feature = "latency-stats")]
+const IOCTL_ECHO_GET_LATENCY_STATS: ULONG = 0x0022_2008;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `memory-pressure` feature. The input buffer holds the ULONG number of
+// write buffer allocations to fail from now on.
+#[cfg(feature = "memory-pressure")]
+const IOCTL_ECHO_FAIL_ALLOCATIONS: ULONG = 0x0022_200C;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x804, METHOD_NEITHER, FILE_ANY_ACCESS), with
+// the `method-neither` feature. The input buffer is copied to the output
+// buffer, both passed as raw user-mode addresses.
+#[cfg(feature = "method-neither")]
+const IOCTL_ECHO_NEITHER: ULONG = 0x0022_2013;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `pending-limit` feature. The input buffer holds the new ULONG maximum
+// number of pending reads, and the output buffer receives the previous one.
+#[cfg(feature = "pending-limit")]
+const IOCTL_ECHO_SET_MAX_PENDING: ULONG = 0x0022_2014;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `transform` feature. The input buffer holds the ULONG kind of transform
+// applied to the next writes, one of the `TRANSFORM_*` kinds of `transform`,
+// followed by the ULONG key of the transform, which must fit in a byte.
+#[cfg(feature = "transform")]
+const IOCTL_ECHO_SET_TRANSFORM: ULONG = 0x0022_2018;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x807, METHOD_BUFFERED, FILE_ANY_ACCESS), with
+// the `queue-diagnostics` feature. The output buffer receives the
+// `EchoQueueState` of the default queue.
+#[cfg(feature = "queue-diagnostics")]
+const IOCTL_ECHO_GET_QUEUE_STATE: ULONG = 0x0022_201C;
+
 // Declare queue context.
 //
 // ====== CONTEXT SETUP ========//
 
+// Context of the driver object, written once by DriverEntry
+pub struct DriverContext {
+    // Tunables read from the Parameters key of the service
+    config: config::DriverConfig,
+}
+wdf_declare_context_type_with_name!(DriverContext, driver_get_context);
+
 // The device context performs the same job as
 // a WDM device extension in the driver frameworks
 pub struct DeviceContext {
     private_device_data: ULONG, // just a placeholder
+    instance: ULONG,
+    // Manual queue that write requests are forwarded to until the timer
+    // completes them
+    manual_queue: WDFQUEUE,
+    // Sequence number of the next write, with the `sequence-numbers` feature
+    #[cfg(feature = "sequence-numbers")]
+    sequence_number: AtomicU64,
+    // Number of file objects currently open on the device
+    open_count: AtomicU32,
+    // Set once the device is being removed, new opens are rejected after that
+    shutting_down: AtomicBool,
+    // Set once the device is surprise-removed, new reads, writes and control
+    // requests are failed with STATUS_DEVICE_REMOVED after that
+    removed: AtomicBool,
+    // Version string of the driver, returned by IOCTL_ECHO_GET_WDF_VERSION
+    version_string: WDFSTRING,
+    // Status the next read or write fails with, set by IOCTL_ECHO_INJECT_FAULT
+    // with the `fault-injection` feature. STATUS_SUCCESS when no fault is
+    // pending.
+    #[cfg(feature = "fault-injection")]
+    injected_status: AtomicI32,
+    // Device that writes are forwarded to with the `forward-writes` feature,
+    // None if it is not configured or could not be opened
+    #[cfg(feature = "forward-writes")]
+    forward_target: Option<wdf_io_target::IoTarget>,
+    // Time the requests completed by the timer stayed in the driver, returned
+    // by IOCTL_ECHO_GET_LATENCY_STATS with the `latency-stats` feature
+    #[cfg(feature = "latency-stats")]
+    latency_stats: latency::LatencyStats,
+    // Number of write buffer allocations left to treat as failed, set by
+    // IOCTL_ECHO_FAIL_ALLOCATIONS with the `memory-pressure` feature
+    #[cfg(feature = "memory-pressure")]
+    failing_allocations: AtomicU32,
+    // Most reads the default queue holds at once with the `pending-limit`
+    // feature, set by IOCTL_ECHO_SET_MAX_PENDING
+    #[cfg(feature = "pending-limit")]
+    max_pending_requests: AtomicU32,
+    // Transform applied to the data of each write with the `transform`
+    // feature, set by IOCTL_ECHO_SET_TRANSFORM
+    #[cfg(feature = "transform")]
+    transform: transform::AtomicTransform,
+    // Threshold of the watchdog with the `timer-watchdog` feature, read from
+    // the device hardware key, 0 if it is not set
+    #[cfg(feature = "timer-watchdog")]
+    watchdog_threshold_ms: ULONG,
 }
 wdf_declare_context_type!(DeviceContext);
 
+// A second context of the device, allocated with WdfObjectAllocateContext once
+// the device is created rather than with WdfDeviceCreate. Each context of an
+// object is found by its type, so this one is retrieved with
+// wdf_object_get_device_stats_context, independently of the DeviceContext.
+pub struct DeviceStatsContext {
+    // Reads that returned data, and the number of bytes they returned
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+    // Writes whose data was stored, and the number of bytes they stored
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+}
+wdf_declare_context_type!(DeviceStatsContext);
+
 pub struct QueueContext {
-    buffer: PVOID,
-    length: usize,
-    timer: wdf::Timer,
+    // Data of the last write, a WDF memory object parented to the queue
+    #[cfg(not(feature = "ring-buffer"))]
+    buffer: Option<wdf_memory::ManagedMemory>,
+    // Offset in `buffer` of the data the next read returns, with the
+    // `partial-reads` feature. Only accessed under the queue context lock.
+    #[cfg(feature = "partial-reads")]
+    read_offset: usize,
+    timer: wdf_timer::Timer,
+    // With the `adaptive-timer` feature, the delay in ms the timer is started
+    // with next, whether a request arrived since it last fired, and whether it
+    // may be started again, which is not the case while the device is
+    // suspended
+    #[cfg(feature = "adaptive-timer")]
+    timer_period: AtomicU32,
+    #[cfg(feature = "adaptive-timer")]
+    work_arrived: AtomicBool,
+    #[cfg(feature = "adaptive-timer")]
+    timer_running: AtomicBool,
+    // With the `one-shot-timer` feature, whether the timer has been armed for
+    // a request and has not fired yet
+    #[cfg(feature = "one-shot-timer")]
+    timer_armed: AtomicBool,
+    #[cfg(feature = "dpc-completion")]
+    dpc: wdf_dpc::Dpc,
+    // With the `timer-watchdog` feature, when `timer` last fired, and a second
+    // timer checking that it keeps firing while requests wait for it
+    #[cfg(feature = "timer-watchdog")]
+    watchdog: watchdog::Watchdog,
+    #[cfg(feature = "timer-watchdog")]
+    watchdog_timer: wdf_timer::Timer,
+    // Work item the timer queues to complete requests at PASSIVE_LEVEL with the
+    // `wait-lock` feature
+    #[cfg(feature = "wait-lock")]
+    work_item: wdf_work_item::WorkItem,
     current_request: WDFREQUEST,
+    // State of `current_request`, changed under the queue lock, which makes
+    // the states the cancel ownership protocol should never reach observable
+    #[cfg(not(feature = "parallel-queue"))]
+    current_state: request_state::AtomicRequestState,
+    // Requests waiting for the timer with the `parallel-queue` feature, which
+    // replaces `current_request`
+    #[cfg(feature = "parallel-queue")]
+    pending_requests: wdf_collection::Collection,
+    // Number of slots for pending requests taken with the `pending-limit`
+    // feature: one per request in `pending_requests`, or about to be added to
+    // it. Capped by the `max_pending_requests` of the device.
+    #[cfg(feature = "pending-limit")]
+    pending_count: AtomicU32,
+    // Reads waiting for a write to provide data with the `blocking-read`
+    // feature. Unlike the pending requests, the timer leaves them alone.
+    #[cfg(feature = "blocking-read")]
+    waiting_reads: wdf_collection::Collection,
+    // Data of the writes waiting to be read with the `ring-buffer` feature,
+    // which replaces `buffer`
+    #[cfg(feature = "ring-buffer")]
+    ring: ring::Ring,
     current_status: NTSTATUS,
-    spin_lock: wdf::SpinLock,
+    lock: QueueLock,
+    // Set once the queue is being torn down, see the `Drop` implementation.
+    // The timer callback returns right away after that.
+    torn_down: AtomicBool,
+    // Queue callbacks currently running with the `callback-trace` feature,
+    // logged to show which of them the framework serializes
+    #[cfg(feature = "callback-trace")]
+    callbacks: callback_tracker::CallbackTracker,
+    // Reference of the queue on itself with the `destroy-callback` feature,
+    // which must be released by its EvtCleanupCallback: the framework only
+    // calls its EvtDestroyCallback once every reference has been released
+    #[cfg(feature = "destroy-callback")]
+    self_reference: Option<wdf_object_reference::RefGuard>,
 }
-wdf_declare_context_type_with_name!(QueueContext, queue_get_context);
+wdf_declare_context_type_with_name_and_drop!(QueueContext, queue_get_context);
 
 pub struct RequestContext {
     cancel_completion_ownership_count: AtomicI32,
+    // Set by the first completion of the request in debug builds, to detect a
+    // second one. The framework zeroes the context, so it starts out false.
+    #[cfg(debug_assertions)]
+    completed: AtomicBool,
+    // Length of a read waiting for data with the `blocking-read` feature
+    #[cfg(feature = "blocking-read")]
+    read_length: usize,
+    // Reference on the request while it waits for the timer, or with the
+    // `blocking-read` feature for a write, taken when it is deferred and
+    // released by whichever of the timer and the cancel routine completes it
+    reference: Option<wdf_object_reference::RefGuard>,
+    // Performance counter when the read or write arrived, with the
+    // `latency-stats` feature. 0 for the requests that are not timed.
+    #[cfg(feature = "latency-stats")]
+    arrival_time: AtomicU64,
+    // Buffers of an IOCTL_ECHO_NEITHER request with the `method-neither`
+    // feature, locked in the context of the calling process by
+    // `echo_evt_io_in_caller_context`. None for any other request.
+    #[cfg(feature = "method-neither")]
+    user_input: Option<wdf_user_buffer::UserBuffer>,
+    #[cfg(feature = "method-neither")]
+    user_output: Option<wdf_user_buffer::UserBuffer>,
 }
 wdf_declare_context_type_with_name!(RequestContext, request_get_context);
-
-// None of the below SIZE constants should be needed after an equivalent `WDF_STRUCTURE_SIZE` macro is added to `wdk-sys`: https://github.com/microsoft/windows-drivers-rs/issues/242
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_DRIVER_CONFIG>() is known to fit in ULONG due to below const assert"
-)]
-const WDF_DRIVER_CONFIG_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_DRIVER_CONFIG>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_DRIVER_CONFIG>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_DRIVER_VERSION_AVAILABLE_PARAMS>() is known to fit in ULONG due to \
-              below const assert"
-)]
-const WDF_DRIVER_VERSION_AVAILABLE_PARAMS_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_DRIVER_VERSION_AVAILABLE_PARAMS>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_DRIVER_VERSION_AVAILABLE_PARAMS>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_IO_QUEUE_CONFIG>() is known to fit in ULONG due to below const assert"
-)]
-const WDF_IO_QUEUE_CONFIG_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_IO_QUEUE_CONFIG>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_IO_QUEUE_CONFIG>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_OBJECT_ATTRIBUTES>() is known to fit in ULONG due to below const \
-              assert"
-)]
-const WDF_OBJECT_ATTRIBUTES_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_OBJECT_ATTRIBUTES>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_OBJECT_ATTRIBUTES>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>() is known to fit in ULONG due to below \
-              const assert"
-)]
-const WDF_OBJECT_CONTEXT_TYPE_INFO_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>() is known to fit in ULONG due to below \
-              const assert"
-)]
-const WDF_PNPPOWER_EVENT_CALLBACKS_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
-
-#[allow(
-    clippy::cast_possible_truncation,
-    reason = "size_of::<WDF_TIMER_CONFIG>() is known to fit in ULONG due to below const assert"
-)]
-const WDF_TIMER_CONFIG_SIZE: ULONG = {
-    const S: usize = core::mem::size_of::<WDF_TIMER_CONFIG>();
-    const {
-        assert!(
-            S <= ULONG::MAX as usize,
-            "size_of::<WDF_TIMER_CONFIG>() should fit in ULONG"
-        );
-    };
-    S as ULONG
-};
@@ -0,0 +1,228 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Logging for the driver, with the backend selected at build time:
+//!
+//! * default: `wdk::println!`, i.e. `DbgPrint`, with no filtering
+//! * `log-dbg-print-ex`: `DbgPrintEx` with the `DPFLTR_IHVDRIVER_ID` component
+//!   and a level matching the macro, so messages can be filtered with the
+//!   `Kd_IHVDRIVER_Mask` debug print filter
+//! * `log-etw`: an ETW provider (see [`ECHO_TRACE_PROVIDER`]) that can be
+//!   consumed with tracelog, xperf or any other ETW session, the same way as
+//!   WPP traces from C drivers
+
+use core::fmt;
+
+use wdk_sys::{ntddk::KeGetCurrentIrql, APC_LEVEL, DISPATCH_LEVEL, KIRQL, PASSIVE_LEVEL};
+
+#[cfg(all(feature = "log-dbg-print-ex", feature = "log-etw"))]
+compile_error!("The `log-dbg-print-ex` and `log-etw` features are mutually exclusive");
+
+/// Severity of a log message
+#[derive(Clone, Copy)]
+pub enum Level {
+    Error,
+    Info,
+}
+
+/// Log an error with [`format_args!`] syntax, tagged with the current IRQL and
+/// the name of the calling function, e.g.
+/// `[DISPATCH][echo_evt_timer_func] ...`
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        crate::log::log(
+            crate::log::Level::Error,
+            crate::log::function_name!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Log an informational message with [`format_args!`] syntax, tagged with the
+/// current IRQL and the name of the calling function, e.g.
+/// `[DISPATCH][echo_evt_timer_func] ...`
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        crate::log::log(
+            crate::log::Level::Info,
+            crate::log::function_name!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Name of the function this macro is expanded in, without its module path.
+///
+/// Rust has no equivalent of `__FUNCTION__`, so this takes the type name of a
+/// nested function item, which is the path of the enclosing function followed
+/// by `::f`.
+macro_rules! function_name {
+    () => {{
+        const fn f() {}
+        fn type_name_of<T>(_: T) -> &'static str {
+            core::any::type_name::<T>()
+        }
+        let name = type_name_of(f);
+        let name = name.strip_suffix("::f").unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }};
+}
+
+pub(crate) use function_name;
+pub(crate) use log_error;
+pub(crate) use log_info;
+
+/// Short name of `irql`, as used in the log tags
+fn irql_name(irql: KIRQL) -> &'static str {
+    match u32::from(irql) {
+        PASSIVE_LEVEL => "PASSIVE",
+        APC_LEVEL => "APC",
+        DISPATCH_LEVEL => "DISPATCH",
+        _ => "DIRQL",
+    }
+}
+
+/// Tag a message with the current IRQL and `function`, and write it to the
+/// selected logging backend. Use [`log_error!`] or [`log_info!`] instead of
+/// calling this directly.
+pub fn log(level: Level, function: &str, args: fmt::Arguments) {
+    // SAFETY: KeGetCurrentIrql can be called at any IRQL
+    let irql = unsafe { KeGetCurrentIrql() };
+    write(
+        level,
+        format_args!("[{}][{function}] {args}", irql_name(irql)),
+    );
+}
+
+/// Write a message to the selected logging backend.
+#[cfg(not(any(feature = "log-dbg-print-ex", feature = "log-etw")))]
+fn write(_level: Level, args: fmt::Arguments) {
+    wdk::println!("{args}");
+}
+
+/// Write a message to the selected logging backend.
+#[cfg(feature = "log-dbg-print-ex")]
+fn write(level: Level, args: fmt::Arguments) {
+    extern crate alloc;
+
+    use wdk_sys::{ntddk::DbgPrintEx, DPFLTR_ERROR_LEVEL, DPFLTR_INFO_LEVEL, ULONG};
+
+    #[allow(
+        clippy::cast_sign_loss,
+        reason = "DPFLTR_IHVDRIVER_ID is a small positive enum value"
+    )]
+    const COMPONENT_ID: ULONG = wdk_sys::_DPFLTR_TYPE::DPFLTR_IHVDRIVER_ID as ULONG;
+
+    let mut message = alloc::fmt::format(args);
+    message.push('\0');
+
+    let level = match level {
+        Level::Error => DPFLTR_ERROR_LEVEL,
+        Level::Info => DPFLTR_INFO_LEVEL,
+    };
+
+    // SAFETY: The format string and `message` are both null-terminated, and the
+    // `%s` conversion consumes exactly the one argument passed.
+    unsafe {
+        DbgPrintEx(COMPONENT_ID, level, c"%s\n".as_ptr(), message.as_ptr());
+    }
+}
+
+/// Provider for the `log-etw` backend.
+// {7A1C4E92-5B3D-4F86-A0E7-1D2C9B8F6354}
+#[cfg(feature = "log-etw")]
+pub const ECHO_TRACE_PROVIDER: wdk_sys::GUID = wdk_sys::GUID {
+    Data1: 0x7A1C_4E92u32,
+    Data2: 0x5B3Du16,
+    Data3: 0x4F86u16,
+    Data4: [
+        0xA0u8, 0xE7u8, 0x1Du8, 0x2Cu8, 0x9Bu8, 0x8Fu8, 0x63u8, 0x54u8,
+    ],
+};
+
+/// `TRACE_LEVEL_ERROR` from evntrace.h
+#[cfg(feature = "log-etw")]
+const TRACE_LEVEL_ERROR: wdk_sys::UCHAR = 2;
+
+/// `TRACE_LEVEL_INFORMATION` from evntrace.h
+#[cfg(feature = "log-etw")]
+const TRACE_LEVEL_INFORMATION: wdk_sys::UCHAR = 4;
+
+/// Registration handle of [`ECHO_TRACE_PROVIDER`], 0 while unregistered
+#[cfg(feature = "log-etw")]
+static REG_HANDLE: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Write a message to the selected logging backend.
+#[cfg(feature = "log-etw")]
+fn write(level: Level, args: fmt::Arguments) {
+    extern crate alloc;
+
+    use alloc::vec::Vec;
+    use core::sync::atomic::Ordering;
+
+    use wdk_sys::ntddk::EtwWriteString;
+
+    let reg_handle = REG_HANDLE.load(Ordering::Acquire);
+    if reg_handle == 0 {
+        return;
+    }
+
+    let level = match level {
+        Level::Error => TRACE_LEVEL_ERROR,
+        Level::Info => TRACE_LEVEL_INFORMATION,
+    };
+    let message: Vec<u16> = alloc::fmt::format(args)
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .collect();
+
+    // SAFETY: `reg_handle` was returned by `EtwRegister` and `message` is a
+    // null-terminated UTF-16 string that outlives the call.
+    unsafe {
+        EtwWriteString(reg_handle, level, 0, core::ptr::null(), message.as_ptr());
+    }
+}
+
+/// Register the ETW provider of the `log-etw` backend. Must be called from
+/// `DriverEntry` before anything is logged, and balanced with
+/// [`uninitialize`] when the driver unloads or fails to load. Logging is
+/// silently dropped if the registration fails.
+#[cfg(feature = "log-etw")]
+pub fn initialize() {
+    use core::sync::atomic::Ordering;
+
+    use wdk::nt_success;
+    use wdk_sys::{ntddk::EtwRegister, REGHANDLE};
+
+    let mut reg_handle: REGHANDLE = 0;
+    // SAFETY: `ECHO_TRACE_PROVIDER` and `reg_handle` are valid for the whole
+    // call, and no enable callback is registered.
+    let nt_status = unsafe {
+        EtwRegister(
+            &ECHO_TRACE_PROVIDER,
+            None,
+            core::ptr::null_mut(),
+            &mut reg_handle,
+        )
+    };
+    if nt_success(nt_status) {
+        REG_HANDLE.store(reg_handle, Ordering::Release);
+    }
+}
+
+/// Unregister the ETW provider registered by [`initialize`].
+#[cfg(feature = "log-etw")]
+pub fn uninitialize() {
+    use core::sync::atomic::Ordering;
+
+    use wdk_sys::ntddk::EtwUnregister;
+
+    let reg_handle = REG_HANDLE.swap(0, Ordering::AcqRel);
+    if reg_handle != 0 {
+        // SAFETY: `reg_handle` was returned by `EtwRegister` and, having been
+        // swapped out, cannot be used for any further writes.
+        unsafe {
+            EtwUnregister(reg_handle);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{
+    ntddk::MmMapLockedPagesSpecifyCache,
+    KPROCESSOR_MODE,
+    MDL_MAPPED_TO_SYSTEM_VA,
+    MDL_SOURCE_IS_NONPAGED_POOL,
+    PMDL,
+    PVOID,
+    ULONG,
+    _MEMORY_CACHING_TYPE,
+    _MM_PAGE_PRIORITY,
+    _MODE,
+};
+
+/// `MdlMappingNoExecute` from wdm.h, requesting a non-executable mapping
+const MDL_MAPPING_NO_EXECUTE: ULONG = 0x4000_0000;
+
+/// Get a system address for the buffer described by `mdl`, mapping it into
+/// system space if it isn't already. This is `MmGetSystemAddressForMdlSafe`,
+/// an inline function of wdm.h that has no binding in `wdk_sys`.
+///
+/// Returns a null pointer if the system is too low on resources to map the
+/// buffer. The mapping is released by the I/O manager when the IRP the MDL
+/// belongs to is completed.
+///
+/// # Safety
+///
+/// `mdl` must point to a valid MDL describing locked pages, such as the MDL
+/// of a direct I/O request.
+pub unsafe fn get_system_address_for_mdl_safe(mdl: PMDL) -> PVOID {
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "MDL flags are declared as ULONG but MdlFlags is a CSHORT"
+    )]
+    const MAPPED_FLAGS: i16 = (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) as i16;
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "KernelMode is 0, which fits in KPROCESSOR_MODE"
+    )]
+    const KERNEL_MODE: KPROCESSOR_MODE = _MODE::KernelMode as KPROCESSOR_MODE;
+
+    #[allow(
+        clippy::cast_sign_loss,
+        reason = "NormalPagePriority is a small positive enum value"
+    )]
+    const PRIORITY: ULONG = _MM_PAGE_PRIORITY::NormalPagePriority as ULONG | MDL_MAPPING_NO_EXECUTE;
+
+    // SAFETY: `mdl` is valid per the contract of this function
+    unsafe {
+        if (*mdl).MdlFlags & MAPPED_FLAGS != 0 {
+            (*mdl).MappedSystemVa
+        } else {
+            MmMapLockedPagesSpecifyCache(
+                mdl,
+                KERNEL_MODE,
+                _MEMORY_CACHING_TYPE::MmCached,
+                core::ptr::null_mut(),
+                0,
+                PRIORITY,
+            )
+        }
+    }
+}
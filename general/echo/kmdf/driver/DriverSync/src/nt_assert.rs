@@ -0,0 +1,125 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Assertions for the invariants of the driver, mirroring `NT_ASSERT` and
+//! `NT_ASSERTMSG` of wdm.h.
+//!
+//! A failed `debug_assert!` panics, and a panic ends in the panic handler,
+//! which at best says where the driver panicked but never lets it resume.
+//! In debug builds, a failed [`nt_assert!`] or [`nt_assert_msg!`] instead logs
+//! its location and breaks into the kernel debugger if one is attached, from
+//! where execution can be resumed to see how the driver copes with the broken
+//! invariant, as with `NT_ASSERT`. Without a debugger, it bug checks with
+//! [`RUST_ASSERTION_FAILURE`], so that the crash dump shows the state of the
+//! driver at the failure.
+//!
+//! In release builds, the condition is type checked like that of
+//! `debug_assert!` but never evaluated, so it must not have side effects.
+
+use wdk_sys::{
+    ntddk::{DbgBreakPoint, KdRefreshDebuggerNotPresent, KeBugCheckEx},
+    ULONG,
+    ULONG_PTR,
+};
+
+use crate::log::log_error;
+
+/// Bug check code of a failed assertion without a debugger attached, `ASRT` in
+/// ASCII. The bug check parameters are:
+///
+/// 1. Address of the null-terminated path of the source file of the assertion,
+///    which can be displayed with `da` in the debugger
+/// 2. The line of the assertion
+/// 3. Address of the null-terminated text of the condition, which can be
+///    displayed with `da`
+/// 4. Address of the null-terminated message of [`nt_assert_msg!`], or 0 for
+///    [`nt_assert!`]
+pub const RUST_ASSERTION_FAILURE: ULONG = 0x4153_5254;
+
+/// Assert that `$condition` holds, like `NT_ASSERT`. See the [module
+/// documentation](self) for what happens when it does not.
+macro_rules! nt_assert {
+    ($condition:expr $(,)?) => {
+        if cfg!(debug_assertions) && !$condition {
+            crate::nt_assert::assertion_failed(
+                concat!(file!(), "\0"),
+                line!(),
+                concat!(stringify!($condition), "\0"),
+                None,
+            );
+        }
+    };
+}
+
+/// Assert that `$condition` holds, like `NT_ASSERTMSG`, logging the string
+/// literal `$message` when it does not. See the [module documentation](self)
+/// for what happens then.
+macro_rules! nt_assert_msg {
+    ($condition:expr, $message:literal $(,)?) => {
+        if cfg!(debug_assertions) && !$condition {
+            crate::nt_assert::assertion_failed(
+                concat!(file!(), "\0"),
+                line!(),
+                concat!(stringify!($condition), "\0"),
+                Some(concat!($message, "\0")),
+            );
+        }
+    };
+}
+
+pub(crate) use nt_assert;
+pub(crate) use nt_assert_msg;
+
+/// Report the failure of an assertion, called by the expansion of
+/// [`nt_assert!`] and [`nt_assert_msg!`] in debug builds. Can be called at any
+/// IRQL.
+///
+/// # Arguments:
+///
+/// * `file` - Null-terminated path of the source file of the assertion.
+/// * `line` - Line of the assertion.
+/// * `condition` - Null-terminated text of the condition that does not hold.
+/// * `message` - Null-terminated message of the assertion, if any.
+#[cold]
+#[inline(never)]
+pub fn assertion_failed(
+    file: &'static str,
+    line: u32,
+    condition: &'static str,
+    message: Option<&'static str>,
+) {
+    let trim = |text: &'static str| text.trim_end_matches('\0');
+    match message {
+        Some(message) => log_error!(
+            "Assertion failed at {}:{line}: {}: {}",
+            trim(file),
+            trim(condition),
+            trim(message)
+        ),
+        None => log_error!(
+            "Assertion failed at {}:{line}: {}",
+            trim(file),
+            trim(condition)
+        ),
+    }
+
+    // SAFETY: KdRefreshDebuggerNotPresent can be called at any IRQL
+    if unsafe { KdRefreshDebuggerNotPresent() } == 0 {
+        // SAFETY: A kernel debugger is attached, which handles the breakpoint.
+        // Execution resumes after the assertion when it is continued.
+        unsafe { DbgBreakPoint() };
+        return;
+    }
+
+    // SAFETY: The invariant the driver relies on does not hold, so the system
+    // is stopped while the caller of the assertion is still on the stack.
+    unsafe {
+        KeBugCheckEx(
+            RUST_ASSERTION_FAILURE,
+            file.as_ptr() as ULONG_PTR,
+            ULONG_PTR::from(line),
+            condition.as_ptr() as ULONG_PTR,
+            message.map_or(0, |message| message.as_ptr() as ULONG_PTR),
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::fmt;
+
+use wdk::nt_success;
+use wdk_sys::{
+    NTSTATUS,
+    STATUS_ACCESS_DENIED,
+    STATUS_BUFFER_OVERFLOW,
+    STATUS_BUFFER_TOO_SMALL,
+    STATUS_CANCELLED,
+    STATUS_DELETE_PENDING,
+    STATUS_DEVICE_BUSY,
+    STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_REQUEST,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_INVALID_PARAMETER,
+    STATUS_NAME_TOO_LONG,
+    STATUS_NOT_SUPPORTED,
+    STATUS_NO_MORE_ENTRIES,
+    STATUS_OBJECT_NAME_COLLISION,
+    STATUS_PENDING,
+    STATUS_SUCCESS,
+    STATUS_UNSUCCESSFUL,
+};
+
+/// `NTSTATUS` to log. It is displayed as its hexadecimal value, followed by
+/// its name when it is one of the statuses the samples deal with, e.g.
+/// `0xC0000120 (STATUS_CANCELLED)`.
+///
+/// ```rust,ignore
+/// log_error!("WdfIoQueueCreate failed {}", NtStatus(nt_status));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NtStatus(pub NTSTATUS);
+
+impl NtStatus {
+    /// Whether the status is a success or informational status, like
+    /// `NT_SUCCESS` in C
+    #[cfg_attr(
+        not(feature = "fault-injection"),
+        allow(dead_code, reason = "only injected faults are checked with it")
+    )]
+    pub const fn is_success(self) -> bool {
+        nt_success(self.0)
+    }
+
+    /// Whether the status is `STATUS_PENDING`
+    #[cfg_attr(
+        not(feature = "fault-injection"),
+        allow(dead_code, reason = "only injected faults are checked with it")
+    )]
+    pub const fn is_pending(self) -> bool {
+        self.0 == STATUS_PENDING
+    }
+
+    /// Name of the status, if it is one of the statuses the samples deal with
+    pub const fn name(self) -> Option<&'static str> {
+        Some(match self.0 {
+            STATUS_SUCCESS => "STATUS_SUCCESS",
+            STATUS_PENDING => "STATUS_PENDING",
+            STATUS_BUFFER_OVERFLOW => "STATUS_BUFFER_OVERFLOW",
+            STATUS_NO_MORE_ENTRIES => "STATUS_NO_MORE_ENTRIES",
+            STATUS_UNSUCCESSFUL => "STATUS_UNSUCCESSFUL",
+            STATUS_INVALID_PARAMETER => "STATUS_INVALID_PARAMETER",
+            STATUS_INVALID_DEVICE_REQUEST => "STATUS_INVALID_DEVICE_REQUEST",
+            STATUS_ACCESS_DENIED => "STATUS_ACCESS_DENIED",
+            STATUS_BUFFER_TOO_SMALL => "STATUS_BUFFER_TOO_SMALL",
+            STATUS_OBJECT_NAME_COLLISION => "STATUS_OBJECT_NAME_COLLISION",
+            STATUS_DELETE_PENDING => "STATUS_DELETE_PENDING",
+            STATUS_INSUFFICIENT_RESOURCES => "STATUS_INSUFFICIENT_RESOURCES",
+            STATUS_NOT_SUPPORTED => "STATUS_NOT_SUPPORTED",
+            STATUS_NAME_TOO_LONG => "STATUS_NAME_TOO_LONG",
+            STATUS_CANCELLED => "STATUS_CANCELLED",
+            STATUS_INVALID_DEVICE_STATE => "STATUS_INVALID_DEVICE_STATE",
+            STATUS_DEVICE_BUSY => "STATUS_DEVICE_BUSY",
+            _ => return None,
+        })
+    }
+}
+
+impl From<NTSTATUS> for NtStatus {
+    fn from(nt_status: NTSTATUS) -> Self {
+        Self(nt_status)
+    }
+}
+
+impl From<NtStatus> for NTSTATUS {
+    fn from(nt_status: NtStatus) -> Self {
+        nt_status.0
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010X}", self.0)?;
+        if let Some(name) = self.name() {
+            write!(f, " ({name})")?;
+        }
+        Ok(())
+    }
+}
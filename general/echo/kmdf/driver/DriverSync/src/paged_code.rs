@@ -0,0 +1,54 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+/// `IRQL_UNEXPECTED_VALUE` bug check code, from bugcodes.h
+#[cfg(debug_assertions)]
+pub const IRQL_UNEXPECTED_VALUE: wdk_sys::ULONG = 0xC8;
+
+/// Stricter drop-in replacement for `wdk::paged_code!` that must be called at
+/// the top of every function placed in the `PAGE` section.
+///
+/// `paged_code!` only `debug_assert!`s the IRQL, and a panic ends in the
+/// `wdk_panic` handler, which stops the driver without saying why. In debug
+/// builds, this macro instead bug checks with `IRQL_UNEXPECTED_VALUE` when the
+/// function is entered above `APC_LEVEL`, where a page fault on its code could
+/// not be serviced. The bug check parameters are:
+///
+/// 1. The current IRQL
+/// 2. `APC_LEVEL`, the highest IRQL pageable code may run at
+/// 3. The line of the call
+/// 4. Address of the null-terminated module path of the caller, which can be
+///    displayed with `da` in the debugger
+///
+/// Like `paged_code!`, this expands to nothing in release builds.
+macro_rules! paged_code_checked {
+    () => {
+        #[cfg(debug_assertions)]
+        {
+            #[allow(
+                clippy::cast_possible_truncation,
+                reason = "APC_LEVEL is 1, which fits in KIRQL"
+            )]
+            const APC_LEVEL: wdk_sys::KIRQL = wdk_sys::APC_LEVEL as wdk_sys::KIRQL;
+
+            // SAFETY: KeGetCurrentIrql can be called at any IRQL
+            let irql = unsafe { wdk_sys::ntddk::KeGetCurrentIrql() };
+            if irql > APC_LEVEL {
+                // SAFETY: Continuing to run pageable code above APC_LEVEL could
+                // page fault at any point, so the system is stopped while the
+                // caller is still on the stack.
+                unsafe {
+                    wdk_sys::ntddk::KeBugCheckEx(
+                        crate::paged_code::IRQL_UNEXPECTED_VALUE,
+                        wdk_sys::ULONG_PTR::from(irql),
+                        wdk_sys::ULONG_PTR::from(APC_LEVEL),
+                        wdk_sys::ULONG_PTR::from(line!()),
+                        concat!(module_path!(), "\0").as_ptr() as wdk_sys::ULONG_PTR,
+                    );
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use paged_code_checked;
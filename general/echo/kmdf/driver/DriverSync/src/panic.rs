@@ -0,0 +1,190 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Panic handler of the driver, selected at build time instead of the one of
+//! `wdk_panic`, which parks the panicking thread in an endless loop without
+//! saying why:
+//!
+//! * `panic-bugcheck`: stop the system right away with the [`RUST_PANIC`] bug
+//!   check code, carrying the location and message of the panic, so that a
+//!   crash dump from the field says where the driver panicked
+//! * `panic-log`: log the location and message of the panic, break into the
+//!   kernel debugger if one is attached, and then park the panicking thread
+//!   like `wdk_panic` does, leaving the rest of the system running so it can be
+//!   inspected
+//!
+//! A panic handler cannot return, so the code that panicked never resumes
+//! with either policy.
+
+#[cfg(all(feature = "panic-bugcheck", feature = "panic-log"))]
+compile_error!("The `panic-bugcheck` and `panic-log` features are mutually exclusive");
+
+use core::panic::PanicInfo;
+
+/// Bug check code the `panic-bugcheck` policy stops the system with. It
+/// spells `RUST` in ASCII, and is not one of the codes of bugcodes.h, so a
+/// panic of the driver can be told apart from any other bug check. The bug
+/// check parameters are:
+///
+/// 1. Address of the null-terminated path of the source file that panicked,
+///    which can be displayed with `da` in the debugger
+/// 2. The line of the panic
+/// 3. The column of the panic
+/// 4. Address of the null-terminated panic message, truncated to
+///    [`MESSAGE_CAPACITY`] bytes, which can be displayed with `da`
+///
+/// The addresses are 0 when another processor panicked at the same time and
+/// already owns the buffers they point to.
+#[cfg(feature = "panic-bugcheck")]
+pub const RUST_PANIC: wdk_sys::ULONG = 0x5255_5354;
+
+/// Capacity of the buffer holding the panic message, including its null
+/// terminator
+#[cfg(feature = "panic-bugcheck")]
+const MESSAGE_CAPACITY: usize = 256;
+
+/// Capacity of the buffer holding the source file path, including its null
+/// terminator
+#[cfg(feature = "panic-bugcheck")]
+const FILE_CAPACITY: usize = 128;
+
+/// Fixed-size buffer that text is formatted into without allocating, since the
+/// panic may come from the allocator itself or from a context it cannot be
+/// called in. Text that does not fit is truncated, and the content is always
+/// null-terminated.
+#[cfg(feature = "panic-bugcheck")]
+struct NulTerminatedBuffer<const N: usize> {
+    bytes: core::cell::UnsafeCell<[u8; N]>,
+}
+
+// SAFETY: The buffers are only written by the processor that won `PANICKING`
+#[cfg(feature = "panic-bugcheck")]
+unsafe impl<const N: usize> Sync for NulTerminatedBuffer<N> {}
+
+#[cfg(feature = "panic-bugcheck")]
+impl<const N: usize> NulTerminatedBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            bytes: core::cell::UnsafeCell::new([0; N]),
+        }
+    }
+
+    /// Format `args` into the buffer, returning the address of its content.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have exclusive access to the buffer.
+    unsafe fn format(&self, args: core::fmt::Arguments) -> wdk_sys::ULONG_PTR {
+        // SAFETY: Access is exclusive per the contract of the caller
+        let bytes = unsafe { &mut *self.bytes.get() };
+        let mut writer = Writer { bytes, length: 0 };
+        // Truncation is not an error, so formatting cannot fail
+        let _ = core::fmt::write(&mut writer, args);
+        writer.bytes[writer.length] = 0;
+        writer.bytes.as_ptr() as wdk_sys::ULONG_PTR
+    }
+}
+
+/// [`core::fmt::Write`] implementation filling a byte slice, always keeping
+/// room for the null terminator
+#[cfg(feature = "panic-bugcheck")]
+struct Writer<'a> {
+    bytes: &'a mut [u8],
+    length: usize,
+}
+
+#[cfg(feature = "panic-bugcheck")]
+impl core::fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let available = self.bytes.len() - 1 - self.length;
+        let count = s.len().min(available);
+        self.bytes[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+/// Whether a processor already started bug checking for a panic, and owns the
+/// buffers
+#[cfg(feature = "panic-bugcheck")]
+static PANICKING: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(feature = "panic-bugcheck")]
+static PANIC_FILE: NulTerminatedBuffer<FILE_CAPACITY> = NulTerminatedBuffer::new();
+
+#[cfg(feature = "panic-bugcheck")]
+static PANIC_MESSAGE: NulTerminatedBuffer<MESSAGE_CAPACITY> = NulTerminatedBuffer::new();
+
+/// Stop the system with the [`RUST_PANIC`] bug check code, carrying the
+/// location and message of the panic.
+#[cfg(feature = "panic-bugcheck")]
+#[panic_handler]
+#[allow(unreachable_code, reason = "KeBugCheckEx does not return")]
+fn panic(info: &PanicInfo) -> ! {
+    use core::sync::atomic::Ordering;
+
+    use wdk_sys::{ntddk::KeBugCheckEx, ULONG_PTR};
+
+    let (file, line, column) = info.location().map_or(("", 0, 0), |location| {
+        (location.file(), location.line(), location.column())
+    });
+
+    let (file_address, message_address) = if PANICKING.swap(true, Ordering::AcqRel) {
+        (0, 0)
+    } else {
+        // SAFETY: Only the first processor to panic gets here, and it never
+        // leaves this function
+        unsafe {
+            (
+                PANIC_FILE.format(format_args!("{file}")),
+                PANIC_MESSAGE.format(format_args!("{}", info.message())),
+            )
+        }
+    };
+
+    // SAFETY: The driver is in an unknown state after a panic, so the system is
+    // stopped while the panicking code is still on the stack.
+    unsafe {
+        KeBugCheckEx(
+            RUST_PANIC,
+            file_address,
+            ULONG_PTR::from(line),
+            ULONG_PTR::from(column),
+            message_address,
+        );
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Log the location and message of the panic, break into the kernel debugger
+/// if one is attached, and park the panicking thread.
+///
+/// Logging allocates the message, so the panic must not come from the
+/// allocator. Parking the thread at `DISPATCH_LEVEL` or above eventually
+/// starves the processor, which Windows reports with a
+/// `DPC_WATCHDOG_VIOLATION` bug check.
+#[cfg(feature = "panic-log")]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use wdk_sys::ntddk::{DbgBreakPoint, KdRefreshDebuggerNotPresent};
+
+    use crate::log::log_error;
+
+    match info.location() {
+        Some(location) => log_error!("Panic at {location}: {}", info.message()),
+        None => log_error!("Panic: {}", info.message()),
+    }
+
+    // SAFETY: KdRefreshDebuggerNotPresent can be called at any IRQL
+    if unsafe { KdRefreshDebuggerNotPresent() } == 0 {
+        // SAFETY: A kernel debugger is attached, which handles the breakpoint
+        unsafe { DbgBreakPoint() };
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -1,112 +1,226 @@
 // Copyright (c) Microsoft Corporation.
 // License: MIT OR Apache-2.0
 
-use core::sync::atomic::Ordering;
-
-use wdk::{nt_success, paged_code, println, wdf};
+#[cfg(feature = "blocking-read")]
+mod blocking_read;
+#[cfg(feature = "queue-diagnostics")]
+mod diagnostics;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
+#[cfg(feature = "forward-writes")]
+mod forward_writes;
+#[cfg(feature = "latency-stats")]
+mod latency_stats;
+#[cfg(feature = "memory-pressure")]
+mod memory_pressure;
+#[cfg(feature = "method-neither")]
+mod method_neither;
+#[cfg(feature = "pending-limit")]
+mod pending_limit;
+#[cfg(feature = "timer-watchdog")]
+mod timer_watchdog;
+#[cfg(feature = "transform")]
+mod transform;
+
+#[cfg(any(feature = "parallel-queue", feature = "ring-buffer"))]
+extern crate alloc;
+
+#[cfg(any(feature = "parallel-queue", feature = "ring-buffer"))]
+use alloc::vec::Vec;
+
+use wdk::nt_success;
+#[cfg(feature = "timer-watchdog")]
+use wdk_sys::ntddk::KeQueryUnbiasedInterruptTime;
+#[cfg(not(feature = "ring-buffer"))]
+use wdk_sys::_POOL_TYPE;
+#[cfg(feature = "direct-io")]
+use wdk_sys::PMDL;
+#[cfg(feature = "read-overflow")]
+use wdk_sys::STATUS_BUFFER_OVERFLOW;
+#[cfg(not(feature = "direct-io"))]
+use wdk_sys::WDFMEMORY;
 use wdk_sys::{
     call_unsafe_wdf_function_binding,
-    ntddk::{ExAllocatePool2, ExFreePool, KeGetCurrentIrql},
-    APC_LEVEL,
+    KPROCESSOR_MODE,
     NTSTATUS,
-    POOL_FLAG_NON_PAGED,
-    SIZE_T,
-    STATUS_BUFFER_OVERFLOW,
+    PVOID,
+    STATUS_ACCESS_DENIED,
     STATUS_CANCELLED,
+    STATUS_DEVICE_BUSY,
+    STATUS_DEVICE_REMOVED,
     STATUS_INSUFFICIENT_RESOURCES,
     STATUS_INVALID_DEVICE_REQUEST,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_NO_MORE_ENTRIES,
     STATUS_SUCCESS,
+    ULONG,
+    UNICODE_STRING,
     WDFDEVICE,
-    WDFMEMORY,
     WDFOBJECT,
     WDFQUEUE,
     WDFREQUEST,
     WDFTIMER,
-    WDF_IO_QUEUE_CONFIG,
     WDF_NO_HANDLE,
-    WDF_OBJECT_ATTRIBUTES,
     WDF_TIMER_CONFIG,
+    _MODE,
     _WDF_EXECUTION_LEVEL,
     _WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_REQUEST_STOP_ACTION_FLAGS,
     _WDF_SYNCHRONIZATION_SCOPE,
-    _WDF_TRI_STATE,
 };
-
+#[cfg(feature = "dpc-completion")]
+use wdk_sys::{WDFDPC, WDF_DPC_CONFIG};
+#[cfg(feature = "wait-lock")]
+use wdk_sys::{WDFWORKITEM, WDF_WORKITEM_CONFIG};
+
+#[cfg(feature = "blocking-read")]
+use self::blocking_read::{echo_complete_waiting_reads, echo_wait_for_write};
+#[cfg(feature = "queue-diagnostics")]
+use self::diagnostics::echo_get_queue_state;
+#[cfg(feature = "fault-injection")]
+use self::fault_injection::{echo_inject_fault, echo_take_injected_fault};
+#[cfg(feature = "forward-writes")]
+use self::forward_writes::echo_forward_write;
+#[cfg(feature = "latency-stats")]
+use self::latency_stats::{echo_get_latency_stats, echo_record_latency, echo_stamp_arrival};
+#[cfg(feature = "memory-pressure")]
+use self::memory_pressure::{echo_fail_allocations, echo_take_allocation_failure};
+#[cfg(feature = "method-neither")]
+pub use self::method_neither::echo_evt_io_in_caller_context;
+#[cfg(feature = "method-neither")]
+use self::method_neither::echo_neither;
+#[cfg(feature = "pending-limit")]
+use self::pending_limit::{
+    echo_release_pending_slot,
+    echo_reserve_pending_slot,
+    echo_set_max_pending,
+};
+#[cfg(feature = "timer-watchdog")]
+use self::timer_watchdog::{echo_evt_watchdog_func, WATCHDOG_PERIOD};
+#[cfg(feature = "transform")]
+use self::transform::{echo_set_transform, echo_transform_write};
+#[cfg(feature = "callback-trace")]
+use crate::callback_tracker::{CallbackGuard, CallbackTracker};
+#[cfg(feature = "chunked-read")]
+use crate::chunks;
+#[cfg(feature = "direct-io")]
+use crate::mdl::get_system_address_for_mdl_safe;
+#[cfg(not(feature = "parallel-queue"))]
+use crate::request_state::{AtomicRequestState, RequestState};
+#[cfg(feature = "ring-buffer")]
+use crate::ring::Ring;
+#[cfg(feature = "timer-watchdog")]
+use crate::watchdog::Watchdog;
+#[cfg(feature = "dpc-completion")]
+use crate::wdf_dpc::Dpc;
+#[cfg(not(feature = "ring-buffer"))]
+use crate::wdf_memory::ManagedMemory;
+#[cfg(any(feature = "adaptive-timer", feature = "one-shot-timer"))]
+use crate::wdf_timer;
+#[cfg(feature = "wait-lock")]
+use crate::wdf_work_item::WorkItem;
+#[cfg(any(
+    feature = "adaptive-timer",
+    feature = "pending-limit",
+    feature = "destroy-callback"
+))]
+use crate::AtomicU32;
+#[cfg(not(feature = "wait-lock"))]
+use crate::SpinLockExt;
+#[cfg(feature = "memory-pressure")]
+use crate::IOCTL_ECHO_FAIL_ALLOCATIONS;
+#[cfg(feature = "latency-stats")]
+use crate::IOCTL_ECHO_GET_LATENCY_STATS;
+#[cfg(feature = "queue-diagnostics")]
+use crate::IOCTL_ECHO_GET_QUEUE_STATE;
+#[cfg(feature = "fault-injection")]
+use crate::IOCTL_ECHO_INJECT_FAULT;
+#[cfg(feature = "method-neither")]
+use crate::IOCTL_ECHO_NEITHER;
+#[cfg(feature = "pending-limit")]
+use crate::IOCTL_ECHO_SET_MAX_PENDING;
+#[cfg(feature = "transform")]
+use crate::IOCTL_ECHO_SET_TRANSFORM;
 use crate::{
+    cancel_protocol::{self, CancelAction, TimerAction, UnmarkAction},
+    config::DriverConfig,
+    log::{log_error, log_info},
+    nt_assert::nt_assert_msg,
+    nt_status::NtStatus,
+    paged_code::paged_code_checked,
+    queue_context_evt_cleanup,
     queue_get_context,
     request_get_context,
-    wdf_object_context::wdf_get_context_type_info,
+    teardown::tear_down,
+    transfer_length::validate_write_length,
+    wdf_io_queue_config::QueueConfig,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_object_get_device_context,
+    wdf_object_get_device_stats_context,
+    wdf_object_reference::RefGuard,
+    wdf_structure_size::wdf_structure_size,
+    wdf_timer::Timer,
+    AtomicBool,
     AtomicI32,
+    DeviceContext,
     QueueContext,
+    QueueLock,
+    Request,
     RequestContext,
-    WDF_IO_QUEUE_CONFIG_SIZE,
-    WDF_OBJECT_ATTRIBUTES_SIZE,
-    WDF_QUEUE_CONTEXT_TYPE_INFO,
-    WDF_TIMER_CONFIG_SIZE,
+    IOCTL_ECHO_GET_WDF_VERSION,
 };
+#[cfg(feature = "parallel-queue")]
+use crate::{nt_assert::nt_assert, wdf_collection::Collection};
+
+/// Longest chunk copied to the memory of a read at once, with the
+/// `chunked-read` feature
+#[cfg(feature = "chunked-read")]
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Number of queues whose `EvtCleanupCallback` has run, but not their
+/// `EvtDestroyCallback` yet, with the `destroy-callback` feature. It is kept
+/// out of the `QueueContext`, which the cleanup callback drops, for the destroy
+/// callback to check.
+#[cfg(feature = "destroy-callback")]
+static QUEUES_CLEANED_UP: AtomicU32 = AtomicU32::new(0);
+
+/// Capacity of the ring that writes accumulate in with the `ring-buffer`
+/// feature, enough for a couple of writes of the maximum length
+#[cfg(feature = "ring-buffer")]
+const fn echo_ring_capacity(config: &DriverConfig) -> usize {
+    2 * config.max_write_length
+}
 
-/// Set max write length for testing
-const MAX_WRITE_LENGTH: usize = 1024 * 40;
-
-/// Set timer period in ms
-const TIMER_PERIOD: u32 = 1000 * 10;
+/// Length of the longest read that can return data, with the `read-overflow`
+/// feature: the data of the longest write, with its sequence number
+#[cfg(all(feature = "read-overflow", not(feature = "ring-buffer")))]
+const fn echo_max_read_length(config: &DriverConfig) -> usize {
+    config.max_write_length + SEQUENCE_NUMBER_LENGTH
+}
 
-/// This routine will interlock increment a value only if the current value
-/// is greater then the floor value.
-///
-/// The volatile keyword on the Target pointer is absolutely required, otherwise
-/// the compiler might rearrange pointer dereferences and that cannot happen.
-///
-/// # Arguments:
-///
-/// * `target` - the  value that will be pontetially incrmented
-/// * `floor` - the value in which the Target value must be greater then if it
-///   is to be incremented
-///
-/// # Return value:
-///
-/// The current value of Target.  To detect failure, the return value will be
-/// <= Floor + 1.  It is +1 because we cannot increment from the Floor value
-/// itself, so Floor+1 cannot be a successful return value.
-fn echo_interlocked_increment_floor(target: &AtomicI32, floor: i32) -> i32 {
-    let mut current_value = target.load(Ordering::SeqCst);
-    loop {
-        if current_value <= floor {
-            return current_value;
-        }
+/// Length of the longest read that can return data, with the `read-overflow`
+/// feature: the content of a full ring
+#[cfg(all(feature = "read-overflow", feature = "ring-buffer"))]
+const fn echo_max_read_length(config: &DriverConfig) -> usize {
+    echo_ring_capacity(config)
+}
 
-        // currentValue will be the value that used to be Target if the exchange
-        // was made or its current value if the exchange was not made.
-        //
-        match target.compare_exchange(
-            current_value,
-            current_value + 1,
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        ) {
-            // If oldValue == currentValue, then no one updated Target in between
-            // the deref at the top and the InterlockecCompareExchange afterward
-            // and we have successfully incremented the value and can exit the loop.
-            Ok(_) => break,
-            Err(v) => current_value = v,
-        }
-    }
+/// Requestor mode of the requests issued by other drivers
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "KernelMode is 0, which fits in KPROCESSOR_MODE"
+)]
+const KERNEL_MODE: KPROCESSOR_MODE = _MODE::KernelMode as KPROCESSOR_MODE;
 
-    current_value + 1
-}
+/// Length of the sequence number that is prepended to each buffer written to
+/// the device with the `sequence-numbers` feature
+#[cfg(feature = "sequence-numbers")]
+const SEQUENCE_NUMBER_LENGTH: usize = core::mem::size_of::<u64>();
 
-/// Increment the value only if it is currently > 0.
-///
-/// # Arguments:
-///
-/// * `target` - the value to be incremented
-///
-/// # Return value:
-///
-/// Upon success, a value > 0.  Upon failure, a value <= 0.
-fn echo_interlocked_increment_gtzero(target: &AtomicI32) -> i32 {
-    echo_interlocked_increment_floor(target, 0)
-}
+/// Length of the sequence number that is prepended to each buffer written to
+/// the device with the `sequence-numbers` feature
+#[cfg(not(feature = "sequence-numbers"))]
+const SEQUENCE_NUMBER_LENGTH: usize = 0;
 
 /// The I/O dispatch callbacks for the frameworks device object
 /// are configured in this function.
@@ -122,41 +236,66 @@ fn echo_interlocked_increment_gtzero(target: &AtomicI32) -> i32 {
 /// Queue object, and we register an optional destructor callback
 /// to release any private allocations, and/or resources.
 ///
+/// A second, manual queue is created for the write requests forwarded by
+/// `echo_evt_io_write`, and stored in the device context.
+///
 /// # Arguments:
 ///
 /// * `device` - Handle to a framework device object.
 ///
 /// # Return value:
 ///
-/// * `NTSTATUS`
+/// * `Ok(())` on success,
+/// * `Err(NTSTATUS)` - the status of the first creation that failed, after
+///   logging it.
 #[link_section = "PAGE"]
-pub unsafe fn echo_queue_initialize(device: WDFDEVICE) -> NTSTATUS {
-    paged_code!();
+pub unsafe fn echo_queue_initialize(device: WDFDEVICE) -> Result<(), NTSTATUS> {
+    paged_code_checked!();
 
     let mut queue = WDF_NO_HANDLE as WDFQUEUE;
+    let config = DriverConfig::current();
 
     // Configure a default queue so that requests that are not
     // configure-fowarded using WdfDeviceConfigureRequestDispatching to goto
-    // other queues get dispatched here.
-    let mut queue_config = WDF_IO_QUEUE_CONFIG {
-        Size: WDF_IO_QUEUE_CONFIG_SIZE,
-        PowerManaged: _WDF_TRI_STATE::WdfUseDefault,
-        DefaultQueue: u8::from(true),
-        DispatchType: _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential,
-        EvtIoRead: Some(echo_evt_io_read),
-        EvtIoWrite: Some(echo_evt_io_write),
-        ..WDF_IO_QUEUE_CONFIG::default()
-    };
-
-    // Fill in a callback for destroy, and our QUEUE_CONTEXT size
-    let mut attributes = WDF_OBJECT_ATTRIBUTES {
-        Size: WDF_OBJECT_ATTRIBUTES_SIZE,
-        ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
-        SynchronizationScope: _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
-        ContextTypeInfo: wdf_get_context_type_info!(QueueContext),
-        EvtDestroyCallback: Some(echo_evt_io_queue_context_destroy),
-        ..WDF_OBJECT_ATTRIBUTES::default()
-    };
+    // other queues get dispatched here. With the `parallel-queue` feature, reads
+    // wait for the timer together in a collection instead of as the single
+    // current request, and the queue is parallel by default: they are presented
+    // without waiting for the previous one to be completed.
+    //
+    // Zero-length reads and writes are completed by the framework unless the
+    // driver is built with `allow-zero-length-requests`
+    let mut queue_config = QueueConfig::default_queue(config.dispatch_type)
+        .allow_zero_length_requests(cfg!(feature = "allow-zero-length-requests"))
+        .read(Some(echo_evt_io_read))
+        .write(Some(echo_evt_io_write))
+        .stop(Some(echo_evt_io_stop))
+        .device_control(Some(echo_evt_io_device_control))
+        .build();
+
+    // Fill in a callback for cleanup, and our QUEUE_CONTEXT size. With the
+    // `wait-lock` feature, the queue callbacks and the cancel routines of its
+    // requests are called at PASSIVE_LEVEL, where the wait lock can be acquired.
+    // With the `queue-serialization` feature, the framework calls them one at a
+    // time, along with the callbacks of the children of the queue created with
+    // AutomaticSerialization.
+    let mut attributes = ObjectAttributes::new()
+        .context::<QueueContext>()
+        .cleanup(Some(queue_context_evt_cleanup))
+        .execution_level(if cfg!(feature = "wait-lock") {
+            _WDF_EXECUTION_LEVEL::WdfExecutionLevelPassive
+        } else {
+            _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent
+        })
+        .synchronization_scope(if cfg!(feature = "queue-serialization") {
+            _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeQueue
+        } else {
+            _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent
+        });
+    // With the `destroy-callback` feature, the queue also gets a callback for
+    // destroy, to show when it runs compared to the cleanup callback
+    #[cfg(feature = "destroy-callback")]
+    let attributes = attributes.destroy(Some(echo_evt_queue_destroy));
+    let mut attributes = attributes.build();
 
     // Create queue.
     let nt_status = unsafe {
@@ -170,128 +309,392 @@ pub unsafe fn echo_queue_initialize(device: WDFDEVICE) -> NTSTATUS {
     };
 
     if !nt_success(nt_status) {
-        println!("WdfIoQueueCreate failed {nt_status:#010X}");
-        return nt_status;
+        log_error!("WdfIoQueueCreate failed {}", NtStatus(nt_status));
+        return Err(nt_status);
     }
 
     // Get our Driver Context memory from the returned Queue handle
-    let queue_context: *mut QueueContext = unsafe { queue_get_context(queue as WDFOBJECT) };
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
     unsafe {
-        (*queue_context).buffer = core::ptr::null_mut();
+        #[cfg(not(feature = "ring-buffer"))]
+        {
+            (*queue_context).buffer = None;
+        }
+        #[cfg(feature = "partial-reads")]
+        {
+            (*queue_context).read_offset = 0;
+        }
         (*queue_context).current_request = core::ptr::null_mut();
+        #[cfg(not(feature = "parallel-queue"))]
+        {
+            (*queue_context).current_state = AtomicRequestState::new();
+        }
         (*queue_context).current_status = STATUS_INVALID_DEVICE_REQUEST;
+        #[cfg(feature = "callback-trace")]
+        {
+            (*queue_context).callbacks = CallbackTracker::new();
+        }
+        (*queue_context).torn_down = AtomicBool::new(false);
+        #[cfg(feature = "pending-limit")]
+        {
+            (*queue_context).pending_count = AtomicU32::new(0);
+        }
+        #[cfg(feature = "adaptive-timer")]
+        {
+            (*queue_context).timer_period = AtomicU32::new(config.timer_period_ms);
+            (*queue_context).work_arrived = AtomicBool::new(false);
+            (*queue_context).timer_running = AtomicBool::new(false);
+        }
+        #[cfg(feature = "one-shot-timer")]
+        {
+            (*queue_context).timer_armed = AtomicBool::new(false);
+        }
     }
 
-    // Create the SpinLock.
-    let mut attributes = WDF_OBJECT_ATTRIBUTES {
-        Size: WDF_OBJECT_ATTRIBUTES_SIZE,
-        ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
-        SynchronizationScope: _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
-        ParentObject: queue as WDFOBJECT,
-        ..WDF_OBJECT_ATTRIBUTES::default()
+    // Hold a reference on the queue until its cleanup callback, with the
+    // `destroy-callback` feature. Released any later, e.g. by the destroy
+    // callback, it would keep the queue from ever being destroyed.
+    #[cfg(feature = "destroy-callback")]
+    unsafe {
+        (*queue_context).self_reference = Some(RefGuard::new(queue as WDFOBJECT));
+    }
+
+    // Create the manual queue that write requests are forwarded to. The
+    // framework never presents the requests of a manual queue to the driver: they
+    // stay in the queue, where the framework cancels them if needed, until the
+    // driver retrieves them with WdfIoQueueRetrieveNextRequest. Requests are
+    // only forwarded explicitly, so no WdfDeviceConfigureRequestDispatching is
+    // needed.
+    let mut manual_queue = WDF_NO_HANDLE as WDFQUEUE;
+    let mut manual_queue_config =
+        QueueConfig::new(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchManual).build();
+    let mut attributes = ObjectAttributes::new().build();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfIoQueueCreate,
+            device,
+            &mut manual_queue_config,
+            &mut attributes,
+            &mut manual_queue
+        )
     };
 
-    match wdf::SpinLock::create(&mut attributes) {
-        Err(status) => {
-            println!("SpinLock create failed {nt_status:#010X}");
-            return status;
-        }
-        Ok(spin_lock) => unsafe { (*queue_context).spin_lock = spin_lock },
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfIoQueueCreate for the manual queue failed {}",
+            NtStatus(nt_status)
+        );
+        return Err(nt_status);
+    }
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
     };
+    unsafe {
+        (*device_context).manual_queue = manual_queue;
+    }
+
+    // Create the queue lock, a SpinLock, or a WaitLock with the `wait-lock`
+    // feature.
+    let mut attributes = ObjectAttributes::new().parent(queue as WDFOBJECT).build();
+
+    let lock = QueueLock::create(&mut attributes).map_err(|status| {
+        log_error!("Queue lock create failed {}", NtStatus(status));
+        status
+    })?;
+    unsafe { (*queue_context).lock = lock };
+
+    // Create the collection of pending requests with the `parallel-queue`
+    // feature. It is only accessed under the queue lock.
+    #[cfg(feature = "parallel-queue")]
+    {
+        let collection = Collection::create(&mut attributes).map_err(|status| {
+            log_error!("Collection create failed {}", NtStatus(status));
+            status
+        })?;
+        unsafe { (*queue_context).pending_requests = collection };
+    }
+
+    // Create the collection of reads waiting for data with the `blocking-read`
+    // feature. It is only accessed under the queue lock.
+    #[cfg(feature = "blocking-read")]
+    {
+        let collection = Collection::create(&mut attributes).map_err(|status| {
+            log_error!("Collection create failed {}", NtStatus(status));
+            status
+        })?;
+        unsafe { (*queue_context).waiting_reads = collection };
+    }
+
+    // Create the ring that writes accumulate in with the `ring-buffer` feature.
+    // It is only accessed under the queue lock.
+    #[cfg(feature = "ring-buffer")]
+    {
+        let ring =
+            Ring::create(echo_ring_capacity(&config), config.pool_tag).map_err(|status| {
+                log_error!("Ring create failed {}", NtStatus(status));
+                status
+            })?;
+        unsafe { (*queue_context).ring = ring };
+    }
+
+    // Create the work item the timer queues with the `wait-lock` feature. It
+    // inherits the passive execution level of the queue, and synchronizes with
+    // the queue callbacks and the cancel routine by acquiring the queue lock
+    // itself.
+    #[cfg(feature = "wait-lock")]
+    {
+        let mut work_item_config = WDF_WORKITEM_CONFIG {
+            Size: wdf_structure_size!(WDF_WORKITEM_CONFIG),
+            EvtWorkItemFunc: Some(echo_evt_work_item_func),
+            AutomaticSerialization: u8::from(false),
+        };
+
+        let work_item =
+            WorkItem::create(&mut work_item_config, &mut attributes).map_err(|status| {
+                log_error!("WorkItem create failed {}", NtStatus(status));
+                status
+            })?;
+        unsafe { (*queue_context).work_item = work_item };
+    }
 
     // Create the Queue timer
     //
     // By not setting the synchronization scope and using the default at
     // WdfIoQueueCreate, we are explicitly *not* serializing against the queue's
-    // lock. Instead, we will do that on our own.
+    // lock. Instead, we will do that on our own. With the `queue-serialization`
+    // feature, the queue has a synchronization scope, and AutomaticSerialization
+    // makes the framework acquire the queue's lock around the timer callback.
+    //
+    // With the `wait-lock` feature, the timer does not inherit the passive
+    // execution level of the queue: it keeps firing at DISPATCH_LEVEL, and only
+    // queues the work item.
+    let mut timer_attributes = ObjectAttributes::new()
+        .parent(queue as WDFOBJECT)
+        .execution_level(if cfg!(feature = "wait-lock") {
+            _WDF_EXECUTION_LEVEL::WdfExecutionLevelDispatch
+        } else {
+            _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent
+        })
+        .build();
     let mut timer_config = WDF_TIMER_CONFIG {
-        Size: WDF_TIMER_CONFIG_SIZE,
+        Size: wdf_structure_size!(WDF_TIMER_CONFIG),
         EvtTimerFunc: Some(echo_evt_timer_func),
-        Period: TIMER_PERIOD,
-        AutomaticSerialization: u8::from(true),
+        // With the `adaptive-timer` feature, the timer fires once each time it
+        // is started, and echo_evt_timer_func starts it again with a new delay.
+        // With the `one-shot-timer` feature, it is started by echo_arm_timer
+        // when a request arrives instead.
+        Period: if cfg!(any(feature = "adaptive-timer", feature = "one-shot-timer")) {
+            0
+        } else {
+            config.timer_period_ms
+        },
+        AutomaticSerialization: u8::from(cfg!(feature = "queue-serialization")),
         TolerableDelay: 0,
         ..WDF_TIMER_CONFIG::default()
     };
 
-    match wdf::Timer::create(&mut timer_config, &mut attributes) {
-        Err(status) => {
-            println!("Timer create failed {nt_status:#010X}");
-            return status;
-        }
-        Ok(wdftimer) => unsafe { (*queue_context).timer = wdftimer },
-    };
+    let wdftimer = Timer::create(&mut timer_config, &mut timer_attributes).map_err(|status| {
+        log_error!("Timer create failed {}", NtStatus(status));
+        status
+    })?;
+    unsafe { (*queue_context).timer = wdftimer };
+
+    // Create the watchdog timer with the `timer-watchdog` feature
+    //
+    // It never uses AutomaticSerialization and always runs at DISPATCH_LEVEL:
+    // it only reads the watchdog and the request counts of the queues, and
+    // must keep firing when the timer is stuck holding the queue lock.
+    #[cfg(feature = "timer-watchdog")]
+    {
+        let mut watchdog_attributes = ObjectAttributes::new()
+            .parent(queue as WDFOBJECT)
+            .execution_level(_WDF_EXECUTION_LEVEL::WdfExecutionLevelDispatch)
+            .build();
+        let mut watchdog_config = WDF_TIMER_CONFIG {
+            Size: wdf_structure_size!(WDF_TIMER_CONFIG),
+            EvtTimerFunc: Some(echo_evt_watchdog_func),
+            Period: WATCHDOG_PERIOD,
+            AutomaticSerialization: u8::from(false),
+            TolerableDelay: 0,
+            ..WDF_TIMER_CONFIG::default()
+        };
+
+        let watchdog_timer = Timer::create(&mut watchdog_config, &mut watchdog_attributes)
+            .map_err(|status| {
+                log_error!("Watchdog timer create failed {}", NtStatus(status));
+                status
+            })?;
+        unsafe {
+            (*queue_context).watchdog = Watchdog::new();
+            (*queue_context).watchdog_timer = watchdog_timer;
+        };
+    }
+
+    // Create the DPC that completes write requests with the `dpc-completion`
+    // feature
+    //
+    // Unlike the timer with the `queue-serialization` feature, the DPC never
+    // uses AutomaticSerialization: it always synchronizes with the queue
+    // callbacks and the cancel routine by acquiring the queue lock itself.
+    #[cfg(feature = "dpc-completion")]
+    {
+        let mut dpc_config = WDF_DPC_CONFIG {
+            Size: wdf_structure_size!(WDF_DPC_CONFIG),
+            EvtDpcFunc: Some(echo_evt_dpc_func),
+            AutomaticSerialization: u8::from(false),
+        };
+
+        let dpc = Dpc::create(&mut dpc_config, &mut attributes).map_err(|status| {
+            log_error!("Dpc create failed {}", NtStatus(status));
+            status
+        })?;
+        unsafe { (*queue_context).dpc = dpc };
+    }
 
-    STATUS_SUCCESS
+    Ok(())
 }
 
-/// This is called when the Queue that our driver context memory
-/// is associated with is destroyed.
+/// Release any resources pointed to in the queue context. This runs in
+/// `queue_context_evt_cleanup`, the `EvtCleanupCallback` of the queue, when the
+/// queue is deleted. The body of the queue context will be released by the
+/// framework afterwards.
 ///
-/// # Arguments:
+/// The timer must not run once this has started, since it would use the data
+/// being released. The framework orders the teardown so that it cannot:
 ///
-/// * `object` - Queue object to be freed.
+/// 1. `echo_evt_device_self_managed_io_suspend` stops the timer, waiting for a
+///    running callback to return, when the device is removed, as when it is
+///    suspended.
+/// 2. `echo_evt_device_self_managed_io_cleanup`, the last callback of a device
+///    being removed, sets `torn_down` and stops the timer again, waiting.
+/// 3. The timer is a child of the queue, so the framework cleans it up, which
+///    stops it and flushes its callback too, before calling the cleanup
+///    callback of the queue.
 ///
-/// # Return value:
+/// These guarantees do not cover a timer started again behind the framework's
+/// back, e.g. by a callback of the `adaptive-timer` feature that was running
+/// during the stop, or a queue deleted without the device being removed, when
+/// adding the device fails. So before anything is released, the queue is torn
+/// down here again with [`tear_down`], as in step 2: `torn_down` is set, which
+/// the timer callback checks first, and the timers are stopped, waiting for a
+/// running callback to return. The flag alone would not suffice, since a
+/// callback that checked it just before it was set would go on, and the wait
+/// alone would not either, since that callback could start the timer again.
 ///
-/// * `VOID`
-extern "C" fn echo_evt_io_queue_context_destroy(object: WDFOBJECT) {
-    let queue_context = unsafe { queue_get_context(object) };
-    // Release any resources pointed to in the queue context.
-    //
-    // The body of the queue context will be released after
-    // this callback handler returns
+/// The [`Timer`]s hold a reference on their WDF objects until they are dropped
+/// with the context, so their handles are still valid here even once the
+/// framework has cleaned them up in step 3, and stopping them then does
+/// nothing.
+impl Drop for QueueContext {
+    fn drop(&mut self) {
+        tear_down(
+            &self.torn_down,
+            &[
+                #[cfg(feature = "timer-watchdog")]
+                &self.watchdog_timer,
+                &self.timer,
+            ],
+        );
 
-    // If Queue context has an I/O buffer, release it
-    unsafe {
-        if !(*queue_context).buffer.is_null() {
-            ExFreePool((*queue_context).buffer);
-            (*queue_context).buffer = core::ptr::null_mut();
+        // The I/O buffer is a WDF memory object parented to the queue, so it is
+        // not released here: the framework deletes it along with the queue.
+        // With the `ring-buffer` feature, the ring releases its storage
+        // when it is dropped, right after this function returns.
+        //
+        // With the `dpc-completion` feature, the DPC, and with the `wait-lock`
+        // feature, the work item, are also children of the queue, stopped in
+        // steps 1 and 3 like the timer.
+        //
+        // With the `destroy-callback` feature, the reference of the queue on
+        // itself is released here, when `self_reference` is dropped right
+        // after this function returns. This is the last chance to release it:
+        // the framework only calls the destroy callback of the queue once it
+        // has no references left.
+        #[cfg(feature = "destroy-callback")]
+        {
+            log_info!("Queue cleanup, releasing the reference of the queue on itself");
+            QUEUES_CLEANED_UP.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
         }
     }
 }
 
-/// Decrements the cancel ownership count for the request.  When the count
-/// reaches zero ownership has been acquired.
+/// `EvtDestroyCallback` of the default queue, with the `destroy-callback`
+/// feature.
+///
+/// When the queue is deleted, the framework first calls its
+/// `EvtCleanupCallback`, `queue_context_evt_cleanup`, which runs the [`Drop`]
+/// implementation of the `QueueContext`. The cleanup callback of a queue is
+/// called at `PASSIVE_LEVEL`, after the cleanup callbacks of its children,
+/// while the handles of the queue and its parent device can still be used: it
+/// is where the queue releases what it holds, and in particular the references
+/// it took on objects.
+///
+/// The framework calls this destroy callback later, once the last reference on
+/// the queue has been released, right before freeing the queue and its
+/// context. This can be from whichever thread releases the last reference, so
+/// it runs at `IRQL <= DISPATCH_LEVEL`. By then the children of the queue are
+/// gone, and its context is still allocated but has already been dropped by
+/// the cleanup callback, so it must not be read: nothing is left to release
+/// but memory that is safe to free at `DISPATCH_LEVEL`.
 ///
 /// # Arguments:
 ///
-/// * `request_context` - the context which holds the count.
+/// * `object` - Handle to the queue being destroyed.
 ///
 /// # Return value:
 ///
-/// * TRUE if the caller can complete the request, FALSE otherwise
-fn echo_decrement_request_cancel_ownership_count(request_context: *mut RequestContext) -> bool {
-    let result = unsafe {
-        (*request_context)
-            .cancel_completion_ownership_count
-            .fetch_sub(1, Ordering::SeqCst)
-    };
-
-    result - 1 == 0
+/// * `VOID`
+#[cfg(feature = "destroy-callback")]
+extern "C" fn echo_evt_queue_destroy(object: WDFOBJECT) {
+    // The context has already been dropped by the cleanup callback, so it is
+    // not read here. The cleanup callback of this queue must have counted it
+    // instead, and each destroy callback takes one queue off the count.
+    let cleaned_up = QUEUES_CLEANED_UP
+        .fetch_update(
+            core::sync::atomic::Ordering::SeqCst,
+            core::sync::atomic::Ordering::SeqCst,
+            |count| count.checked_sub(1),
+        )
+        .is_ok();
+    nt_assert_msg!(cleaned_up, "queue destroyed before its cleanup callback");
+    log_info!("Queue {object:?} destroyed, cleanup callback run before: {cleaned_up}");
 }
 
-/// Attempts to increment the request ownership count so that it cannot be
-/// completed until the count has been decremented
+/// Track `callback` of `queue` with the `CallbackTracker` of its context until
+/// the returned guard is dropped, logging the other queue callbacks running at
+/// the same time.
 ///
 /// # Arguments:
 ///
-/// * `request_context` - the context which holds the count.
+/// * `queue` - Handle to the default queue of the device.
+/// * `callback` - Name of the callback, for the log.
 ///
 /// # Return value:
 ///
-/// * TRUE if the count was incremented, FALSE otherwise
-fn echo_increment_request_cancel_ownership_count(request_context: *mut RequestContext) -> bool {
-    // See comments in echo_interlocked_increment_floor as to why <= 1 is failure
-    //
-    (unsafe {
-        echo_interlocked_increment_gtzero(&(*request_context).cancel_completion_ownership_count)
-    }) > 1
+/// * The guard, or `None` if the queue has no context.
+#[cfg(feature = "callback-trace")]
+fn echo_enter_callback(queue: WDFQUEUE, callback: &'static str) -> Option<CallbackGuard<'static>> {
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return None;
+    };
+    // SAFETY: The queue context lives as long as the queue, which outlives the
+    // callbacks the framework calls for it
+    Some(unsafe { &*queue_context }.callbacks.enter(callback))
 }
 
 /// Called when an I/O request is cancelled after the driver has marked
 /// the request cancellable. This callback is not automatically synchronized
 /// with the I/O callbacks since we have chosen not to use frameworks Device
-/// or Queue level locking.
+/// or Queue level locking, unless the driver is built with the
+/// `queue-serialization` feature.
 ///
 /// # Arguments:
 ///
@@ -302,320 +705,1671 @@ fn echo_increment_request_cancel_ownership_count(request_context: *mut RequestCo
 /// * `VOID`
 extern "C" fn echo_evt_request_cancel(request: WDFREQUEST) {
     let queue = unsafe { call_unsafe_wdf_function_binding!(WdfRequestGetIoQueue, request) };
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
-    let request_context = unsafe { request_get_context(request as WDFOBJECT) };
+    #[cfg(feature = "callback-trace")]
+    let _callback = echo_enter_callback(queue, "echo_evt_request_cancel");
+    let (Some(queue_context), Some(request_context)) = (unsafe {
+        (
+            queue_get_context(queue as WDFOBJECT),
+            request_get_context(request as WDFOBJECT),
+        )
+    }) else {
+        // Without the contexts, the request cannot be the current request of the
+        // queue, so nothing else can complete it
+        log_error!("Request {request:?} or its queue {queue:?} has no context");
+        // SAFETY: The cancel routine owns the request when nothing else can
+        // complete it
+        unsafe { Request::from_raw(request) }.complete_with_information(STATUS_CANCELLED, 0);
+        return;
+    };
 
-    println!("echo_evt_request_cancel called on Request {:?}", request);
+    log_info!("echo_evt_request_cancel called on Request {:?}", request);
+
+    #[cfg(feature = "etw-events")]
+    crate::etw_events::request_cancel(request);
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_request_cancel(request);
 
     // This book keeping is synchronized by the common
     // Queue presentation lock which we are now acquiring
-    unsafe { (*queue_context).spin_lock.acquire() };
+    let complete_request = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
 
-    let complete_request: bool = echo_decrement_request_cancel_ownership_count(request_context);
-
-    if complete_request {
-        unsafe {
-            (*queue_context).current_request = core::ptr::null_mut();
-        }
-    } else {
+        // The cancel routine of the current request runs at most once, whether
+        // the timer is completing the request or not
+        #[cfg(not(feature = "parallel-queue"))]
         unsafe {
-            (*queue_context).current_status = STATUS_CANCELLED;
+            (*queue_context)
+                .current_state
+                .transition(RequestState::Cancelled);
         }
-    }
 
-    unsafe { (*queue_context).spin_lock.release() };
+        match cancel_protocol::on_cancel(unsafe {
+            &(*request_context).cancel_completion_ownership_count
+        }) {
+            CancelAction::CompleteCancelled => {
+                unsafe { echo_remove_pending_request(queue_context, request) };
+                #[cfg(not(feature = "parallel-queue"))]
+                unsafe {
+                    (*queue_context)
+                        .current_state
+                        .transition(RequestState::Idle);
+                }
+                true
+            }
+            CancelAction::MarkCancelled => {
+                unsafe {
+                    (*queue_context).current_status = STATUS_CANCELLED;
+                }
+                false
+            }
+        }
+    };
 
     // Complete the request outside of holding any locks
     if complete_request {
-        unsafe {
-            call_unsafe_wdf_function_binding!(
-                WdfRequestCompleteWithInformation,
-                request,
-                STATUS_CANCELLED,
-                0
-            );
-        }
+        // SAFETY: The cancel ownership count reached zero, so this routine owns the
+        // request and is the only one completing it
+        let request = unsafe { Request::from_raw(request) };
+        let reference = unsafe { echo_take_request_reference(request_context) };
+        request.complete_with_information(STATUS_CANCELLED, 0);
+        drop(reference);
     }
 }
 
-/// Setup the request, intialize its context and mark it as cancelable.
+/// This event is called by the framework for the request the driver owns
+/// when the queue is stopped: when the device leaves D0 (Dx transitions,
+/// including the ones caused by `WdfIoQueueStopSynchronously` in
+/// `echo_evt_device_self_managed_io_suspend`) or when it is removed, including
+/// surprise removal.
+///
+/// The only request the driver ever owns is the queue context's
+/// `current_request`, which is waiting for the timer to complete it (with the
+/// `parallel-queue` feature, one of its `pending_requests`, see below):
+///
+/// * On a power-down (`WdfRequestStopActionSuspend`), the request is
+///   acknowledged and kept, cancelable, as the current request. The timer
+///   completes it once the device is back in D0.
+/// * On a removal (`WdfRequestStopActionPurge`), the request is completed right
+///   away with `STATUS_CANCELLED`. This goes through
+///   `echo_complete_current_request`, which claims completion ownership with
+///   the same cancel ownership count as the timer, so a cancel routine running
+///   at the same time still completes the request exactly once.
+///
+/// With the `parallel-queue` feature, a removal completes all of the pending
+/// requests at once, with the data they were given, as the timer would.
 ///
 /// # Arguments:
 ///
-/// * `request` - Request being set up.
-/// * `queue` - Queue associated with the request
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `action_flags` - `WDF_REQUEST_STOP_ACTION_FLAGS` describing why the queue
+///   is being stopped.
 ///
 /// # Return value:
 ///
 /// * `VOID`
-fn echo_set_current_request(request: WDFREQUEST, queue: WDFQUEUE) {
-    let status: NTSTATUS;
-    let request_context = unsafe { request_get_context(request as WDFOBJECT) };
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
+extern "C" fn echo_evt_io_stop(queue: WDFQUEUE, request: WDFREQUEST, action_flags: ULONG) {
+    #[allow(
+        clippy::cast_sign_loss,
+        reason = "WDF_REQUEST_STOP_ACTION_FLAGS values are all positive"
+    )]
+    const STOP_ACTION_PURGE: ULONG =
+        _WDF_REQUEST_STOP_ACTION_FLAGS::WdfRequestStopActionPurge as ULONG;
+
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
 
-    // Set the ownership count to one.  When a caller wants to claim ownership,
-    // they will interlock decrement the count.  When the count reaches zero,
-    // ownership has been acquired and the caller may complete the request.
-    unsafe {
-        (*request_context).cancel_completion_ownership_count = AtomicI32::new(1);
-    }
+    log_info!(
+        "echo_evt_io_stop called! queue {:?}, request {:?}, action flags {:#X}",
+        queue,
+        request,
+        action_flags
+    );
 
-    // Defer the completion to another thread from the timer dpc
-    unsafe { (*queue_context).spin_lock.acquire() };
-    unsafe {
-        (*queue_context).current_request = request;
-        (*queue_context).current_status = STATUS_SUCCESS;
+    let purge = action_flags & STOP_ACTION_PURGE != 0;
+    let is_current_request = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        let is_current_request = unsafe { echo_is_pending_request(queue_context, request) };
+        if is_current_request && purge {
+            unsafe {
+                (*queue_context).current_status = STATUS_CANCELLED;
+            }
+        }
+        is_current_request
+    };
+
+    if !is_current_request {
+        // The request was completed by the timer or the cancel routine after
+        // the framework decided to call this routine, there is nothing left to
+        // do.
+        return;
     }
 
-    // Set the cancel routine under the lock, otherwise if we set it outside
-    // of the lock, the timer could run and attempt to mark the request
-    // uncancelable before we can mark it cancelable on this thread. Use
-    // WdfRequestMarkCancelableEx here to prevent to deadlock with ourselves
-    // (cancel routine tries to acquire the queue object lock).
-    unsafe {
-        status = call_unsafe_wdf_function_binding!(
-            WdfRequestMarkCancelableEx,
-            request,
-            Some(echo_evt_request_cancel)
-        );
-        if !nt_success(status) {
-            (*queue_context).current_request = core::ptr::null_mut();
-        }
+    if purge {
+        // Complete the request as the timer would have, with the status set
+        // above. If the cancel routine has already claimed the request, it
+        // completes it instead.
+        echo_complete_current_request(queue);
+        // The reads waiting for data are cancelled, since no write will come
+        #[cfg(feature = "blocking-read")]
+        echo_complete_waiting_reads(queue, true);
+    } else {
+        // SAFETY: The request is still the current request, so it is owned by
+        // the driver. Acknowledging it does not complete it.
+        let request = unsafe { Request::from_raw(request) };
+        request.stop_acknowledge(false);
     }
+}
 
-    unsafe { (*queue_context).spin_lock.release() };
+/// Make `request` the current request of the queue, or with the
+/// `parallel-queue` feature, add it to the pending requests. Must be called
+/// with the queue context lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or `STATUS_DEVICE_BUSY` if there is already a current
+///   request, in which case the request must be forwarded to the manual queue
+///   rather than completed.
+#[cfg(not(feature = "parallel-queue"))]
+unsafe fn echo_add_pending_request(
+    queue_context: *mut QueueContext,
+    request: WDFREQUEST,
+) -> Result<(), NTSTATUS> {
+    let current_request = unsafe { (*queue_context).current_request };
+    if !current_request.is_null() {
+        log_error!("Request {request:?} presented while {current_request:?} is still pending");
+        return Err(STATUS_DEVICE_BUSY);
+    }
 
     unsafe {
-        // Complete the request with an error when unable to mark it cancelable.
-        if !nt_success(status) {
-            call_unsafe_wdf_function_binding!(
-                WdfRequestCompleteWithInformation,
-                request,
-                status,
-                0
-            );
-        }
+        (*queue_context).current_request = request;
+        (*queue_context).current_status = STATUS_SUCCESS;
     }
+    Ok(())
 }
 
-/// This event is called when the framework receives `IRP_MJ_READ` request.
-/// It will copy the content from the queue-context buffer to the request
-/// buffer. If the driver hasn't received any write request earlier, the read
-/// returns zero.
+/// Make `request` the current request of the queue, or with the
+/// `parallel-queue` feature, add it to the pending requests. Must be called
+/// with the queue context lock held.
 ///
-/// # Arguments:
+/// # Safety
 ///
-/// * `queue` - Handle to the framework queue object that is associated with the
-///   I/O request.
-/// * `request` - Handle to a framework request object.
-/// * `length` -  number of bytes to be read. The default property of the queue
-///   is to not dispatch zero lenght read & write requests to the driver and
-///   complete is with status success. So we will never get a zero length
-///   request.
+/// `queue_context` must be valid.
 ///
 /// # Return value:
 ///
-/// * `VOID`
-extern "C" fn echo_evt_io_read(queue: WDFQUEUE, request: WDFREQUEST, mut length: usize) {
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
-    let mut memory = WDF_NO_HANDLE as WDFMEMORY;
-    let mut nt_status: NTSTATUS;
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(feature = "parallel-queue")]
+unsafe fn echo_add_pending_request(
+    queue_context: *mut QueueContext,
+    request: WDFREQUEST,
+) -> Result<(), NTSTATUS> {
+    // Only the sequential queue has a current request
+    nt_assert!(unsafe { (*queue_context).current_request.is_null() });
+
+    let result = unsafe { (*queue_context).pending_requests.add(request as WDFOBJECT) };
+
+    // With the `pending-limit` feature, give back the slot the request took in
+    // echo_set_current_request, since it is not pending after all
+    #[cfg(feature = "pending-limit")]
+    if result.is_err() {
+        unsafe { echo_release_pending_slot(queue_context) };
+    }
 
-    println!(
-        "echo_evt_io_read called! queue {:?}, request {:?}, length {:?}",
-        queue, request, length
-    );
+    result
+}
 
-    // No data to read
+/// Stop tracking `request` as the current request, or with the
+/// `parallel-queue` feature, as a pending request, if it is tracked. Must be
+/// called with the queue context lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(not(feature = "parallel-queue"))]
+unsafe fn echo_remove_pending_request(queue_context: *mut QueueContext, request: WDFREQUEST) {
     unsafe {
-        if (*queue_context).buffer.is_null() {
-            call_unsafe_wdf_function_binding!(
-                WdfRequestCompleteWithInformation,
-                request,
-                STATUS_SUCCESS,
-                0,
-            );
-            return;
+        if (*queue_context).current_request == request {
+            (*queue_context).current_request = core::ptr::null_mut();
         }
     }
+}
 
-    // Read what we have
-    unsafe {
-        if (*queue_context).length < length {
-            length = (*queue_context).length;
+/// Stop tracking `request` as the current request, or with the
+/// `parallel-queue` feature, as a pending request, if it is tracked. Must be
+/// called with the queue context lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(feature = "parallel-queue")]
+unsafe fn echo_remove_pending_request(queue_context: *mut QueueContext, request: WDFREQUEST) {
+    let pending_requests = unsafe { &(*queue_context).pending_requests };
+    if pending_requests.contains(request as WDFOBJECT) {
+        pending_requests.remove(request as WDFOBJECT);
+        #[cfg(feature = "pending-limit")]
+        unsafe {
+            echo_release_pending_slot(queue_context);
         }
     }
 
-    // Get the request memory
-    unsafe {
-        nt_status =
-            call_unsafe_wdf_function_binding!(WdfRequestRetrieveOutputMemory, request, &mut memory);
-
-        if !nt_success(nt_status) {
-            println!("echo_evt_io_read Could not get request memory buffer {nt_status:#010X}");
-            call_unsafe_wdf_function_binding!(
-                WdfRequestCompleteWithInformation,
-                request,
-                nt_status,
-                0
-            );
-            return;
+    // With the `blocking-read` feature, the request can be a read waiting for
+    // data instead
+    #[cfg(feature = "blocking-read")]
+    {
+        let waiting_reads = unsafe { &(*queue_context).waiting_reads };
+        if waiting_reads.contains(request as WDFOBJECT) {
+            waiting_reads.remove(request as WDFOBJECT);
         }
     }
+}
 
-    // Copy the memory out
-    unsafe {
-        nt_status = call_unsafe_wdf_function_binding!(
-            WdfMemoryCopyFromBuffer,
-            memory,
-            0,
-            (*queue_context).buffer,
-            length
-        );
-
-        if !nt_success(nt_status) {
-            println!("echo_evt_io_read: WdfMemoryCopyFromBuffer failed {nt_status:#010X}");
-            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, nt_status);
-            return;
-        }
-    }
+/// Whether `request` is the current request, or with the `parallel-queue`
+/// feature, one of the pending requests. Must be called with the queue context
+/// lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(not(feature = "parallel-queue"))]
+unsafe fn echo_is_pending_request(queue_context: *mut QueueContext, request: WDFREQUEST) -> bool {
+    unsafe { (*queue_context).current_request == request }
+}
 
-    // Set transfer information
-    let [()] = unsafe {
-        [call_unsafe_wdf_function_binding!(
-            WdfRequestSetInformation,
-            request,
-            length as u64
-        )]
+/// Whether `request` is the current request, or with the `parallel-queue`
+/// feature, one of the pending requests. Must be called with the queue context
+/// lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(feature = "parallel-queue")]
+unsafe fn echo_is_pending_request(queue_context: *mut QueueContext, request: WDFREQUEST) -> bool {
+    let is_pending = unsafe {
+        (*queue_context)
+            .pending_requests
+            .contains(request as WDFOBJECT)
     };
 
-    // Mark the request is cancelable.  This must be the last thing we do because
-    // the cancel routine can run immediately after we set it.  This means that
-    // CurrentRequest and CurrentStatus must be initialized before we mark the
-    // request cancelable.
-    echo_set_current_request(request, queue);
+    // With the `blocking-read` feature, the request can be a read waiting for
+    // data instead
+    #[cfg(feature = "blocking-read")]
+    let is_pending = is_pending
+        || unsafe {
+            (*queue_context)
+                .waiting_reads
+                .contains(request as WDFOBJECT)
+        };
+
+    is_pending
 }
 
-/// This event is invoked when the framework receives `IRP_MJ_WRITE` request.
-/// This routine allocates memory buffer, copies the data from the request to
-/// it, and stores the buffer pointer in the queue-context with the length
-/// variable representing the buffers length. The actual completion of the
-/// request is defered to the periodic timer dpc.
+/// Take the reference `echo_set_current_request` holds on a deferred request,
+/// to release it once the request has been completed. Each deferred request is
+/// completed exactly once, so the reference must still be there.
 ///
-/// # Arguments:
+/// # Safety
 ///
-/// * `queue` - Handle to the framework queue object that is associated with the
-///   I/O request.
-/// * `request` - Handle to a framework request object.
-/// * `length` -  number of bytes to be read. The default property of the queue
-///   is to not dispatch zero lenght read & write requests to the driver and
-///   complete is with status success. So we will never get a zero length
-///   request.
+/// `request_context` must be valid, and the caller must own the request.
 ///
 /// # Return value:
 ///
-/// * `VOID`
-extern "C" fn echo_evt_io_write(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
-    let mut memory = WDF_NO_HANDLE as WDFMEMORY;
-    let mut status: NTSTATUS;
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
-
-    println!(
-        "echo_evt_io_write called! queue {:?}, request {:?}, length {:?}",
-        queue, request, length
+/// * The reference, or `None` if it was already taken.
+unsafe fn echo_take_request_reference(request_context: *mut RequestContext) -> Option<RefGuard> {
+    let reference = unsafe { (*request_context).reference.take() };
+    nt_assert_msg!(
+        reference.is_some(),
+        "deferred request completed without its reference"
     );
+    reference
+}
 
-    if length > MAX_WRITE_LENGTH {
-        println!(
-            "echo_evt_io_write Buffer Length to big {:?}, Max is {:?}",
-            length, MAX_WRITE_LENGTH
+/// Setup the request, intialize its context and mark it as cancelable.
+///
+/// The sequential queue does not present another request until the current
+/// one has been completed, so there should never be a current request
+/// already. If there is one, the new request is not made the current request,
+/// which would leave the previous one to never be completed. It is forwarded
+/// to the manual queue instead, like a write, and the timer retrieves and
+/// completes it along with the writes, once the current request is done. It
+/// cannot be requeued to the default queue with `WdfRequestRequeue`, which is
+/// only allowed for requests of manual queues.
+///
+/// # Arguments:
+///
+/// * `request` - Request being set up.
+/// * `queue` - Queue associated with the request
+///
+/// # Return value:
+///
+/// * `VOID`
+fn echo_set_current_request(request: Request, queue: WDFQUEUE) {
+    let (Some(request_context), Some(queue_context)) = (unsafe {
+        (
+            request_get_context(request.as_raw() as WDFOBJECT),
+            queue_get_context(queue as WDFOBJECT),
+        )
+    }) else {
+        log_error!(
+            "Request {:?} or its queue {queue:?} has no context",
+            request.as_raw()
         );
-        unsafe {
-            call_unsafe_wdf_function_binding!(
-                WdfRequestCompleteWithInformation,
-                request,
-                STATUS_BUFFER_OVERFLOW,
-                0
-            );
-        }
+        request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+        return;
+    };
+
+    // With the `pending-limit` feature, take one of the slots for pending
+    // requests, or fail the request right away when they are all taken. From
+    // here on, the slot is given back whenever the request is not added to the
+    // pending requests, or leaves them.
+    #[cfg(feature = "pending-limit")]
+    if let Err(status) = unsafe { echo_reserve_pending_slot(queue, queue_context) } {
+        request.complete_with_information(status, 0);
+        return;
     }
 
-    // Get the memory buffer
+    // Set the ownership count to one.  When a caller wants to claim ownership,
+    // they will interlock decrement the count.  When the count reaches zero,
+    // ownership has been acquired and the caller may complete the request.
     unsafe {
-        status =
-            call_unsafe_wdf_function_binding!(WdfRequestRetrieveInputMemory, request, &mut memory);
-        if !nt_success(status) {
-            println!("echo_evt_io_write Could not get request memory buffer {status:#010X}");
-            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, status);
-            return;
-        }
+        (*request_context).cancel_completion_ownership_count =
+            AtomicI32::new(cancel_protocol::INITIAL_OWNERSHIP_COUNT);
     }
 
-    // Release previous buffer if set
+    // Reference the request for as long as it is deferred. It is taken before the
+    // timer and the cancel routine can see the request, and released by
+    // whichever of them completes it, only after the completion, so the handle
+    // they read from the queue context is valid for as long as they use it.
     unsafe {
-        if !(*queue_context).buffer.is_null() {
-            ExFreePool((*queue_context).buffer);
-            (*queue_context).buffer = core::ptr::null_mut();
-            (*queue_context).length = 0;
-        }
-
-        // FIXME: Memory Tag
-        (*queue_context).buffer =
-            ExAllocatePool2(POOL_FLAG_NON_PAGED, length as SIZE_T, 's' as u32);
-        if (*queue_context).buffer.is_null() {
-            println!(
-                "echo_evt_io_write Could not allocate {:?} byte buffer",
-                length
+        (*request_context).reference = Some(RefGuard::new(request.as_raw() as WDFOBJECT));
+    }
+
+    // Defer the completion to another thread from the timer dpc
+    let result = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe { echo_add_pending_request(queue_context, request.as_raw()) }.and_then(|()| {
+            // Set the cancel routine under the lock, otherwise if we set it outside
+            // of the lock, the timer could run and attempt to mark the request
+            // uncancelable before we can mark it cancelable on this thread. Use
+            // WdfRequestMarkCancelableEx here to prevent to deadlock with ourselves
+            // (cancel routine tries to acquire the queue object lock).
+            let result = request.mark_cancelable(Some(echo_evt_request_cancel));
+            if result.is_err() {
+                unsafe { echo_remove_pending_request(queue_context, request.as_raw()) };
+            }
+
+            #[cfg(not(feature = "parallel-queue"))]
+            if result.is_ok() {
+                unsafe {
+                    (*queue_context)
+                        .current_state
+                        .transition(RequestState::Pending)
+                };
+            }
+
+            result
+        })
+    };
+
+    // With the `adaptive-timer` feature, make the timer wait the full period
+    // again now that there is a request for it
+    #[cfg(feature = "adaptive-timer")]
+    if result.is_ok() {
+        unsafe { echo_reset_timer_period(queue_context) };
+    }
+
+    // With the `one-shot-timer` feature, arm the timer for the request
+    #[cfg(feature = "one-shot-timer")]
+    if result.is_ok() {
+        unsafe { echo_arm_timer(queue_context) };
+    }
+
+    // Forward the request to the manual queue when there is already a current
+    // request. Neither the timer nor the cancel routine saw it, so the
+    // reference is released here.
+    #[cfg(not(feature = "parallel-queue"))]
+    if result == Err(STATUS_DEVICE_BUSY) {
+        log_info!(
+            "Request {:?} presented while busy, forwarding it to the manual queue",
+            request.as_raw()
+        );
+        let reference = unsafe { echo_take_request_reference(request_context) };
+        let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+        match unsafe { wdf_object_get_device_context(device as WDFOBJECT) } {
+            Some(device_context) => {
+                let _ = unsafe { echo_forward_to_manual_queue(request, device_context) };
+            }
+            None => {
+                log_error!("Device {device:?} has no DeviceContext");
+                request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+            }
+        }
+        drop(reference);
+        return;
+    }
+
+    // Complete the request with an error when unable to mark it cancelable.
+    // Neither the timer nor the cancel routine saw it, so the reference is
+    // released here.
+    //
+    // STATUS_CANCELLED means the request was cancelled before it could be
+    // marked cancelable. The framework does not call the cancel routine for it
+    // then, and leaves completing it to the driver: this is the only place it
+    // is completed, so it is not completed twice.
+    if let Err(status) = result {
+        if status == STATUS_CANCELLED {
+            log_info!(
+                "Request {:?} cancelled before being marked cancelable",
+                request.as_raw()
             );
+        }
+        let reference = unsafe { echo_take_request_reference(request_context) };
+        request.complete_with_information(status, 0);
+        drop(reference);
+    }
+}
+
+/// Copy `length` bytes from `buffer` to the output buffer of a read request.
+///
+/// With buffered I/O, the default, the I/O manager gives the driver a
+/// system buffer that it copies back to the application's buffer when the
+/// request is completed, so the data is copied twice, but the application
+/// buffer never needs to be accessed by the driver.
+///
+/// With the `direct-io` feature, the I/O manager instead locks the application
+/// buffer in memory and describes it with an MDL, which has to be mapped into
+/// system space before the driver can write to it. This saves the copy for
+/// large transfers, but the pages of the application buffer stay locked until
+/// the request is completed, and the application can see the buffer change
+/// while the request is pending. Both modes are transparent to `ReadFile` and
+/// `WriteFile` callers otherwise.
+///
+/// With the `chunked-read` feature, the buffer is copied in chunks of
+/// `READ_CHUNK_SIZE` bytes instead, each to its own offset in the request
+/// memory.
+///
+/// # Safety
+///
+/// `buffer` must be valid for reads of `length` bytes, and `length` must not
+/// exceed the length of the read request.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(not(feature = "direct-io"))]
+unsafe fn echo_copy_to_request_buffer(
+    request: &Request,
+    buffer: PVOID,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    let mut memory = WDF_NO_HANDLE as WDFMEMORY;
+
+    // Get the request memory
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputMemory,
+            request.as_raw(),
+            &mut memory
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "Could not get request memory buffer {}",
+            NtStatus(nt_status)
+        );
+        return Err(nt_status);
+    }
+
+    #[cfg(not(feature = "chunked-read"))]
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfMemoryCopyFromBuffer, memory, 0, buffer, length)
+    };
+    #[cfg(not(feature = "chunked-read"))]
+    if !nt_success(nt_status) {
+        log_error!("WdfMemoryCopyFromBuffer failed {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    // With the `chunked-read` feature, copy each chunk from its offset in
+    // `buffer` to the same offset in the request memory
+    #[cfg(feature = "chunked-read")]
+    for chunk in chunks::chunks(length, READ_CHUNK_SIZE) {
+        // SAFETY: The chunks are within the `length` bytes of `buffer`
+        let source = unsafe { buffer.cast::<u8>().add(chunk.offset) };
+        let nt_status = unsafe {
             call_unsafe_wdf_function_binding!(
-                WdfRequestComplete,
-                request,
-                STATUS_INSUFFICIENT_RESOURCES
+                WdfMemoryCopyFromBuffer,
+                memory,
+                chunk.offset,
+                source.cast(),
+                chunk.length
+            )
+        };
+        if !nt_success(nt_status) {
+            log_error!(
+                "WdfMemoryCopyFromBuffer of {chunk:?} failed {}",
+                NtStatus(nt_status)
+            );
+            return Err(nt_status);
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy `length` bytes from `buffer` to the output buffer of a read request,
+/// through the MDL of the `direct-io` feature. See the buffered I/O variant of
+/// this function for the differences between the two modes.
+///
+/// # Safety
+///
+/// `buffer` must be valid for reads of `length` bytes, and `length` must not
+/// exceed the length of the read request.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(feature = "direct-io")]
+unsafe fn echo_copy_to_request_buffer(
+    request: &Request,
+    buffer: PVOID,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    let mut mdl: PMDL = core::ptr::null_mut();
+
+    // Get the MDL describing the application buffer
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputWdmMdl,
+            request.as_raw(),
+            &mut mdl
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("Could not get request MDL {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    // SAFETY: The MDL of a direct I/O request describes locked pages
+    let destination = unsafe { get_system_address_for_mdl_safe(mdl) };
+    if destination.is_null() {
+        log_error!("Could not map request MDL {mdl:?}");
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    // SAFETY: The mapped buffer is at least as long as the request, and is
+    // distinct from `buffer`, which was allocated by the driver
+    unsafe {
+        core::ptr::copy_nonoverlapping(buffer.cast::<u8>(), destination.cast::<u8>(), length);
+    }
+    Ok(())
+}
+
+/// Copy `length` bytes from the input buffer of a write request to `buffer`.
+/// See `echo_copy_to_request_buffer` for the differences between buffered and
+/// direct I/O.
+///
+/// # Safety
+///
+/// `buffer` must be valid for writes of `length` bytes, and `length` must not
+/// exceed the length of the write request.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(not(feature = "direct-io"))]
+unsafe fn echo_copy_from_request_buffer(
+    request: &Request,
+    buffer: PVOID,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    let mut memory = WDF_NO_HANDLE as WDFMEMORY;
+
+    // Get the memory buffer
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveInputMemory,
+            request.as_raw(),
+            &mut memory
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "Could not get request memory buffer {}",
+            NtStatus(nt_status)
+        );
+        return Err(nt_status);
+    }
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfMemoryCopyToBuffer, memory, 0, buffer, length)
+    };
+    if !nt_success(nt_status) {
+        log_error!("WdfMemoryCopyToBuffer failed {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+    Ok(())
+}
+
+/// Copy `length` bytes from the input buffer of a write request to `buffer`,
+/// through the MDL of the `direct-io` feature.
+///
+/// # Safety
+///
+/// `buffer` must be valid for writes of `length` bytes, and `length` must not
+/// exceed the length of the write request.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(feature = "direct-io")]
+unsafe fn echo_copy_from_request_buffer(
+    request: &Request,
+    buffer: PVOID,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    let mut mdl: PMDL = core::ptr::null_mut();
+
+    // Get the MDL describing the application buffer
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfRequestRetrieveInputWdmMdl, request.as_raw(), &mut mdl)
+    };
+    if !nt_success(nt_status) {
+        log_error!("Could not get request MDL {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    // SAFETY: The MDL of a direct I/O request describes locked pages
+    let source = unsafe { get_system_address_for_mdl_safe(mdl) };
+    if source.is_null() {
+        log_error!("Could not map request MDL {mdl:?}");
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    // SAFETY: The mapped buffer is at least as long as the request, and is
+    // distinct from `buffer`, which was allocated by the driver
+    unsafe {
+        core::ptr::copy_nonoverlapping(source.cast::<u8>(), buffer.cast::<u8>(), length);
+    }
+    Ok(())
+}
+
+/// Log whether `request` was issued from user mode, by an application, or from
+/// kernel mode, by another driver. With the `reject-kernel-requestors` feature,
+/// requests from kernel mode are rejected.
+///
+/// A kernel-mode requestor is trusted by the I/O manager: the buffers it passes
+/// are not probed, and it is not subject to the access checks made when an
+/// application opens the device. A driver that only expects applications as
+/// callers can refuse anything else, as a defense in depth.
+///
+/// # Arguments:
+///
+/// * `request` - Read or write request to check.
+///
+/// # Return value:
+///
+/// * `Err(STATUS_ACCESS_DENIED)` if the request must be rejected, `Ok(())`
+///   otherwise.
+fn echo_check_requestor_mode(request: &Request) -> Result<(), NTSTATUS> {
+    let is_kernel_mode = request.get_requestor_mode() == KERNEL_MODE;
+    log_info!(
+        "Request {:?} issued from {} mode",
+        request.as_raw(),
+        if is_kernel_mode { "kernel" } else { "user" }
+    );
+
+    if cfg!(feature = "reject-kernel-requestors") && is_kernel_mode {
+        log_error!("Rejecting kernel mode request {:?}", request.as_raw());
+        return Err(STATUS_ACCESS_DENIED);
+    }
+
+    Ok(())
+}
+
+/// Check that the device a request was presented to has not been
+/// surprise-removed, i.e. marked as removed by
+/// `echo_evt_device_surprise_removal`.
+///
+/// Once the device is gone, handles opened on it stay valid until the
+/// application closes them, and requests issued through them can still be
+/// presented to the queue. Such requests are failed right away, instead of
+/// being held for the timer, so that the application sees the removal rather
+/// than waiting on requests that may never complete.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue the request was presented on.
+///
+/// # Return value:
+///
+/// * `Err(STATUS_DEVICE_REMOVED)` if the device was removed, `Ok(())`
+///   otherwise.
+fn echo_check_device_present(queue: WDFQUEUE) -> Result<(), NTSTATUS> {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
+
+    if unsafe {
+        (*device_context)
+            .removed
+            .load(core::sync::atomic::Ordering::Acquire)
+    } {
+        log_error!("Device was surprise-removed, failing the request");
+        return Err(STATUS_DEVICE_REMOVED);
+    }
+
+    Ok(())
+}
+
+/// Copy the content of the queue-context buffer to the buffer of a read
+/// request, up to `length` bytes.
+///
+/// With the `partial-reads` feature, the copy starts at the read offset of the
+/// queue instead of the start of the buffer, and the offset is advanced past
+/// the bytes copied, so that a read shorter than the data leaves the rest for
+/// the next reads. Once they have drained all of it, the buffer is released and
+/// reads find no data until the next write.
+///
+/// # Safety
+///
+/// `queue_context` must be valid, and nothing else may access its buffer
+/// during the call.
+///
+/// # Return value:
+///
+/// * `Ok(length)` - the number of bytes copied, 0 if no data has been written
+///   yet, or with the `partial-reads` feature, if it has all been read,
+/// * `Err(NTSTATUS)` - the status to complete the request with.
+#[cfg(not(feature = "ring-buffer"))]
+unsafe fn echo_read_buffer(
+    queue_context: *mut QueueContext,
+    request: &Request,
+    length: usize,
+) -> Result<usize, NTSTATUS> {
+    // No data to read
+    let Some(buffer) = (unsafe { &(*queue_context).buffer }) else {
+        return Ok(0);
+    };
+
+    // With the `partial-reads` feature, read from where the previous read
+    // stopped
+    #[cfg(feature = "partial-reads")]
+    let offset = unsafe { (*queue_context).read_offset };
+    #[cfg(not(feature = "partial-reads"))]
+    let offset = 0;
+
+    // Read what we have
+    let available = buffer.size() - offset;
+    let length = length.min(available);
+
+    // Copy the memory out
+    // SAFETY: The offset is never past the end of the buffer
+    let source = unsafe { buffer.as_ptr().cast::<u8>().add(offset) };
+    unsafe { echo_copy_to_request_buffer(request, source.cast(), length)? };
+
+    // Keep the rest for the next reads, or release the buffer once drained
+    #[cfg(feature = "partial-reads")]
+    if length == available {
+        log_info!("echo_evt_io_read Drained {:?} byte buffer", buffer.size());
+        if let Some(buffer) = unsafe { (*queue_context).buffer.take() } {
+            buffer.delete();
+        }
+        unsafe { (*queue_context).read_offset = 0 };
+    } else {
+        unsafe { (*queue_context).read_offset = offset + length };
+    }
+
+    Ok(length)
+}
+
+/// Move up to `length` bytes from the front of the queue-context ring to the
+/// buffer of a read request, with the `ring-buffer` feature.
+///
+/// The data is popped into a temporary buffer under the queue context lock,
+/// and only copied to the request once the lock has been released, so that
+/// the lock is never held while accessing the memory of the request. If that
+/// copy fails, the data popped is lost, as with a pipe whose reader fails.
+///
+/// # Safety
+///
+/// `queue_context` must be valid, and the queue context lock must not be held
+/// by the caller.
+///
+/// # Return value:
+///
+/// * `Ok(length)` - the number of bytes copied, 0 if the ring is empty,
+/// * `Err(NTSTATUS)` - the status to complete the request with.
+#[cfg(feature = "ring-buffer")]
+unsafe fn echo_read_ring(
+    queue_context: *mut QueueContext,
+    request: &Request,
+    length: usize,
+) -> Result<usize, NTSTATUS> {
+    let mut data = Vec::new();
+    if data.try_reserve_exact(length).is_err() {
+        log_error!("echo_evt_io_read Could not allocate {length:?} byte buffer");
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    data.resize(length, 0u8);
+
+    let length = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe { (*queue_context).ring.pop(&mut data) }
+    };
+
+    // No data to read
+    if length == 0 {
+        return Ok(0);
+    }
+
+    // Copy the memory out
+    unsafe { echo_copy_to_request_buffer(request, data.as_mut_ptr().cast(), length)? };
+
+    Ok(length)
+}
+
+/// This event is called when the framework receives `IRP_MJ_READ` request.
+/// It will copy the content from the queue-context buffer to the request
+/// buffer. If the driver hasn't received any write request earlier, the read
+/// returns zero, or with the `blocking-read` feature, waits for one.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `length` -  number of bytes to be read. The default property of the queue
+///   is to not dispatch zero lenght read & write requests to the driver and
+///   complete is with status success. With the `allow-zero-length-requests`
+///   feature they are dispatched, and completed right away with no data.
+///
+/// # Return value:
+///
+/// * `VOID`
+extern "C" fn echo_evt_io_read(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    log_info!(
+        "echo_evt_io_read called! queue {:?}, request {:?}, length {:?}",
+        queue,
+        request,
+        length
+    );
+
+    #[cfg(feature = "callback-trace")]
+    let _callback = echo_enter_callback(queue, "echo_evt_io_read");
+
+    #[cfg(feature = "etw-events")]
+    crate::etw_events::request_start(request, crate::etw_events::RequestKind::Read, length);
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_io_read(queue, request, length);
+
+    // SAFETY: The framework hands ownership of the request to this callback
+    let request = unsafe { Request::from_raw(request) };
+
+    #[cfg(feature = "latency-stats")]
+    echo_stamp_arrival(&request);
+
+    if let Err(status) = echo_check_requestor_mode(&request) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    if let Err(status) = echo_check_device_present(queue) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    #[cfg(feature = "fault-injection")]
+    if let Err(status) = echo_take_injected_fault(queue) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    // Nothing to read into. This is only dispatched with the
+    // `allow-zero-length-requests` feature.
+    if length == 0 {
+        request.complete_with_information(STATUS_SUCCESS, 0);
+        return;
+    }
+
+    // With the `read-overflow` feature, a read longer than any data the device
+    // can hold is completed with STATUS_BUFFER_OVERFLOW, and its information set
+    // to the longest useful length, instead of returning whatever data there is.
+    #[cfg(feature = "read-overflow")]
+    if let Err(required_length) =
+        echo_validate_read_length(length, echo_max_read_length(&DriverConfig::current()))
+    {
+        log_error!("echo_evt_io_read Buffer Length too big {length:?}, Max is {required_length:?}");
+        // STATUS_BUFFER_OVERFLOW is a warning, not an error: like a success, it
+        // makes the I/O manager copy `information` bytes of the buffer back to the
+        // application, so they are zeroed first rather than left uninitialized.
+        match unsafe { echo_zero_request_buffer(&request, required_length) } {
+            Ok(()) => request.complete_with_information(STATUS_BUFFER_OVERFLOW, required_length),
+            Err(status) => request.complete_with_information(status, 0),
+        }
+        return;
+    }
+
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+        return;
+    };
+
+    // With the `parallel-queue` feature, a write can replace the buffer while it
+    // is being copied, and with the `partial-reads` feature, the read offset
+    // changes with each read, so they are only accessed under the queue context
+    // lock.
+    #[cfg(not(feature = "ring-buffer"))]
+    let result = {
+        #[cfg(any(feature = "parallel-queue", feature = "partial-reads"))]
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe { echo_read_buffer(queue_context, &request, length) }
+    };
+    // The ring is always accessed under the lock, which echo_read_ring takes
+    #[cfg(feature = "ring-buffer")]
+    let result = unsafe { echo_read_ring(queue_context, &request, length) };
+
+    let length = match result {
+        Err(nt_status) => {
+            request.complete_with_information(nt_status, 0);
+            return;
+        }
+        // No data to read. With the `blocking-read` feature, the read waits for
+        // a write to provide some instead.
+        Ok(0) => {
+            #[cfg(feature = "blocking-read")]
+            echo_wait_for_write(request, queue, length);
+            #[cfg(not(feature = "blocking-read"))]
+            request.complete_with_information(STATUS_SUCCESS, 0);
+            return;
+        }
+        Ok(length) => length,
+    };
+
+    echo_count_transfer(queue, Transfer::Read, length);
+
+    // Set transfer information
+    request.set_information(length);
+
+    // Mark the request is cancelable.  This must be the last thing we do because
+    // the cancel routine can run immediately after we set it.  This means that
+    // CurrentRequest and CurrentStatus must be initialized before we mark the
+    // request cancelable.
+    echo_set_current_request(request, queue);
+}
+
+/// Direction of a transfer counted by `echo_count_transfer`
+#[derive(Clone, Copy)]
+enum Transfer {
+    Read,
+    Write,
+}
+
+/// Count a read or write of `length` bytes in the `DeviceStatsContext` of the
+/// device of `queue`. It is the second context of the device, retrieved by its
+/// own type with `wdf_object_get_device_stats_context`, while the queue
+/// callbacks find the `DeviceContext` with `wdf_object_get_device_context`.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the default queue of the device.
+/// * `transfer` - Whether data was read or written.
+/// * `length` - Number of bytes transferred.
+///
+/// # Return value:
+///
+/// * `VOID`
+fn echo_count_transfer(queue: WDFQUEUE, transfer: Transfer, length: usize) {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(stats_context) = (unsafe { wdf_object_get_device_stats_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceStatsContext");
+        return;
+    };
+
+    let (count, bytes) = unsafe {
+        match transfer {
+            Transfer::Read => (&(*stats_context).reads, &(*stats_context).bytes_read),
+            Transfer::Write => (&(*stats_context).writes, &(*stats_context).bytes_written),
+        }
+    };
+    count.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    bytes.fetch_add(length as u64, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Checks that a read request is not longer than any data the device can hold,
+/// with the `read-overflow` feature. Like
+/// [`validate_write_length`](crate::transfer_length::validate_write_length),
+/// it does not depend on WDF.
+///
+/// # Arguments:
+///
+/// * `length` - number of bytes to be read.
+/// * `max_read_length` - length of the longest read that can return data, from
+///   `echo_max_read_length`.
+///
+/// # Return value:
+///
+/// * `Ok(())` - if the read can be accepted,
+/// * `Err(max_read_length)` - the length the read should have had, if `length`
+///   exceeds it.
+#[cfg(feature = "read-overflow")]
+const fn echo_validate_read_length(length: usize, max_read_length: usize) -> Result<(), usize> {
+    if length > max_read_length {
+        return Err(max_read_length);
+    }
+    Ok(())
+}
+
+/// Zero the first `length` bytes of the output buffer of a read request, with
+/// the `read-overflow` feature. `WdfRequestRetrieveOutputBuffer` maps the MDL
+/// of the request with direct I/O, so this works with both I/O types.
+///
+/// # Safety
+///
+/// `length` must not exceed the length of the read request.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(feature = "read-overflow")]
+unsafe fn echo_zero_request_buffer(request: &Request, length: usize) -> Result<(), NTSTATUS> {
+    let mut buffer: PVOID = core::ptr::null_mut();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputBuffer,
+            request.as_raw(),
+            length,
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("Could not get request buffer {}", NtStatus(nt_status));
+        return Err(nt_status);
+    }
+
+    // SAFETY: The buffer is at least `length` bytes long, as checked by
+    // WdfRequestRetrieveOutputBuffer
+    unsafe {
+        buffer.cast::<u8>().write_bytes(0, length);
+    }
+    Ok(())
+}
+
+/// Replace the queue-context buffer with the content of a write request of
+/// `length` bytes.
+///
+/// The buffer is a WDF memory object parented to `queue`, rather than a raw
+/// pool allocation: the last one is deleted by the framework along with the
+/// queue, so it does not have to be freed by hand when the queue is destroyed.
+///
+/// # Safety
+///
+/// `queue_context` and `device_context` must be valid, and nothing else may
+/// access the queue-context buffer during the call.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(not(feature = "ring-buffer"))]
+#[cfg_attr(
+    not(any(
+        feature = "sequence-numbers",
+        feature = "memory-pressure",
+        feature = "transform"
+    )),
+    allow(
+        unused_variables,
+        reason = "device_context is only used for sequence numbers, memory pressure and transforms"
+    )
+)]
+unsafe fn echo_write_buffer(
+    queue: WDFQUEUE,
+    queue_context: *mut QueueContext,
+    device_context: *mut DeviceContext,
+    request: &Request,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    // With the `sequence-numbers` feature, the buffer starts with the sequence
+    // number of the write, followed by the data. It is echoed as is, so a read
+    // needs SEQUENCE_NUMBER_LENGTH extra bytes to get all of the data back,
+    // and is truncated like any other short read otherwise.
+    let buffer_length = length + SEQUENCE_NUMBER_LENGTH;
+
+    // Release previous buffer if set
+    if let Some(buffer) = unsafe { (*queue_context).buffer.take() } {
+        buffer.delete();
+    }
+
+    // With the `partial-reads` feature, the next read starts at the new data,
+    // whose remainder is lost if it was not drained
+    #[cfg(feature = "partial-reads")]
+    unsafe {
+        (*queue_context).read_offset = 0;
+    }
+
+    // With the `memory-pressure` feature, the allocation can be treated as
+    // failed, which leaves the queue without data, as a real failure would
+    #[cfg(feature = "memory-pressure")]
+    if unsafe { echo_take_allocation_failure(device_context) } {
+        log_error!(
+            "echo_evt_io_write Simulated failure to allocate {:?} byte buffer",
+            buffer_length
+        );
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    let mut buffer = ManagedMemory::create(
+        queue as WDFOBJECT,
+        _POOL_TYPE::NonPagedPoolNx,
+        DriverConfig::current().pool_tag,
+        buffer_length,
+    )
+    .map_err(|status| {
+        log_error!(
+            "echo_evt_io_write Could not allocate {:?} byte buffer {}",
+            buffer_length,
+            NtStatus(status)
+        );
+        status
+    })?;
+
+    // Copy the memory in, after the sequence number
+    let destination = buffer.as_slice_mut()[SEQUENCE_NUMBER_LENGTH..].as_mut_ptr();
+    if let Err(status) =
+        unsafe { echo_copy_from_request_buffer(request, destination.cast(), length) }
+    {
+        buffer.delete();
+        return Err(status);
+    }
+
+    // With the `transform` feature, transform the copy, never the memory of
+    // the request. The sequence number is left as it is.
+    #[cfg(feature = "transform")]
+    unsafe {
+        echo_transform_write(
+            device_context,
+            &mut buffer.as_slice_mut()[SEQUENCE_NUMBER_LENGTH..],
+        );
+    }
+
+    // Stamp the buffer with the next sequence number. The buffer is only
+    // replaced by one write at a time, so the numbers are increasing in the
+    // order the writes were received.
+    #[cfg(feature = "sequence-numbers")]
+    {
+        let sequence_number = unsafe {
+            (*device_context)
+                .sequence_number
+                .fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+        };
+        log_info!("echo_evt_io_write sequence number {sequence_number}");
+        buffer.as_slice_mut()[..SEQUENCE_NUMBER_LENGTH]
+            .copy_from_slice(&sequence_number.to_ne_bytes());
+    }
+
+    unsafe {
+        (*queue_context).buffer = Some(buffer);
+    }
+
+    Ok(())
+}
+
+/// Append the content of a write request of `length` bytes to the
+/// queue-context ring, with the `ring-buffer` feature. Writes accumulate in the
+/// ring until reads drain it, in the order they were received.
+///
+/// The request is copied into a temporary buffer before taking the queue
+/// context lock, so that the lock is never held while accessing the memory of
+/// the request. A write is pushed whole or not at all: if the ring does not
+/// have room for it, it is failed with `STATUS_DEVICE_BUSY`, and can be retried
+/// once reads have made room.
+///
+/// # Safety
+///
+/// `queue_context` and `device_context` must be valid, and the queue context
+/// lock must not be held by the caller.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+#[cfg(feature = "ring-buffer")]
+#[cfg_attr(
+    not(any(
+        feature = "sequence-numbers",
+        feature = "memory-pressure",
+        feature = "transform"
+    )),
+    allow(
+        unused_variables,
+        reason = "device_context is only used for sequence numbers, memory pressure and transforms"
+    )
+)]
+unsafe fn echo_write_ring(
+    queue_context: *mut QueueContext,
+    device_context: *mut DeviceContext,
+    request: &Request,
+    length: usize,
+) -> Result<(), NTSTATUS> {
+    // With the `sequence-numbers` feature, the data is preceded by the sequence
+    // number of the write, as in the single buffer
+    let buffer_length = length + SEQUENCE_NUMBER_LENGTH;
+    // With the `memory-pressure` feature, the allocation can be treated as
+    // failed, which leaves the ring as it is, as a real failure would
+    #[cfg(feature = "memory-pressure")]
+    if unsafe { echo_take_allocation_failure(device_context) } {
+        log_error!(
+            "echo_evt_io_write Simulated failure to allocate {:?} byte buffer",
+            buffer_length
+        );
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    let mut data = Vec::new();
+    if data.try_reserve_exact(buffer_length).is_err() {
+        log_error!(
+            "echo_evt_io_write Could not allocate {:?} byte buffer",
+            buffer_length
+        );
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    data.resize(buffer_length, 0u8);
+
+    // Copy the memory in, after the sequence number
+    unsafe {
+        echo_copy_from_request_buffer(
+            request,
+            data.as_mut_ptr().add(SEQUENCE_NUMBER_LENGTH).cast(),
+            length,
+        )?;
+    }
+
+    // With the `transform` feature, transform the copy before it is pushed
+    #[cfg(feature = "transform")]
+    unsafe {
+        echo_transform_write(device_context, &mut data[SEQUENCE_NUMBER_LENGTH..]);
+    }
+
+    let _guard = unsafe { (*queue_context).lock.lock() };
+
+    // Stamp the data under the lock, so that the numbers are increasing in the
+    // order the writes are pushed. A write that does not fit skips a number.
+    #[cfg(feature = "sequence-numbers")]
+    {
+        let sequence_number = unsafe {
+            (*device_context)
+                .sequence_number
+                .fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+        };
+        log_info!("echo_evt_io_write sequence number {sequence_number}");
+        data[..SEQUENCE_NUMBER_LENGTH].copy_from_slice(&sequence_number.to_ne_bytes());
+    }
+
+    if !unsafe { (*queue_context).ring.push(&data) } {
+        log_error!(
+            "echo_evt_io_write Ring full, {:?} bytes free for {:?}",
+            unsafe { (*queue_context).ring.free_space() },
+            buffer_length
+        );
+        return Err(STATUS_DEVICE_BUSY);
+    }
+
+    Ok(())
+}
+
+/// This event is invoked when the framework receives `IRP_MJ_WRITE` request.
+/// This routine allocates memory buffer, copies the data from the request to
+/// it, and stores the buffer pointer in the queue-context with the length
+/// variable representing the buffers length. The request is then forwarded to
+/// the manual queue of the device, and the actual completion of the request is
+/// defered to the periodic timer dpc.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `length` -  number of bytes to be written. The default property of the
+///   queue is to not dispatch zero lenght read & write requests to the driver
+///   and complete is with status success. With the `allow-zero-length-requests`
+///   feature they are dispatched, and completed right away without touching the
+///   queue-context buffer.
+///
+/// # Return value:
+///
+/// * `VOID`
+extern "C" fn echo_evt_io_write(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    log_info!(
+        "echo_evt_io_write called! queue {:?}, request {:?}, length {:?}",
+        queue,
+        request,
+        length
+    );
+
+    #[cfg(feature = "callback-trace")]
+    let _callback = echo_enter_callback(queue, "echo_evt_io_write");
+
+    #[cfg(feature = "etw-events")]
+    crate::etw_events::request_start(request, crate::etw_events::RequestKind::Write, length);
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_io_write(queue, request, length);
+
+    // SAFETY: The framework hands ownership of the request to this callback
+    let request = unsafe { Request::from_raw(request) };
+
+    #[cfg(feature = "latency-stats")]
+    echo_stamp_arrival(&request);
+
+    if let Err(status) = echo_check_requestor_mode(&request) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    if let Err(status) = echo_check_device_present(queue) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    #[cfg(feature = "fault-injection")]
+    if let Err(status) = echo_take_injected_fault(queue) {
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    // Nothing to write. This is only dispatched with the
+    // `allow-zero-length-requests` feature, and must not replace the data of the
+    // previous write.
+    if length == 0 {
+        request.complete_with_information(STATUS_SUCCESS, 0);
+        return;
+    }
+
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+        return;
+    };
+
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+        return;
+    };
+
+    // Completing the request consumes it, so an oversized write must return here
+    // rather than fall through to the code below
+    let max_write_length = DriverConfig::current().max_write_length;
+    if let Err(status) = validate_write_length(length, max_write_length) {
+        log_error!(
+            "echo_evt_io_write Buffer Length to big {:?}, Max is {:?}",
+            length,
+            max_write_length
+        );
+        request.complete_with_information(status, 0);
+        return;
+    }
+
+    // With the `parallel-queue` feature, reads and other writes can access the
+    // buffer at the same time, and with the `partial-reads` feature, the write
+    // resets the read offset, so it is only replaced under the queue context
+    // lock.
+    #[cfg(not(feature = "ring-buffer"))]
+    let result = {
+        #[cfg(any(feature = "parallel-queue", feature = "partial-reads"))]
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe { echo_write_buffer(queue, queue_context, device_context, &request, length) }
+    };
+    // The ring is always accessed under the lock, which echo_write_ring takes
+    #[cfg(feature = "ring-buffer")]
+    let result = unsafe { echo_write_ring(queue_context, device_context, &request, length) };
+    if let Err(status) = result {
+        request.complete(status);
+        return;
+    }
+
+    echo_count_transfer(queue, Transfer::Write, length);
+
+    // Now that there is data, complete the reads that were waiting for it
+    #[cfg(feature = "blocking-read")]
+    echo_complete_waiting_reads(queue, false);
+
+    // Set transfer information
+    request.set_information(length);
+
+    // Send a copy of the data to the forward target, if there is one. This never
+    // fails the write itself.
+    #[cfg(feature = "forward-writes")]
+    unsafe {
+        echo_forward_write(device_context, &request, length);
+    }
+
+    // Forward the request to the manual queue, where it waits for the timer to
+    // retrieve and complete it. Once forwarded, the request belongs to the
+    // manual queue, so it must not be touched here anymore, and the default
+    // queue is free to present the next request.
+    if unsafe { echo_forward_to_manual_queue(request, device_context) }.is_err() {
+        return;
+    }
+
+    // With the `adaptive-timer` feature, make the timer wait the full period
+    // again now that there is a request for it
+    #[cfg(feature = "adaptive-timer")]
+    unsafe {
+        echo_reset_timer_period(queue_context);
+    }
+
+    // With the `one-shot-timer` feature, arm the timer for the request
+    #[cfg(feature = "one-shot-timer")]
+    unsafe {
+        echo_arm_timer(queue_context);
+    }
+
+    // Complete the request from a DPC right away instead of waiting for the
+    // next timer tick. If the DPC is already queued, it will complete this
+    // request when it runs.
+    #[cfg(feature = "dpc-completion")]
+    let _ = unsafe { (*queue_context).dpc.enqueue() };
+}
+
+/// This event is called when the framework receives `IRP_MJ_DEVICE_CONTROL`
+/// request.
+///
+/// * `IOCTL_ECHO_GET_WDF_VERSION` copies the version string of the driver to
+///   the output buffer, see `echo_get_wdf_version`.
+/// * `IOCTL_ECHO_INJECT_FAULT`, with the `fault-injection` feature, makes the
+///   next read or write fail, see `echo_inject_fault`.
+/// * `IOCTL_ECHO_GET_LATENCY_STATS`, with the `latency-stats` feature, copies
+///   how long requests stay in the driver to the output buffer, see
+///   `echo_get_latency_stats`.
+/// * `IOCTL_ECHO_FAIL_ALLOCATIONS`, with the `memory-pressure` feature, makes
+///   the next write buffer allocations fail, see `echo_fail_allocations`.
+/// * `IOCTL_ECHO_NEITHER`, with the `method-neither` feature, copies the input
+///   buffer to the output buffer, see `echo_neither`.
+/// * `IOCTL_ECHO_SET_MAX_PENDING`, with the `pending-limit` feature, changes
+///   how many reads the queue holds at once, see `echo_set_max_pending`.
+/// * `IOCTL_ECHO_SET_TRANSFORM`, with the `transform` feature, selects the
+///   transform applied to the data of the next writes, see
+///   `echo_set_transform`.
+/// * `IOCTL_ECHO_GET_QUEUE_STATE`, with the `queue-diagnostics` feature, copies
+///   the state of the default queue to the output buffer, see
+///   `echo_get_queue_state`.
+///
+/// Any other control code, including those of features the driver was built
+/// without, is logged and failed with `STATUS_INVALID_DEVICE_REQUEST` (use with
+/// `echoapp -BadIoctl`).
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `_output_buffer_length` - length of the request's output buffer, checked
+///   by `WdfRequestRetrieveOutputBuffer`.
+/// * `_input_buffer_length` - length of the request's input buffer, checked by
+///   `WdfRequestRetrieveInputBuffer`.
+/// * `io_control_code` - the driver-defined or system-defined I/O control code
+///   associated with the request.
+///
+/// # Return value:
+///
+/// * `VOID`
+extern "C" fn echo_evt_io_device_control(
+    queue: WDFQUEUE,
+    request: WDFREQUEST,
+    _output_buffer_length: usize,
+    _input_buffer_length: usize,
+    io_control_code: ULONG,
+) {
+    log_info!(
+        "echo_evt_io_device_control called! queue {:?}, request {:?}, io_control_code {:#010X}",
+        queue,
+        request,
+        io_control_code
+    );
+
+    #[cfg(feature = "callback-trace")]
+    let _callback = echo_enter_callback(queue, "echo_evt_io_device_control");
+
+    #[cfg(feature = "etw-events")]
+    crate::etw_events::request_start(
+        request,
+        crate::etw_events::RequestKind::DeviceControl(io_control_code),
+        0,
+    );
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_io_device_control(queue, request, io_control_code);
+
+    // SAFETY: The framework hands ownership of the request to this callback
+    let request = unsafe { Request::from_raw(request) };
+
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        request.complete(STATUS_INVALID_DEVICE_STATE);
+        return;
+    };
+
+    if let Err(status) = echo_check_device_present(queue) {
+        request.complete(status);
+        return;
+    }
+
+    match io_control_code {
+        IOCTL_ECHO_GET_WDF_VERSION => unsafe { echo_get_wdf_version(request, device_context) },
+        #[cfg(feature = "fault-injection")]
+        IOCTL_ECHO_INJECT_FAULT => unsafe { echo_inject_fault(request, device_context) },
+        #[cfg(feature = "latency-stats")]
+        IOCTL_ECHO_GET_LATENCY_STATS => unsafe { echo_get_latency_stats(request, device_context) },
+        #[cfg(feature = "memory-pressure")]
+        IOCTL_ECHO_FAIL_ALLOCATIONS => unsafe { echo_fail_allocations(request, device_context) },
+        #[cfg(feature = "method-neither")]
+        IOCTL_ECHO_NEITHER => echo_neither(request),
+        #[cfg(feature = "pending-limit")]
+        IOCTL_ECHO_SET_MAX_PENDING => unsafe { echo_set_max_pending(request, device_context) },
+        #[cfg(feature = "transform")]
+        IOCTL_ECHO_SET_TRANSFORM => unsafe { echo_set_transform(request, device_context) },
+        #[cfg(feature = "queue-diagnostics")]
+        IOCTL_ECHO_GET_QUEUE_STATE => echo_get_queue_state(request, queue),
+        // Every request must be completed, or the application waits for it
+        // forever, so unknown control codes are failed rather than ignored
+        _ => {
+            log_error!(
+                "Unknown io_control_code {io_control_code:#010X}: device type {:#06X}, function \
+                 {:#05X}, method {}",
+                io_control_code >> 16,
+                (io_control_code >> 2) & 0xFFF,
+                io_control_code & 0x3
             );
-            return;
+            request.complete(STATUS_INVALID_DEVICE_REQUEST);
         }
     }
+}
 
-    // Copy the memory in
+/// Handle `IOCTL_ECHO_GET_WDF_VERSION`: copy the version string of the driver,
+/// in UTF-16 and without a terminating null, to the output buffer of
+/// `request`, and complete it with the number of bytes copied.
+///
+/// The string object is created with the device, because retrieving the
+/// version string is only allowed at `PASSIVE_LEVEL`, while reading an existing
+/// string with `WdfStringGetUnicodeString` is allowed at the `DISPATCH_LEVEL`
+/// this callback can be called at.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_GET_WDF_VERSION` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+unsafe fn echo_get_wdf_version(request: Request, device_context: *mut DeviceContext) {
+    let mut us = UNICODE_STRING::default();
     unsafe {
-        status = call_unsafe_wdf_function_binding!(
-            WdfMemoryCopyToBuffer,
-            memory,
-            0,
-            (*queue_context).buffer,
-            length
+        call_unsafe_wdf_function_binding!(
+            WdfStringGetUnicodeString,
+            (*device_context).version_string,
+            &mut us
         );
+    };
+    let length = usize::from(us.Length);
 
-        if !nt_success(status) {
-            println!("echo_evt_io_write WdfMemoryCopyToBuffer failed {status:#010X}");
-            ExFreePool((*queue_context).buffer);
-            (*queue_context).buffer = core::ptr::null_mut();
-            (*queue_context).length = 0;
-            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, status);
-            return;
-        }
-
-        (*queue_context).length = length;
+    // Fails with STATUS_BUFFER_TOO_SMALL if the output buffer cannot hold the
+    // whole string
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputBuffer,
+            request.as_raw(),
+            length,
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveOutputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
     }
 
-    // Set transfer information
+    // SAFETY: The output buffer holds at least `length` bytes, and the string
+    // object owns `us.Buffer`, which holds exactly `length` bytes
     unsafe {
-        call_unsafe_wdf_function_binding!(WdfRequestSetInformation, request, length as u64);
+        core::ptr::copy_nonoverlapping(us.Buffer.cast::<u8>(), buffer.cast::<u8>(), length);
     }
 
-    // Mark the request is cancelable.  This must be the last thing we do because
-    // the cancel routine can run immediately after we set it.  This means that
-    // CurrentRequest and CurrentStatus must be initialized before we mark the
-    // request cancelable.
-    echo_set_current_request(request, queue);
+    request.complete_with_information(STATUS_SUCCESS, length);
 }
 
 /// This is the `TimerDPC` the driver sets up to complete requests.
 /// This function is registered when the WDFTIMER object is created.
 ///
-/// This function does *NOT* automatically synchronize with the I/O Queue
-/// callbacks and cancel routine, we must do it ourself in the routine.
-///
 /// # Arguments:
 ///
 /// * `timer` - Handle to a framework Timer object.
@@ -624,101 +2378,572 @@ extern "C" fn echo_evt_io_write(queue: WDFQUEUE, request: WDFREQUEST, length: us
 ///
 /// * `VOID`
 unsafe extern "C" fn echo_evt_timer_func(timer: WDFTIMER) {
-    // Default to failure.  status is initialized so that the compiler does not
-    // think we are using an uninitialized value when completing the request.
-    let mut status;
-    let mut cancel = false;
-    let complete_request;
     let queue: WDFQUEUE;
-    let request: WDFREQUEST;
-    let mut request_context: *mut RequestContext = core::ptr::null_mut();
     unsafe {
         queue = call_unsafe_wdf_function_binding!(WdfTimerGetParentObject, timer,) as WDFQUEUE;
     }
-    let queue_context = unsafe { queue_get_context(queue as WDFOBJECT) };
+    #[cfg(feature = "callback-trace")]
+    let _callback = echo_enter_callback(queue, "echo_evt_timer_func");
+
+    // The queue is being torn down, see the Drop implementation of QueueContext
+    if let Some(queue_context) = unsafe { queue_get_context(queue as WDFOBJECT) } {
+        if unsafe {
+            (*queue_context)
+                .torn_down
+                .load(core::sync::atomic::Ordering::SeqCst)
+        } {
+            log_info!("Timer {timer:?} fired after queue {queue:?} was torn down");
+            return;
+        }
 
-    // We must synchronize with the cancel routine which will be taking the
-    // request out of the context under this lock.
-    unsafe { (*queue_context).spin_lock.acquire() };
-    unsafe {
-        request = (*queue_context).current_request;
+        // With the `one-shot-timer` feature, a request arriving from now on
+        // arms the timer again, in case it is not completed below
+        #[cfg(feature = "one-shot-timer")]
+        unsafe {
+            (*queue_context)
+                .timer_armed
+                .store(false, core::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(feature = "wpp-tracing")]
+    crate::wpp::trace_timer(timer);
+
+    // With the `timer-watchdog` feature, tell the watchdog the timer fired
+    #[cfg(feature = "timer-watchdog")]
+    if let Some(queue_context) = unsafe { queue_get_context(queue as WDFOBJECT) } {
+        unsafe {
+            (*queue_context)
+                .watchdog
+                .feed(KeQueryUnbiasedInterruptTime());
+        };
     }
-    if !request.is_null() {
-        request_context = unsafe { request_get_context(request as WDFOBJECT) };
-        if echo_increment_request_cancel_ownership_count(request_context) {
-            cancel = true;
+
+    // With the `wait-lock` feature, the queue lock cannot be acquired at
+    // DISPATCH_LEVEL, so the requests are completed by the work item instead
+    #[cfg(feature = "wait-lock")]
+    {
+        let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+            log_error!("Queue {queue:?} has no QueueContext");
+            return;
+        };
+        unsafe { (*queue_context).work_item.enqueue() };
+    }
+
+    #[cfg(not(feature = "wait-lock"))]
+    {
+        echo_complete_current_request(queue);
+        echo_complete_forwarded_requests(queue);
+    }
+
+    // With the `adaptive-timer` feature, the timer is not periodic and must be
+    // started again
+    #[cfg(feature = "adaptive-timer")]
+    echo_restart_timer(queue);
+}
+
+/// Start the timer of `queue` again, with the `adaptive-timer` feature, since
+/// it is not periodic. If no request arrived since the timer last fired, its
+/// delay is halved, down to `DriverConfig::min_timer_period_ms`, otherwise it
+/// is reset to the period of the `DriverConfig`. The timer is not started while
+/// the device is suspended.
+///
+/// A request arriving while this runs can be counted for the next time the
+/// timer fires instead, or have its restart replaced by the one here, which is
+/// never later: either way the request is completed, at worst one period late.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue the timer belongs to.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "adaptive-timer")]
+fn echo_restart_timer(queue: WDFQUEUE) {
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
+
+    let config = DriverConfig::current();
+    let (previous_period, period) = unsafe {
+        let work_arrived = (*queue_context)
+            .work_arrived
+            .swap(false, core::sync::atomic::Ordering::SeqCst);
+        let previous_period = (*queue_context)
+            .timer_period
+            .load(core::sync::atomic::Ordering::SeqCst);
+        let period = if work_arrived {
+            config.timer_period_ms
         } else {
-            // What has happened is that the cancel routine has executed and
-            // has already claimed cancel ownership of the request, but has not
-            // yet acquired the object lock and cleared the CurrentRequest field
-            // in queueContext.  In this case, do nothing and let the cancel
-            // routine run to completion and complete the request.
+            (previous_period / 2).max(config.min_timer_period_ms())
+        };
+        (*queue_context)
+            .timer_period
+            .store(period, core::sync::atomic::Ordering::SeqCst);
+        (previous_period, period)
+    };
+
+    if period != previous_period {
+        log_info!("Timer period adjusted from {previous_period} ms to {period} ms");
+    }
+
+    unsafe {
+        if (*queue_context)
+            .timer_running
+            .load(core::sync::atomic::Ordering::SeqCst)
+        {
+            let _ = (*queue_context)
+                .timer
+                .start(wdf_timer::relative_due_time(period));
         }
     }
+}
 
-    unsafe { (*queue_context).spin_lock.release() };
+/// Record that a request waits for the timer, with the `adaptive-timer`
+/// feature. If the timer had shortened its delay, the delay is reset to the
+/// period of the `DriverConfig`, and the timer is stopped and started again
+/// with it. A timer already waiting the full period is left alone, so that a
+/// steady stream of requests cannot keep postponing it.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `queue_context` - Context of the queue the request waits in.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "adaptive-timer")]
+unsafe fn echo_reset_timer_period(queue_context: *mut QueueContext) {
+    let timer_period = DriverConfig::current().timer_period_ms;
+    unsafe {
+        (*queue_context)
+            .work_arrived
+            .store(true, core::sync::atomic::Ordering::SeqCst);
+        let previous_period = (*queue_context)
+            .timer_period
+            .swap(timer_period, core::sync::atomic::Ordering::SeqCst);
+        if previous_period != timer_period
+            && (*queue_context)
+                .timer_running
+                .load(core::sync::atomic::Ordering::SeqCst)
+        {
+            let _ = wdf_timer::restart(&(*queue_context).timer, timer_period);
+            log_info!("Timer period reset from {previous_period} ms to {timer_period} ms");
+        }
+    }
+}
 
-    // If we could not claim cancel ownership, we are done.
-    if !cancel {
+/// Arm the timer to fire once, `CompletionDelayMs` from now, with the
+/// `one-shot-timer` feature, unless it is already armed.
+///
+/// A request arriving while nothing else waits for the timer is completed
+/// exactly the configured delay after it arrived. A timer already armed is
+/// left alone, so that requests arriving one after another cannot keep
+/// postponing it: a request arriving then is completed along with the others,
+/// at most the delay after it arrived.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `queue_context` - Context of the queue the request waits in.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "one-shot-timer")]
+unsafe fn echo_arm_timer(queue_context: *mut QueueContext) {
+    if unsafe {
+        (*queue_context)
+            .timer_armed
+            .swap(true, core::sync::atomic::Ordering::SeqCst)
+    } {
         return;
     }
 
-    // The request handle and requestContext are valid until we release
-    // the cancel ownership count we already acquired.
+    // With the `timer-watchdog` feature, the timer does not fire while no
+    // request waits, which does not count as being stuck
+    #[cfg(feature = "timer-watchdog")]
     unsafe {
-        status = call_unsafe_wdf_function_binding!(WdfRequestUnmarkCancelable, request,);
-        if status == STATUS_CANCELLED {
-            complete_request = echo_decrement_request_cancel_ownership_count(request_context);
+        (*queue_context)
+            .watchdog
+            .feed(KeQueryUnbiasedInterruptTime());
+    }
 
-            if complete_request {
-                println!(
-                    "CustomTimerDPC Request {:?} is STATUS_CANCELLED, but claimed completion \
-                     ownership",
-                    request
-                );
-            } else {
-                println!(
-                    "CustomTimerDPC Request {:?} is STATUS_CANCELLED, not completing",
-                    request
+    let delay = DriverConfig::current().completion_delay_ms;
+    let _ = unsafe {
+        (*queue_context)
+            .timer
+            .start(wdf_timer::relative_due_time(delay))
+    };
+    log_info!("Timer armed to fire in {delay} ms");
+}
+
+/// This is the `EvtWorkItemFunc` of the work item that `echo_evt_timer_func`
+/// queues to complete requests at `PASSIVE_LEVEL` when the driver is built with
+/// the `wait-lock` feature. This function is registered when the WDFWORKITEM
+/// object is created.
+///
+/// # Arguments:
+///
+/// * `work_item` - Handle to a framework work item object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "wait-lock")]
+unsafe extern "C" fn echo_evt_work_item_func(work_item: WDFWORKITEM) {
+    let queue: WDFQUEUE;
+    unsafe {
+        queue =
+            call_unsafe_wdf_function_binding!(WdfWorkItemGetParentObject, work_item) as WDFQUEUE;
+    }
+    echo_complete_current_request(queue);
+    echo_complete_forwarded_requests(queue);
+}
+
+/// This is the `EvtDpcFunc` of the DPC that `echo_evt_io_write` queues to
+/// complete requests when the driver is built with the `dpc-completion`
+/// feature. This function is registered when the WDFDPC object is created.
+///
+/// With the `queue-serialization` feature, the timer is created with
+/// `AutomaticSerialization`, so the framework serializes it with the queue
+/// callbacks. The DPC is deliberately created without it: it runs as soon as
+/// possible after being queued, and `echo_complete_current_request`
+/// synchronizes with the I/O Queue callbacks and cancel routine through the
+/// queue lock instead. Unlike the periodic timer, the DPC only
+/// runs when it is queued, and queueing it again before it has run does not
+/// make it run twice.
+///
+/// # Arguments:
+///
+/// * `dpc` - Handle to a framework DPC object.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "dpc-completion")]
+unsafe extern "C" fn echo_evt_dpc_func(dpc: WDFDPC) {
+    let queue: WDFQUEUE;
+    unsafe {
+        queue = call_unsafe_wdf_function_binding!(WdfDpcGetParentObject, dpc) as WDFQUEUE;
+    }
+    echo_complete_current_request(queue);
+    echo_complete_forwarded_requests(queue);
+}
+
+/// Forward `request` to the manual queue of the device, where it waits for the
+/// timer to retrieve it with `echo_complete_forwarded_requests`, or complete it
+/// with the error if it cannot be forwarded.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Errors
+///
+/// The status the request was completed with if it could not be forwarded.
+unsafe fn echo_forward_to_manual_queue(
+    request: Request,
+    device_context: *mut DeviceContext,
+) -> Result<(), NTSTATUS> {
+    let manual_queue = unsafe { (*device_context).manual_queue };
+    request
+        .forward_to_io_queue(manual_queue)
+        .map_err(|(request, status)| {
+            log_error!("WdfRequestForwardToIoQueue failed {}", NtStatus(status));
+            request.complete_with_information(status, 0);
+            status
+        })
+}
+
+/// Complete the requests forwarded to the manual queue of the device `queue`
+/// belongs to: the writes of `echo_evt_io_write`, and the reads presented while
+/// the sequential queue still had a current request, see
+/// `echo_set_current_request`. Called from the `TimerDPC` and, with the
+/// `dpc-completion` feature, from the DPC queued by `echo_evt_io_write`, or
+/// with the `wait-lock` feature, from the work item queued by the `TimerDPC`.
+///
+/// Unlike the current request, a request retrieved from the manual queue is
+/// never cancelable: the framework stops cancelling it as soon as it is
+/// retrieved, so no cancel ownership has to be claimed before completing it.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the default queue of the device.
+///
+/// # Return value:
+///
+/// * `VOID`
+fn echo_complete_forwarded_requests(queue: WDFQUEUE) {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return;
+    };
+
+    loop {
+        let mut request = WDF_NO_HANDLE as WDFREQUEST;
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoQueueRetrieveNextRequest,
+                (*device_context).manual_queue,
+                &mut request
+            )
+        };
+        if !nt_success(nt_status) {
+            // STATUS_NO_MORE_ENTRIES once the queue is empty. The queue can also
+            // be stopped while the device is leaving D0, in which case the
+            // remaining requests are completed once it is restarted.
+            if nt_status != STATUS_NO_MORE_ENTRIES {
+                log_error!(
+                    "WdfIoQueueRetrieveNextRequest failed {}",
+                    NtStatus(nt_status)
                 );
             }
-        } else {
-            println!(
-                "CustomTimerDPC successfully cleared cancel routine on request {:?}, status {:?}",
-                request, status
-            );
+            return;
+        }
+
+        log_info!("CustomTimerDPC Completing forwarded request {request:?}");
+
+        // SAFETY: Retrieving the request from the manual queue hands its
+        // ownership to the driver. The information value was set by
+        // echo_evt_io_write or echo_evt_io_read before forwarding it.
+        let request = unsafe { Request::from_raw(request) };
+
+        #[cfg(feature = "latency-stats")]
+        echo_record_latency(queue, &request);
+
+        request.complete(STATUS_SUCCESS);
+    }
+}
 
-            // Since we successfully removed the cancel routine (and we are not
-            // currently racing with it), there is no need to use an interlocked
-            // decrement to lower the cancel ownership count.
+/// Complete the current request of `queue`, if there is one and its cancel
+/// routine has not already claimed it. Called from the `TimerDPC` and, with the
+/// `dpc-completion` feature, from the DPC queued by `echo_evt_io_write`, or
+/// with the `wait-lock` feature, from the work item queued by the `TimerDPC`.
+///
+/// This function does *NOT* automatically synchronize with the I/O Queue
+/// callbacks and cancel routine, we must do it ourself in the routine.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue whose current request should be completed.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(not(feature = "parallel-queue"))]
+fn echo_complete_current_request(queue: WDFQUEUE) {
+    let mut status;
+    let request: WDFREQUEST;
+    let mut request_context: *mut RequestContext = core::ptr::null_mut();
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
 
-            // 2 is the initial count we set when we initialized
-            // CancelCompletionOwnershipCount plus the call to
-            // EchoIncrementRequestCancelOwnershipCount()
-            (*request_context)
-                .cancel_completion_ownership_count
-                .fetch_sub(2, Ordering::SeqCst);
-            complete_request = true;
+    // We must synchronize with the cancel routine which will be taking the
+    // request out of the context under this lock.
+    let action = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe {
+            request = (*queue_context).current_request;
+        }
+        if request.is_null() {
+            TimerAction::Leave
+        } else {
+            match unsafe { request_get_context(request as WDFOBJECT) } {
+                None => {
+                    // Cancel ownership cannot be claimed without the context,
+                    // so leave the request to the cancel routine.
+                    log_error!("Request {request:?} has no RequestContext");
+                    TimerAction::Leave
+                }
+                Some(context) => {
+                    request_context = context;
+                    let action = cancel_protocol::on_timer_fire(unsafe {
+                        &(*request_context).cancel_completion_ownership_count
+                    });
+                    if action == TimerAction::UnmarkCancelable {
+                        unsafe {
+                            (*queue_context)
+                                .current_state
+                                .transition(RequestState::Completing);
+                        }
+                    }
+                    action
+                }
+            }
         }
+    };
+
+    // If we could not claim cancel ownership, we are done.
+    if action == TimerAction::Leave {
+        return;
     }
 
-    if complete_request {
-        println!(
-            "CustomTimerDPC Completing request {:?}, status {:?}",
-            request, status
+    // SAFETY: The request was marked cancelable, so it is still owned by the
+    // driver, and it is only completed below once completion ownership has been
+    // claimed
+    let request = unsafe { Request::from_raw(request) };
+
+    // The request handle and requestContext are valid until we release
+    // the cancel ownership count we already acquired.
+    status = match request.unmark_cancelable() {
+        Ok(()) => STATUS_SUCCESS,
+        Err(status) => status,
+    };
+    let action = cancel_protocol::on_unmark_cancelable(
+        unsafe { &(*request_context).cancel_completion_ownership_count },
+        status == STATUS_CANCELLED,
+    );
+    match (status == STATUS_CANCELLED, action) {
+        (true, UnmarkAction::Complete) => log_info!(
+            "CustomTimerDPC Request {:?} is STATUS_CANCELLED, but claimed completion ownership",
+            request.as_raw()
+        ),
+        (true, UnmarkAction::Leave) => log_info!(
+            "CustomTimerDPC Request {:?} is STATUS_CANCELLED, not completing",
+            request.as_raw()
+        ),
+        (false, _) => log_info!(
+            "CustomTimerDPC successfully cleared cancel routine on request {:?}, status {}",
+            request.as_raw(),
+            NtStatus(status)
+        ),
+    }
+
+    if action == UnmarkAction::Complete {
+        log_info!(
+            "CustomTimerDPC Completing request {:?}, status {}",
+            request.as_raw(),
+            NtStatus(status)
         );
 
         // Clear the current request out of the queue context and complete
         // the request.
-        unsafe { (*queue_context).spin_lock.acquire() };
-        unsafe {
-            (*queue_context).current_request = core::ptr::null_mut();
-            status = (*queue_context).current_status;
+        {
+            let _guard = unsafe { (*queue_context).lock.lock() };
+            unsafe {
+                (*queue_context).current_request = core::ptr::null_mut();
+                (*queue_context)
+                    .current_state
+                    .transition(RequestState::Idle);
+                status = (*queue_context).current_status;
+            }
         }
-        unsafe { (*queue_context).spin_lock.release() };
 
-        unsafe {
-            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, status);
+        #[cfg(feature = "latency-stats")]
+        echo_record_latency(queue, &request);
+
+        let reference = unsafe { echo_take_request_reference(request_context) };
+        request.complete(status);
+        log_info!(
+            "CustomTimerDPC Completed request {:?}, releasing its reference",
+            reference.as_ref().map(RefGuard::as_raw)
+        );
+    }
+}
+
+/// Complete the pending requests of `queue` whose cancel routine has not
+/// already claimed them, with the `parallel-queue` feature. Called from the
+/// `TimerDPC` and, with the `dpc-completion` feature, from the DPC queued by
+/// `echo_evt_io_write`, or with the `wait-lock` feature, from the work item
+/// queued by the `TimerDPC`.
+///
+/// Each pending request follows the same cancel ownership protocol as the
+/// single current request of the sequential queue. The requests this routine
+/// claims are taken out of the collection under the queue context lock, and
+/// only completed once it has been released.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue whose pending requests should be completed.
+///
+/// # Return value:
+///
+/// * `VOID`
+#[cfg(feature = "parallel-queue")]
+fn echo_complete_current_request(queue: WDFQUEUE) {
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
+
+    let mut claimed_requests: Vec<(WDFREQUEST, *mut RequestContext)> = Vec::new();
+    {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        let pending_requests = unsafe { &(*queue_context).pending_requests };
+        let mut index = 0;
+        while index < pending_requests.get_count() {
+            let request = pending_requests.get_item(index) as WDFREQUEST;
+            let claimed_request_context = match unsafe { request_get_context(request as WDFOBJECT) }
+            {
+                None => {
+                    // Cancel ownership cannot be claimed without the context,
+                    // so leave the request to the cancel routine.
+                    log_error!("Request {request:?} has no RequestContext");
+                    None
+                }
+                Some(request_context) => (cancel_protocol::on_timer_fire(unsafe {
+                    &(*request_context).cancel_completion_ownership_count
+                }) == TimerAction::UnmarkCancelable)
+                    .then_some(request_context),
+            };
+
+            if let Some(request_context) = claimed_request_context {
+                // The next request moves to `index`
+                pending_requests.remove(request as WDFOBJECT);
+                #[cfg(feature = "pending-limit")]
+                unsafe {
+                    echo_release_pending_slot(queue_context);
+                }
+                claimed_requests.push((request, request_context));
+            } else {
+                // Left in the collection for the cancel routine to remove
+                index += 1;
+            }
+        }
+    }
+
+    for (request, request_context) in claimed_requests {
+        // SAFETY: The request was marked cancelable, so it is still owned by
+        // the driver, and it is only completed below once completion ownership
+        // has been claimed
+        let request = unsafe { Request::from_raw(request) };
+
+        let status = match request.unmark_cancelable() {
+            Ok(()) => STATUS_SUCCESS,
+            Err(status) => status,
+        };
+        let action = cancel_protocol::on_unmark_cancelable(
+            unsafe { &(*request_context).cancel_completion_ownership_count },
+            status == STATUS_CANCELLED,
+        );
+
+        if action == UnmarkAction::Complete {
+            log_info!(
+                "CustomTimerDPC Completing pending request {:?}, status {}",
+                request.as_raw(),
+                NtStatus(status)
+            );
+
+            #[cfg(feature = "latency-stats")]
+            echo_record_latency(queue, &request);
+
+            let reference = unsafe { echo_take_request_reference(request_context) };
+            request.complete(status);
+            drop(reference);
         }
     }
 }
@@ -0,0 +1,247 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Reads waiting for a write, with the `blocking-read` feature.
+//!
+//! A read issued while there is no data is not completed right away: it is
+//! marked cancelable and kept until a write provides data, and the write
+//! callback completes it, claiming it with the same cancel ownership count as
+//! the timer. The feature builds on the parallel queue, so that writes are
+//! still presented while reads wait.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use wdk_sys::{
+    STATUS_CANCELLED,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_SUCCESS,
+    WDFOBJECT,
+    WDFQUEUE,
+    WDFREQUEST,
+};
+
+#[cfg(not(feature = "ring-buffer"))]
+use super::echo_read_buffer;
+#[cfg(feature = "ring-buffer")]
+use super::echo_read_ring;
+use super::{echo_evt_request_cancel, echo_remove_pending_request, echo_take_request_reference};
+#[cfg(not(feature = "wait-lock"))]
+use crate::SpinLockExt;
+use crate::{
+    cancel_protocol::{self, TimerAction, UnmarkAction},
+    log::{log_error, log_info},
+    queue_get_context,
+    request_get_context,
+    wdf_object_reference::RefGuard,
+    AtomicI32,
+    QueueContext,
+    Request,
+    RequestContext,
+};
+
+/// Keep a read of `length` bytes that found no data until a write provides
+/// some, with the `blocking-read` feature. Like the current request, the read
+/// is marked cancelable, but it is added to the waiting reads, which only
+/// `echo_complete_waiting_reads` completes.
+///
+/// A write can store its data between the read finding none and the read being
+/// added to the waiting reads, in which case the write does not see the read.
+/// The data is checked for again once the read is waiting, so that it is
+/// completed right away instead of waiting for the next write.
+///
+/// # Arguments:
+///
+/// * `request` - Read request with no data.
+/// * `queue` - Queue associated with the request
+/// * `length` - Number of bytes to be read.
+pub(super) fn echo_wait_for_write(request: Request, queue: WDFQUEUE, length: usize) {
+    let (Some(request_context), Some(queue_context)) = (unsafe {
+        (
+            request_get_context(request.as_raw() as WDFOBJECT),
+            queue_get_context(queue as WDFOBJECT),
+        )
+    }) else {
+        log_error!(
+            "Request {:?} or its queue {queue:?} has no context",
+            request.as_raw()
+        );
+        request.complete_with_information(STATUS_INVALID_DEVICE_STATE, 0);
+        return;
+    };
+
+    unsafe {
+        (*request_context).cancel_completion_ownership_count =
+            AtomicI32::new(cancel_protocol::INITIAL_OWNERSHIP_COUNT);
+        (*request_context).read_length = length;
+        // Referenced while it waits, like the current request
+        (*request_context).reference = Some(RefGuard::new(request.as_raw() as WDFOBJECT));
+    }
+
+    log_info!("Read {:?} waiting for a write", request.as_raw());
+
+    let result = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe {
+            (*queue_context)
+                .waiting_reads
+                .add(request.as_raw() as WDFOBJECT)
+        }
+        .and_then(|()| {
+            // Marked cancelable under the lock for the same reason as the
+            // current request, see echo_set_current_request
+            let result = request.mark_cancelable(Some(echo_evt_request_cancel));
+            if result.is_err() {
+                unsafe { echo_remove_pending_request(queue_context, request.as_raw()) };
+            }
+
+            result
+        })
+    };
+    // As for the current request, STATUS_CANCELLED means the framework will not
+    // call the cancel routine, so the read is only completed here
+    if let Err(status) = result {
+        if status == STATUS_CANCELLED {
+            log_info!(
+                "Read {:?} cancelled before being marked cancelable",
+                request.as_raw()
+            );
+        }
+        let reference = unsafe { echo_take_request_reference(request_context) };
+        request.complete_with_information(status, 0);
+        drop(reference);
+        return;
+    }
+
+    let has_data = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        unsafe { echo_has_data(queue_context) }
+    };
+    if has_data {
+        echo_complete_waiting_reads(queue, false);
+    }
+}
+
+/// Whether a read would find data in the queue-context buffer, with the
+/// `blocking-read` feature. Must be called with the queue context lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(not(feature = "ring-buffer"))]
+unsafe fn echo_has_data(queue_context: *mut QueueContext) -> bool {
+    unsafe { (*queue_context).buffer.is_some() }
+}
+
+/// Whether a read would find data in the queue-context ring, with the
+/// `blocking-read` feature. Must be called with the queue context lock held.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+#[cfg(feature = "ring-buffer")]
+unsafe fn echo_has_data(queue_context: *mut QueueContext) -> bool {
+    unsafe { !(*queue_context).ring.is_empty() }
+}
+
+/// Complete the reads of `queue` waiting for data whose cancel routine has not
+/// already claimed them, with the `blocking-read` feature. Called by
+/// `echo_evt_io_write` once it has stored its data.
+///
+/// The waiting reads are claimed with the same cancel ownership protocol as
+/// the pending requests the timer completes. Each claimed read is given the
+/// data like any other read, and completed right away. A read that finds no
+/// data, because another read with the `ring-buffer` feature took all of it,
+/// waits again.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue whose waiting reads should be completed.
+/// * `cancel` - Whether to complete the reads with `STATUS_CANCELLED` instead
+///   of data, when the queue is purged.
+pub(super) fn echo_complete_waiting_reads(queue: WDFQUEUE, cancel: bool) {
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        return;
+    };
+
+    let mut claimed_requests: Vec<(WDFREQUEST, *mut RequestContext)> = Vec::new();
+    {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+        let waiting_reads = unsafe { &(*queue_context).waiting_reads };
+        let mut index = 0;
+        while index < waiting_reads.get_count() {
+            let request = waiting_reads.get_item(index) as WDFREQUEST;
+            let claimed_request_context = match unsafe { request_get_context(request as WDFOBJECT) }
+            {
+                None => {
+                    log_error!("Request {request:?} has no RequestContext");
+                    None
+                }
+                Some(request_context) => (cancel_protocol::on_timer_fire(unsafe {
+                    &(*request_context).cancel_completion_ownership_count
+                }) == TimerAction::UnmarkCancelable)
+                    .then_some(request_context),
+            };
+
+            if let Some(request_context) = claimed_request_context {
+                // The next request moves to `index`
+                waiting_reads.remove(request as WDFOBJECT);
+                claimed_requests.push((request, request_context));
+            } else {
+                // Left in the collection for the cancel routine to remove
+                index += 1;
+            }
+        }
+    }
+
+    for (request, request_context) in claimed_requests {
+        // SAFETY: The request was marked cancelable, so it is still owned by
+        // the driver, and it is only completed below once completion ownership
+        // has been claimed
+        let request = unsafe { Request::from_raw(request) };
+
+        let status = match request.unmark_cancelable() {
+            Ok(()) => STATUS_SUCCESS,
+            Err(status) => status,
+        };
+        let action = cancel_protocol::on_unmark_cancelable(
+            unsafe { &(*request_context).cancel_completion_ownership_count },
+            status == STATUS_CANCELLED,
+        );
+        if action == UnmarkAction::Leave {
+            continue;
+        }
+
+        // Released at the end of the iteration, once the read has been completed
+        // or has taken a new reference to wait again
+        let _reference = unsafe { echo_take_request_reference(request_context) };
+
+        if cancel || status == STATUS_CANCELLED {
+            request.complete_with_information(STATUS_CANCELLED, 0);
+            continue;
+        }
+
+        let length = unsafe { (*request_context).read_length };
+        #[cfg(not(feature = "ring-buffer"))]
+        let result = {
+            let _guard = unsafe { (*queue_context).lock.lock() };
+            unsafe { echo_read_buffer(queue_context, &request, length) }
+        };
+        #[cfg(feature = "ring-buffer")]
+        let result = unsafe { echo_read_ring(queue_context, &request, length) };
+
+        match result {
+            Err(status) => request.complete_with_information(status, 0),
+            Ok(0) => echo_wait_for_write(request, queue, length),
+            Ok(length) => {
+                log_info!(
+                    "Completing waiting read {:?} with {length} bytes",
+                    request.as_raw()
+                );
+                request.complete_with_information(STATUS_SUCCESS, length);
+            }
+        }
+    }
+}
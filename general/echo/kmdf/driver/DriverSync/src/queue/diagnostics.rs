@@ -0,0 +1,138 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! `IOCTL_ECHO_GET_QUEUE_STATE`, with the `queue-diagnostics` feature, which
+//! is only available in debug builds. It returns the number of requests
+//! waiting for the timer, the state and cancel completion ownership count of
+//! the current request, and the status it is completed with, so that the
+//! cancel protocol can be observed from user mode.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    PVOID,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_SUCCESS,
+    WDFOBJECT,
+    WDFQUEUE,
+    WDFREQUEST,
+};
+
+#[cfg(not(feature = "wait-lock"))]
+use crate::SpinLockExt;
+use crate::{
+    diagnostics::EchoQueueState,
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    queue_get_context,
+    request_get_context,
+    Request,
+};
+
+/// Handle `IOCTL_ECHO_GET_QUEUE_STATE`, with the `queue-diagnostics` feature:
+/// copy the `EchoQueueState` of `queue` to the output buffer of `request`, and
+/// complete it with its size.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_GET_QUEUE_STATE` request.
+/// * `queue` - Handle to the default queue, which the request was presented on.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) fn echo_get_queue_state(request: Request, queue: WDFQUEUE) {
+    let length = core::mem::size_of::<EchoQueueState>();
+
+    // Fails with STATUS_BUFFER_TOO_SMALL if the output buffer cannot hold the
+    // state
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputBuffer,
+            request.as_raw(),
+            length,
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveOutputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
+    }
+
+    let Some(queue_context) = (unsafe { queue_get_context(queue as WDFOBJECT) }) else {
+        log_error!("Queue {queue:?} has no QueueContext");
+        request.complete(STATUS_INVALID_DEVICE_STATE);
+        return;
+    };
+
+    let state = {
+        let _guard = unsafe { (*queue_context).lock.lock() };
+
+        #[cfg(not(feature = "parallel-queue"))]
+        let (pending_requests, current_request_state, waiting_request) = unsafe {
+            let current_request = (*queue_context).current_request;
+            (
+                u32::from(!current_request.is_null()),
+                u32::from((*queue_context).current_state.load() as u8),
+                current_request,
+            )
+        };
+
+        #[cfg(feature = "parallel-queue")]
+        let (pending_requests, current_request_state, waiting_request) = unsafe {
+            let pending_requests = &(*queue_context).pending_requests;
+            let count = pending_requests.get_count();
+            (
+                count,
+                0,
+                if count == 0 {
+                    core::ptr::null_mut()
+                } else {
+                    pending_requests.get_item(0) as WDFREQUEST
+                },
+            )
+        };
+
+        // SAFETY: Every path completing a waiting request first stops tracking
+        // it under the queue lock: echo_complete_current_request, which the
+        // timer and echo_evt_io_stop complete requests through, clears
+        // `current_request` or removes the claimed requests from
+        // `pending_requests`, and echo_evt_request_cancel removes the request
+        // before completing it. So a request still tracked while the lock is
+        // held has not been completed, and the reference taken on it by
+        // echo_set_current_request, only released after its completion, keeps
+        // it and its context alive.
+        let ownership_count = if waiting_request.is_null() {
+            0
+        } else {
+            unsafe { request_get_context(waiting_request as WDFOBJECT) }.map_or(
+                0,
+                |request_context| unsafe {
+                    (*request_context)
+                        .cancel_completion_ownership_count
+                        .load(core::sync::atomic::Ordering::SeqCst)
+                },
+            )
+        };
+
+        EchoQueueState {
+            pending_requests,
+            current_request_state,
+            ownership_count,
+            current_status: unsafe { (*queue_context).current_status },
+        }
+    };
+    log_info!("Queue {queue:?} state {state:?}");
+
+    // SAFETY: The output buffer holds at least `length` bytes, but the
+    // application may not have aligned it
+    unsafe { buffer.cast::<EchoQueueState>().write_unaligned(state) };
+
+    request.complete_with_information(STATUS_SUCCESS, length);
+}
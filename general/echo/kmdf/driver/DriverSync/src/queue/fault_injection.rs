@@ -0,0 +1,122 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! `IOCTL_ECHO_INJECT_FAULT`, with the `fault-injection` feature, which makes
+//! the next read or write fail with a given status, so that applications can
+//! exercise their error paths.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PVOID,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_INVALID_PARAMETER,
+    STATUS_SUCCESS,
+    WDFOBJECT,
+    WDFQUEUE,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    wdf_object_get_device_context,
+    DeviceContext,
+    Request,
+};
+
+/// Handle `IOCTL_ECHO_INJECT_FAULT`, with the `fault-injection` feature: store
+/// the `NTSTATUS` in the input buffer of `request` in the device context. The
+/// next read or write is completed with it instead of being processed, so
+/// that applications can exercise their error paths without actually
+/// exhausting the resources of the system. Injecting a success status, e.g.
+/// `STATUS_SUCCESS`, clears a pending fault, and `STATUS_PENDING` is rejected
+/// with `STATUS_INVALID_PARAMETER`.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_INJECT_FAULT` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe fn echo_inject_fault(request: Request, device_context: *mut DeviceContext) {
+    // Fails with STATUS_BUFFER_TOO_SMALL if the input buffer cannot hold an
+    // NTSTATUS
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveInputBuffer,
+            request.as_raw(),
+            core::mem::size_of::<NTSTATUS>(),
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveInputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
+    }
+
+    // SAFETY: The input buffer holds at least an NTSTATUS, but the application
+    // may not have aligned it
+    let injected_status = NtStatus(unsafe { buffer.cast::<NTSTATUS>().read_unaligned() });
+
+    // A request can never be completed with STATUS_PENDING
+    if injected_status.is_pending() {
+        log_error!("Cannot inject {injected_status}");
+        request.complete(STATUS_INVALID_PARAMETER);
+        return;
+    }
+
+    log_info!("Injecting status {injected_status} in the next read or write");
+    unsafe {
+        (*device_context)
+            .injected_status
+            .store(injected_status.into(), core::sync::atomic::Ordering::SeqCst);
+    }
+
+    request.complete(STATUS_SUCCESS);
+}
+
+/// Take the fault injected by `IOCTL_ECHO_INJECT_FAULT` in the device of
+/// `queue`, if any, with the `fault-injection` feature. A fault only fails one
+/// request.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the queue the read or write was presented on.
+///
+/// # Return value:
+///
+/// * `Err(NTSTATUS)` - the status to complete the request with instead of
+///   processing it, `Ok(())` otherwise.
+pub(super) fn echo_take_injected_fault(queue: WDFQUEUE) -> Result<(), NTSTATUS> {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
+
+    let injected_status = NtStatus(unsafe {
+        (*device_context)
+            .injected_status
+            .swap(STATUS_SUCCESS, core::sync::atomic::Ordering::SeqCst)
+    });
+    if injected_status.is_success() {
+        return Ok(());
+    }
+
+    log_info!("Failing the request with the injected status {injected_status}");
+    Err(injected_status.into())
+}
@@ -0,0 +1,120 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Forwarding writes to another device, with the `forward-writes` feature.
+//!
+//! The driver also acts as a client of another device: it opens the device
+//! named by the `ForwardTarget` value of its hardware key as a remote I/O
+//! target, and sends it a copy of each write with `WdfRequestSend`, so that the
+//! data is echoed by both devices.
+
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    PWDF_REQUEST_COMPLETION_PARAMS,
+    WDFCONTEXT,
+    WDFIOTARGET,
+    WDFOBJECT,
+    WDFREQUEST,
+    _POOL_TYPE,
+};
+
+use super::{echo_copy_from_request_buffer, KERNEL_MODE};
+use crate::{
+    config::DriverConfig,
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    wdf_memory::ManagedMemory,
+    DeviceContext,
+    Request,
+};
+
+/// Send a copy of the data of a write request to the forward target of the
+/// device, with the `forward-writes` feature. The copy is sent asynchronously,
+/// and `echo_evt_forward_completion` deletes it once the target completes it.
+///
+/// Writes issued from kernel mode are not forwarded, since the forwarded writes
+/// are themselves issued from kernel mode: two devices forwarding to each other
+/// would otherwise bounce the same data back and forth forever.
+///
+/// Failures are only logged, since the write is echoed whether or not the copy
+/// reaches the target.
+///
+/// # Safety
+///
+/// `device_context` must be valid, and `length` must not exceed the length of
+/// the write request.
+pub(super) unsafe fn echo_forward_write(
+    device_context: *mut DeviceContext,
+    request: &Request,
+    length: usize,
+) {
+    let Some(target) = (unsafe { &(*device_context).forward_target }) else {
+        return;
+    };
+    if request.get_requestor_mode() == KERNEL_MODE {
+        return;
+    }
+
+    let forwarded = match target.create_request() {
+        Ok(forwarded) => forwarded,
+        Err(nt_status) => {
+            log_error!("Could not create forwarded request {}", NtStatus(nt_status));
+            return;
+        }
+    };
+
+    // The copy is parented to the forwarded request, so it is deleted with it
+    let result = ManagedMemory::create(
+        forwarded as WDFOBJECT,
+        _POOL_TYPE::NonPagedPoolNx,
+        DriverConfig::current().pool_tag,
+        length,
+    )
+    .and_then(|mut memory| {
+        let destination = memory.as_slice_mut().as_mut_ptr();
+        unsafe { echo_copy_from_request_buffer(request, destination.cast(), length)? };
+        target.format_write(forwarded, memory.as_raw())?;
+        target.send(forwarded, Some(echo_evt_forward_completion))
+    });
+    if let Err(nt_status) = result {
+        log_error!("Could not forward write {}", NtStatus(nt_status));
+        // SAFETY: The request was not sent, so it is still owned by this driver
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfObjectDelete, forwarded as WDFOBJECT);
+        };
+    }
+}
+
+/// Completion routine of the writes forwarded by `echo_forward_write`, called
+/// once the forward target completes them. It logs their outcome, and deletes
+/// them along with their copy of the data.
+///
+/// # Arguments:
+///
+/// * `request` - Forwarded request, created by `echo_forward_write`.
+/// * `_target` - Forward target of the device.
+/// * `_params` - Completion parameters of the request.
+/// * `_context` - Unused completion context.
+extern "C" fn echo_evt_forward_completion(
+    request: WDFREQUEST,
+    _target: WDFIOTARGET,
+    _params: PWDF_REQUEST_COMPLETION_PARAMS,
+    _context: WDFCONTEXT,
+) {
+    // SAFETY: The request was created by `echo_forward_write`, and is only
+    // deleted below
+    let nt_status = unsafe { call_unsafe_wdf_function_binding!(WdfRequestGetStatus, request) };
+    // SAFETY: Same as above
+    let information =
+        unsafe { call_unsafe_wdf_function_binding!(WdfRequestGetInformation, request) };
+    log_info!(
+        "Forwarded write {request:?} completed {}, {information} bytes",
+        NtStatus(nt_status)
+    );
+
+    // SAFETY: The target completed the request, which belongs to this driver
+    // again, and is not used after being deleted
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfObjectDelete, request as WDFOBJECT);
+    };
+}
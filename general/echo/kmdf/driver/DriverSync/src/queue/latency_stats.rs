@@ -0,0 +1,136 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Latency of the reads and writes, with the `latency-stats` feature.
+//!
+//! Each read and write is stamped with the performance counter when it
+//! arrives, in its request context, and the time until the timer completes it
+//! is accumulated in the device context, which `IOCTL_ECHO_GET_LATENCY_STATS`
+//! returns to applications.
+
+use wdk::nt_success;
+use wdk_sys::{call_unsafe_wdf_function_binding, PVOID, STATUS_SUCCESS, WDFOBJECT, WDFQUEUE};
+
+use crate::{
+    latency::{timestamp, EchoLatencyStats},
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    request_get_context,
+    wdf_object_get_device_context,
+    DeviceContext,
+    Request,
+};
+
+/// Handle `IOCTL_ECHO_GET_LATENCY_STATS`, with the `latency-stats` feature:
+/// copy the `EchoLatencyStats` of the device, how long the requests completed
+/// by the timer stayed in the driver, to the output buffer of `request`, and
+/// complete it with their size.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_GET_LATENCY_STATS` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe fn echo_get_latency_stats(request: Request, device_context: *mut DeviceContext) {
+    let length = core::mem::size_of::<EchoLatencyStats>();
+
+    // Fails with STATUS_BUFFER_TOO_SMALL if the output buffer cannot hold the
+    // statistics
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveOutputBuffer,
+            request.as_raw(),
+            length,
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveOutputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
+    }
+
+    let stats = unsafe { (*device_context).latency_stats.snapshot() };
+    log_info!(
+        "Latency of {} requests: min {} us, max {} us, average {} us",
+        stats.count,
+        stats.min_us,
+        stats.max_us,
+        stats.average_us
+    );
+
+    // SAFETY: The output buffer holds at least `length` bytes, but the
+    // application may not have aligned it
+    unsafe { buffer.cast::<EchoLatencyStats>().write_unaligned(stats) };
+
+    request.complete_with_information(STATUS_SUCCESS, length);
+}
+
+/// Stamp `request` with the performance counter when it arrives, with the
+/// `latency-stats` feature, so that `echo_record_latency` can tell how long it
+/// stayed in the driver.
+///
+/// # Arguments:
+///
+/// * `request` - Read or write presented to the queue.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) fn echo_stamp_arrival(request: &Request) {
+    match unsafe { request_get_context(request.as_raw() as WDFOBJECT) } {
+        Some(request_context) => unsafe {
+            (*request_context)
+                .arrival_time
+                .store(timestamp(), core::sync::atomic::Ordering::Relaxed);
+        },
+        None => log_error!("Request {:?} has no RequestContext", request.as_raw()),
+    }
+}
+
+/// Record how long `request` stayed in the driver in the latency statistics of
+/// the device of `queue`, with the `latency-stats` feature. Called by the
+/// `TimerDPC` right before completing the request.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the default queue of the device.
+/// * `request` - Request about to be completed.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) fn echo_record_latency(queue: WDFQUEUE, request: &Request) {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let (Some(device_context), Some(request_context)) = (unsafe {
+        (
+            wdf_object_get_device_context(device as WDFOBJECT),
+            request_get_context(request.as_raw() as WDFOBJECT),
+        )
+    }) else {
+        log_error!(
+            "Device {device:?} or request {:?} has no context",
+            request.as_raw()
+        );
+        return;
+    };
+
+    unsafe {
+        let arrival_time = (*request_context)
+            .arrival_time
+            .load(core::sync::atomic::Ordering::Relaxed);
+        (*device_context).latency_stats.record(arrival_time);
+    }
+}
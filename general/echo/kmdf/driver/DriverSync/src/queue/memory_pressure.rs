@@ -0,0 +1,100 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! `IOCTL_ECHO_FAIL_ALLOCATIONS`, with the `memory-pressure` feature, which
+//! makes the next write buffer allocations be treated as failed, so that the
+//! cleanup after a failed allocation can be exercised without running the
+//! system out of memory.
+
+use wdk::nt_success;
+use wdk_sys::{call_unsafe_wdf_function_binding, PVOID, STATUS_SUCCESS, ULONG};
+
+use crate::{
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    DeviceContext,
+    Request,
+};
+
+/// Handle `IOCTL_ECHO_FAIL_ALLOCATIONS`, with the `memory-pressure` feature:
+/// store the `ULONG` in the input buffer of `request` in the device context.
+/// That many of the next write buffer allocations are treated as failed, and
+/// the writes are completed with `STATUS_INSUFFICIENT_RESOURCES`, so that the
+/// cleanup after a failed allocation runs without exhausting the memory of the
+/// system. A count of 0 stops failing allocations.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_FAIL_ALLOCATIONS` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe fn echo_fail_allocations(request: Request, device_context: *mut DeviceContext) {
+    // Fails with STATUS_BUFFER_TOO_SMALL if the input buffer cannot hold a
+    // ULONG
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveInputBuffer,
+            request.as_raw(),
+            core::mem::size_of::<ULONG>(),
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveInputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
+    }
+
+    // SAFETY: The input buffer holds at least a ULONG, but the application may
+    // not have aligned it
+    let count = unsafe { buffer.cast::<ULONG>().read_unaligned() };
+
+    log_info!("Failing the next {count} write buffer allocations");
+    unsafe {
+        (*device_context)
+            .failing_allocations
+            .store(count, core::sync::atomic::Ordering::SeqCst);
+    }
+
+    request.complete(STATUS_SUCCESS);
+}
+
+/// Take one of the allocation failures requested with
+/// `IOCTL_ECHO_FAIL_ALLOCATIONS`, if any are left, with the `memory-pressure`
+/// feature.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `device_context` - Context of the device the write was sent to.
+///
+/// # Return value:
+///
+/// * `true` if the allocation must be treated as failed, `false` otherwise.
+pub(super) unsafe fn echo_take_allocation_failure(device_context: *mut DeviceContext) -> bool {
+    unsafe {
+        (*device_context)
+            .failing_allocations
+            .fetch_update(
+                core::sync::atomic::Ordering::SeqCst,
+                core::sync::atomic::Ordering::SeqCst,
+                |count| count.checked_sub(1),
+            )
+            .is_ok()
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! `IOCTL_ECHO_NEITHER`, with the `method-neither` feature.
+//!
+//! The control code uses `METHOD_NEITHER`: its buffers are raw addresses in
+//! the calling process, which the driver probes and locks in
+//! `EvtIoInCallerContext` before the request is queued, and copies through a
+//! kernel buffer.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    STATUS_BUFFER_TOO_SMALL,
+    STATUS_INVALID_BUFFER_SIZE,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_SUCCESS,
+    USHORT,
+    WDFDEVICE,
+    WDFOBJECT,
+    WDFREQUEST,
+    WDF_REQUEST_PARAMETERS,
+    _WDF_REQUEST_TYPE,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    request_get_context,
+    wdf_structure_size::wdf_structure_size,
+    wdf_user_buffer::UserBuffer,
+    Request,
+    IOCTL_ECHO_NEITHER,
+};
+
+/// Longest input buffer of `IOCTL_ECHO_NEITHER`. It is copied to a buffer on
+/// the kernel stack, which is small.
+const NEITHER_MAX_LENGTH: usize = 512;
+
+/// `EvtIoInCallerContext` of the device, with the `method-neither` feature. The
+/// framework calls it for every request, in the context of the thread that
+/// sent it, before the request is queued.
+///
+/// The buffers of `IOCTL_ECHO_NEITHER` are user-mode addresses, which can only
+/// be used from the calling process: they are probed and locked in memory
+/// here, and the locked buffers are stored in the request context for
+/// `echo_neither`, which can run in any thread. Every request is then queued
+/// with `WdfDeviceEnqueueRequest`, since the framework only queues it by itself
+/// when the driver has no `EvtIoInCallerContext`.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to the device the request was sent to.
+/// * `request` - Handle to a framework request object.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub extern "C" fn echo_evt_io_in_caller_context(device: WDFDEVICE, request: WDFREQUEST) {
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "WDF_REQUEST_PARAMETERS is a few dozen bytes"
+    )]
+    let mut parameters = WDF_REQUEST_PARAMETERS {
+        Size: wdf_structure_size!(WDF_REQUEST_PARAMETERS) as USHORT,
+        ..WDF_REQUEST_PARAMETERS::default()
+    };
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfRequestGetParameters, request, &mut parameters);
+    }
+
+    // SAFETY: The DeviceIoControl parameters are the ones set for a device
+    // control request
+    let is_neither = parameters.Type == _WDF_REQUEST_TYPE::WdfRequestTypeDeviceControl
+        && unsafe { parameters.Parameters.DeviceIoControl.IoControlCode } == IOCTL_ECHO_NEITHER;
+    if is_neither {
+        if let Err(status) = unsafe { echo_lock_user_buffers(request) } {
+            log_error!(
+                "Could not lock the buffers of request {request:?} {}",
+                NtStatus(status)
+            );
+            // SAFETY: The request has not been queued, so the driver owns it
+            unsafe { Request::from_raw(request) }.complete(status);
+            return;
+        }
+    }
+
+    let nt_status =
+        unsafe { call_unsafe_wdf_function_binding!(WdfDeviceEnqueueRequest, device, request) };
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceEnqueueRequest failed {}", NtStatus(nt_status));
+        // SAFETY: The request could not be queued, so the driver still owns it
+        unsafe { Request::from_raw(request) }.complete(nt_status);
+    }
+}
+
+/// Probe and lock the input and output buffers of an `IOCTL_ECHO_NEITHER`
+/// request, with the `method-neither` feature, and store them in its context.
+///
+/// # Safety
+///
+/// `request` must be owned by the driver, and this must be called from
+/// `EvtIoInCallerContext`.
+///
+/// # Return value:
+///
+/// * `Ok(())` on success, or the `NTSTATUS` to complete the request with.
+unsafe fn echo_lock_user_buffers(request: WDFREQUEST) -> Result<(), NTSTATUS> {
+    let Some(request_context) = (unsafe { request_get_context(request as WDFOBJECT) }) else {
+        log_error!("Request {request:?} has no RequestContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
+
+    // SAFETY: This is called from EvtIoInCallerContext, in the context of the
+    // process that owns the buffers
+    let input = unsafe { UserBuffer::probe_and_lock_input(request) }?;
+    let output = unsafe { UserBuffer::probe_and_lock_output(request) }?;
+
+    unsafe {
+        (*request_context).user_input = Some(input);
+        (*request_context).user_output = Some(output);
+    }
+    Ok(())
+}
+
+/// Handle `IOCTL_ECHO_NEITHER`, with the `method-neither` feature: copy the
+/// input buffer of `request` to its output buffer, and complete it with the
+/// number of bytes copied.
+///
+/// The buffers were locked by `echo_evt_io_in_caller_context`. The input is
+/// first copied to a kernel buffer, and only that copy is used afterwards: the
+/// application can change its own buffer at any time, so reading it twice
+/// could give two different answers, e.g. a length checked on the first read
+/// and used on the second.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_NEITHER` request.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) fn echo_neither(request: Request) {
+    let Some(request_context) = (unsafe { request_get_context(request.as_raw() as WDFOBJECT) })
+    else {
+        log_error!("Request {:?} has no RequestContext", request.as_raw());
+        request.complete(STATUS_INVALID_DEVICE_STATE);
+        return;
+    };
+
+    let (Some(input), Some(output)) = (unsafe {
+        (
+            (*request_context).user_input.take(),
+            (*request_context).user_output.take(),
+        )
+    }) else {
+        log_error!("Request {:?} has no locked buffers", request.as_raw());
+        request.complete(STATUS_INVALID_DEVICE_STATE);
+        return;
+    };
+
+    let length = input.length();
+    if length > NEITHER_MAX_LENGTH {
+        request.complete(STATUS_INVALID_BUFFER_SIZE);
+        return;
+    }
+    if output.length() < length {
+        request.complete(STATUS_BUFFER_TOO_SMALL);
+        return;
+    }
+
+    let mut buffer = [0u8; NEITHER_MAX_LENGTH];
+    let buffer = &mut buffer[..length];
+    if let Err(status) = input
+        .copy_to(buffer)
+        .and_then(|()| output.copy_from(buffer))
+    {
+        log_error!("Copying the buffers failed {}", NtStatus(status));
+        request.complete(status);
+        return;
+    }
+
+    log_info!("echo_neither copied {length} bytes");
+    request.complete_with_information(STATUS_SUCCESS, length);
+}
@@ -0,0 +1,174 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Limit on the reads the parallel queue holds, with the `pending-limit`
+//! feature.
+//!
+//! The queue holds at most a given number of reads waiting for the timer, set
+//! with `IOCTL_ECHO_SET_MAX_PENDING`. Reads beyond it are failed right away
+//! with `STATUS_DEVICE_BUSY` rather than held, so that an application issuing
+//! too many cannot make the driver hold an unbounded number of requests. Reads
+//! waiting for a write with the `blocking-read` feature are not counted.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PVOID,
+    STATUS_DEVICE_BUSY,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_INVALID_PARAMETER,
+    STATUS_SUCCESS,
+    ULONG,
+    WDFOBJECT,
+    WDFQUEUE,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    nt_assert::nt_assert_msg,
+    nt_status::NtStatus,
+    wdf_object_get_device_context,
+    DeviceContext,
+    QueueContext,
+    Request,
+};
+
+/// Take a slot for a pending request of `queue`, with the `pending-limit`
+/// feature, unless the queue already holds as many as the
+/// `max_pending_requests` of its device. The slot must be given back with
+/// `echo_release_pending_slot` once the request is no longer pending.
+///
+/// The count is only compared and incremented in a single atomic operation, so
+/// two reads arriving together cannot both take the last slot.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the default queue of the device.
+/// * `queue_context` - Context of `queue`.
+///
+/// # Return value:
+///
+/// * `Ok(())` if a slot was taken, or the `NTSTATUS` to complete the request
+///   with, `STATUS_DEVICE_BUSY` when all the slots are taken.
+pub(super) unsafe fn echo_reserve_pending_slot(
+    queue: WDFQUEUE,
+    queue_context: *mut QueueContext,
+) -> Result<(), NTSTATUS> {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    };
+
+    let max_pending_requests = unsafe {
+        (*device_context)
+            .max_pending_requests
+            .load(core::sync::atomic::Ordering::SeqCst)
+    };
+    unsafe {
+        (*queue_context).pending_count.fetch_update(
+            core::sync::atomic::Ordering::SeqCst,
+            core::sync::atomic::Ordering::SeqCst,
+            |count| (count < max_pending_requests).then_some(count + 1),
+        )
+    }
+    .map(|_| ())
+    .map_err(|count| {
+        log_error!("{count} requests already pending, the limit is {max_pending_requests}");
+        STATUS_DEVICE_BUSY
+    })
+}
+
+/// Give back the slot taken by `echo_reserve_pending_slot` for a request that
+/// is no longer pending, with the `pending-limit` feature. Must be called
+/// exactly once for each slot: when the request is not added to the pending
+/// requests, or when it is removed from them, by whichever of the timer and
+/// the cancel routine removes it.
+///
+/// # Safety
+///
+/// `queue_context` must be valid.
+pub(super) unsafe fn echo_release_pending_slot(queue_context: *mut QueueContext) {
+    let count = unsafe {
+        (*queue_context)
+            .pending_count
+            .fetch_sub(1, core::sync::atomic::Ordering::SeqCst)
+    };
+    nt_assert_msg!(count > 0, "more pending slots released than taken");
+}
+
+/// Handle `IOCTL_ECHO_SET_MAX_PENDING`, with the `pending-limit` feature: store
+/// the `ULONG` in the input buffer of `request` as the most reads the queue
+/// holds at once, and return the previous one in the output buffer. The reads
+/// already pending are left alone, even if there are more of them than the new
+/// limit. A limit of 0 is rejected with `STATUS_INVALID_PARAMETER`.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_SET_MAX_PENDING` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe fn echo_set_max_pending(request: Request, device_context: *mut DeviceContext) {
+    // Fail with STATUS_BUFFER_TOO_SMALL if either buffer cannot hold a ULONG
+    let mut input_buffer: PVOID = core::ptr::null_mut();
+    let mut output_buffer: PVOID = core::ptr::null_mut();
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveInputBuffer,
+            request.as_raw(),
+            core::mem::size_of::<ULONG>(),
+            &mut input_buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if nt_success(nt_status) {
+        nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveOutputBuffer,
+                request.as_raw(),
+                core::mem::size_of::<ULONG>(),
+                &mut output_buffer,
+                core::ptr::null_mut()
+            )
+        };
+    }
+    if !nt_success(nt_status) {
+        log_error!("Retrieving the buffers failed {}", NtStatus(nt_status));
+        request.complete(nt_status);
+        return;
+    }
+
+    // SAFETY: The input buffer holds at least a ULONG, but the application may
+    // not have aligned it
+    let max_pending_requests = unsafe { input_buffer.cast::<ULONG>().read_unaligned() };
+    if max_pending_requests == 0 {
+        log_error!("Cannot limit the pending reads to 0");
+        request.complete(STATUS_INVALID_PARAMETER);
+        return;
+    }
+
+    let previous = unsafe {
+        (*device_context)
+            .max_pending_requests
+            .swap(max_pending_requests, core::sync::atomic::Ordering::SeqCst)
+    };
+    log_info!("Limiting the pending reads to {max_pending_requests}, instead of {previous}");
+
+    // SAFETY: The output buffer holds at least a ULONG. With buffered I/O, it
+    // is the same system buffer as the input, which has already been read.
+    unsafe { output_buffer.cast::<ULONG>().write_unaligned(previous) };
+    request.complete_with_information(STATUS_SUCCESS, core::mem::size_of::<ULONG>());
+}
@@ -0,0 +1,115 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Watchdog timer of the default queue, with the `timer-watchdog` feature.
+//!
+//! A second timer watches the one that completes the requests, which records
+//! when it fires. If requests are waiting and it has not fired for twice its
+//! period, or the longer `WatchdogThresholdMs` of the device hardware key, its
+//! DPC is taken to be stuck and the driver bug checks with
+//! `ECHO_TIMER_WATCHDOG`. The check is skipped while a kernel debugger is
+//! attached, since breaking into it stops the timer too.
+
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ntddk::{KdRefreshDebuggerNotPresent, KeBugCheckEx, KeQueryUnbiasedInterruptTime},
+    ULONG_PTR,
+    WDFOBJECT,
+    WDFQUEUE,
+    WDFTIMER,
+};
+
+use crate::{
+    config::DriverConfig,
+    log::log_error,
+    queue_get_context,
+    watchdog::{self, ECHO_TIMER_WATCHDOG},
+    wdf_object_get_device_context,
+    wdf_queue::Queue,
+};
+
+/// Period of the watchdog timer in ms
+pub(super) const WATCHDOG_PERIOD: u32 = 1000;
+
+/// `EvtTimerFunc` of the watchdog timer, with the `timer-watchdog` feature.
+/// Bug check with `ECHO_TIMER_WATCHDOG` when requests wait for the timer of
+/// the queue, and it has not fired for longer than the threshold of the device.
+///
+/// Nothing is checked while a kernel debugger is attached: breaking into it
+/// stops the timer as well, which must not be mistaken for a stuck DPC.
+///
+/// # Arguments:
+///
+/// * `timer` - Handle to the watchdog timer, a child of the default queue.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe extern "C" fn echo_evt_watchdog_func(timer: WDFTIMER) {
+    // SAFETY: KdRefreshDebuggerNotPresent can be called at any IRQL
+    if unsafe { KdRefreshDebuggerNotPresent() } == 0 {
+        return;
+    }
+
+    let queue =
+        unsafe { call_unsafe_wdf_function_binding!(WdfTimerGetParentObject, timer) } as WDFQUEUE;
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let (Some(queue_context), Some(device_context)) = (unsafe {
+        (
+            queue_get_context(queue as WDFOBJECT),
+            wdf_object_get_device_context(device as WDFOBJECT),
+        )
+    }) else {
+        log_error!("Queue {queue:?} or device {device:?} has no context");
+        return;
+    };
+
+    // SAFETY: KeQueryUnbiasedInterruptTime can be called at any IRQL
+    let starved_ms = unsafe {
+        (*queue_context)
+            .watchdog
+            .starved_ms(KeQueryUnbiasedInterruptTime())
+    };
+    // By default, the timer may miss one period, or with the `one-shot-timer`
+    // feature, one completion delay. The `WatchdogThresholdMs` of the device
+    // can make that longer, but not shorter.
+    let config = DriverConfig::current();
+    #[cfg(not(feature = "one-shot-timer"))]
+    let timer_delay_ms = config.timer_period_ms;
+    #[cfg(feature = "one-shot-timer")]
+    let timer_delay_ms = config.completion_delay_ms;
+    let threshold_ms = unsafe { (*device_context).watchdog_threshold_ms }.max(2 * timer_delay_ms);
+    // The reads held by the driver until the timer completes them, and the
+    // writes waiting in the manual queue for the timer to forward them
+    //
+    // SAFETY: Both queues are children of the device, which outlives its
+    // watchdog timer
+    let pending_requests = unsafe { Queue::from_raw(queue) }
+        .request_counts()
+        .driver_owned
+        .saturating_add(
+            unsafe { Queue::from_raw((*device_context).manual_queue) }
+                .request_counts()
+                .queued,
+        );
+
+    if !watchdog::is_stuck(starved_ms, threshold_ms, pending_requests) {
+        return;
+    }
+
+    log_error!(
+        "Timer of queue {queue:?} has not fired for {starved_ms} ms, with {pending_requests} \
+         requests waiting"
+    );
+    // SAFETY: The system is stopped on purpose, while the stuck timer can still
+    // be found in the dump
+    unsafe {
+        KeBugCheckEx(
+            ECHO_TIMER_WATCHDOG,
+            queue as ULONG_PTR,
+            starved_ms,
+            ULONG_PTR::from(threshold_ms),
+            ULONG_PTR::from(pending_requests),
+        );
+    }
+}
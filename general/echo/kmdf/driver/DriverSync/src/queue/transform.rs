@@ -0,0 +1,95 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! `IOCTL_ECHO_SET_TRANSFORM`, with the `transform` feature, and applying the
+//! transform it selects to the data of each write before it is stored, so that
+//! reads return something else than what was written. The transform is kept in
+//! the device context, which parameterizes how every write is processed.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    PVOID,
+    STATUS_INVALID_PARAMETER,
+    STATUS_SUCCESS,
+    ULONG,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    nt_status::NtStatus,
+    transform::Transform,
+    DeviceContext,
+    Request,
+};
+
+/// Handle `IOCTL_ECHO_SET_TRANSFORM`, with the `transform` feature: store the
+/// transform described by the kind and key in the input buffer of `request` in
+/// the device context. It applies to the writes received from now on, while
+/// the data already stored is left as it is. An unknown kind, or a key that
+/// does not fit in a byte, is rejected with `STATUS_INVALID_PARAMETER`.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+///
+/// # Arguments:
+///
+/// * `request` - The `IOCTL_ECHO_SET_TRANSFORM` request.
+/// * `device_context` - Context of the device the request was sent to.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub(super) unsafe fn echo_set_transform(request: Request, device_context: *mut DeviceContext) {
+    // Fails with STATUS_BUFFER_TOO_SMALL if the input buffer cannot hold the
+    // kind and the key
+    let mut buffer: PVOID = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestRetrieveInputBuffer,
+            request.as_raw(),
+            2 * core::mem::size_of::<ULONG>(),
+            &mut buffer,
+            core::ptr::null_mut()
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!(
+            "WdfRequestRetrieveInputBuffer failed {}",
+            NtStatus(nt_status)
+        );
+        request.complete(nt_status);
+        return;
+    }
+
+    // SAFETY: The input buffer holds at least two ULONGs, but the application
+    // may not have aligned them
+    let (kind, key) = unsafe {
+        (
+            buffer.cast::<ULONG>().read_unaligned(),
+            buffer.cast::<ULONG>().add(1).read_unaligned(),
+        )
+    };
+    let Some(transform) = Transform::from_request(kind, key) else {
+        log_error!("Unknown transform kind {kind} with key {key}");
+        request.complete(STATUS_INVALID_PARAMETER);
+        return;
+    };
+
+    let previous = unsafe { (*device_context).transform.swap(transform) };
+    log_info!("Transforming the next writes with {transform:?}, instead of {previous:?}");
+
+    request.complete(STATUS_SUCCESS);
+}
+
+/// Apply the transform selected by `IOCTL_ECHO_SET_TRANSFORM` to `data`, the
+/// copy of a write that is about to be stored, with the `transform` feature.
+///
+/// # Safety
+///
+/// `device_context` must be valid.
+pub(super) unsafe fn echo_transform_write(device_context: *mut DeviceContext, data: &mut [u8]) {
+    let transform = unsafe { (*device_context).transform.load() };
+    transform.apply(data);
+}
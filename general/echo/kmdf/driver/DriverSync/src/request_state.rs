@@ -0,0 +1,213 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! State of the current request of the sequential queue, kept in the queue
+//! context next to the cancel completion ownership count of the request.
+//!
+//! The count decides which of the timer and the cancel routine completes the
+//! request, see `cancel_protocol`. The state does not take part in that
+//! decision: it records where the request is in its life, so that a state the
+//! protocol should never reach, e.g. a second request becoming pending while
+//! one is still waiting, is caught by a `debug_assert!` at the transition
+//! leading to it rather than by its consequences. The state is only changed
+//! with the queue context lock held, and like `cancel_protocol`, this module
+//! has no dependency on WDF.
+//!
+//! ```text
+//!            set current                 timer fires
+//!   Idle ----------------> Pending --------------------> Completing
+//!    ^                        |                             |  |
+//!    |                        | cancel routine              |  |
+//!    |                        v              cancel routine |  |
+//!    +--------------------- Cancelled <---------------------+  |
+//!    |      completed                                          |
+//!    +---------------------------------------------------------+
+//!                            completed by the timer
+//! ```
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Where the current request of the queue is in its life
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RequestState {
+    /// There is no current request. A zero-initialized state is `Idle`.
+    Idle = 0,
+    /// The current request has been marked cancelable and waits for the timer
+    Pending,
+    /// The timer holds a reference on the current request, and is unmarking it
+    /// cancelable to complete it
+    Completing,
+    /// The cancel routine of the current request has run. Whichever of the
+    /// timer and the cancel routine owns the request completes it with
+    /// `STATUS_CANCELLED`.
+    Cancelled,
+}
+
+impl RequestState {
+    /// State stored as `value` by [`AtomicRequestState`]
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Pending,
+            2 => Self::Completing,
+            3 => Self::Cancelled,
+            _ => Self::Idle,
+        }
+    }
+}
+
+/// Whether the current request can go from state `from` to state `to`.
+///
+/// # Arguments:
+///
+/// * `from` - the state of the current request before the transition.
+/// * `to` - the state of the current request after the transition.
+///
+/// # Return value:
+///
+/// * `true` if the transition is one of the edges of the state diagram of this
+///   module, `false` otherwise
+pub const fn is_valid_transition(from: RequestState, to: RequestState) -> bool {
+    matches!(
+        (from, to),
+        (RequestState::Idle, RequestState::Pending)
+            | (
+                RequestState::Pending,
+                RequestState::Completing | RequestState::Cancelled
+            )
+            | (
+                RequestState::Completing,
+                RequestState::Cancelled | RequestState::Idle
+            )
+            | (RequestState::Cancelled, RequestState::Idle)
+    )
+}
+
+/// [`RequestState`] that can be shared between the queue callbacks, the timer
+/// and the cancel routine
+pub struct AtomicRequestState(AtomicU8);
+
+impl AtomicRequestState {
+    /// State of a queue without a current request
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(RequestState::Idle as u8))
+    }
+
+    /// Move to state `to`, asserting in debug builds that the transition is
+    /// valid.
+    ///
+    /// # Arguments:
+    ///
+    /// * `to` - the new state of the current request.
+    ///
+    /// # Return value:
+    ///
+    /// * The previous state
+    pub fn transition(&self, to: RequestState) -> RequestState {
+        let from = RequestState::from_u8(self.0.swap(to as u8, Ordering::SeqCst));
+        debug_assert!(
+            is_valid_transition(from, to),
+            "invalid request state transition from {from:?} to {to:?}"
+        );
+        from
+    }
+
+    /// Current state, as returned by `IOCTL_ECHO_GET_QUEUE_STATE` with the
+    /// `queue-diagnostics` feature
+    #[cfg(feature = "queue-diagnostics")]
+    pub fn load(&self) -> RequestState {
+        RequestState::from_u8(self.0.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [RequestState; 4] = [
+        RequestState::Idle,
+        RequestState::Pending,
+        RequestState::Completing,
+        RequestState::Cancelled,
+    ];
+
+    #[test]
+    fn only_diagram_edges_are_valid() {
+        let edges = [
+            (RequestState::Idle, RequestState::Pending),
+            (RequestState::Pending, RequestState::Completing),
+            (RequestState::Pending, RequestState::Cancelled),
+            (RequestState::Completing, RequestState::Cancelled),
+            (RequestState::Completing, RequestState::Idle),
+            (RequestState::Cancelled, RequestState::Idle),
+        ];
+
+        for from in STATES {
+            for to in STATES {
+                assert_eq!(
+                    is_valid_transition(from, to),
+                    edges.contains(&(from, to)),
+                    "transition from {from:?} to {to:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn state_round_trips_through_u8() {
+        for state in STATES {
+            assert_eq!(RequestState::from_u8(state as u8), state);
+        }
+        assert_eq!(RequestState::from_u8(u8::MAX), RequestState::Idle);
+    }
+
+    #[test]
+    fn timer_completion_returns_to_idle() {
+        let state = AtomicRequestState::new();
+        assert_eq!(state.load(), RequestState::Idle);
+
+        assert_eq!(state.transition(RequestState::Pending), RequestState::Idle);
+        assert_eq!(
+            state.transition(RequestState::Completing),
+            RequestState::Pending
+        );
+        assert_eq!(
+            state.transition(RequestState::Idle),
+            RequestState::Completing
+        );
+        assert_eq!(state.load(), RequestState::Idle);
+    }
+
+    #[test]
+    fn cancellation_returns_to_idle() {
+        let state = AtomicRequestState::new();
+
+        state.transition(RequestState::Pending);
+        assert_eq!(
+            state.transition(RequestState::Cancelled),
+            RequestState::Pending
+        );
+        assert_eq!(
+            state.transition(RequestState::Idle),
+            RequestState::Cancelled
+        );
+
+        // Cancelled while the timer is completing the request
+        state.transition(RequestState::Pending);
+        state.transition(RequestState::Completing);
+        assert_eq!(
+            state.transition(RequestState::Cancelled),
+            RequestState::Completing
+        );
+        assert_eq!(state.load(), RequestState::Cancelled);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalid request state transition from Pending to Pending")]
+    fn second_pending_request_asserts() {
+        let state = AtomicRequestState::new();
+        state.transition(RequestState::Pending);
+        state.transition(RequestState::Pending);
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! A fixed-capacity FIFO of bytes, used with the `ring-buffer` feature to
+//! accumulate the data of several writes until reads drain it, like a pipe.
+//!
+//! The storage is a single nonpaged pool allocation made when the ring is
+//! created, so pushing and popping never allocate and can be done at
+//! `DISPATCH_LEVEL`. A [`Ring`] is not synchronized: concurrent accesses have
+//! to be serialized by the caller, e.g. with the queue context spinlock.
+
+use wdk_sys::{
+    ntddk::{ExAllocatePool2, ExFreePool},
+    NTSTATUS,
+    POOL_FLAG_NON_PAGED,
+    SIZE_T,
+    STATUS_INSUFFICIENT_RESOURCES,
+};
+
+/// Nonpaged pool memory owned by the driver, and freed when dropped.
+///
+/// A zeroed `PoolAllocation` owns nothing and is empty, so it can be part of a
+/// context that the framework zero-initializes before the driver assigns it.
+pub struct PoolAllocation {
+    pointer: *mut u8,
+    length: usize,
+}
+
+impl PoolAllocation {
+    /// Allocate `length` bytes of nonpaged pool tagged with `tag`. The memory
+    /// is zero-initialized by `ExAllocatePool2`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `STATUS_INSUFFICIENT_RESOURCES` if the pool
+    /// allocation fails.
+    pub fn allocate(length: usize, tag: u32) -> Result<Self, NTSTATUS> {
+        // SAFETY: ExAllocatePool2 can be called at up to DISPATCH_LEVEL for
+        // nonpaged pool, and its result is checked for null below
+        let pointer =
+            unsafe { ExAllocatePool2(POOL_FLAG_NON_PAGED, length as SIZE_T, tag) }.cast::<u8>();
+        if pointer.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        Ok(Self { pointer, length })
+    }
+
+    /// The allocated memory
+    pub fn as_slice(&self) -> &[u8] {
+        if self.pointer.is_null() {
+            return &[];
+        }
+        // SAFETY: `pointer` is an initialized allocation of `length` bytes owned
+        // by `self`
+        unsafe { core::slice::from_raw_parts(self.pointer, self.length) }
+    }
+
+    /// The allocated memory
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.pointer.is_null() {
+            return &mut [];
+        }
+        // SAFETY: `pointer` is an initialized allocation of `length` bytes owned
+        // by `self`, which is borrowed mutably
+        unsafe { core::slice::from_raw_parts_mut(self.pointer, self.length) }
+    }
+}
+
+impl Drop for PoolAllocation {
+    fn drop(&mut self) {
+        if !self.pointer.is_null() {
+            // SAFETY: `pointer` was allocated by ExAllocatePool2 and is only freed
+            // here
+            unsafe { ExFreePool(self.pointer.cast()) };
+            self.pointer = core::ptr::null_mut();
+            self.length = 0;
+        }
+    }
+}
+
+/// Fixed-capacity FIFO of bytes over a [`PoolAllocation`].
+///
+/// The data starts at `head` and wraps around at the end of the allocation.
+/// Like [`PoolAllocation`], a zeroed `Ring` is valid, with a capacity of 0.
+pub struct Ring {
+    storage: PoolAllocation,
+    head: usize,
+    length: usize,
+}
+
+impl Ring {
+    /// Create an empty [`Ring`] that can hold `capacity` bytes, allocated from
+    /// nonpaged pool tagged with `tag`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `STATUS_INSUFFICIENT_RESOURCES` if the pool
+    /// allocation fails.
+    pub fn create(capacity: usize, tag: u32) -> Result<Self, NTSTATUS> {
+        Ok(Self {
+            storage: PoolAllocation::allocate(capacity, tag)?,
+            head: 0,
+            length: 0,
+        })
+    }
+
+    /// Number of bytes the [`Ring`] can hold
+    pub fn capacity(&self) -> usize {
+        self.storage.as_slice().len()
+    }
+
+    /// Whether the [`Ring`] holds no data
+    pub const fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Number of bytes that can be pushed before the [`Ring`] is full
+    pub fn free_space(&self) -> usize {
+        self.capacity() - self.length
+    }
+
+    /// Append all of `data` at the back of the [`Ring`].
+    ///
+    /// # Return value:
+    ///
+    /// * `true` if `data` was pushed, `false` if it does not fit in the free
+    ///   space, in which case nothing is pushed.
+    #[must_use]
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if data.len() > self.free_space() {
+            return false;
+        }
+        if data.is_empty() {
+            return true;
+        }
+
+        let capacity = self.capacity();
+        let tail = (self.head + self.length) % capacity;
+
+        // Fill up to the end of the storage, then wrap around to its start
+        let first = data.len().min(capacity - tail);
+        let storage = self.storage.as_mut_slice();
+        storage[tail..tail + first].copy_from_slice(&data[..first]);
+        storage[..data.len() - first].copy_from_slice(&data[first..]);
+
+        self.length += data.len();
+        true
+    }
+
+    /// Remove up to `data.len()` bytes from the front of the [`Ring`] into
+    /// `data`.
+    ///
+    /// # Return value:
+    ///
+    /// * The number of bytes popped, 0 if the [`Ring`] is empty.
+    pub fn pop(&mut self, data: &mut [u8]) -> usize {
+        let count = data.len().min(self.length);
+        if count == 0 {
+            return 0;
+        }
+
+        let capacity = self.capacity();
+
+        // Read up to the end of the storage, then wrap around to its start
+        let first = count.min(capacity - self.head);
+        let storage = self.storage.as_slice();
+        data[..first].copy_from_slice(&storage[self.head..self.head + first]);
+        data[first..count].copy_from_slice(&storage[..count - first]);
+
+        self.head = (self.head + count) % capacity;
+        self.length -= count;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG: u32 = u32::from_le_bytes(*b"Test");
+
+    #[test]
+    fn empty_ring_pops_nothing() {
+        let mut ring = Ring::create(8, TAG).unwrap();
+        assert!(ring.is_empty());
+        assert_eq!(ring.capacity(), 8);
+        assert_eq!(ring.free_space(), 8);
+
+        let mut data = [0xFF; 4];
+        assert_eq!(ring.pop(&mut data), 0);
+        assert_eq!(data, [0xFF; 4]);
+    }
+
+    #[test]
+    fn full_ring_rejects_push() {
+        let mut ring = Ring::create(8, TAG).unwrap();
+        assert!(!ring.push(&[1; 9]), "push longer than the capacity");
+        assert!(ring.is_empty());
+
+        assert!(ring.push(&[1, 2, 3, 4, 5]));
+        assert!(!ring.push(&[6, 7, 8, 9]), "push longer than the free space");
+        assert_eq!(ring.free_space(), 3);
+
+        assert!(ring.push(&[6, 7, 8]));
+        assert_eq!(ring.free_space(), 0);
+        assert!(!ring.push(&[9]));
+        assert!(ring.push(&[]), "empty push into a full ring");
+
+        let mut data = [0; 8];
+        assert_eq!(ring.pop(&mut data), 8);
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn push_and_pop_wrap_around() {
+        let mut ring = Ring::create(8, TAG).unwrap();
+        assert!(ring.push(&[1, 2, 3, 4, 5, 6]));
+
+        let mut data = [0; 4];
+        assert_eq!(ring.pop(&mut data), 4);
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        // Fills the last 2 bytes of the storage, then 3 from its start
+        assert!(ring.push(&[7, 8, 9, 10, 11]));
+        assert_eq!(ring.free_space(), 1);
+
+        // Reads across the end of the storage
+        let mut data = [0; 5];
+        assert_eq!(ring.pop(&mut data), 5);
+        assert_eq!(data, [5, 6, 7, 8, 9]);
+
+        let mut data = [0; 8];
+        assert_eq!(ring.pop(&mut data), 2);
+        assert_eq!(data[..2], [10, 11]);
+        assert!(ring.is_empty());
+        assert_eq!(ring.free_space(), 8);
+    }
+
+    #[test]
+    fn zero_capacity_ring_holds_nothing() {
+        let mut ring = Ring::create(0, TAG).unwrap();
+        assert_eq!(ring.capacity(), 0);
+        assert!(ring.push(&[]));
+        assert!(!ring.push(&[1]));
+        assert_eq!(ring.pop(&mut [0; 1]), 0);
+    }
+
+    #[test]
+    fn failed_allocation_is_insufficient_resources() {
+        assert_eq!(
+            Ring::create(usize::MAX, TAG).err(),
+            Some(STATUS_INSUFFICIENT_RESOURCES)
+        );
+    }
+}
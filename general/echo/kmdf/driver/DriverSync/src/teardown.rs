@@ -0,0 +1,163 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Stopping the timers of the queue when it is torn down.
+//!
+//! The timer callback uses the queue context, so none may be running, or run
+//! later, once the queue starts releasing what the context holds. The queue is
+//! first marked as torn down, so that a callback starting from then on returns
+//! right away, and its timers are then stopped, waiting for a callback already
+//! running to return. See the [`Drop`] implementation of `QueueContext` for
+//! where this is done and the ordering of the framework it complements.
+//!
+//! This module has no dependency on WDF, so that the order of the steps can be
+//! tested with timers standing in for `wdf_timer::Timer`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Timer that can be stopped when the queue is torn down
+pub trait StopTimer {
+    /// Stop the timer, waiting for a running callback to return if `wait` is
+    /// set. Returns `true` if the timer was waiting to fire.
+    fn stop(&self, wait: bool) -> bool;
+}
+
+/// Mark the queue as torn down, then stop each of `timers` and wait for its
+/// callback to return.
+///
+/// # Arguments:
+///
+/// * `torn_down` - Flag the timer callbacks check before using the queue.
+/// * `timers` - Timers whose callbacks use the queue, stopped in this order.
+///
+/// # Return value:
+///
+/// * `VOID`
+pub fn tear_down(torn_down: &AtomicBool, timers: &[&dyn StopTimer]) {
+    torn_down.store(true, Ordering::SeqCst);
+    for timer in timers {
+        let _ = timer.stop(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// What happened to the queue, in order
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Event {
+        Stopped {
+            timer: usize,
+            wait: bool,
+            torn_down: bool,
+        },
+        BufferFreed,
+    }
+
+    struct FakeTimer<'a> {
+        id: usize,
+        torn_down: &'a AtomicBool,
+        events: &'a RefCell<Vec<Event>>,
+    }
+
+    impl StopTimer for FakeTimer<'_> {
+        fn stop(&self, wait: bool) -> bool {
+            self.events.borrow_mut().push(Event::Stopped {
+                timer: self.id,
+                wait,
+                torn_down: self.torn_down.load(Ordering::SeqCst),
+            });
+            true
+        }
+    }
+
+    struct FakeBuffer<'a> {
+        events: &'a RefCell<Vec<Event>>,
+    }
+
+    impl Drop for FakeBuffer<'_> {
+        fn drop(&mut self) {
+            self.events.borrow_mut().push(Event::BufferFreed);
+        }
+    }
+
+    /// Stands in for `QueueContext`, tearing down in its `Drop` implementation
+    /// before its fields are dropped
+    struct FakeQueueContext<'a> {
+        timer: FakeTimer<'a>,
+        _buffer: FakeBuffer<'a>,
+        torn_down: &'a AtomicBool,
+    }
+
+    impl Drop for FakeQueueContext<'_> {
+        fn drop(&mut self) {
+            tear_down(self.torn_down, &[&self.timer]);
+        }
+    }
+
+    #[test]
+    fn tear_down_marks_torn_down_before_stopping_and_waiting() {
+        let torn_down = AtomicBool::new(false);
+        let events = RefCell::new(Vec::new());
+        let watchdog = FakeTimer {
+            id: 0,
+            torn_down: &torn_down,
+            events: &events,
+        };
+        let timer = FakeTimer {
+            id: 1,
+            torn_down: &torn_down,
+            events: &events,
+        };
+
+        tear_down(&torn_down, &[&watchdog, &timer]);
+
+        assert!(torn_down.load(Ordering::SeqCst));
+        assert_eq!(
+            events.into_inner(),
+            vec![
+                Event::Stopped {
+                    timer: 0,
+                    wait: true,
+                    torn_down: true,
+                },
+                Event::Stopped {
+                    timer: 1,
+                    wait: true,
+                    torn_down: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dropping_queue_context_stops_timer_before_freeing_buffer() {
+        let torn_down = AtomicBool::new(false);
+        let events = RefCell::new(Vec::new());
+
+        drop(FakeQueueContext {
+            timer: FakeTimer {
+                id: 0,
+                torn_down: &torn_down,
+                events: &events,
+            },
+            _buffer: FakeBuffer { events: &events },
+            torn_down: &torn_down,
+        });
+
+        assert_eq!(
+            events.into_inner(),
+            vec![
+                Event::Stopped {
+                    timer: 0,
+                    wait: true,
+                    torn_down: true,
+                },
+                Event::BufferFreed,
+            ]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Checks of the length of a write against the longest the queue-context
+//! buffer accepts.
+//!
+//! `echo_evt_io_write` completes a write that is too long with the status
+//! returned here, and stores any other. Like `request_state`, this module has
+//! no dependency on WDF, so that the decision to reject a write is kept
+//! separate from completing the request.
+
+use wdk_sys::{NTSTATUS, STATUS_BUFFER_OVERFLOW};
+
+/// Checks that a write request fits in the queue-context buffer.
+///
+/// # Arguments:
+///
+/// * `length` - number of bytes to be written.
+/// * `max_write_length` - longest write accepted, from the `DriverConfig`.
+///
+/// # Return value:
+///
+/// * `Ok(())` - if the write can be accepted,
+/// * `Err(STATUS_BUFFER_OVERFLOW)` - if `length` exceeds `max_write_length`.
+pub const fn validate_write_length(length: usize, max_write_length: usize) -> Result<(), NTSTATUS> {
+    if length > max_write_length {
+        return Err(STATUS_BUFFER_OVERFLOW);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_WRITE_LENGTH: usize = 40 * 1024;
+
+    #[test]
+    fn write_up_to_max_is_accepted() {
+        assert_eq!(validate_write_length(0, MAX_WRITE_LENGTH), Ok(()));
+        assert_eq!(validate_write_length(1, MAX_WRITE_LENGTH), Ok(()));
+        assert_eq!(
+            validate_write_length(MAX_WRITE_LENGTH, MAX_WRITE_LENGTH),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn write_over_max_overflows() {
+        assert_eq!(
+            validate_write_length(MAX_WRITE_LENGTH + 1, MAX_WRITE_LENGTH),
+            Err(STATUS_BUFFER_OVERFLOW)
+        );
+        assert_eq!(
+            validate_write_length(usize::MAX, MAX_WRITE_LENGTH),
+            Err(STATUS_BUFFER_OVERFLOW)
+        );
+        assert_eq!(validate_write_length(1, 0), Err(STATUS_BUFFER_OVERFLOW));
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Transforms applied to the data of each write with the `transform` feature,
+//! selected by `IOCTL_ECHO_SET_TRANSFORM` and kept in the device context.
+//!
+//! The data is transformed once, when the write is stored, and reads return it
+//! as stored, so an application gets back its data transformed and can undo
+//! the transform to check it. Like `request_state`, this module has no
+//! dependency on WDF: transforming a buffer is a pure function of the
+//! transform and the data.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Kind of `IOCTL_ECHO_SET_TRANSFORM` leaving the data as it is
+pub const TRANSFORM_IDENTITY: u32 = 0;
+/// Kind of `IOCTL_ECHO_SET_TRANSFORM` XOR-ing each byte with the key
+pub const TRANSFORM_XOR: u32 = 1;
+/// Kind of `IOCTL_ECHO_SET_TRANSFORM` adding the key to each byte, wrapping
+/// around
+pub const TRANSFORM_INCREMENT: u32 = 2;
+
+/// Transform applied to the data of a write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transform {
+    /// Echo the data verbatim. A zero-initialized transform is `Identity`.
+    Identity,
+    /// XOR each byte with the key, which is its own inverse
+    Xor(u8),
+    /// Add the key to each byte, wrapping around
+    Increment(u8),
+}
+
+impl Transform {
+    /// Transform selected by the `kind` and `key` of an
+    /// `IOCTL_ECHO_SET_TRANSFORM` request.
+    ///
+    /// # Arguments:
+    ///
+    /// * `kind` - one of the `TRANSFORM_*` kinds.
+    /// * `key` - the byte to XOR or add, ignored by `TRANSFORM_IDENTITY`.
+    ///
+    /// # Return value:
+    ///
+    /// * `None` if `kind` is unknown or `key` does not fit in a byte, the
+    ///   transform otherwise
+    pub fn from_request(kind: u32, key: u32) -> Option<Self> {
+        let key = u8::try_from(key).ok()?;
+        match kind {
+            TRANSFORM_IDENTITY => Some(Self::Identity),
+            TRANSFORM_XOR => Some(Self::Xor(key)),
+            TRANSFORM_INCREMENT => Some(Self::Increment(key)),
+            _ => None,
+        }
+    }
+
+    /// Transform `data` in place.
+    pub fn apply(self, data: &mut [u8]) {
+        match self {
+            Self::Identity => {}
+            Self::Xor(key) => {
+                for byte in data {
+                    *byte ^= key;
+                }
+            }
+            Self::Increment(key) => {
+                for byte in data {
+                    *byte = byte.wrapping_add(key);
+                }
+            }
+        }
+    }
+
+    /// Kind in the upper bits and key in the low byte, as stored by
+    /// [`AtomicTransform`]
+    const fn to_bits(self) -> u32 {
+        match self {
+            Self::Identity => TRANSFORM_IDENTITY << 8,
+            Self::Xor(key) => (TRANSFORM_XOR << 8) | key as u32,
+            Self::Increment(key) => (TRANSFORM_INCREMENT << 8) | key as u32,
+        }
+    }
+
+    /// Transform stored as `bits` by [`AtomicTransform`]
+    const fn from_bits(bits: u32) -> Self {
+        let key = bits.to_le_bytes()[0];
+        match bits >> 8 {
+            TRANSFORM_XOR => Self::Xor(key),
+            TRANSFORM_INCREMENT => Self::Increment(key),
+            _ => Self::Identity,
+        }
+    }
+}
+
+/// [`Transform`] that can be changed by `IOCTL_ECHO_SET_TRANSFORM` while
+/// writes read it
+pub struct AtomicTransform(AtomicU32);
+
+impl AtomicTransform {
+    /// Transform of a device that echoes the data verbatim
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(Transform::Identity.to_bits()))
+    }
+
+    /// Transform to apply to the next write
+    pub fn load(&self) -> Transform {
+        Transform::from_bits(self.0.load(Ordering::SeqCst))
+    }
+
+    /// Apply `transform` to the next writes, and return the previous one.
+    pub fn swap(&self, transform: Transform) -> Transform {
+        Transform::from_bits(self.0.swap(transform.to_bits(), Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every byte value, so that wrapping around is covered
+    fn all_bytes() -> [u8; 256] {
+        core::array::from_fn(|i| u8::try_from(i).unwrap())
+    }
+
+    #[test]
+    fn request_selects_transform() {
+        assert_eq!(
+            Transform::from_request(TRANSFORM_IDENTITY, 0x5A),
+            Some(Transform::Identity)
+        );
+        assert_eq!(
+            Transform::from_request(TRANSFORM_XOR, 0x5A),
+            Some(Transform::Xor(0x5A))
+        );
+        assert_eq!(
+            Transform::from_request(TRANSFORM_INCREMENT, 0xFF),
+            Some(Transform::Increment(0xFF))
+        );
+        assert_eq!(Transform::from_request(3, 0), None);
+        assert_eq!(Transform::from_request(TRANSFORM_XOR, 0x100), None);
+    }
+
+    #[test]
+    fn identity_leaves_data() {
+        let mut data = all_bytes();
+        Transform::Identity.apply(&mut data);
+        assert_eq!(data, all_bytes());
+    }
+
+    #[test]
+    fn xor_is_its_own_inverse() {
+        let mut data = all_bytes();
+        Transform::Xor(0xA5).apply(&mut data);
+        assert_eq!(data[0], 0xA5);
+        assert_eq!(data[0xA5], 0);
+
+        Transform::Xor(0xA5).apply(&mut data);
+        assert_eq!(data, all_bytes());
+    }
+
+    #[test]
+    fn increment_wraps_around() {
+        let mut data = all_bytes();
+        Transform::Increment(3).apply(&mut data);
+        assert_eq!(data[0], 3);
+        assert_eq!(data[0xFE], 1);
+
+        // Adding the two's complement of the key undoes it
+        Transform::Increment(3_u8.wrapping_neg()).apply(&mut data);
+        assert_eq!(data, all_bytes());
+    }
+
+    #[test]
+    fn transform_round_trips_through_bits() {
+        let transforms = [
+            Transform::Identity,
+            Transform::Xor(0),
+            Transform::Xor(0xFF),
+            Transform::Increment(1),
+            Transform::Increment(0xFF),
+        ];
+        for transform in transforms {
+            assert_eq!(Transform::from_bits(transform.to_bits()), transform);
+        }
+    }
+
+    #[test]
+    fn atomic_transform_swaps() {
+        let transform = AtomicTransform::new();
+        assert_eq!(transform.load(), Transform::Identity);
+
+        assert_eq!(transform.swap(Transform::Xor(7)), Transform::Identity);
+        assert_eq!(transform.load(), Transform::Xor(7));
+        assert_eq!(transform.swap(Transform::Increment(9)), Transform::Xor(7));
+        assert_eq!(transform.load(), Transform::Increment(9));
+    }
+}
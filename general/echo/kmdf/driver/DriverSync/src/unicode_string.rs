@@ -0,0 +1,265 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Conversions between Rust strings and `UNICODE_STRING`.
+//!
+//! A `UNICODE_STRING` is a UTF-16 buffer that is not necessarily null
+//! terminated. `Length` is the length of the string and `MaximumLength` the
+//! size of the buffer, both in bytes rather than characters, so they are twice
+//! the number of UTF-16 code units, and can be at most `u16::MAX`.
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::str::FromStr;
+
+use wdk_sys::{NTSTATUS, STATUS_NAME_TOO_LONG, UNICODE_STRING};
+
+/// Size of a UTF-16 code unit, in bytes
+const CODE_UNIT_SIZE: usize = core::mem::size_of::<u16>();
+
+/// Number of UTF-16 code units in `length` bytes. A string cannot have an odd
+/// length, so the last byte of one is ignored.
+fn code_units(length: u16) -> usize {
+    usize::from(length) / CODE_UNIT_SIZE
+}
+
+/// Length in bytes of `code_units` UTF-16 code units.
+///
+/// # Errors
+///
+/// This function will return `STATUS_NAME_TOO_LONG` if the length does not fit
+/// in the `u16` of a `UNICODE_STRING`.
+fn byte_length(code_units: usize) -> Result<u16, NTSTATUS> {
+    code_units
+        .checked_mul(CODE_UNIT_SIZE)
+        .and_then(|length| u16::try_from(length).ok())
+        .ok_or(STATUS_NAME_TOO_LONG)
+}
+
+/// Copy the string described by `unicode_string` into a [`String`], replacing
+/// invalid UTF-16 with `U+FFFD`. Only `Length` bytes are read: the rest of the
+/// buffer, up to `MaximumLength`, is not part of the string.
+///
+/// # Safety
+///
+/// `unicode_string.Buffer` must be null, or valid for reads of
+/// `unicode_string.Length` bytes.
+pub unsafe fn to_string(unicode_string: &UNICODE_STRING) -> String {
+    if unicode_string.Buffer.is_null() {
+        return String::new();
+    }
+
+    // SAFETY: `Buffer` is valid for `Length` bytes per the contract of the
+    // caller, which is at least `code_units(Length)` code units.
+    String::from_utf16_lossy(unsafe {
+        core::slice::from_raw_parts(unicode_string.Buffer, code_units(unicode_string.Length))
+    })
+}
+
+/// Copy the string described by `unicode_string` into an array of `N` UTF-16
+/// code units and null terminate it, without allocating, so that it can be
+/// printed with `%ws` where the heap must be avoided. A string longer than
+/// `N - 1` code units is truncated, and the first half of a surrogate pair cut
+/// by the truncation is dropped. As with [`to_string`], only `Length` bytes
+/// are read.
+///
+/// # Safety
+///
+/// `unicode_string.Buffer` must be null, or valid for reads of
+/// `unicode_string.Length` bytes.
+#[cfg(feature = "heap-free")]
+pub unsafe fn to_fixed_buffer<const N: usize>(unicode_string: &UNICODE_STRING) -> [u16; N] {
+    const {
+        assert!(N > 0, "the buffer needs room for the null terminator");
+    }
+
+    let mut buffer = [0; N];
+    if unicode_string.Buffer.is_null() {
+        return buffer;
+    }
+
+    let string_length = code_units(unicode_string.Length);
+    let length = string_length.min(N - 1);
+    // SAFETY: `Buffer` is valid for `Length` bytes per the contract of the
+    // caller, which is at least `length` code units.
+    buffer[..length]
+        .copy_from_slice(unsafe { core::slice::from_raw_parts(unicode_string.Buffer, length) });
+
+    if length < string_length && length > 0 && (0xD800..0xDC00).contains(&buffer[length - 1]) {
+        buffer[length - 1] = 0;
+    }
+
+    buffer
+}
+
+/// `UNICODE_STRING` backed by a UTF-16 buffer it owns, to pass a Rust string to
+/// the system. The buffer is not null terminated, and its `MaximumLength` is
+/// its `Length`.
+///
+/// ```rust,ignore
+/// let name = OwnedUnicodeString::from_str(r"\Device\RustEcho")?;
+/// call_unsafe_wdf_function_binding!(WdfDeviceInitAssignName, device_init, name.as_unicode_string());
+/// ```
+pub struct OwnedUnicodeString {
+    // Never accessed, but `unicode_string` points into it
+    _buffer: Vec<u16>,
+    unicode_string: UNICODE_STRING,
+}
+
+impl OwnedUnicodeString {
+    /// The `UNICODE_STRING`, valid for as long as the [`OwnedUnicodeString`]
+    pub const fn as_unicode_string(&self) -> &UNICODE_STRING {
+        &self.unicode_string
+    }
+}
+
+impl FromStr for OwnedUnicodeString {
+    type Err = NTSTATUS;
+
+    /// Encode `string` as UTF-16.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `STATUS_NAME_TOO_LONG` if `string` is too long
+    /// for a `UNICODE_STRING`.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut buffer: Vec<u16> = string.encode_utf16().collect();
+        let length = byte_length(buffer.len())?;
+        let unicode_string = UNICODE_STRING {
+            Length: length,
+            MaximumLength: length,
+            // The heap buffer of a Vec does not move with it
+            Buffer: if buffer.is_empty() {
+                core::ptr::null_mut()
+            } else {
+                buffer.as_mut_ptr()
+            },
+        };
+
+        Ok(Self {
+            _buffer: buffer,
+            unicode_string,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Longest string a `UNICODE_STRING` can describe, in code units
+    const MAX_CODE_UNITS: usize = u16::MAX as usize / CODE_UNIT_SIZE;
+
+    #[test]
+    fn byte_length_fits_in_u16() {
+        assert_eq!(byte_length(0), Ok(0));
+        assert_eq!(byte_length(MAX_CODE_UNITS), Ok(u16::MAX - 1));
+        assert_eq!(byte_length(MAX_CODE_UNITS + 1), Err(STATUS_NAME_TOO_LONG));
+        assert_eq!(byte_length(usize::MAX), Err(STATUS_NAME_TOO_LONG));
+    }
+
+    #[test]
+    fn odd_length_ignores_last_byte() {
+        assert_eq!(code_units(0), 0);
+        assert_eq!(code_units(5), 2);
+        assert_eq!(code_units(u16::MAX), MAX_CODE_UNITS);
+    }
+
+    #[test]
+    fn from_str_sets_lengths_in_bytes() {
+        let name = OwnedUnicodeString::from_str(r"\Device\RustEcho").unwrap();
+        let unicode_string = name.as_unicode_string();
+        assert_eq!(unicode_string.Length, 32);
+        assert_eq!(unicode_string.MaximumLength, 32);
+        // SAFETY: The buffer is owned by `name`
+        assert_eq!(unsafe { to_string(unicode_string) }, r"\Device\RustEcho");
+
+        // A character outside the BMP is a surrogate pair
+        let name = OwnedUnicodeString::from_str("\u{1F980}").unwrap();
+        assert_eq!(name.as_unicode_string().Length, 4);
+    }
+
+    #[test]
+    fn from_str_of_empty_string_has_no_buffer() {
+        let name = OwnedUnicodeString::from_str("").unwrap();
+        let unicode_string = name.as_unicode_string();
+        assert_eq!(unicode_string.Length, 0);
+        assert_eq!(unicode_string.MaximumLength, 0);
+        assert!(unicode_string.Buffer.is_null());
+        // SAFETY: The buffer is null
+        assert_eq!(unsafe { to_string(unicode_string) }, "");
+    }
+
+    #[test]
+    fn from_str_rejects_too_long_string() {
+        let longest = "a".repeat(MAX_CODE_UNITS);
+        assert_eq!(
+            OwnedUnicodeString::from_str(&longest)
+                .unwrap()
+                .as_unicode_string()
+                .Length,
+            u16::MAX - 1
+        );
+
+        let too_long = "a".repeat(MAX_CODE_UNITS + 1);
+        assert_eq!(
+            OwnedUnicodeString::from_str(&too_long).err(),
+            Some(STATUS_NAME_TOO_LONG)
+        );
+    }
+
+    #[test]
+    fn to_string_reads_only_length() {
+        let mut buffer: Vec<u16> = "echo\0junk".encode_utf16().collect();
+        let unicode_string = UNICODE_STRING {
+            Length: 9,
+            MaximumLength: 18,
+            Buffer: buffer.as_mut_ptr(),
+        };
+        // SAFETY: The buffer is valid for 18 bytes
+        assert_eq!(unsafe { to_string(&unicode_string) }, "echo");
+    }
+
+    #[test]
+    fn to_string_replaces_invalid_utf16() {
+        let mut buffer = [u16::from(b'a'), 0xD800, u16::from(b'b')];
+        let unicode_string = UNICODE_STRING {
+            Length: 6,
+            MaximumLength: 6,
+            Buffer: buffer.as_mut_ptr(),
+        };
+        // SAFETY: The buffer is valid for 6 bytes
+        assert_eq!(unsafe { to_string(&unicode_string) }, "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn fixed_buffer_truncates_and_terminates() {
+        let name = OwnedUnicodeString::from_str("echo").unwrap();
+
+        // SAFETY: The buffer is owned by `name`
+        let buffer = unsafe { to_fixed_buffer::<8>(name.as_unicode_string()) };
+        assert_eq!(buffer[..5], [0x65, 0x63, 0x68, 0x6F, 0]);
+
+        // SAFETY: The buffer is owned by `name`
+        let buffer = unsafe { to_fixed_buffer::<3>(name.as_unicode_string()) };
+        assert_eq!(buffer, [0x65, 0x63, 0]);
+
+        // SAFETY: The buffer is owned by `name`
+        let buffer = unsafe { to_fixed_buffer::<1>(name.as_unicode_string()) };
+        assert_eq!(buffer, [0]);
+    }
+
+    #[test]
+    fn fixed_buffer_drops_cut_surrogate_pair() {
+        let name = OwnedUnicodeString::from_str("a\u{1F980}").unwrap();
+
+        // SAFETY: The buffer is owned by `name`
+        let buffer = unsafe { to_fixed_buffer::<3>(name.as_unicode_string()) };
+        assert_eq!(buffer, [u16::from(b'a'), 0, 0]);
+
+        // SAFETY: The buffer is owned by `name`
+        let buffer = unsafe { to_fixed_buffer::<4>(name.as_unicode_string()) };
+        assert_eq!(buffer, [u16::from(b'a'), 0xD83E, 0xDD80, 0]);
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Watchdog of the timer completing the requests, with the `timer-watchdog`
+//! feature.
+//!
+//! The timer feeds the [`Watchdog`] each time it fires, and a second timer
+//! checks periodically that it was fed recently while requests wait for the
+//! timer. When it was not, the timer DPC is most likely stuck, e.g. spinning on
+//! a lock or never queued again, and the driver bug checks with
+//! [`ECHO_TIMER_WATCHDOG`], so that the dump shows the state of the system
+//! instead of the requests hanging forever. Like `request_state`, this module
+//! has no dependency on WDF: the caller passes in the current time.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Bug check code of the watchdog, `ECHO` in ASCII. The parameters are the
+/// queue, the time since the timer last fired in ms, the threshold in ms and
+/// the number of requests waiting.
+pub const ECHO_TIMER_WATCHDOG: u32 = 0x4543_484F;
+
+/// Units of the times passed to the [`Watchdog`], which are those of
+/// `KeQueryUnbiasedInterruptTime`, in a millisecond
+const TICKS_PER_MS: u64 = 10_000;
+
+/// Last time the watched timer fired, in the 100-nanosecond units of
+/// `KeQueryUnbiasedInterruptTime`, which does not count the time the system
+/// sleeps.
+pub struct Watchdog {
+    last_fed: AtomicU64,
+}
+
+impl Watchdog {
+    /// Watchdog of a timer that never fired
+    pub const fn new() -> Self {
+        Self {
+            last_fed: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that the timer fired, or was started, at `now`.
+    pub fn feed(&self, now: u64) {
+        self.last_fed.store(now, Ordering::SeqCst);
+    }
+
+    /// Time from the last [`Watchdog::feed`] to `now`, in ms
+    pub fn starved_ms(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_fed.load(Ordering::SeqCst)) / TICKS_PER_MS
+    }
+}
+
+/// Whether the watched timer is stuck: requests are waiting for it, and it has
+/// not fired for longer than `threshold_ms`.
+///
+/// # Arguments:
+///
+/// * `starved_ms` - Time since the timer last fired, from
+///   [`Watchdog::starved_ms`].
+/// * `threshold_ms` - Longest time the timer may go without firing.
+/// * `pending_requests` - Number of requests waiting for the timer.
+pub fn is_stuck(starved_ms: u64, threshold_ms: u32, pending_requests: u32) -> bool {
+    pending_requests > 0 && starved_ms > u64::from(threshold_ms)
+}
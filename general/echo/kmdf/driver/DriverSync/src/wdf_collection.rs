@@ -0,0 +1,114 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    ULONG,
+    WDFCOLLECTION,
+    WDFOBJECT,
+    WDF_OBJECT_ATTRIBUTES,
+};
+
+/// WDF Collection.
+///
+/// An ordered list of WDF objects, each of which is referenced while it is in
+/// the [`Collection`]. A collection is not synchronized: concurrent accesses
+/// have to be serialized by the caller, e.g. with a [`wdk::wdf::SpinLock`].
+pub struct Collection {
+    wdf_collection: WDFCOLLECTION,
+}
+
+impl Collection {
+    /// Try to construct a WDF collection object. `attributes.ParentObject`
+    /// defaults to the driver object.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a
+    /// collection. The error variant will contain a [`NTSTATUS`] of the
+    /// failure. Full error documentation is available in the [WdfCollectionCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfcollection/nf-wdfcollection-wdfcollectioncreate#return-value)
+    pub fn create(attributes: &mut WDF_OBJECT_ATTRIBUTES) -> Result<Self, NTSTATUS> {
+        let mut collection = Self {
+            wdf_collection: core::ptr::null_mut(),
+        };
+
+        let nt_status;
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfCollectionCreate,
+                attributes,
+                &mut collection.wdf_collection,
+            );
+        }
+        nt_success(nt_status).then_some(collection).ok_or(nt_status)
+    }
+
+    /// Add `object` at the end of the [`Collection`], taking a reference on
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to allocate memory for
+    /// the new item.
+    pub fn add(&self, object: WDFOBJECT) -> Result<(), NTSTATUS> {
+        let nt_status;
+        // SAFETY: `wdf_collection` is a private member of `Collection`,
+        // originally created by WDF, and this module guarantees that it is always
+        // in a valid state.
+        unsafe {
+            nt_status =
+                call_unsafe_wdf_function_binding!(WdfCollectionAdd, self.wdf_collection, object);
+        }
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+
+    /// Remove `object` from the [`Collection`], releasing the reference taken
+    /// by [`Collection::add`]. `object` must be in the collection.
+    pub fn remove(&self, object: WDFOBJECT) {
+        // SAFETY: `wdf_collection` is a private member of `Collection`,
+        // originally created by WDF, and this module guarantees that it is always
+        // in a valid state.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfCollectionRemove, self.wdf_collection, object);
+        }
+    }
+
+    /// Number of objects in the [`Collection`]
+    #[must_use]
+    pub fn get_count(&self) -> ULONG {
+        let count;
+        // SAFETY: `wdf_collection` is a private member of `Collection`,
+        // originally created by WDF, and this module guarantees that it is always
+        // in a valid state.
+        unsafe {
+            count = call_unsafe_wdf_function_binding!(WdfCollectionGetCount, self.wdf_collection);
+        }
+        count
+    }
+
+    /// Object at `index` in the [`Collection`], or a null handle if `index` is
+    /// out of range
+    #[must_use]
+    pub fn get_item(&self, index: ULONG) -> WDFOBJECT {
+        let object;
+        // SAFETY: `wdf_collection` is a private member of `Collection`,
+        // originally created by WDF, and this module guarantees that it is always
+        // in a valid state.
+        unsafe {
+            object =
+                call_unsafe_wdf_function_binding!(WdfCollectionGetItem, self.wdf_collection, index);
+        }
+        object
+    }
+
+    /// Whether `object` is in the [`Collection`]
+    #[must_use]
+    pub fn contains(&self, object: WDFOBJECT) -> bool {
+        (0..self.get_count()).any(|index| self.get_item(index) == object)
+    }
+}
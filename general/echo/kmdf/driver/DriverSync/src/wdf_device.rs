@@ -0,0 +1,216 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::str::FromStr;
+
+use wdk::nt_success;
+#[cfg(any(feature = "named-device", feature = "restricted-access"))]
+use wdk_sys::WDFDEVICE_INIT;
+use wdk_sys::{call_unsafe_wdf_function_binding, GUID, NTSTATUS, WDFDEVICE};
+#[cfg(feature = "idle-power-policy")]
+use wdk_sys::{
+    ULONG,
+    WDF_DEVICE_POWER_POLICY_IDLE_SETTINGS,
+    WDF_DEVICE_POWER_POLICY_WAKE_SETTINGS,
+    _DEVICE_POWER_STATE,
+    _WDF_POWER_POLICY_IDLE_TIMEOUT_TYPE,
+    _WDF_POWER_POLICY_S0_IDLE_CAPABILITIES,
+    _WDF_POWER_POLICY_S0_IDLE_USER_CONTROL,
+    _WDF_POWER_POLICY_SX_WAKE_USER_CONTROL,
+    _WDF_TRI_STATE,
+};
+
+use crate::unicode_string::OwnedUnicodeString;
+#[cfg(feature = "idle-power-policy")]
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Create a device interface of class `interface_class_guid` for `device`, so
+/// that applications can find it and open it.
+///
+/// If `reference_string` is set, it is appended to the symbolic link of the
+/// interface, which tells apart several interfaces of the same class on the
+/// same device, or several devices of the same class. It is converted to the
+/// UTF-16 `UNICODE_STRING` WDF expects, without a terminating null. WDF copies
+/// the string, so it only needs to live until this function returns.
+///
+/// `device` must be a handle returned by `WdfDeviceCreate`.
+///
+/// # Errors
+///
+/// This function will return an error if `reference_string` is too long for a
+/// `UNICODE_STRING`, or if WDF fails to create the interface. The error variant
+/// will contain a [`NTSTATUS`] of the failure. Full error documentation is
+/// available in the [WdfDeviceCreateDeviceInterface Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdevicecreatedeviceinterface#return-value)
+pub fn create_device_interface(
+    device: WDFDEVICE,
+    interface_class_guid: &GUID,
+    reference_string: Option<&str>,
+) -> Result<(), NTSTATUS> {
+    let unicode_string = OwnedUnicodeString::from_str(reference_string.unwrap_or_default())?;
+
+    // SAFETY: `unicode_string` outlives the call, and is only passed when there
+    // is a reference string.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateDeviceInterface,
+            device,
+            interface_class_guid,
+            if reference_string.is_some() {
+                core::ptr::from_ref(unicode_string.as_unicode_string())
+            } else {
+                core::ptr::null()
+            },
+        )
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
+
+/// Give the device object of `device_init` the name `device_name`, e.g.
+/// `\Device\RustEcho`, so that a symbolic link can point to it. It must be
+/// called before the device is created. WDF copies the string.
+///
+/// # Errors
+///
+/// This function will return an error if `device_name` is too long for a
+/// `UNICODE_STRING`, or if WDF fails to assign the name. The error variant will
+/// contain a [`NTSTATUS`] of the failure. Full error documentation is available
+/// in the [WdfDeviceInitAssignName Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdeviceinitassignname#return-value)
+#[cfg(feature = "named-device")]
+pub fn assign_name(device_init: &mut WDFDEVICE_INIT, device_name: &str) -> Result<(), NTSTATUS> {
+    let unicode_string = OwnedUnicodeString::from_str(device_name)?;
+
+    // SAFETY: `unicode_string` outlives the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitAssignName,
+            device_init,
+            unicode_string.as_unicode_string()
+        )
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
+
+/// Restrict who can open the device object of `device_init` to the security
+/// descriptor `sddl`, e.g. `D:P(A;;GA;;;SY)(A;;GA;;;BA)` for the system and
+/// administrators only, like `SDDL_DEVOBJ_SYS_ALL_ADM_ALL`. It must be called
+/// before the device is created. WDF copies the string.
+///
+/// # Errors
+///
+/// This function will return an error if `sddl` is too long for a
+/// `UNICODE_STRING`, or if WDF fails to assign it, e.g. because it is not a
+/// valid SDDL string. The error variant will contain a [`NTSTATUS`] of the
+/// failure. Full error documentation is available in the [WdfDeviceInitAssignSDDLString Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdeviceinitassignsddlstring#return-value)
+#[cfg(feature = "restricted-access")]
+pub fn assign_sddl(device_init: &mut WDFDEVICE_INIT, sddl: &str) -> Result<(), NTSTATUS> {
+    let unicode_string = OwnedUnicodeString::from_str(sddl)?;
+
+    // SAFETY: `unicode_string` outlives the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitAssignSDDLString,
+            device_init,
+            unicode_string.as_unicode_string()
+        )
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
+
+/// Create the symbolic link `symbolic_link_name`, e.g.
+/// `\DosDevices\RustEcho`, to the named device object of `device`, so that
+/// applications can open it as `\\.\RustEcho`. The framework deletes the link
+/// when the device is removed. WDF copies the string.
+///
+/// `device` must be a handle returned by `WdfDeviceCreate`, for a device that
+/// was named with [`assign_name`].
+///
+/// # Errors
+///
+/// This function will return an error if `symbolic_link_name` is too long for
+/// a `UNICODE_STRING`, or if WDF fails to create the link, e.g. because another
+/// device already uses the name. The error variant will contain a
+/// [`NTSTATUS`] of the failure. Full error documentation is available in the
+/// [WdfDeviceCreateSymbolicLink Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdevicecreatesymboliclink#return-value)
+#[cfg(feature = "named-device")]
+pub fn create_symbolic_link(device: WDFDEVICE, symbolic_link_name: &str) -> Result<(), NTSTATUS> {
+    let unicode_string = OwnedUnicodeString::from_str(symbolic_link_name)?;
+
+    // SAFETY: `unicode_string` outlives the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateSymbolicLink,
+            device,
+            unicode_string.as_unicode_string()
+        )
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
+
+/// Let the framework power `device` down to D3 once it has been idle, with no
+/// request in its power-managed queues, for `idle_timeout_ms` milliseconds,
+/// and power it back up as soon as a request arrives, like
+/// `WDF_DEVICE_POWER_POLICY_IDLE_SETTINGS_INIT` with `IdleCannotWakeFromS0`
+/// in C. The device does not need to be able to signal a wake itself, and the
+/// user can turn idling off in the Power Management tab of Device Manager.
+///
+/// `device` must be a handle returned by `WdfDeviceCreate`, for a device whose
+/// driver is the power policy owner, which a function driver is by default.
+///
+/// # Errors
+///
+/// This function will return an error if WDF fails to apply the settings. The
+/// error variant will contain a [`NTSTATUS`] of the failure. Full error
+/// documentation is available in the [WdfDeviceAssignS0IdleSettings Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdeviceassigns0idlesettings#return-value)
+#[cfg(feature = "idle-power-policy")]
+pub fn assign_s0_idle_settings(device: WDFDEVICE, idle_timeout_ms: ULONG) -> Result<(), NTSTATUS> {
+    let mut settings = WDF_DEVICE_POWER_POLICY_IDLE_SETTINGS {
+        Size: wdf_structure_size!(WDF_DEVICE_POWER_POLICY_IDLE_SETTINGS),
+        IdleCaps: _WDF_POWER_POLICY_S0_IDLE_CAPABILITIES::IdleCannotWakeFromS0,
+        DxState: _DEVICE_POWER_STATE::PowerDeviceD3,
+        IdleTimeout: idle_timeout_ms,
+        UserControlOfIdleSettings: _WDF_POWER_POLICY_S0_IDLE_USER_CONTROL::IdleAllowUserControl,
+        Enabled: _WDF_TRI_STATE::WdfUseDefault,
+        PowerUpIdleDeviceOnSystemWake: _WDF_TRI_STATE::WdfUseDefault,
+        IdleTimeoutType: _WDF_POWER_POLICY_IDLE_TIMEOUT_TYPE::DriverManagedIdleTimeout,
+        ExcludeD3Cold: _WDF_TRI_STATE::WdfUseDefault,
+    };
+
+    // SAFETY: `device` is a valid device, and WDF copies the settings.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfDeviceAssignS0IdleSettings, device, &mut settings)
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
+
+/// Arm `device` to wake the system from a sleep state, like
+/// `WDF_DEVICE_POWER_POLICY_WAKE_SETTINGS_INIT` in C: the framework picks the
+/// lowest device power state the device can wake the system from, and the
+/// user can turn waking off in the Power Management tab of Device Manager.
+///
+/// `device` must be a handle returned by `WdfDeviceCreate`, for a device whose
+/// driver is the power policy owner.
+///
+/// # Errors
+///
+/// This function will return an error if WDF fails to apply the settings, e.g.
+/// `STATUS_POWER_STATE_INVALID` if the bus driver reports that the device
+/// cannot wake the system, which is the case of a root-enumerated device. The
+/// error variant will contain a [`NTSTATUS`] of the failure. Full error
+/// documentation is available in the [WdfDeviceAssignSxWakeSettings Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdevice/nf-wdfdevice-wdfdeviceassignsxwakesettings#return-value)
+#[cfg(feature = "idle-power-policy")]
+pub fn assign_sx_wake_settings(device: WDFDEVICE) -> Result<(), NTSTATUS> {
+    let mut settings = WDF_DEVICE_POWER_POLICY_WAKE_SETTINGS {
+        Size: wdf_structure_size!(WDF_DEVICE_POWER_POLICY_WAKE_SETTINGS),
+        DxState: _DEVICE_POWER_STATE::PowerDeviceMaximum,
+        UserControlOfWakeSettings: _WDF_POWER_POLICY_SX_WAKE_USER_CONTROL::WakeAllowUserControl,
+        Enabled: _WDF_TRI_STATE::WdfUseDefault,
+        ArmForWakeIfChildrenAreArmedForWake: 0,
+        IndicateChildWakeOnParentWake: 0,
+    };
+
+    // SAFETY: `device` is a valid device, and WDF copies the settings.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfDeviceAssignSxWakeSettings, device, &mut settings)
+    };
+    nt_success(nt_status).then_some(()).ok_or(nt_status)
+}
@@ -0,0 +1,79 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    WDFDPC,
+    WDF_DPC_CONFIG,
+    WDF_OBJECT_ATTRIBUTES,
+};
+
+/// WDF DPC.
+///
+/// Unlike the periodic [`crate::wdf_timer::Timer`], a [`Dpc`] only runs its
+/// `EvtDpcFunc` when it is explicitly queued with [`Dpc::enqueue`], and wraps a
+/// plain `KDPC` without any timer behind it.
+pub struct Dpc {
+    wdf_dpc: WDFDPC,
+}
+
+impl Dpc {
+    /// Try to construct a WDF DPC object. `attributes.ParentObject` must be a
+    /// device or a queue.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a DPC. The
+    /// error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfDpcCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdpc/nf-wdfdpc-wdfdpccreate#return-value)
+    pub fn create(
+        dpc_config: &mut WDF_DPC_CONFIG,
+        attributes: &mut WDF_OBJECT_ATTRIBUTES,
+    ) -> Result<Self, NTSTATUS> {
+        let mut dpc = Self {
+            wdf_dpc: core::ptr::null_mut(),
+        };
+
+        let nt_status;
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfDpcCreate,
+                dpc_config,
+                attributes,
+                &mut dpc.wdf_dpc,
+            );
+        }
+        nt_success(nt_status).then_some(dpc).ok_or(nt_status)
+    }
+
+    /// Queue the [`Dpc`] for execution. Returns `false` if it was already
+    /// queued, in which case it still only runs once.
+    #[must_use]
+    pub fn enqueue(&self) -> bool {
+        let result;
+        // SAFETY: `wdf_dpc` is a private member of `Dpc`, originally created by
+        // WDF, and this module guarantees that it is always in a valid state.
+        unsafe {
+            result = call_unsafe_wdf_function_binding!(WdfDpcEnqueue, self.wdf_dpc);
+        }
+        result != 0
+    }
+
+    /// Remove the [`Dpc`] from the DPC queue, optionally waiting for a running
+    /// `EvtDpcFunc` to return. Returns `true` if it was dequeued before it ran.
+    #[must_use]
+    pub fn cancel(&self, wait: bool) -> bool {
+        let result;
+        // SAFETY: `wdf_dpc` is a private member of `Dpc`, originally created by
+        // WDF, and this module guarantees that it is always in a valid state.
+        unsafe {
+            result = call_unsafe_wdf_function_binding!(WdfDpcCancel, self.wdf_dpc, u8::from(wait));
+        }
+        result != 0
+    }
+}
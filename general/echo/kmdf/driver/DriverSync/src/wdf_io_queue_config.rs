@@ -0,0 +1,187 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{
+    PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL,
+    PFN_WDF_IO_QUEUE_IO_READ,
+    PFN_WDF_IO_QUEUE_IO_STOP,
+    PFN_WDF_IO_QUEUE_IO_WRITE,
+    ULONG,
+    WDF_IO_QUEUE_CONFIG,
+    WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_TRI_STATE,
+};
+
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Builder of `WDF_IO_QUEUE_CONFIG`, like `WDF_IO_QUEUE_CONFIG_INIT` and
+/// `WDF_IO_QUEUE_CONFIG_INIT_DEFAULT_QUEUE` in C.
+///
+/// The configuration is correctly sized, and the queue is power-managed if the
+/// driver is the power policy owner of the device, as `PowerManaged` is left
+/// to `WdfUseDefault`. A parallel queue presents any number of requests at
+/// once, as `NumberOfPresentedRequests` is set to `(ULONG)-1`.
+///
+/// ```rust,ignore
+/// let mut queue_config =
+///     QueueConfig::default_queue(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential)
+///         .read(Some(echo_evt_io_read))
+///         .write(Some(echo_evt_io_write))
+///         .build();
+/// ```
+#[must_use]
+pub struct QueueConfig {
+    config: WDF_IO_QUEUE_CONFIG,
+}
+
+impl QueueConfig {
+    /// Configuration of a queue dispatching its requests with `dispatch_type`,
+    /// which only gets the requests the driver sends or forwards to it
+    pub fn new(dispatch_type: WDF_IO_QUEUE_DISPATCH_TYPE) -> Self {
+        let mut config = WDF_IO_QUEUE_CONFIG {
+            Size: wdf_structure_size!(WDF_IO_QUEUE_CONFIG),
+            PowerManaged: _WDF_TRI_STATE::WdfUseDefault,
+            DispatchType: dispatch_type,
+            ..WDF_IO_QUEUE_CONFIG::default()
+        };
+        if dispatch_type == _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel {
+            config.Settings.Parallel.NumberOfPresentedRequests = ULONG::MAX;
+        }
+        Self { config }
+    }
+
+    /// Configuration of the default queue of the device, which gets every
+    /// request that `WdfDeviceConfigureRequestDispatching` does not send to
+    /// another queue
+    pub fn default_queue(dispatch_type: WDF_IO_QUEUE_DISPATCH_TYPE) -> Self {
+        let mut queue_config = Self::new(dispatch_type);
+        queue_config.config.DefaultQueue = u8::from(true);
+        queue_config
+    }
+
+    /// Have the framework present zero-length reads and writes to the driver
+    /// instead of completing them itself
+    pub fn allow_zero_length_requests(mut self, allow: bool) -> Self {
+        self.config.AllowZeroLengthRequests = u8::from(allow);
+        self
+    }
+
+    /// Set the `EvtIoRead` callback
+    pub const fn read(mut self, callback: PFN_WDF_IO_QUEUE_IO_READ) -> Self {
+        self.config.EvtIoRead = callback;
+        self
+    }
+
+    /// Set the `EvtIoWrite` callback
+    pub const fn write(mut self, callback: PFN_WDF_IO_QUEUE_IO_WRITE) -> Self {
+        self.config.EvtIoWrite = callback;
+        self
+    }
+
+    /// Set the `EvtIoDeviceControl` callback
+    pub const fn device_control(mut self, callback: PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL) -> Self {
+        self.config.EvtIoDeviceControl = callback;
+        self
+    }
+
+    /// Set the `EvtIoStop` callback, called for each request the driver owns
+    /// when the device leaves D0
+    pub const fn stop(mut self, callback: PFN_WDF_IO_QUEUE_IO_STOP) -> Self {
+        self.config.EvtIoStop = callback;
+        self
+    }
+
+    /// The configuration, to pass by pointer to `WdfIoQueueCreate`
+    pub const fn build(self) -> WDF_IO_QUEUE_CONFIG {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wdk_sys::{WDFQUEUE, WDFREQUEST};
+
+    use super::*;
+
+    unsafe extern "C" fn transfer(_queue: WDFQUEUE, _request: WDFREQUEST, _length: usize) {}
+
+    unsafe extern "C" fn device_control(
+        _queue: WDFQUEUE,
+        _request: WDFREQUEST,
+        _output_buffer_length: usize,
+        _input_buffer_length: usize,
+        _io_control_code: ULONG,
+    ) {
+    }
+
+    unsafe extern "C" fn stop(_queue: WDFQUEUE, _request: WDFREQUEST, _action_flags: ULONG) {}
+
+    /// `NumberOfPresentedRequests` of a configuration
+    fn presented_requests(config: &WDF_IO_QUEUE_CONFIG) -> ULONG {
+        // SAFETY: The settings of every dispatch type start with
+        // NumberOfPresentedRequests, which is 0 unless set
+        unsafe { config.Settings.Parallel.NumberOfPresentedRequests }
+    }
+
+    #[test]
+    fn sequential_queue_is_sized_and_power_managed_by_default() {
+        let config =
+            QueueConfig::new(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential).build();
+
+        assert_eq!(
+            config.Size as usize,
+            core::mem::size_of::<WDF_IO_QUEUE_CONFIG>()
+        );
+        assert_eq!(
+            config.DispatchType,
+            _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential
+        );
+        assert_eq!(config.PowerManaged, _WDF_TRI_STATE::WdfUseDefault);
+        assert_eq!(config.DefaultQueue, 0);
+        assert_eq!(config.AllowZeroLengthRequests, 0);
+        assert_eq!(presented_requests(&config), 0);
+        assert!(config.EvtIoRead.is_none());
+        assert!(config.EvtIoWrite.is_none());
+        assert!(config.EvtIoDeviceControl.is_none());
+        assert!(config.EvtIoStop.is_none());
+    }
+
+    #[test]
+    fn parallel_queue_presents_any_number_of_requests() {
+        let config =
+            QueueConfig::new(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel).build();
+        assert_eq!(presented_requests(&config), ULONG::MAX);
+
+        let config =
+            QueueConfig::new(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchManual).build();
+        assert_eq!(presented_requests(&config), 0);
+    }
+
+    #[test]
+    fn default_queue_is_marked_default() {
+        let config =
+            QueueConfig::default_queue(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel)
+                .build();
+        assert_eq!(config.DefaultQueue, 1);
+        assert_eq!(config.PowerManaged, _WDF_TRI_STATE::WdfUseDefault);
+        assert_eq!(presented_requests(&config), ULONG::MAX);
+    }
+
+    #[test]
+    fn callbacks_and_zero_length_requests_are_set() {
+        let config = QueueConfig::new(_WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential)
+            .read(Some(transfer))
+            .write(Some(transfer))
+            .device_control(Some(device_control))
+            .stop(Some(stop))
+            .allow_zero_length_requests(true)
+            .build();
+
+        assert!(config.EvtIoRead.is_some());
+        assert!(config.EvtIoWrite.is_some());
+        assert!(config.EvtIoDeviceControl.is_some());
+        assert!(config.EvtIoStop.is_some());
+        assert_eq!(config.AllowZeroLengthRequests, 1);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::str::FromStr;
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    FILE_ATTRIBUTE_NORMAL,
+    FILE_NON_DIRECTORY_FILE,
+    FILE_OPEN,
+    FILE_SHARE_READ,
+    FILE_SHARE_WRITE,
+    GENERIC_WRITE,
+    NTSTATUS,
+    PFN_WDF_REQUEST_COMPLETION_ROUTINE,
+    WDFDEVICE,
+    WDFIOTARGET,
+    WDFMEMORY,
+    WDFOBJECT,
+    WDFREQUEST,
+    WDF_IO_TARGET_OPEN_PARAMS,
+    WDF_NO_CONTEXT,
+    WDF_NO_OBJECT_ATTRIBUTES,
+    WDF_NO_SEND_OPTIONS,
+    _WDF_IO_TARGET_OPEN_TYPE,
+};
+
+use crate::{
+    unicode_string::OwnedUnicodeString,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_structure_size::wdf_structure_size,
+};
+
+/// WDF remote I/O target.
+///
+/// Unlike the default I/O target of a device, which is the next driver in its
+/// own stack, a remote I/O target sends requests to the top of the stack of
+/// another device, like a file handle opened on it would. The target is
+/// parented to the device that opened it, and is closed and deleted with it.
+pub struct IoTarget {
+    wdf_io_target: WDFIOTARGET,
+}
+
+impl IoTarget {
+    /// Create an I/O target parented to `device`, and open it for writing on
+    /// the device object or symbolic link `name`, e.g. `\Device\RustEcho1`. It
+    /// must be called at `PASSIVE_LEVEL`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `name` is too long for a
+    /// `UNICODE_STRING`, or if WDF fails to create or open the target, e.g.
+    /// because no device has that name. The error variant will contain a
+    /// [`NTSTATUS`] of the failure. Full error documentation is available in
+    /// the [WdfIoTargetOpen Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfiotarget/nf-wdfiotarget-wdfiotargetopen#return-value)
+    pub fn open_by_name(device: WDFDEVICE, name: &str) -> Result<Self, NTSTATUS> {
+        let name = OwnedUnicodeString::from_str(name)?;
+
+        let mut io_target = Self {
+            wdf_io_target: core::ptr::null_mut(),
+        };
+
+        // SAFETY: `device` is a valid device per the contract of the caller, and
+        // the resulting ffi object is stored in a private member.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetCreate,
+                device,
+                WDF_NO_OBJECT_ATTRIBUTES,
+                &mut io_target.wdf_io_target,
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        // Like WDF_IO_TARGET_OPEN_PARAMS_INIT_OPEN_BY_NAME in C
+        let mut open_params = WDF_IO_TARGET_OPEN_PARAMS {
+            Size: wdf_structure_size!(WDF_IO_TARGET_OPEN_PARAMS),
+            Type: _WDF_IO_TARGET_OPEN_TYPE::WdfIoTargetOpenByName,
+            TargetDeviceName: *name.as_unicode_string(),
+            DesiredAccess: GENERIC_WRITE,
+            ShareAccess: FILE_SHARE_READ | FILE_SHARE_WRITE,
+            FileAttributes: FILE_ATTRIBUTE_NORMAL,
+            CreateDisposition: FILE_OPEN,
+            CreateOptions: FILE_NON_DIRECTORY_FILE,
+            ..WDF_IO_TARGET_OPEN_PARAMS::default()
+        };
+
+        // SAFETY: `wdf_io_target` was created above, and `TargetDeviceName`
+        // points into the buffer of `name`, which outlives the call.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetOpen,
+                io_target.wdf_io_target,
+                &mut open_params
+            )
+        };
+        if !nt_success(nt_status) {
+            // SAFETY: The target was never opened, and is not used after being
+            // deleted
+            unsafe {
+                call_unsafe_wdf_function_binding!(
+                    WdfObjectDelete,
+                    io_target.wdf_io_target as WDFOBJECT
+                );
+            }
+            return Err(nt_status);
+        }
+
+        Ok(io_target)
+    }
+
+    /// Create a request to send to the [`IoTarget`]. The request is parented to
+    /// the target, and must be deleted with `WdfObjectDelete` once it has been
+    /// completed, or if it could not be sent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to create the request.
+    /// The error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfRequestCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestcreate#return-value)
+    pub fn create_request(&self) -> Result<WDFREQUEST, NTSTATUS> {
+        let mut attributes = ObjectAttributes::new()
+            .parent(self.wdf_io_target as WDFOBJECT)
+            .build();
+        let mut request: WDFREQUEST = core::ptr::null_mut();
+
+        // SAFETY: `wdf_io_target` is a private member of `IoTarget`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestCreate,
+                &mut attributes,
+                self.wdf_io_target,
+                &mut request
+            )
+        };
+        nt_success(nt_status).then_some(request).ok_or(nt_status)
+    }
+
+    /// Format `request`, created with [`IoTarget::create_request`], as a write
+    /// of the whole content of `memory` to the [`IoTarget`]. `memory` must stay
+    /// valid until the request is completed, e.g. by being parented to it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to format the request.
+    /// The error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfIoTargetFormatRequestForWrite Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfiotarget/nf-wdfiotarget-wdfiotargetformatrequestforwrite#return-value)
+    pub fn format_write(&self, request: WDFREQUEST, memory: WDFMEMORY) -> Result<(), NTSTATUS> {
+        // SAFETY: `wdf_io_target` is a private member of `IoTarget`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state. `request` and `memory` are valid per the contract of the caller.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetFormatRequestForWrite,
+                self.wdf_io_target,
+                request,
+                memory,
+                core::ptr::null_mut(),
+                core::ptr::null_mut()
+            )
+        };
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+
+    /// Send `request`, formatted with [`IoTarget::format_write`], to the
+    /// [`IoTarget`] asynchronously. `completion_routine` is called once the
+    /// target completes it, and must delete it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the status of the request if WDF fails to send
+    /// it, in which case `completion_routine` is not called, and the caller
+    /// still owns the request. Full error documentation is available in the
+    /// [WdfRequestSend Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestsend#return-value)
+    pub fn send(
+        &self,
+        request: WDFREQUEST,
+        completion_routine: PFN_WDF_REQUEST_COMPLETION_ROUTINE,
+    ) -> Result<(), NTSTATUS> {
+        // SAFETY: `request` is valid and owned by the caller per the contract of
+        // this function.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestSetCompletionRoutine,
+                request,
+                completion_routine,
+                WDF_NO_CONTEXT
+            );
+        }
+
+        // SAFETY: `wdf_io_target` is a private member of `IoTarget`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state.
+        let sent = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestSend,
+                request,
+                self.wdf_io_target,
+                WDF_NO_SEND_OPTIONS.cast()
+            )
+        };
+        if sent != 0 {
+            return Ok(());
+        }
+
+        // SAFETY: The request was not sent, so it is still owned by the caller
+        let nt_status = unsafe { call_unsafe_wdf_function_binding!(WdfRequestGetStatus, request) };
+        Err(nt_status)
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    POOL_TYPE,
+    PVOID,
+    ULONG,
+    WDFMEMORY,
+    WDFOBJECT,
+};
+
+use crate::wdf_object_attributes::ObjectAttributes;
+
+/// WDF Memory object, owning a buffer allocated by `WdfMemoryCreate`.
+///
+/// Unlike a raw `ExAllocatePool2` allocation, the buffer is part of the WDF
+/// object tree: it is deleted along with its parent, so it cannot outlive it
+/// or be leaked past it. The KMDF Verifier can track the references to the
+/// memory object and reports misuse of its handle, while Driver Verifier only
+/// reports a leaked pool allocation when the driver is unloaded, long after the
+/// code that lost it ran. The underlying pool allocation still carries the pool
+/// tag given to [`ManagedMemory::create`], so it shows up in the pool tracking
+/// of Driver Verifier and in `!poolused`.
+///
+/// Dropping a [`ManagedMemory`] does not delete the memory object, since its
+/// parent may already have deleted it. It is either deleted explicitly with
+/// [`ManagedMemory::delete`], or when its parent is.
+pub struct ManagedMemory {
+    wdf_memory: WDFMEMORY,
+    buffer: *mut u8,
+    size: usize,
+}
+
+impl ManagedMemory {
+    /// Try to construct a WDF memory object of `size` bytes, allocated from
+    /// `pool_type` with `pool_tag`, and parented to `parent`. The buffer is
+    /// zero-initialized.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a memory
+    /// object. The error variant will contain a [`NTSTATUS`] of the failure.
+    /// Full error documentation is available in the [WdfMemoryCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfmemory/nf-wdfmemory-wdfmemorycreate#return-value)
+    pub fn create(
+        parent: WDFOBJECT,
+        pool_type: POOL_TYPE,
+        pool_tag: ULONG,
+        size: usize,
+    ) -> Result<Self, NTSTATUS> {
+        let mut attributes = ObjectAttributes::new().parent(parent).build();
+        let mut memory = Self {
+            wdf_memory: core::ptr::null_mut(),
+            buffer: core::ptr::null_mut(),
+            size,
+        };
+        let mut buffer: PVOID = core::ptr::null_mut();
+
+        let nt_status;
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfMemoryCreate,
+                &mut attributes,
+                pool_type,
+                pool_tag,
+                size,
+                &mut memory.wdf_memory,
+                &mut buffer,
+            );
+        }
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        memory.buffer = buffer.cast();
+        // SAFETY: WDF allocated `size` bytes at `buffer`, which are not
+        // initialized, so they are zeroed before being handed out as a slice
+        unsafe {
+            memory.buffer.write_bytes(0, size);
+        }
+        Ok(memory)
+    }
+
+    /// Handle of the memory object, e.g. to format a request with it
+    pub const fn as_raw(&self) -> WDFMEMORY {
+        self.wdf_memory
+    }
+
+    /// Pointer to the buffer, e.g. to pass it to the framework
+    pub const fn as_ptr(&self) -> PVOID {
+        self.buffer.cast()
+    }
+
+    /// Size of the buffer in bytes
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The buffer, for the driver to fill
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        // SAFETY: The buffer is an initialized allocation of `size` bytes owned
+        // by the memory object, which is not deleted while `self` is borrowed
+        unsafe { core::slice::from_raw_parts_mut(self.buffer, self.size) }
+    }
+
+    /// Delete the memory object now, instead of when its parent is deleted.
+    pub fn delete(self) {
+        // SAFETY: `wdf_memory` is a private member of `ManagedMemory`, originally
+        // created by WDF, and consuming `self` prevents any further use of it.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfObjectDelete, self.wdf_memory as WDFOBJECT);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+    PFN_WDF_OBJECT_CONTEXT_CLEANUP,
+    PFN_WDF_OBJECT_CONTEXT_DESTROY,
+    PVOID,
+    WDFOBJECT,
+    WDF_EXECUTION_LEVEL,
+    WDF_OBJECT_ATTRIBUTES,
+    WDF_SYNCHRONIZATION_SCOPE,
+    _WDF_EXECUTION_LEVEL,
+    _WDF_SYNCHRONIZATION_SCOPE,
+};
+
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Type that can be used as the context of a WDF object. It is implemented by
+/// the `wdf_declare_context_type*!` macros, so that
+/// [`ObjectAttributes::context`] can find the type info of a context from its
+/// type.
+pub trait ObjectContext {
+    /// Type info that `WDF_OBJECT_ATTRIBUTES.ContextTypeInfo` must point to
+    fn type_info() -> PCWDF_OBJECT_CONTEXT_TYPE_INFO;
+}
+
+/// Builder of `WDF_OBJECT_ATTRIBUTES`, like `WDF_OBJECT_ATTRIBUTES_INIT` and
+/// `WDF_OBJECT_ATTRIBUTES_INIT_CONTEXT_TYPE` in C.
+///
+/// The attributes are correctly sized, and inherit the execution level and
+/// synchronization scope of the parent object unless changed afterwards.
+///
+/// ```rust,ignore
+/// let mut attributes = ObjectAttributes::new()
+///     .context::<QueueContext>()
+///     .cleanup(Some(queue_context_evt_cleanup))
+///     .parent(device as WDFOBJECT)
+///     .build();
+/// ```
+#[must_use]
+pub struct ObjectAttributes {
+    attributes: WDF_OBJECT_ATTRIBUTES,
+}
+
+impl ObjectAttributes {
+    /// Attributes with no context, no callbacks and the default parent
+    pub fn new() -> Self {
+        Self {
+            attributes: WDF_OBJECT_ATTRIBUTES {
+                Size: wdf_structure_size!(WDF_OBJECT_ATTRIBUTES),
+                ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
+                SynchronizationScope:
+                    _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
+                ..WDF_OBJECT_ATTRIBUTES::default()
+            },
+        }
+    }
+
+    /// Allocate a context of type `T` with the object
+    pub fn context<T: ObjectContext>(mut self) -> Self {
+        self.attributes.ContextTypeInfo = T::type_info();
+        self
+    }
+
+    /// Set the `EvtCleanupCallback` of the object, e.g. the
+    /// `<context_type>_evt_cleanup` generated by
+    /// `wdf_declare_context_type_with_name_and_drop!`
+    pub const fn cleanup(mut self, callback: PFN_WDF_OBJECT_CONTEXT_CLEANUP) -> Self {
+        self.attributes.EvtCleanupCallback = callback;
+        self
+    }
+
+    /// Set the `EvtDestroyCallback` of the object, which the framework calls
+    /// after the `EvtCleanupCallback`, once the last reference on the object is
+    /// released, right before freeing its contexts
+    pub const fn destroy(mut self, callback: PFN_WDF_OBJECT_CONTEXT_DESTROY) -> Self {
+        self.attributes.EvtDestroyCallback = callback;
+        self
+    }
+
+    /// Parent the object to `parent`, which deletes it when it is deleted
+    pub const fn parent(mut self, parent: WDFOBJECT) -> Self {
+        self.attributes.ParentObject = parent;
+        self
+    }
+
+    /// Set the highest IRQL the framework calls the callbacks of the object at,
+    /// e.g. `WdfExecutionLevelPassive`, instead of inheriting it from the
+    /// parent
+    pub const fn execution_level(mut self, execution_level: WDF_EXECUTION_LEVEL) -> Self {
+        self.attributes.ExecutionLevel = execution_level;
+        self
+    }
+
+    /// Set which callbacks of the object the framework serializes with each
+    /// other, e.g. `WdfSynchronizationScopeQueue` for the callbacks of a queue
+    /// and of its children created with `AutomaticSerialization`, instead of
+    /// inheriting it from the parent
+    pub const fn synchronization_scope(
+        mut self,
+        synchronization_scope: WDF_SYNCHRONIZATION_SCOPE,
+    ) -> Self {
+        self.attributes.SynchronizationScope = synchronization_scope;
+        self
+    }
+
+    /// The attributes, to pass by pointer to the function creating the object
+    pub const fn build(self) -> WDF_OBJECT_ATTRIBUTES {
+        self.attributes
+    }
+}
+
+impl Default for ObjectAttributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Allocate a context of type `T` for `object`, in addition to the contexts it
+/// was created with, like `WdfObjectAllocateContext` in C. An object can have
+/// one context of each type, each retrieved with the casting function of its
+/// type. The context is zero-initialized, and freed with the object.
+///
+/// `object` must be a valid handle of a WDF object.
+///
+/// # Errors
+///
+/// This function will return an error if WDF fails to allocate the context,
+/// e.g. `STATUS_OBJECT_NAME_EXISTS` if `object` already has a context of type
+/// `T`. The error variant will contain a [`NTSTATUS`] of the failure. Full
+/// error documentation is available in the [WdfObjectAllocateContext Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfobject/nf-wdfobject-wdfobjectallocatecontext#return-value)
+pub fn allocate_context<T: ObjectContext>(object: WDFOBJECT) -> Result<*mut T, NTSTATUS> {
+    let mut attributes = ObjectAttributes::new().context::<T>().build();
+    let mut context: PVOID = core::ptr::null_mut();
+
+    // SAFETY: `object` is valid, and the attributes name the type info of `T`,
+    // so the context allocated is a `T`.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfObjectAllocateContext,
+            object,
+            &mut attributes,
+            &mut context
+        )
+    };
+    nt_success(nt_status)
+        .then_some(context.cast::<T>())
+        .ok_or(nt_status)
+}
@@ -22,6 +22,20 @@ pub const fn get_unique_type(&self) -> PCWDF_OBJECT_CONTEXT_TYPE_INFO {
     }
 }
 
+/// Alignment of the context memory allocated by WDF: two pointers, like
+/// `MEMORY_ALLOCATION_ALIGNMENT` in C
+const MEMORY_ALLOCATION_ALIGNMENT: usize = 2 * core::mem::size_of::<usize>();
+
+/// Whether `T` has a size WDF accepts as `ContextSize`, which must not be zero
+pub const fn has_valid_context_size<T>() -> bool {
+    core::mem::size_of::<T>() != 0
+}
+
+/// Whether the context memory allocated by WDF is aligned enough for `T`
+pub const fn has_valid_context_alignment<T>() -> bool {
+    core::mem::align_of::<T>() <= MEMORY_ALLOCATION_ALIGNMENT
+}
+
 macro_rules! wdf_get_context_type_info {
     ($context_type:ident) => {
         paste::paste! {
@@ -37,24 +51,46 @@ macro_rules! wdf_declare_context_type_with_name {
         paste::paste! {
             type [<WDFPointerType$context_type>] = *mut $context_type;
 
+            // Reject a context type WDF cannot allocate at compile time, instead
+            // of failing object creation or misaligning the context
+            const _: () = {
+                assert!(
+                    crate::wdf_object_context::has_valid_context_size::<$context_type>(),
+                    concat!(stringify!($context_type), " cannot be used as a WDF object context because it is zero-sized")
+                );
+                assert!(
+                    crate::wdf_object_context::has_valid_context_alignment::<$context_type>(),
+                    concat!(stringify!($context_type), " cannot be used as a WDF object context because its alignment exceeds MEMORY_ALLOCATION_ALIGNMENT")
+                );
+            };
+
             #[link_section = ".data"]
             pub static [<WDF_ $context_type:snake:upper _TYPE_INFO>]: crate::wdf_object_context::WDFObjectContextTypeInfo = crate::wdf_object_context::WDFObjectContextTypeInfo::new(
                 WDF_OBJECT_CONTEXT_TYPE_INFO {
-                Size: crate::WDF_OBJECT_CONTEXT_TYPE_INFO_SIZE,
+                Size: crate::wdf_structure_size::wdf_structure_size!(WDF_OBJECT_CONTEXT_TYPE_INFO),
                 ContextName: concat!(stringify!($context_type),'\0').as_bytes().as_ptr().cast(),
                 ContextSize: core::mem::size_of::<$context_type>(),
                 UniqueType: core::ptr::addr_of!([<WDF_ $context_type:snake:upper _TYPE_INFO>]).cast(),
                 EvtDriverGetUniqueContextType: None,
             });
 
-            pub unsafe fn $casting_function(handle: WDFOBJECT) -> [<WDFPointerType$context_type>] {
-                unsafe {
+            impl crate::wdf_object_attributes::ObjectContext for $context_type {
+                fn type_info() -> wdk_sys::PCWDF_OBJECT_CONTEXT_TYPE_INFO {
+                    crate::wdf_object_context::wdf_get_context_type_info!($context_type)
+                }
+            }
+
+            /// Get the context of `handle`, or `None` if the object was not
+            /// created with this context type.
+            pub unsafe fn $casting_function(handle: WDFOBJECT) -> Option<[<WDFPointerType$context_type>]> {
+                let context: [<WDFPointerType$context_type>] = unsafe {
                     call_unsafe_wdf_function_binding!(
                         WdfObjectGetTypedContextWorker,
                         handle,
                         crate::wdf_object_context::wdf_get_context_type_info!($context_type),
                     ).cast()
-                }
+                };
+                (!context.is_null()).then_some(context)
             }
         }
     };
@@ -71,3 +107,66 @@ macro_rules! wdf_declare_context_type {
 }
 
 pub(crate) use wdf_declare_context_type;
+
+/// Same as `wdf_declare_context_type_with_name!`, but also generates
+/// `<context_type>_evt_cleanup`, an `EvtCleanupCallback` that runs the [`Drop`]
+/// implementation of the context type when the object is deleted. This ties
+/// the lifetime of anything the context owns to the lifetime of the WDF object.
+///
+/// The callback must be set as the `EvtCleanupCallback` of every object created
+/// with this context type. The framework zero-initializes the context memory,
+/// so the context type must either be valid when all-zero, or be initialized
+/// (e.g. with `core::ptr::write`) before the object can be deleted.
+macro_rules! wdf_declare_context_type_with_name_and_drop {
+    ($context_type:ident, $casting_function:ident) => {
+        crate::wdf_object_context::wdf_declare_context_type_with_name!(
+            $context_type,
+            $casting_function
+        );
+
+        paste::paste! {
+            pub extern "C" fn [<$context_type:snake _evt_cleanup>](object: WDFOBJECT) {
+                // SAFETY: This is the EvtCleanupCallback of an object created with this
+                // context type, so the context is valid, and the framework calls it
+                // exactly once, after which the context is no longer used.
+                if let Some(context) = unsafe { $casting_function(object) } {
+                    unsafe {
+                        core::ptr::drop_in_place(context);
+                    }
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use wdf_declare_context_type_with_name_and_drop;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(dead_code, reason = "only the layout of the type is checked")]
+    struct ZeroSized;
+
+    #[allow(dead_code, reason = "only the layout of the type is checked")]
+    #[repr(align(16))]
+    struct MemoryAllocationAligned(u8);
+
+    #[allow(dead_code, reason = "only the layout of the type is checked")]
+    #[repr(align(32))]
+    struct OverAligned(u8);
+
+    // Checked when the tests are built, like the assertions made by
+    // wdf_declare_context_type_with_name! when the driver is built
+    const _: () = {
+        assert!(!has_valid_context_size::<ZeroSized>());
+        assert!(!has_valid_context_size::<[u64; 0]>());
+        assert!(has_valid_context_size::<u8>());
+
+        assert!(has_valid_context_alignment::<u64>());
+        assert!(!has_valid_context_alignment::<OverAligned>());
+    };
+
+    #[cfg(target_pointer_width = "64")]
+    const _: () = assert!(has_valid_context_alignment::<MemoryAllocationAligned>());
+}
@@ -0,0 +1,105 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{ffi::c_void, ptr::NonNull};
+
+use wdk_sys::{call_unsafe_wdf_function_binding, LONG, WDFOBJECT};
+
+/// Path of this file, null-terminated, which the framework records along with
+/// the line of each reference when the KMDF verifier tracks object references
+const FILE: &str = concat!(file!(), "\0");
+
+/// Number of references currently held by [`RefGuard`]s, in debug builds
+#[cfg(debug_assertions)]
+static OUTSTANDING_REFERENCES: AtomicUsize = AtomicUsize::new(0);
+
+/// Reference on a WDF object, taken with `WdfObjectReference` and released
+/// with `WdfObjectDereference` when dropped.
+///
+/// The framework does not free an object while it is referenced, so the handle
+/// stays valid as long as the guard is alive, even once the object has been
+/// deleted or, for a request, completed. Only the handle stays valid: a
+/// completed request must not be used for anything else than logging it or
+/// reading its context.
+///
+/// The handle is stored as a [`NonNull`], so an `Option<RefGuard>` is the size
+/// of a handle, and a zero-initialized one, e.g. in an object context the
+/// framework has just allocated, is `None`.
+///
+/// ```rust,ignore
+/// let reference = unsafe { RefGuard::new(request.as_raw() as WDFOBJECT) };
+/// request.complete(STATUS_SUCCESS);
+/// log_info!("Completed {:?}", reference.as_raw()); // Still a valid handle
+/// ```
+#[must_use = "the reference is released as soon as the guard is dropped"]
+pub struct RefGuard {
+    object: NonNull<c_void>,
+}
+
+impl RefGuard {
+    /// Take a reference on `object`.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be a valid handle of a WDF object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `object` is null.
+    pub unsafe fn new(object: WDFOBJECT) -> Self {
+        let object = NonNull::new(object.cast::<c_void>()).expect("object handle is null");
+
+        // SAFETY: `object` is valid per the contract of the caller, and `FILE`
+        // is null-terminated.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfObjectReferenceActual,
+                object.as_ptr().cast(),
+                core::ptr::null_mut(),
+                LONG::try_from(line!()).unwrap_or_default(),
+                FILE.as_ptr().cast_mut().cast()
+            );
+        }
+
+        #[cfg(debug_assertions)]
+        OUTSTANDING_REFERENCES.fetch_add(1, Ordering::Relaxed);
+
+        Self { object }
+    }
+
+    /// Get the referenced handle
+    pub const fn as_raw(&self) -> WDFOBJECT {
+        self.object.as_ptr().cast()
+    }
+}
+
+impl Drop for RefGuard {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            let outstanding = OUTSTANDING_REFERENCES.fetch_sub(1, Ordering::Relaxed);
+            debug_assert!(outstanding > 0, "more references released than taken");
+        }
+
+        // SAFETY: The reference taken by `new` kept the object alive, and it is
+        // released exactly once, here.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfObjectDereferenceActual,
+                self.as_raw(),
+                core::ptr::null_mut(),
+                LONG::try_from(line!()).unwrap_or_default(),
+                FILE.as_ptr().cast_mut().cast()
+            );
+        }
+    }
+}
+
+/// Number of references currently held by [`RefGuard`]s, which must be 0 once
+/// every object they were taken on is gone, in debug builds
+#[cfg(debug_assertions)]
+pub fn outstanding_references() -> usize {
+    OUTSTANDING_REFERENCES.load(Ordering::Relaxed)
+}
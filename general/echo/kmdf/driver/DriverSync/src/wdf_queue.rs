@@ -0,0 +1,73 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Purging a WDF I/O queue, with the `purge-on-surprise-removal` feature, and
+//! counting its requests, which the `timer-watchdog` feature also does.
+//!
+//! Purging a queue makes the framework fail any new request sent to it, cancel
+//! the requests it still holds, and call the cancel routine of the requests it
+//! presented to the driver that are marked cancelable. The driver then only
+//! has to complete those, rather than waiting for the timer to get to them.
+
+use wdk_sys::{call_unsafe_wdf_function_binding, ULONG, WDFQUEUE};
+
+/// Requests of a [`Queue`], as reported by `WdfIoQueueGetState`
+pub struct RequestCounts {
+    /// Requests waiting in the queue, not presented to the driver yet
+    pub queued: ULONG,
+    /// Requests presented to the driver and not completed yet
+    pub driver_owned: ULONG,
+}
+
+/// WDF I/O queue, not owned: the device deletes it.
+pub struct Queue {
+    wdf_queue: WDFQUEUE,
+}
+
+impl Queue {
+    /// Wrap a `WDFQUEUE` handle, e.g. from `WdfDeviceGetDefaultQueue`.
+    ///
+    /// # Safety
+    ///
+    /// `wdf_queue` must be a valid queue, and stay valid as long as the
+    /// [`Queue`] is used.
+    pub const unsafe fn from_raw(wdf_queue: WDFQUEUE) -> Self {
+        Self { wdf_queue }
+    }
+
+    /// Number of requests in the [`Queue`] and owned by the driver. They can
+    /// change as soon as this returns, so they are only meant to be logged, or
+    /// to tell whether the queue was busy.
+    pub fn request_counts(&self) -> RequestCounts {
+        let mut counts = RequestCounts {
+            queued: 0,
+            driver_owned: 0,
+        };
+
+        // SAFETY: `wdf_queue` is valid per the contract of `from_raw`, and the
+        // counts are written to local variables.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoQueueGetState,
+                self.wdf_queue,
+                &mut counts.queued,
+                &mut counts.driver_owned
+            );
+        }
+        counts
+    }
+
+    /// Purge the [`Queue`], and wait until every request it presented to the
+    /// driver has been completed. The queue fails new requests until it is
+    /// started again with `WdfIoQueueStart`.
+    ///
+    /// This must be called at `PASSIVE_LEVEL`, and not from a callback of the
+    /// queue or while holding a lock its requests are completed under, since
+    /// it would wait for itself.
+    pub fn purge_synchronously(&self) {
+        // SAFETY: `wdf_queue` is valid per the contract of `from_raw`.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfIoQueuePurgeSynchronously, self.wdf_queue);
+        }
+    }
+}
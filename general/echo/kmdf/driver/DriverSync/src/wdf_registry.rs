@@ -0,0 +1,94 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::str::FromStr;
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    KEY_QUERY_VALUE,
+    NTSTATUS,
+    ULONG,
+    WDFDRIVER,
+    WDFKEY,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::unicode_string::OwnedUnicodeString;
+
+/// Registry key opened by the framework, closed with `WdfRegistryClose` when
+/// the [`RegistryKey`] is dropped.
+pub struct RegistryKey {
+    wdf_key: WDFKEY,
+}
+
+impl RegistryKey {
+    /// Open the `Parameters` subkey of the service key of `driver` to read its
+    /// values, with `WdfDriverOpenParametersRegistryKey`. Must be called at
+    /// `PASSIVE_LEVEL`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key could not be opened. The
+    /// error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfDriverOpenParametersRegistryKey Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfdriver/nf-wdfdriver-wdfdriveropenparametersregistrykey#return-value)
+    pub fn open_driver_parameters(driver: WDFDRIVER) -> Result<Self, NTSTATUS> {
+        let mut wdf_key: WDFKEY = core::ptr::null_mut();
+
+        // SAFETY: `driver` is a valid driver object, and the key is only
+        // written on success.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDriverOpenParametersRegistryKey,
+                driver,
+                KEY_QUERY_VALUE,
+                WDF_NO_OBJECT_ATTRIBUTES,
+                &mut wdf_key
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        Ok(Self { wdf_key })
+    }
+
+    /// Read the `REG_DWORD` value named `value_name`, with
+    /// `WdfRegistryQueryULong`. Must be called at `PASSIVE_LEVEL`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the value does not exist or is
+    /// not a `REG_DWORD`, or if `value_name` is too long for a
+    /// `UNICODE_STRING`. The error variant will contain a [`NTSTATUS`] of
+    /// the failure. Full error documentation is available in the [WdfRegistryQueryULong Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfregistry/nf-wdfregistry-wdfregistryqueryulong#return-value)
+    pub fn query_ulong(&self, value_name: &str) -> Result<ULONG, NTSTATUS> {
+        let value_name = OwnedUnicodeString::from_str(value_name)?;
+
+        let mut value: ULONG = 0;
+        // SAFETY: `wdf_key` is open, and `value_name` outlives the call.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRegistryQueryULong,
+                self.wdf_key,
+                value_name.as_unicode_string(),
+                &mut value
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        Ok(value)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        // SAFETY: `wdf_key` was opened by the framework and is not used after
+        // being closed.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRegistryClose, self.wdf_key);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::Ordering;
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    KPROCESSOR_MODE,
+    NTSTATUS,
+    PFN_WDF_REQUEST_CANCEL,
+    WDFQUEUE,
+    WDFREQUEST,
+};
+#[cfg(debug_assertions)]
+use wdk_sys::{ntddk::KeBugCheckEx, ULONG, ULONG_PTR, WDFOBJECT};
+
+#[cfg(debug_assertions)]
+use crate::request_get_context;
+
+/// `MULTIPLE_IRP_COMPLETE_REQUESTS` bug check code, from bugcodes.h
+#[cfg(debug_assertions)]
+const MULTIPLE_IRP_COMPLETE_REQUESTS: ULONG = 0x44;
+
+/// WDF Request.
+///
+/// The completion methods take `self` by value, so a `Request` cannot be used
+/// after it has been returned to the framework.
+pub struct Request {
+    wdf_request: WDFREQUEST,
+}
+
+impl Request {
+    /// Wrap a `WDFREQUEST` handle received from the framework.
+    ///
+    /// # Safety
+    ///
+    /// `wdf_request` must be a valid request that is currently owned by the
+    /// driver, and no other `Request` may complete it.
+    pub const unsafe fn from_raw(wdf_request: WDFREQUEST) -> Self {
+        Self { wdf_request }
+    }
+
+    /// Get the underlying `WDFREQUEST` handle, e.g. to retrieve its buffers or
+    /// context.
+    pub const fn as_raw(&self) -> WDFREQUEST {
+        self.wdf_request
+    }
+
+    /// Complete the [`Request`] with `nt_status`. The information value that
+    /// was last set with [`Request::set_information`] is returned to the
+    /// caller.
+    pub fn complete(self, nt_status: NTSTATUS) {
+        #[cfg(debug_assertions)]
+        self.check_single_completion(nt_status);
+
+        #[cfg(feature = "etw-events")]
+        crate::etw_events::request_complete(
+            self.wdf_request,
+            nt_status,
+            // SAFETY: `wdf_request` is owned by the driver per the contract of
+            // `from_raw`.
+            unsafe {
+                call_unsafe_wdf_function_binding!(WdfRequestGetInformation, self.wdf_request)
+            },
+        );
+
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`, and consuming `self` prevents any further use of it.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRequestComplete, self.wdf_request, nt_status);
+        }
+    }
+
+    /// Complete the [`Request`] with `nt_status` and `information`, which is
+    /// usually the number of bytes transferred.
+    pub fn complete_with_information(self, nt_status: NTSTATUS, information: usize) {
+        #[cfg(debug_assertions)]
+        self.check_single_completion(nt_status);
+
+        #[cfg(feature = "etw-events")]
+        crate::etw_events::request_complete(self.wdf_request, nt_status, information as u64);
+
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`, and consuming `self` prevents any further use of it.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestCompleteWithInformation,
+                self.wdf_request,
+                nt_status,
+                information as u64
+            );
+        }
+    }
+
+    /// In debug builds, flag the [`Request`] as completed in its
+    /// `RequestContext`, and bug check with `MULTIPLE_IRP_COMPLETE_REQUESTS` if
+    /// it already was, e.g. because the cancel routine and the timer both
+    /// completed it. The bug check parameters are the request handle and the
+    /// status of the second completion.
+    ///
+    /// Consuming `self` prevents completing the same `Request` twice, but not
+    /// completing two `Request`s wrapping the same handle. The request may also
+    /// be freed as soon as it is completed, so this catches the races where
+    /// both completions run close to each other rather than every misuse.
+    #[cfg(debug_assertions)]
+    fn check_single_completion(&self, nt_status: NTSTATUS) {
+        // SAFETY: `wdf_request` is a valid request per the contract of
+        // `from_raw`, and the device gives every request a `RequestContext`.
+        let Some(request_context) = (unsafe { request_get_context(self.wdf_request as WDFOBJECT) })
+        else {
+            return;
+        };
+
+        // SAFETY: The context lives as long as the request, and the flag is
+        // only accessed atomically.
+        if unsafe { (*request_context).completed.swap(true, Ordering::SeqCst) } {
+            #[allow(
+                clippy::cast_sign_loss,
+                reason = "the status is passed as its bit pattern, like in C"
+            )]
+            let nt_status = nt_status as ULONG;
+            // SAFETY: Completing the request again would corrupt the state of the
+            // framework, so the system is stopped while both completions can
+            // still be found on the stacks.
+            unsafe {
+                KeBugCheckEx(
+                    MULTIPLE_IRP_COMPLETE_REQUESTS,
+                    self.wdf_request as ULONG_PTR,
+                    ULONG_PTR::from(nt_status),
+                    0,
+                    0,
+                );
+            }
+        }
+    }
+
+    /// Set the information value, usually the number of bytes transferred, that
+    /// is returned when the [`Request`] is completed.
+    pub fn set_information(&self, information: usize) {
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestSetInformation,
+                self.wdf_request,
+                information as u64
+            );
+        }
+    }
+
+    /// Get the processor mode of the thread that issued the [`Request`],
+    /// `UserMode` for an application, or `KernelMode` for another driver.
+    pub fn get_requestor_mode(&self) -> KPROCESSOR_MODE {
+        let requestor_mode;
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`.
+        unsafe {
+            requestor_mode =
+                call_unsafe_wdf_function_binding!(WdfRequestGetRequestorMode, self.wdf_request);
+        }
+        requestor_mode
+    }
+
+    /// Acknowledge a call to `EvtIoStop` for the [`Request`], keeping ownership
+    /// of it instead of completing it. If `requeue` is `true`, the request is
+    /// returned to the queue instead, and must no longer be used by the driver.
+    pub fn stop_acknowledge(&self, requeue: bool) {
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestStopAcknowledge,
+                self.wdf_request,
+                u8::from(requeue)
+            );
+        }
+    }
+
+    /// Forward the [`Request`] to `queue`, another queue of the device, which
+    /// takes ownership of it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the request back, along with the error, if it
+    /// could not be forwarded. The driver still owns it then, and must complete
+    /// it. Full error documentation is available in the [WdfRequestForwardToIoQueue Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestforwardtoioqueue#return-value)
+    pub fn forward_to_io_queue(self, queue: WDFQUEUE) -> Result<(), (Self, NTSTATUS)> {
+        let nt_status;
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`. Once it has been forwarded, consuming `self` prevents any
+        // further use of it.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfRequestForwardToIoQueue,
+                self.wdf_request,
+                queue
+            );
+        }
+        if nt_success(nt_status) {
+            Ok(())
+        } else {
+            Err((self, nt_status))
+        }
+    }
+
+    /// Make the [`Request`] cancelable, with `evt_request_cancel` being called
+    /// if it gets cancelled.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request could not be marked
+    /// cancelable. `STATUS_CANCELLED` means the request was already cancelled,
+    /// and the driver must still complete it. Full error documentation is
+    /// available in the [WdfRequestMarkCancelableEx Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestmarkcancelableex#return-value)
+    pub fn mark_cancelable(
+        &self,
+        evt_request_cancel: PFN_WDF_REQUEST_CANCEL,
+    ) -> Result<(), NTSTATUS> {
+        let nt_status;
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfRequestMarkCancelableEx,
+                self.wdf_request,
+                evt_request_cancel
+            );
+        }
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+
+    /// Make the [`Request`] no longer cancelable.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the cancel routine could not be
+    /// removed. `STATUS_CANCELLED` means the cancel routine has already been
+    /// called or is about to be. Full error documentation is available in the
+    /// [WdfRequestUnmarkCancelable Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestunmarkcancelable#return-value)
+    pub fn unmark_cancelable(&self) -> Result<(), NTSTATUS> {
+        let nt_status;
+        // SAFETY: `wdf_request` is owned by the driver per the contract of
+        // `from_raw`.
+        unsafe {
+            nt_status =
+                call_unsafe_wdf_function_binding!(WdfRequestUnmarkCancelable, self.wdf_request);
+        }
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+}
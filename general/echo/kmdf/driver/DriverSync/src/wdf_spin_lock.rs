@@ -0,0 +1,48 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::wdf::SpinLock;
+
+/// Scoped locking for [`SpinLock`].
+///
+/// [`SpinLock::acquire`] and [`SpinLock::release`] have to be balanced by hand
+/// on every path, including early returns. [`SpinLockExt::lock`] instead
+/// returns a guard that releases the lock when it goes out of scope. Keep the
+/// guard's scope as tight as possible: requests must be completed after the
+/// guard has been dropped, never while the lock is held.
+///
+/// ```rust,ignore
+/// let complete_request = {
+///     let _guard = unsafe { (*queue_context).spin_lock.lock() };
+///     unsafe { (*queue_context).current_request = core::ptr::null_mut() };
+///     echo_decrement_request_cancel_ownership_count(request_context)
+/// }; // The lock is released here
+///
+/// if complete_request {
+///     request.complete(STATUS_CANCELLED);
+/// }
+/// ```
+pub trait SpinLockExt {
+    /// Acquire the spinlock, returning a guard that releases it on drop
+    fn lock(&self) -> SpinLockGuard<'_>;
+}
+
+impl SpinLockExt for SpinLock {
+    fn lock(&self) -> SpinLockGuard<'_> {
+        self.acquire();
+        SpinLockGuard { spin_lock: self }
+    }
+}
+
+/// Holds a [`SpinLock`] acquired with [`SpinLockExt::lock`] and releases it
+/// when dropped.
+#[must_use = "the spinlock is released as soon as the guard is dropped"]
+pub struct SpinLockGuard<'a> {
+    spin_lock: &'a SpinLock,
+}
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        self.spin_lock.release();
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+/// Size of a WDF structure as the `ULONG` expected in its `Size` field, like
+/// the `WDF_STRUCTURE_SIZE` macro in C. Fails to compile if the size does not
+/// fit in a `ULONG`.
+///
+/// This macro should not be needed after an equivalent `WDF_STRUCTURE_SIZE`
+/// macro is added to `wdk-sys`: <https://github.com/microsoft/windows-drivers-rs/issues/242>
+///
+/// ```rust,ignore
+/// let mut timer_config = WDF_TIMER_CONFIG {
+///     Size: wdf_structure_size!(WDF_TIMER_CONFIG),
+///     ..WDF_TIMER_CONFIG::default()
+/// };
+/// ```
+macro_rules! wdf_structure_size {
+    ($structure:ty) => {{
+        #[allow(
+            clippy::cast_possible_truncation,
+            reason = "the size is known to fit in ULONG due to below const assert"
+        )]
+        const SIZE: wdk_sys::ULONG = {
+            const S: usize = core::mem::size_of::<$structure>();
+            const {
+                assert!(
+                    S <= wdk_sys::ULONG::MAX as usize,
+                    concat!(
+                        "size_of::<",
+                        stringify!($structure),
+                        ">() should fit in ULONG"
+                    )
+                );
+            };
+            S as wdk_sys::ULONG
+        };
+        SIZE
+    }};
+}
+
+pub(crate) use wdf_structure_size;
+
+#[cfg(test)]
+mod tests {
+    #[allow(dead_code, reason = "only the layout of the structure is checked")]
+    #[repr(C)]
+    struct Config {
+        size: u32,
+        enabled: u8,
+        callback: Option<extern "C" fn()>,
+    }
+
+    #[test]
+    fn size_is_size_of_structure() {
+        assert_eq!(wdf_structure_size!(u8), 1);
+        assert_eq!(wdf_structure_size!([u32; 5]), 20);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn size_includes_padding() {
+        // 4 bytes of padding align the callback, as with sizeof in C
+        assert_eq!(wdf_structure_size!(Config), 16);
+    }
+
+    #[test]
+    fn size_is_a_constant() {
+        const SIZE: wdk_sys::ULONG = wdf_structure_size!(u64);
+        assert_eq!(SIZE, 8);
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! WDF timer of the queue, and restarting it with a new due time, with the
+//! `adaptive-timer` and `one-shot-timer` features.
+//!
+//! A periodic WDF timer keeps the period it was created with. To change how
+//! often it fires, the timer is created without a period instead, so that it
+//! fires once each time it is started, and it is started again with the due
+//! time wanted next.
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    WDFOBJECT,
+    WDFTIMER,
+    WDF_OBJECT_ATTRIBUTES,
+    WDF_TIMER_CONFIG,
+};
+
+use crate::{teardown::StopTimer, wdf_object_reference::RefGuard};
+
+/// WDF timer.
+///
+/// Unlike [`wdk::wdf::Timer`], a [`Timer`] holds a reference on its WDF object
+/// until it is dropped. The framework cleans a timer up along with its parent,
+/// before the cleanup callback of the parent, and the reference keeps the
+/// handle valid past that, so that the parent can still stop the timer when it
+/// is torn down.
+pub struct Timer {
+    wdf_timer: WDFTIMER,
+    // `None` in a zero-initialized object context, before the timer is created
+    _reference: Option<RefGuard>,
+}
+
+impl Timer {
+    /// Try to construct a WDF timer object, and take a reference on it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a timer.
+    /// The error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfTimerCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdftimer/nf-wdftimer-wdftimercreate#return-value)
+    pub fn create(
+        timer_config: &mut WDF_TIMER_CONFIG,
+        attributes: &mut WDF_OBJECT_ATTRIBUTES,
+    ) -> Result<Self, NTSTATUS> {
+        let mut wdf_timer: WDFTIMER = core::ptr::null_mut();
+
+        let nt_status;
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfTimerCreate,
+                timer_config,
+                attributes,
+                &mut wdf_timer,
+            );
+        }
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        // SAFETY: `wdf_timer` was just created by WDF
+        let reference = unsafe { RefGuard::new(wdf_timer as WDFOBJECT) };
+        Ok(Self {
+            wdf_timer,
+            _reference: Some(reference),
+        })
+    }
+
+    /// Start the [`Timer`] to fire at `due_time`, in 100-nanosecond units,
+    /// relative if negative. Returns `true` if it was already waiting to fire,
+    /// in which case it is started again with the new due time.
+    #[must_use]
+    pub fn start(&self, due_time: i64) -> bool {
+        let result;
+        // SAFETY: `wdf_timer` is a private member of `Timer`, originally created
+        // by WDF, and referenced until `Timer` is dropped.
+        unsafe {
+            result = call_unsafe_wdf_function_binding!(WdfTimerStart, self.wdf_timer, due_time);
+        }
+        result != 0
+    }
+
+    /// Stop the [`Timer`], optionally waiting for a running `EvtTimerFunc` to
+    /// return, which must be done at `PASSIVE_LEVEL`. Returns `true` if it was
+    /// waiting to fire.
+    #[must_use]
+    pub fn stop(&self, wait: bool) -> bool {
+        let result;
+        // SAFETY: `wdf_timer` is a private member of `Timer`, originally created
+        // by WDF, and referenced until `Timer` is dropped.
+        unsafe {
+            result =
+                call_unsafe_wdf_function_binding!(WdfTimerStop, self.wdf_timer, u8::from(wait));
+        }
+        result != 0
+    }
+}
+
+impl StopTimer for Timer {
+    /// Stop the [`Timer`] as [`Timer::stop`] does. A queue may be torn down
+    /// before its timers are created, e.g. when creating one of them fails, so
+    /// a timer still zero-initialized in the queue context is left alone.
+    fn stop(&self, wait: bool) -> bool {
+        !self.wdf_timer.is_null() && Self::stop(self, wait)
+    }
+}
+
+/// Due time of `milliseconds` from now, in the 100-nanosecond units of
+/// `WdfTimerStart`, where relative due times are negative.
+#[cfg(any(feature = "adaptive-timer", feature = "one-shot-timer"))]
+pub fn relative_due_time(milliseconds: u32) -> i64 {
+    -i64::from(milliseconds) * 10_000
+}
+
+/// Stop `timer`, without waiting for a callback already running, and start it
+/// again to fire once `milliseconds` from now.
+///
+/// # Arguments:
+///
+/// * `timer` - Timer created without a period.
+/// * `milliseconds` - Delay until the timer fires.
+///
+/// # Return value:
+///
+/// * `true` if the timer was waiting to fire when it was stopped, `false` if it
+///   had already fired or was not started.
+#[cfg(feature = "adaptive-timer")]
+pub fn restart(timer: &Timer, milliseconds: u32) -> bool {
+    let was_waiting = timer.stop(false);
+    let _ = timer.start(relative_due_time(milliseconds));
+    was_waiting
+}
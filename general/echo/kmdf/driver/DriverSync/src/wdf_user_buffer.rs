@@ -0,0 +1,235 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Buffers of `METHOD_NEITHER` control codes, with the `method-neither`
+//! feature.
+//!
+//! With `METHOD_NEITHER`, the I/O manager neither copies nor maps the buffers
+//! of a request: the driver gets the raw addresses the application passed to
+//! `DeviceIoControl`. They are only meaningful in the address space of the
+//! calling process, so they must be retrieved in `EvtIoInCallerContext`, and
+//! nothing stops the application from passing a kernel address, an unmapped
+//! one, or from freeing the buffer or changing its content while the driver
+//! reads it.
+//!
+//! In C, drivers check such addresses with `ProbeForRead` and `ProbeForWrite`
+//! inside a `__try`/`__except` block, since they raise an exception instead of
+//! returning a status when the check fails. Rust cannot catch that exception,
+//! so they are never called directly here. The driver uses
+//! `WdfRequestProbeAndLockUserBufferForRead` and
+//! `WdfRequestProbeAndLockUserBufferForWrite` instead, which probe the buffer
+//! and lock its pages under the exception handler of the framework, and return
+//! a status along with a `WDFMEMORY` describing the locked pages. The memory
+//! object can then be used from any thread until the request is completed.
+
+use core::{ffi::c_void, ptr::NonNull};
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PVOID,
+    STATUS_UNSUCCESSFUL,
+    WDFMEMORY,
+    WDFREQUEST,
+};
+
+/// Direction a [`UserBuffer`] is locked for
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// User-mode buffer of a `METHOD_NEITHER` request, probed and locked in
+/// memory by the framework.
+///
+/// The `WDFMEMORY` is owned by the request, which deletes it when it is
+/// completed, so a [`UserBuffer`] must not be used after that.
+///
+/// The handle is stored as a [`NonNull`], so a zero-initialized
+/// `Option<UserBuffer>`, e.g. in a request context the framework has just
+/// allocated, is `None`.
+pub struct UserBuffer {
+    memory: NonNull<c_void>,
+    length: usize,
+}
+
+impl UserBuffer {
+    /// Probe the input buffer of `request` for reading and lock it in memory.
+    ///
+    /// # Safety
+    ///
+    /// `request` must be a valid `METHOD_NEITHER` request owned by the driver,
+    /// and this must be called from `EvtIoInCallerContext`, in the context of
+    /// the process that sent the request.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request has no input buffer,
+    /// or if the buffer is not readable memory of the calling process. The
+    /// error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfRequestProbeAndLockUserBufferForRead Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestprobeandlockuserbufferforread#return-value)
+    pub unsafe fn probe_and_lock_input(request: WDFREQUEST) -> Result<Self, NTSTATUS> {
+        let mut buffer: PVOID = core::ptr::null_mut();
+        let mut length: usize = 0;
+
+        // SAFETY: `request` is valid per the contract of the caller. A minimum
+        // length of 1 fails requests without an input buffer with
+        // STATUS_BUFFER_TOO_SMALL.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveUnsafeUserInputBuffer,
+                request,
+                1,
+                &mut buffer,
+                &mut length
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        // SAFETY: The caller runs in the context of the process that owns
+        // `buffer`, which the framework probes before using it.
+        unsafe { Self::probe_and_lock(request, buffer, length, Access::Read) }
+    }
+
+    /// Probe the output buffer of `request` for writing and lock it in memory.
+    ///
+    /// # Safety
+    ///
+    /// `request` must be a valid `METHOD_NEITHER` request owned by the driver,
+    /// and this must be called from `EvtIoInCallerContext`, in the context of
+    /// the process that sent the request.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request has no output buffer,
+    /// or if the buffer is not writable memory of the calling process. The
+    /// error variant will contain a [`NTSTATUS`] of the failure. Full error
+    /// documentation is available in the [WdfRequestProbeAndLockUserBufferForWrite Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfrequest/nf-wdfrequest-wdfrequestprobeandlockuserbufferforwrite#return-value)
+    pub unsafe fn probe_and_lock_output(request: WDFREQUEST) -> Result<Self, NTSTATUS> {
+        let mut buffer: PVOID = core::ptr::null_mut();
+        let mut length: usize = 0;
+
+        // SAFETY: `request` is valid per the contract of the caller. A minimum
+        // length of 1 fails requests without an output buffer with
+        // STATUS_BUFFER_TOO_SMALL.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveUnsafeUserOutputBuffer,
+                request,
+                1,
+                &mut buffer,
+                &mut length
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        // SAFETY: The caller runs in the context of the process that owns
+        // `buffer`, which the framework probes before using it.
+        unsafe { Self::probe_and_lock(request, buffer, length, Access::Write) }
+    }
+
+    /// Probe `length` bytes at `buffer` for `access` and lock them in memory.
+    ///
+    /// # Safety
+    ///
+    /// `request` must be valid, and this must be called in the context of the
+    /// process that sent it.
+    unsafe fn probe_and_lock(
+        request: WDFREQUEST,
+        buffer: PVOID,
+        length: usize,
+        access: Access,
+    ) -> Result<Self, NTSTATUS> {
+        let mut memory: WDFMEMORY = core::ptr::null_mut();
+
+        // SAFETY: The framework probes `buffer` under an exception handler, so
+        // an invalid address only makes the call fail.
+        let nt_status = unsafe {
+            match access {
+                Access::Read => call_unsafe_wdf_function_binding!(
+                    WdfRequestProbeAndLockUserBufferForRead,
+                    request,
+                    buffer,
+                    length,
+                    &mut memory
+                ),
+                Access::Write => call_unsafe_wdf_function_binding!(
+                    WdfRequestProbeAndLockUserBufferForWrite,
+                    request,
+                    buffer,
+                    length,
+                    &mut memory
+                ),
+            }
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+
+        // The framework always returns a memory object on success
+        NonNull::new(memory.cast::<c_void>())
+            .map(|memory| Self { memory, length })
+            .ok_or(STATUS_UNSUCCESSFUL)
+    }
+
+    /// Length of the buffer, in bytes
+    pub const fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Copy the start of the buffer to `destination`, a kernel buffer that is
+    /// at most as long.
+    ///
+    /// The application can still change the content of the buffer while it is
+    /// locked, so anything the driver checks must be checked on the copy rather
+    /// than read from the buffer again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `destination` is longer than the
+    /// buffer. The error variant will contain a [`NTSTATUS`] of the failure.
+    /// Full error documentation is available in the [WdfMemoryCopyToBuffer Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfmemory/nf-wdfmemory-wdfmemorycopytobuffer#return-value)
+    pub fn copy_to(&self, destination: &mut [u8]) -> Result<(), NTSTATUS> {
+        // SAFETY: The memory object is valid until the request is completed,
+        // and `destination` is valid for `destination.len()` bytes.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfMemoryCopyToBuffer,
+                self.memory.as_ptr().cast(),
+                0,
+                destination.as_mut_ptr().cast(),
+                destination.len()
+            )
+        };
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+
+    /// Copy `source`, a kernel buffer that is at most as long as the buffer, to
+    /// the start of the buffer.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `source` is longer than the
+    /// buffer. The error variant will contain a [`NTSTATUS`] of the failure.
+    /// Full error documentation is available in the [WdfMemoryCopyFromBuffer Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfmemory/nf-wdfmemory-wdfmemorycopyfrombuffer#return-value)
+    pub fn copy_from(&self, source: &[u8]) -> Result<(), NTSTATUS> {
+        // SAFETY: The memory object is valid until the request is completed,
+        // and the framework only reads `source.len()` bytes from `source`.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfMemoryCopyFromBuffer,
+                self.memory.as_ptr().cast(),
+                0,
+                source.as_ptr().cast_mut().cast(),
+                source.len()
+            )
+        };
+        nt_success(nt_status).then_some(()).ok_or(nt_status)
+    }
+}
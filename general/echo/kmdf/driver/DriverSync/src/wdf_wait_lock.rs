@@ -0,0 +1,127 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::time::Duration;
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    LONGLONG,
+    NTSTATUS,
+    STATUS_SUCCESS,
+    WDFWAITLOCK,
+    WDF_OBJECT_ATTRIBUTES,
+};
+
+/// WDF wait lock.
+///
+/// Unlike a [`wdk::wdf::SpinLock`], a [`WaitLock`] does not raise the IRQL:
+/// a thread waiting for it sleeps instead of spinning, and the thread holding
+/// it stays at `PASSIVE_LEVEL`, where it can wait, touch pageable memory or
+/// call functions that must run at `PASSIVE_LEVEL`. In exchange, waiting for it
+/// is only allowed at `PASSIVE_LEVEL`, so every code path that acquires it must
+/// be guaranteed to run there, e.g. the callbacks of a queue created with
+/// `WdfExecutionLevelPassive`, or a work item.
+///
+/// ```rust,ignore
+/// let complete_request = {
+///     let _guard = unsafe { (*queue_context).lock.lock() };
+///     unsafe { (*queue_context).current_request = core::ptr::null_mut() };
+///     echo_decrement_request_cancel_ownership_count(request_context)
+/// }; // The lock is released here
+/// ```
+pub struct WaitLock {
+    wdf_wait_lock: WDFWAITLOCK,
+}
+
+impl WaitLock {
+    /// Try to construct a WDF wait lock object. It is deleted along with
+    /// `attributes.ParentObject`, or the driver if there is none.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a wait
+    /// lock. The error variant will contain a [`NTSTATUS`] of the failure. Full
+    /// error documentation is available in the [WdfWaitLockCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfsync/nf-wdfsync-wdfwaitlockcreate#return-value)
+    pub fn create(attributes: &mut WDF_OBJECT_ATTRIBUTES) -> Result<Self, NTSTATUS> {
+        let mut wait_lock = Self {
+            wdf_wait_lock: core::ptr::null_mut(),
+        };
+
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfWaitLockCreate,
+                attributes,
+                &mut wait_lock.wdf_wait_lock,
+            )
+        };
+        nt_success(nt_status).then_some(wait_lock).ok_or(nt_status)
+    }
+
+    /// Acquire the wait lock, waiting as long as it takes, and return a guard
+    /// that releases it on drop. It must be called at `PASSIVE_LEVEL`.
+    pub fn lock(&self) -> WaitLockGuard<'_> {
+        // SAFETY: `wdf_wait_lock` is a private member of `WaitLock`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state. Without a timeout, the acquisition always succeeds.
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfWaitLockAcquire,
+                self.wdf_wait_lock,
+                core::ptr::null_mut()
+            );
+        }
+        WaitLockGuard { wait_lock: self }
+    }
+
+    /// Try to acquire the wait lock, waiting at most `timeout`, and return a
+    /// guard that releases it on drop, or `None` if the lock is still held by
+    /// someone else once `timeout` has elapsed. It must be called at
+    /// `PASSIVE_LEVEL`, unless `timeout` is zero, in which case it does not
+    /// wait and can be called up to `DISPATCH_LEVEL`.
+    #[allow(
+        dead_code,
+        reason = "the queue always waits for the lock without a timeout"
+    )]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<WaitLockGuard<'_>> {
+        let mut timeout = relative_timeout(timeout);
+
+        // SAFETY: `wdf_wait_lock` is a private member of `WaitLock`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state.
+        let nt_status = unsafe {
+            call_unsafe_wdf_function_binding!(WdfWaitLockAcquire, self.wdf_wait_lock, &mut timeout)
+        };
+        // STATUS_TIMEOUT is a success status, so the lock is only held when the
+        // status is exactly STATUS_SUCCESS
+        (nt_status == STATUS_SUCCESS).then_some(WaitLockGuard { wait_lock: self })
+    }
+}
+
+/// Timeout of `WdfWaitLockAcquire` for `duration`: a negative number of 100ns
+/// units, which the framework takes as relative to the current time, saturated
+/// to the longest timeout it can express.
+fn relative_timeout(duration: Duration) -> LONGLONG {
+    let units = duration.as_nanos() / 100;
+    LONGLONG::try_from(units).map_or(LONGLONG::MIN, |units| -units)
+}
+
+/// Holds a [`WaitLock`] acquired with [`WaitLock::lock`] or
+/// [`WaitLock::try_lock_for`], and releases it when dropped.
+#[must_use = "the wait lock is released as soon as the guard is dropped"]
+pub struct WaitLockGuard<'a> {
+    wait_lock: &'a WaitLock,
+}
+
+impl Drop for WaitLockGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: The lock was acquired when the guard was created, and is
+        // released exactly once, here.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfWaitLockRelease, self.wait_lock.wdf_wait_lock);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    WDFWORKITEM,
+    WDF_OBJECT_ATTRIBUTES,
+    WDF_WORKITEM_CONFIG,
+};
+
+/// WDF work item.
+///
+/// Like the DPC of the `dpc-completion` feature, a [`WorkItem`] only runs its
+/// `EvtWorkItemFunc` when it is explicitly queued with [`WorkItem::enqueue`],
+/// but it runs it at `PASSIVE_LEVEL`, from a system worker thread.
+pub struct WorkItem {
+    wdf_work_item: WDFWORKITEM,
+}
+
+impl WorkItem {
+    /// Try to construct a WDF work item object. `attributes.ParentObject` must
+    /// be a device or a queue.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if WDF fails to construct a work
+    /// item. The error variant will contain a [`NTSTATUS`] of the failure. Full
+    /// error documentation is available in the [WdfWorkItemCreate Documentation](https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/wdfworkitem/nf-wdfworkitem-wdfworkitemcreate#return-value)
+    pub fn create(
+        work_item_config: &mut WDF_WORKITEM_CONFIG,
+        attributes: &mut WDF_OBJECT_ATTRIBUTES,
+    ) -> Result<Self, NTSTATUS> {
+        let mut work_item = Self {
+            wdf_work_item: core::ptr::null_mut(),
+        };
+
+        let nt_status;
+        // SAFETY: The resulting ffi object is stored in a private member and not
+        // accessible outside of this module, and this module guarantees that it is
+        // always in a valid state.
+        unsafe {
+            nt_status = call_unsafe_wdf_function_binding!(
+                WdfWorkItemCreate,
+                work_item_config,
+                attributes,
+                &mut work_item.wdf_work_item,
+            );
+        }
+        nt_success(nt_status).then_some(work_item).ok_or(nt_status)
+    }
+
+    /// Queue the [`WorkItem`] for execution. If it is already queued and has
+    /// not started running yet, it still only runs once.
+    pub fn enqueue(&self) {
+        // SAFETY: `wdf_work_item` is a private member of `WorkItem`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfWorkItemEnqueue, self.wdf_work_item);
+        }
+    }
+
+    /// Wait for a queued or running `EvtWorkItemFunc` to return. It must be
+    /// called at `PASSIVE_LEVEL`, and not from the work item itself.
+    pub fn flush(&self) {
+        // SAFETY: `wdf_work_item` is a private member of `WorkItem`, originally
+        // created by WDF, and this module guarantees that it is always in a valid
+        // state.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfWorkItemFlush, self.wdf_work_item);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+// C half of the `wpp-tracing` feature, see wpp.rs.
+//
+// The build script runs tracewpp.exe on this file, which generates wpp.tmh
+// with the macros behind each TraceEvents call below, and then compiles it
+// into a static library linked into the driver. Each function is declared in
+// wpp.rs and called from Rust through a safe wrapper.
+
+#include <ntddk.h>
+#include <wdf.h>
+
+// {5C1D8A3E-7F24-4B90-9E61-2AD4F8B37C05}
+#define WPP_CONTROL_GUIDS                                              \
+    WPP_DEFINE_CONTROL_GUID(                                           \
+        RustEchoTraceGuid, (5C1D8A3E, 7F24, 4B90, 9E61, 2AD4F8B37C05), \
+        WPP_DEFINE_BIT(TRACE_DRIVER)                                   \
+        WPP_DEFINE_BIT(TRACE_DEVICE)                                   \
+        WPP_DEFINE_BIT(TRACE_QUEUE))
+
+// TraceEvents(LEVEL, FLAGS, MSG, ...), as declared to tracewpp.exe by the
+// build script, is enabled when the flag is set and the level is at least as
+// verbose as the one of the trace session
+#define WPP_LEVEL_FLAGS_LOGGER(lvl, flags) WPP_LEVEL_LOGGER(flags)
+#define WPP_LEVEL_FLAGS_ENABLED(lvl, flags) \
+    (WPP_LEVEL_ENABLED(flags) && WPP_CONTROL(WPP_BIT_##flags).Level >= lvl)
+
+#include "wpp.tmh"
+
+VOID
+EchoWppInitialize(
+    _In_ PDRIVER_OBJECT DriverObject,
+    _In_ PCUNICODE_STRING RegistryPath
+    )
+{
+    WPP_INIT_TRACING(DriverObject, RegistryPath);
+}
+
+VOID
+EchoWppCleanup(
+    _In_ PDRIVER_OBJECT DriverObject
+    )
+{
+    WPP_CLEANUP(DriverObject);
+}
+
+VOID
+EchoWppTraceDriverEntry(
+    _In_ NTSTATUS Status
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_DRIVER,
+                "DriverEntry returning %!STATUS!", Status);
+}
+
+VOID
+EchoWppTraceDriverUnload(
+    VOID
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_DRIVER,
+                "echo_evt_driver_unload");
+}
+
+VOID
+EchoWppTraceDeviceAdd(
+    _In_ NTSTATUS Status
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_DEVICE,
+                "echo_evt_device_add returning %!STATUS!", Status);
+}
+
+VOID
+EchoWppTraceIoRead(
+    _In_ WDFQUEUE Queue,
+    _In_ WDFREQUEST Request,
+    _In_ size_t Length
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_QUEUE,
+                "echo_evt_io_read Queue %p, Request %p, Length %Iu",
+                Queue, Request, Length);
+}
+
+VOID
+EchoWppTraceIoWrite(
+    _In_ WDFQUEUE Queue,
+    _In_ WDFREQUEST Request,
+    _In_ size_t Length
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_QUEUE,
+                "echo_evt_io_write Queue %p, Request %p, Length %Iu",
+                Queue, Request, Length);
+}
+
+VOID
+EchoWppTraceIoDeviceControl(
+    _In_ WDFQUEUE Queue,
+    _In_ WDFREQUEST Request,
+    _In_ ULONG IoControlCode
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_QUEUE,
+                "echo_evt_io_device_control Queue %p, Request %p, IoControlCode 0x%08X",
+                Queue, Request, IoControlCode);
+}
+
+VOID
+EchoWppTraceRequestCancel(
+    _In_ WDFREQUEST Request
+    )
+{
+    TraceEvents(TRACE_LEVEL_INFORMATION, TRACE_QUEUE,
+                "echo_evt_request_cancel Request %p", Request);
+}
+
+VOID
+EchoWppTraceTimer(
+    _In_ WDFTIMER Timer
+    )
+{
+    TraceEvents(TRACE_LEVEL_VERBOSE, TRACE_QUEUE,
+                "echo_evt_timer_func Timer %p", Timer);
+}
@@ -0,0 +1,116 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! WPP software tracing, with the `wpp-tracing` feature.
+//!
+//! WPP is not a library but a preprocessor: `tracewpp.exe` scans C sources for
+//! calls to the trace message functions, here `TraceEvents(LEVEL, FLAGS, MSG,
+//! ...)`, and generates a `.tmh` header defining a macro for each call site,
+//! which packs the arguments into a binary message and writes it to ETW. The
+//! format strings never reach the driver binary: they end up in its PDB, from
+//! which `tracepdb.exe` or `tracefmt.exe` decode the messages.
+//!
+//! Since it only understands C, the trace messages are written in `wpp.c`, one
+//! function per message. The build script runs `tracewpp.exe` on it and
+//! compiles it with the generated `wpp.tmh` into a static library, and this
+//! module declares those functions and wraps each in a safe function called
+//! from the matching callback. `WPP_INIT_TRACING` and `WPP_CLEANUP` are
+//! macros as well, so they are wrapped the same way.
+//!
+//! The messages are only written while a trace session enables the provider,
+//! `{5C1D8A3E-7F24-4B90-9E61-2AD4F8B37C05}`, with their flag: `TRACE_DRIVER`
+//! (0x1), `TRACE_DEVICE` (0x2) or `TRACE_QUEUE` (0x4).
+
+use wdk_sys::{NTSTATUS, PCUNICODE_STRING, PDRIVER_OBJECT, ULONG, WDFQUEUE, WDFREQUEST, WDFTIMER};
+
+// Functions of `wpp.c`, linked from the static library built by the build
+// script
+extern "C" {
+    fn EchoWppInitialize(driver_object: PDRIVER_OBJECT, registry_path: PCUNICODE_STRING);
+    fn EchoWppCleanup(driver_object: PDRIVER_OBJECT);
+    fn EchoWppTraceDriverEntry(status: NTSTATUS);
+    fn EchoWppTraceDriverUnload();
+    fn EchoWppTraceDeviceAdd(status: NTSTATUS);
+    fn EchoWppTraceIoRead(queue: WDFQUEUE, request: WDFREQUEST, length: usize);
+    fn EchoWppTraceIoWrite(queue: WDFQUEUE, request: WDFREQUEST, length: usize);
+    fn EchoWppTraceIoDeviceControl(queue: WDFQUEUE, request: WDFREQUEST, io_control_code: ULONG);
+    fn EchoWppTraceRequestCancel(request: WDFREQUEST);
+    fn EchoWppTraceTimer(timer: WDFTIMER);
+}
+
+/// Register the WPP provider, with `WPP_INIT_TRACING`. Must be called from
+/// `DriverEntry` before `WdfDriverCreate`, and balanced with [`cleanup`] when
+/// the driver unloads or fails to load. Messages traced before are dropped.
+///
+/// # Safety
+///
+/// `driver_object` and `registry_path` must be the arguments of `DriverEntry`.
+pub unsafe fn initialize(driver_object: PDRIVER_OBJECT, registry_path: PCUNICODE_STRING) {
+    // SAFETY: The arguments are valid per the contract of the caller.
+    unsafe { EchoWppInitialize(driver_object, registry_path) };
+}
+
+/// Unregister the WPP provider registered by [`initialize`], with
+/// `WPP_CLEANUP`. Messages traced after are dropped.
+///
+/// # Safety
+///
+/// `driver_object` must be the driver object [`initialize`] was called with,
+/// and this must be called at `PASSIVE_LEVEL`.
+pub unsafe fn cleanup(driver_object: PDRIVER_OBJECT) {
+    // SAFETY: `driver_object` is valid per the contract of the caller.
+    unsafe { EchoWppCleanup(driver_object) };
+}
+
+// The functions of `wpp.c` only copy their arguments into a message, and do
+// nothing while the provider is not registered or enabled, so they are safe to
+// call with any value at any time.
+
+/// Trace the status `DriverEntry` returns
+pub fn trace_driver_entry(nt_status: NTSTATUS) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceDriverEntry(nt_status) };
+}
+
+/// Trace a call to `EvtDriverUnload`, before the provider is unregistered
+pub fn trace_driver_unload() {
+    // SAFETY: See above
+    unsafe { EchoWppTraceDriverUnload() };
+}
+
+/// Trace the status `EvtDeviceAdd` returns
+pub fn trace_device_add(nt_status: NTSTATUS) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceDeviceAdd(nt_status) };
+}
+
+/// Trace a call to `EvtIoRead`
+pub fn trace_io_read(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceIoRead(queue, request, length) };
+}
+
+/// Trace a call to `EvtIoWrite`
+pub fn trace_io_write(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceIoWrite(queue, request, length) };
+}
+
+/// Trace a call to `EvtIoDeviceControl`
+pub fn trace_io_device_control(queue: WDFQUEUE, request: WDFREQUEST, io_control_code: ULONG) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceIoDeviceControl(queue, request, io_control_code) };
+}
+
+/// Trace a call to `EvtRequestCancel`
+pub fn trace_request_cancel(request: WDFREQUEST) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceRequestCancel(request) };
+}
+
+/// Trace a call to `EvtTimerFunc`, at the verbose level since it fires every
+/// period
+pub fn trace_timer(timer: WDFTIMER) {
+    // SAFETY: See above
+    unsafe { EchoWppTraceTimer(timer) };
+}
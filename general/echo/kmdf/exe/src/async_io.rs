@@ -0,0 +1,765 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-Async`: reads and writes issued asynchronously through an I/O completion
+//! port, either forever or a given number of times, with at most
+//! `NUM_ASYNCH_IO` pending at once. Ctrl-C cancels the pending requests before
+//! exiting.
+
+use std::{
+    error::Error,
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle,
+        GetLastError,
+        BOOL,
+        ERROR_DEVICE_REMOVED,
+        ERROR_IO_PENDING,
+        FALSE,
+        HANDLE,
+        INVALID_HANDLE_VALUE,
+        STATUS_CONTROL_C_EXIT,
+        TRUE,
+    },
+    Storage::FileSystem::{
+        CreateFileW,
+        ReadFile,
+        WriteFile,
+        FILE_FLAG_OVERLAPPED,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::{
+        Console::{SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_C_EVENT},
+        Threading::INFINITE,
+        IO::{
+            CancelIoEx,
+            CreateIoCompletionPort,
+            GetQueuedCompletionStatus,
+            OVERLAPPED,
+            OVERLAPPED_0,
+        },
+    },
+};
+
+use crate::{device_path::display_path, BUFFER_SIZE, GLOBAL_DATA};
+
+// Device and completion port handles of the async I/O loop while it runs, which
+// the Ctrl-C handler cancels the requests of and closes
+static ASYNC_HANDLES: Mutex<Option<AsyncHandles>> = Mutex::new(None);
+// Completion keys of the device handles the async I/O loop reads from and
+// writes to, both associated with one completion port.
+// GetQueuedCompletionStatus returns the key of the handle a request was issued
+// on, which tells reads and writes apart
+static READ_COMPLETION_KEY: usize = 1;
+static WRITE_COMPLETION_KEY: usize = 2;
+static NUM_ASYNCH_IO: usize = 100;
+
+/// What the async I/O loop does once one of its requests has completed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AsyncIoAction {
+    /// Issue the request again, with the same `OVERLAPPED` and buffer
+    Reissue,
+    /// Wait for the next completion without issuing anything
+    Wait,
+}
+
+/// Request accounting of the async I/O loop, kept apart from the I/O itself.
+/// The reads and the writes of the loop each have their own, and each issue
+/// `total` requests.
+///
+/// Without a limit, `NUM_ASYNCH_IO` requests are issued, and each one that
+/// completes is issued again, forever. With a limit of `total` requests, at
+/// most `NUM_ASYNCH_IO` are pending at once: completed requests are issued
+/// again until `total` have been issued, and the loop ends once `total` have
+/// completed. With a `total` of 0, nothing is issued and the loop does not run.
+#[derive(Debug)]
+struct AsyncIoPlan {
+    limited: bool,
+    /// Requests to issue before waiting for the first completion
+    initial_requests: usize,
+    /// Requests to issue in all, only meaningful with a limit
+    total: usize,
+    /// Requests issued so far, only counted with a limit
+    issued: usize,
+    /// Requests completed so far, only counted with a limit
+    completed: usize,
+}
+
+impl AsyncIoPlan {
+    fn new(limited: bool, total: usize) -> Self {
+        let initial_requests = if limited {
+            total.min(NUM_ASYNCH_IO)
+        } else {
+            NUM_ASYNCH_IO
+        };
+
+        Self {
+            limited,
+            initial_requests,
+            total,
+            issued: initial_requests,
+            completed: 0,
+        }
+    }
+
+    /// Whether every request has completed, which never happens without a
+    /// limit
+    const fn is_complete(&self) -> bool {
+        self.limited && self.completed == self.total
+    }
+
+    /// Accounts for a completed request, and returns whether to issue it again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more requests completed than were issued, which would mean a
+    /// completion was counted twice or came from another handle.
+    fn on_completion(&mut self) -> AsyncIoAction {
+        if !self.limited {
+            return AsyncIoAction::Reissue;
+        }
+
+        self.completed += 1;
+        assert!(
+            self.completed <= self.issued,
+            "{} requests completed, but only {} were issued",
+            self.completed,
+            self.issued
+        );
+
+        if self.issued == self.total {
+            return AsyncIoAction::Wait;
+        }
+
+        self.issued += 1;
+        assert!(
+            self.issued <= self.total,
+            "more requests issued than requested"
+        );
+        AsyncIoAction::Reissue
+    }
+}
+
+/// Handles of the async I/O loop: the devices opened for its reads and for its
+/// writes, and the completion port both are associated with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AsyncHandles {
+    reader: HANDLE,
+    writer: HANDLE,
+    completion_port: HANDLE,
+}
+
+/// Reads or writes of the async I/O loop, issued on their own device handle,
+/// with their `OVERLAPPED`s, buffers and request accounting
+struct AsyncIoStream {
+    /// Completion key of the device handle, `READ_COMPLETION_KEY` or
+    /// `WRITE_COMPLETION_KEY`, which also tells whether it reads or writes
+    completion_key: usize,
+    h_device: HANDLE,
+    ov_list: Vec<OVERLAPPED>,
+    buf: Vec<u8>,
+    plan: AsyncIoPlan,
+    /// Requests issued that have not completed yet
+    pending: usize,
+}
+
+impl AsyncIoStream {
+    fn new(completion_key: usize, h_device: HANDLE, plan: AsyncIoPlan) -> Self {
+        let max_pending_requests = plan.initial_requests;
+
+        Self {
+            completion_key,
+            h_device,
+            ov_list: vec![
+                OVERLAPPED {
+                    Internal: 0,
+                    InternalHigh: 0,
+                    Anonymous: OVERLAPPED_0 {
+                        Pointer: std::ptr::null_mut(),
+                    },
+                    hEvent: 0,
+                };
+                max_pending_requests
+            ],
+            buf: vec![0; max_pending_requests * BUFFER_SIZE],
+            plan,
+            pending: 0,
+        }
+    }
+
+    const fn operation(&self) -> &'static str {
+        if self.completion_key == READ_COMPLETION_KEY {
+            "Read"
+        } else {
+            "Write"
+        }
+    }
+
+    /// Issues request number `i`, with its own `OVERLAPPED` and part of the
+    /// buffer
+    fn issue(&mut self, i: usize) -> Result<(), Box<dyn Error>> {
+        let buffer = self.buf[i * BUFFER_SIZE..(i + 1) * BUFFER_SIZE].as_mut_ptr();
+        let overlapped = std::ptr::addr_of_mut!(self.ov_list[i]);
+        let r: BOOL;
+
+        if self.completion_key == READ_COMPLETION_KEY {
+            // SAFETY:
+            // Call Win32 API FFI ReadFile to read from driver with an overlap option.
+            // The buffer and the OVERLAPPED are not touched again until the request
+            // completes
+            unsafe {
+                r = ReadFile(
+                    self.h_device,
+                    buffer.cast(),
+                    u32::try_from(BUFFER_SIZE).unwrap(),
+                    std::ptr::null_mut(),
+                    overlapped,
+                );
+            }
+        } else {
+            // SAFETY:
+            // Call Win32 API FFI WriteFile to write to driver with an overlap option.
+            // The number of bytes written is reported by the completion port
+            unsafe {
+                r = WriteFile(
+                    self.h_device,
+                    buffer.cast(),
+                    u32::try_from(BUFFER_SIZE).unwrap(),
+                    std::ptr::null_mut(),
+                    overlapped,
+                );
+            }
+        }
+
+        if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+            // or WriteFile
+            let error = unsafe { GetLastError() };
+            if error != ERROR_IO_PENDING {
+                return Err(async_io_failure(
+                    &format!("{i}th {}", self.operation()),
+                    error,
+                ));
+            }
+        }
+
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Issues the requests pending before the first completion
+    fn issue_initial_requests(&mut self) -> Result<(), Box<dyn Error>> {
+        for i in 0..self.plan.initial_requests {
+            self.issue(i)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles the completion of one of the requests of the stream, issuing it
+    /// again if the plan says so
+    fn on_completion(&mut self, completion: &AsyncIoCompletion) -> Result<(), Box<dyn Error>> {
+        self.pending -= 1;
+
+        // SAFETY:
+        // Perform pointer math to determine which index 'i' to use by determining the
+        // offset of the completed OVERLAPPED from the start of the array given by
+        // 'ov_list'. The completion carried the key of this stream, so the
+        // OVERLAPPED is one of its own
+        let offset = unsafe { completion.overlapped.offset_from(self.ov_list.as_ptr()) };
+        let i = usize::try_from(offset)?;
+        if i >= self.ov_list.len() {
+            return Err(
+                format!("{} completed with an unknown OVERLAPPED", self.operation()).into(),
+            );
+        }
+
+        let number_of_bytes_transferred = match completion.result {
+            Ok(number_of_bytes_transferred) => number_of_bytes_transferred,
+            Err(error) => {
+                return Err(async_io_failure(
+                    &format!("{i}th {}", self.operation()),
+                    error,
+                ))
+            }
+        };
+
+        if self.completion_key == READ_COMPLETION_KEY {
+            println!("Number of bytes read by request number {i} is {number_of_bytes_transferred}");
+        } else {
+            println!(
+                "Number of bytes written by request number {i} is {number_of_bytes_transferred}",
+            );
+
+            // The driver accepts writes of up to BUFFER_SIZE bytes, and must have
+            // taken all of them
+            if usize::try_from(number_of_bytes_transferred)? != BUFFER_SIZE {
+                return Err(format!(
+                    "{i}th Write completed with {number_of_bytes_transferred} bytes, SB \
+                     {BUFFER_SIZE}"
+                )
+                .into());
+            }
+        }
+
+        if self.plan.on_completion() == AsyncIoAction::Reissue {
+            self.issue(i)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens the device for overlapped I/O
+fn open_async_device(path: &[u16]) -> Result<HANDLE, Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Cannot open {} error {error}", display_path(path)).into());
+    }
+
+    Ok(h_device)
+}
+
+/// Closes the handles of the async I/O loop
+fn close_async_handles(handles: AsyncHandles) {
+    for handle in [handles.completion_port, handles.writer, handles.reader] {
+        if handle != 0 && handle != INVALID_HANDLE_VALUE {
+            // SAFETY:
+            // Call Win32 API FFI CloseHandle to close the completion port and device
+            // handles
+            unsafe {
+                CloseHandle(handle);
+            }
+        }
+    }
+}
+
+/// Reads from and writes to the device asynchronously from a single thread.
+/// The reads and the writes are issued on two device handles, associated with
+/// one completion port under `READ_COMPLETION_KEY` and `WRITE_COMPLETION_KEY`,
+/// and the key returned with each completion tells which of them completed.
+pub fn async_io_work(loops: Option<usize>) -> Result<(), Box<dyn Error>> {
+    let globals = GLOBAL_DATA.read()?;
+
+    let mut handles = AsyncHandles {
+        reader: open_async_device(&globals.device_path)?,
+        writer: 0,
+        completion_port: 0,
+    };
+
+    match open_async_device(&globals.device_path) {
+        Ok(writer) => handles.writer = writer,
+        Err(e) => {
+            close_async_handles(handles);
+            return Err(e);
+        }
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CreateIoCompletionPort to create the completion port,
+    // associating the reader device with the key of the reads
+    handles.completion_port =
+        unsafe { CreateIoCompletionPort(handles.reader, 0, READ_COMPLETION_KEY, 0) };
+
+    // CreateIoCompletionPort returns NULL on failure, not INVALID_HANDLE_VALUE
+    if handles.completion_port == 0 {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // CreateIoCompletionPort
+        let error = unsafe { GetLastError() };
+        close_async_handles(handles);
+        return Err(format!("Cannot open completion port {error}").into());
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CreateIoCompletionPort to associate the writer device
+    // with the same port, under the key of the writes. It returns the port
+    if unsafe {
+        CreateIoCompletionPort(
+            handles.writer,
+            handles.completion_port,
+            WRITE_COMPLETION_KEY,
+            0,
+        )
+    } != handles.completion_port
+    {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // CreateIoCompletionPort
+        let error = unsafe { GetLastError() };
+        close_async_handles(handles);
+        return Err(format!("Cannot associate the writer with the completion port {error}").into());
+    }
+
+    *ASYNC_HANDLES.lock()? = Some(handles);
+
+    let result = run_async_io(handles, loops.is_some(), loops.unwrap_or_default());
+    drop(globals);
+
+    // Once taken out, the handles can no longer be closed by the Ctrl-C handler
+    ASYNC_HANDLES.lock()?.take();
+    close_async_handles(handles);
+
+    result
+}
+
+/// Issues the reads and the writes of the async I/O loop on the handles of
+/// `handles`, and routes each completion to them by its key until both are
+/// done, which only happens with `limited` loops
+fn run_async_io(handles: AsyncHandles, limited: bool, total: usize) -> Result<(), Box<dyn Error>> {
+    let mut reader = AsyncIoStream::new(
+        READ_COMPLETION_KEY,
+        handles.reader,
+        AsyncIoPlan::new(limited, total),
+    );
+    let mut writer = AsyncIoStream::new(
+        WRITE_COMPLETION_KEY,
+        handles.writer,
+        AsyncIoPlan::new(limited, total),
+    );
+
+    let result = issue_and_complete_async_io(handles, &mut reader, &mut writer);
+
+    if reader.pending + writer.pending > 0 {
+        // The requests still outstanding write to the OVERLAPPEDs and buffers
+        // of the streams, which must not be freed before they complete
+        cancel_async_io(handles, &mut reader, &mut writer)?;
+    }
+
+    match result {
+        // The driver fails the requests of a removed device, which is the end
+        // of the test rather than a failure
+        Err(e) if e.is::<DeviceRemoved>() => {
+            println!("{e}, stopping async I/O");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Issues the first requests of `reader` and `writer`, then waits for their
+/// completions and routes each to them by its key until both are done
+fn issue_and_complete_async_io(
+    handles: AsyncHandles,
+    reader: &mut AsyncIoStream,
+    writer: &mut AsyncIoStream,
+) -> Result<(), Box<dyn Error>> {
+    reader.issue_initial_requests()?;
+    writer.issue_initial_requests()?;
+
+    while !(reader.plan.is_complete() && writer.plan.is_complete()) {
+        let completion = wait_for_completion(handles.completion_port)?;
+        stream_for_key(completion.key, reader, writer)?.on_completion(&completion)?;
+    }
+
+    // Only reached with a limit, once as many requests completed as were asked
+    for stream in [reader, writer] {
+        assert_eq!(
+            stream.plan.completed,
+            stream.plan.total,
+            "{} completions do not match the loop count",
+            stream.operation()
+        );
+        assert_eq!(
+            stream.plan.issued,
+            stream.plan.total,
+            "{} requests issued do not match the loop count",
+            stream.operation()
+        );
+    }
+
+    Ok(())
+}
+
+/// Completion of a request dequeued from the completion port of the async I/O
+/// loop
+struct AsyncIoCompletion {
+    /// Completion key of the device handle the request was issued on
+    key: usize,
+    overlapped: *const OVERLAPPED,
+    /// Number of bytes the request transferred, or the error it failed with
+    result: Result<u32, u32>,
+}
+
+/// Waits for a request issued on a device associated with `h_completion_port`
+/// to complete. Fails if the wait itself fails, but not if the request does.
+fn wait_for_completion(h_completion_port: HANDLE) -> Result<AsyncIoCompletion, Box<dyn Error>> {
+    let mut number_of_bytes_transferred = 0;
+    let mut key = 0;
+    let mut completed_ov_ptr: *mut OVERLAPPED = std::ptr::null_mut();
+
+    // SAFETY:
+    // Call Win32 API FFI GetQueuedCompletionStatus to access the status of the
+    // completion request
+    let r = unsafe {
+        GetQueuedCompletionStatus(
+            h_completion_port,
+            &mut number_of_bytes_transferred,
+            &mut key,
+            std::ptr::addr_of_mut!(completed_ov_ptr),
+            INFINITE,
+        )
+    };
+
+    let result = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // GetQueuedCompletionStatus
+        let error = unsafe { GetLastError() };
+
+        // Without an OVERLAPPED, no request completed and the wait failed
+        if completed_ov_ptr.is_null() {
+            return Err(async_io_failure("GetQueuedCompletionStatus", error));
+        }
+
+        Err(error)
+    } else {
+        Ok(number_of_bytes_transferred)
+    };
+
+    Ok(AsyncIoCompletion {
+        key,
+        overlapped: completed_ov_ptr,
+        result,
+    })
+}
+
+/// Returns whichever of `reader` and `writer` has the completion key `key`,
+/// the one of the device handle a completed request was issued on
+fn stream_for_key<'a>(
+    key: usize,
+    reader: &'a mut AsyncIoStream,
+    writer: &'a mut AsyncIoStream,
+) -> Result<&'a mut AsyncIoStream, Box<dyn Error>> {
+    match key {
+        k if k == reader.completion_key => Ok(reader),
+        k if k == writer.completion_key => Ok(writer),
+        _ => Err(format!("Completion with unknown key {key}").into()),
+    }
+}
+
+/// Cancels the requests of `reader` and `writer` still outstanding on the
+/// devices of `handles`, and waits for all of them to complete, whatever their
+/// status
+fn cancel_async_io(
+    handles: AsyncHandles,
+    reader: &mut AsyncIoStream,
+    writer: &mut AsyncIoStream,
+) -> Result<(), Box<dyn Error>> {
+    for h_device in [handles.reader, handles.writer] {
+        // SAFETY:
+        // Call Win32 API FFI CancelIoEx to cancel every request issued on the
+        // device by the async I/O loop
+        unsafe {
+            CancelIoEx(h_device, std::ptr::null());
+        }
+    }
+
+    while reader.pending + writer.pending > 0 {
+        let completion = wait_for_completion(handles.completion_port)?;
+        stream_for_key(completion.key, reader, writer)?.pending -= 1;
+    }
+
+    Ok(())
+}
+
+/// Error of an async request that failed with `ERROR_DEVICE_REMOVED`, which
+/// ends the async I/O loop cleanly
+#[derive(Debug)]
+struct DeviceRemoved {
+    operation: String,
+}
+
+impl fmt::Display for DeviceRemoved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed, the device was removed", self.operation)
+    }
+}
+
+impl Error for DeviceRemoved {}
+
+/// Error for an async request or completion that failed with `error`. A
+/// device removed while requests are outstanding, e.g. disabled in Device
+/// Manager, makes the driver fail them with `STATUS_DEVICE_REMOVED`, which is
+/// reported as a `DeviceRemoved` error rather than as a bare error code.
+fn async_io_failure(operation: &str, error: u32) -> Box<dyn Error> {
+    if error == ERROR_DEVICE_REMOVED {
+        Box::new(DeviceRemoved {
+            operation: operation.to_owned(),
+        })
+    } else {
+        format!("{operation} failed {error}").into()
+    }
+}
+
+/// Installs [`console_ctrl_handler`], so that Ctrl-C cleans up the async I/O
+/// loop.
+pub fn set_console_ctrl_handler() -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI SetConsoleCtrlHandler to add the handler
+    if unsafe { SetConsoleCtrlHandler(Some(console_ctrl_handler), TRUE) } == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // SetConsoleCtrlHandler
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to set the Ctrl-C handler. Error {error}").into());
+    }
+
+    Ok(())
+}
+
+/// Console control handler installed by the async mode. On Ctrl-C or
+/// Ctrl-Break, it cancels the requests still pending on the devices opened by
+/// the async I/O loop with `CancelIoEx`, which makes the driver complete them
+/// from its cancel routine, and closes the devices and their completion port
+/// before exiting.
+#[allow(clippy::significant_drop_tightening)]
+extern "system" fn console_ctrl_handler(ctrl_type: u32) -> BOOL {
+    if ctrl_type != CTRL_C_EVENT && ctrl_type != CTRL_BREAK_EVENT {
+        return FALSE;
+    }
+
+    // The lock is held until the process exits, so that the loop cannot close
+    // the handles at the same time
+    let handles = ASYNC_HANDLES.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(handles) = *handles {
+        for h_device in [handles.reader, handles.writer] {
+            // SAFETY:
+            // Call Win32 API FFI CancelIoEx to cancel every request issued on the
+            // device by the async I/O loop
+            unsafe {
+                CancelIoEx(h_device, std::ptr::null());
+            }
+        }
+
+        close_async_handles(handles);
+
+        println!("Cancelled the requests of the async I/O loop");
+    }
+
+    std::process::exit(STATUS_CONTROL_C_EXIT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Completes the requests of `plan` one at a time until it is complete, as
+    /// the async I/O loop does, checking that no more than `NUM_ASYNCH_IO` are
+    /// ever pending, and returns the action taken after each completion
+    fn run_plan(plan: &mut AsyncIoPlan) -> Vec<AsyncIoAction> {
+        let mut pending = plan.initial_requests;
+        let mut actions = Vec::new();
+
+        while !plan.is_complete() {
+            assert!(pending > 0, "waiting with no request pending");
+            let action = plan.on_completion();
+            if action == AsyncIoAction::Wait {
+                pending -= 1;
+            }
+            assert!(pending <= NUM_ASYNCH_IO);
+            actions.push(action);
+        }
+
+        assert_eq!(pending, 0, "requests still pending once complete");
+        actions
+    }
+
+    #[test]
+    fn async_io_plan_fewer_requests_than_pending_limit() {
+        let total = NUM_ASYNCH_IO / 10;
+        let mut plan = AsyncIoPlan::new(true, total);
+        assert_eq!(plan.initial_requests, total);
+
+        let actions = run_plan(&mut plan);
+        assert_eq!(actions, vec![AsyncIoAction::Wait; total]);
+        assert_eq!(plan.issued, total);
+        assert_eq!(plan.completed, total);
+    }
+
+    #[test]
+    fn async_io_plan_as_many_requests_as_pending_limit() {
+        let mut plan = AsyncIoPlan::new(true, NUM_ASYNCH_IO);
+        assert_eq!(plan.initial_requests, NUM_ASYNCH_IO);
+
+        let actions = run_plan(&mut plan);
+        assert_eq!(actions, vec![AsyncIoAction::Wait; NUM_ASYNCH_IO]);
+        assert_eq!(plan.issued, NUM_ASYNCH_IO);
+        assert_eq!(plan.completed, NUM_ASYNCH_IO);
+    }
+
+    #[test]
+    fn async_io_plan_many_more_requests_than_pending_limit() {
+        let total = NUM_ASYNCH_IO * 25 + 7;
+        let mut plan = AsyncIoPlan::new(true, total);
+        assert_eq!(plan.initial_requests, NUM_ASYNCH_IO);
+
+        // Every completion is issued again until all requests have been
+        // issued, then the last NUM_ASYNCH_IO are only waited for
+        let actions = run_plan(&mut plan);
+        let reissued = actions
+            .iter()
+            .take_while(|&&action| action == AsyncIoAction::Reissue)
+            .count();
+        assert_eq!(reissued, total - NUM_ASYNCH_IO);
+        assert_eq!(actions.len(), total);
+        assert!(actions[reissued..]
+            .iter()
+            .all(|&action| action == AsyncIoAction::Wait));
+        assert_eq!(plan.issued, total);
+        assert_eq!(plan.completed, total);
+    }
+
+    #[test]
+    fn async_io_plan_no_requests() {
+        let mut plan = AsyncIoPlan::new(true, 0);
+        assert_eq!(plan.initial_requests, 0);
+        assert!(plan.is_complete());
+        assert!(run_plan(&mut plan).is_empty());
+    }
+
+    #[test]
+    fn async_io_plan_without_limit_reissues_forever() {
+        let mut plan = AsyncIoPlan::new(false, 0);
+        assert_eq!(plan.initial_requests, NUM_ASYNCH_IO);
+
+        for _ in 0..NUM_ASYNCH_IO * 3 {
+            assert_eq!(plan.on_completion(), AsyncIoAction::Reissue);
+            assert!(!plan.is_complete());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "requests completed, but only")]
+    fn async_io_plan_rejects_extra_completion() {
+        let mut plan = AsyncIoPlan::new(true, 1);
+        plan.on_completion();
+        plan.on_completion();
+    }
+}
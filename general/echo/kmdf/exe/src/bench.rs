@@ -0,0 +1,371 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `--bench` and `--threads`: timed write and read round trips, on one handle
+//! or on several threads at once.
+
+use std::{
+    error::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GetLastError, FALSE, HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW,
+        ReadFile,
+        WriteFile,
+        FILE_FLAG_OVERLAPPED,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::Threading::INFINITE,
+};
+
+use crate::{
+    create_pattern_buffer,
+    overlapped_io_with_timeout,
+    verify_pattern_buffer,
+    GLOBAL_DATA,
+    SEQUENCE_NUMBER_LENGTH,
+};
+
+static STRESS_CYCLES: usize = 100;
+
+/// Sends `round_trips` overlapped writes of `test_length` bytes, each followed
+/// by a read of the data back, waiting for each request to complete before
+/// sending the next one. The time from issuing a write to completing its read
+/// is recorded for each round trip, and a summary is printed as `key=value`
+/// lines, e.g. to compare drivers built with and without the `parallel-queue`
+/// feature.
+pub fn perform_benchmark(
+    path: &[u16],
+    round_trips: usize,
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let start = Instant::now();
+    let result = benchmark_round_trips(h_device, round_trips, test_length);
+    let elapsed = start.elapsed();
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    let (mut latencies, bytes_transferred) = result?;
+    latencies.sort_unstable();
+
+    let elapsed_us = elapsed.as_micros().max(1);
+    println!("round_trips={round_trips}");
+    println!("request_length={test_length}");
+    println!("bytes_transferred={bytes_transferred}");
+    println!("elapsed_us={elapsed_us}");
+    println!(
+        "throughput_bytes_per_sec={}",
+        u128::from(bytes_transferred) * 1_000_000 / elapsed_us
+    );
+    for (name, percentile) in [
+        ("min", 0),
+        ("p50", 50),
+        ("p90", 90),
+        ("p99", 99),
+        ("max", 100),
+    ] {
+        // Nearest-rank percentile
+        let rank = ((latencies.len() * percentile + 99) / 100).max(1);
+        println!("latency_{name}_us={}", latencies[rank - 1].as_micros());
+    }
+
+    Ok(())
+}
+
+/// Runs `thread_count` threads at once, each opening its own handle to the
+/// device and running `STRESS_CYCLES` synchronous write and read round trips
+/// of `test_length` bytes, to stress how the driver serializes requests from
+/// several callers. Every thread writes the same pattern, so whichever write a
+/// read returns the data of, it must be that pattern. Each thread reports the
+/// round trips whose data did not verify and the reads that returned no data,
+/// which only a driver built with `ring-buffer` should have, when another
+/// thread drained the data first. Any other error ends the thread, and the test
+/// fails if any thread failed or saw data that did not verify.
+pub fn perform_stress_test(
+    path: &[u16],
+    thread_count: usize,
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    if thread_count == 0 {
+        return Err("The stress test needs at least one thread".into());
+    }
+
+    // The sequence numbers of reads from different threads are not ordered, so
+    // they are skipped rather than checked
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+
+    println!("Starting {thread_count} threads of {STRESS_CYCLES} round trips");
+
+    // Every thread is joined before the scope returns, and each one closes its
+    // own handle
+    let results: Vec<Result<StressReport, String>> = thread::scope(|scope| {
+        // Collected so that every thread is started before the first one is
+        // joined
+        #[allow(clippy::needless_collect)]
+        let threads: Vec<_> = (0..thread_count)
+            .map(|_| {
+                scope.spawn(move || {
+                    stress_thread(path, test_length, sequence_numbers).map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err("Thread panicked".to_string()))
+            })
+            .collect()
+    });
+
+    let mut failed_threads = 0;
+    for (index, result) in results.iter().enumerate() {
+        match result {
+            Ok(report) => {
+                println!(
+                    "Thread {index}: {} round trips, {} verification failures, {} empty reads",
+                    report.round_trips, report.verification_failures, report.empty_reads
+                );
+                if report.verification_failures > 0 {
+                    failed_threads += 1;
+                }
+            }
+            Err(error) => {
+                println!("Thread {index}: {error}");
+                failed_threads += 1;
+            }
+        }
+    }
+
+    if failed_threads > 0 {
+        return Err(format!("{failed_threads} of {thread_count} threads failed").into());
+    }
+
+    println!("Stress test of {thread_count} threads passed");
+    Ok(())
+}
+
+/// Round trips run by one thread of [`perform_stress_test`]
+#[derive(Default, Debug)]
+struct StressReport {
+    round_trips: usize,
+    verification_failures: usize,
+    empty_reads: usize,
+}
+
+/// Opens a handle to the device at `path` and runs the round trips of one
+/// thread of [`perform_stress_test`] on it.
+fn stress_thread(
+    path: &[u16],
+    test_length: u32,
+    sequence_numbers: bool,
+) -> Result<StressReport, Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let mut report = StressReport::default();
+    let result = (0..STRESS_CYCLES)
+        .try_for_each(|_| stress_round_trip(h_device, test_length, sequence_numbers, &mut report));
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result.map(|()| report)
+}
+
+/// Writes the pattern and reads data back on `h_device`, and records in
+/// `report` whether the data verified.
+fn stress_round_trip(
+    h_device: HANDLE,
+    test_length: u32,
+    sequence_numbers: bool,
+    report: &mut StressReport,
+) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+    let read_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length)?];
+    let mut bytes_transferred: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI WriteFile to write the pattern to the driver
+    let r = unsafe {
+        WriteFile(
+            h_device,
+            write_buffer.as_ptr().cast(),
+            test_length,
+            &mut bytes_transferred,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+        let error = unsafe { GetLastError() };
+        return Err(format!("WriteFile failed: Error {error}").into());
+    }
+
+    bytes_transferred = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI ReadFile to read data from the driver
+    let r = unsafe {
+        ReadFile(
+            h_device,
+            read_buffer.as_mut_ptr().cast(),
+            read_length,
+            &mut bytes_transferred,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+        let error = unsafe { GetLastError() };
+        return Err(format!("ReadFile failed: Error {error}").into());
+    }
+
+    report.round_trips += 1;
+    if bytes_transferred == 0 {
+        report.empty_reads += 1;
+        return Ok(());
+    }
+
+    let verified = if bytes_transferred == read_length {
+        verify_pattern_buffer(&read_buffer[read_buffer.len() - usize::try_from(test_length)?..])
+    } else {
+        Err(format!("Read {bytes_transferred}, SB {read_length}").into())
+    };
+    if let Err(error) = verified {
+        report.verification_failures += 1;
+        println!("Round trip {}: {error}", report.round_trips);
+    }
+
+    Ok(())
+}
+
+/// Runs the round trips of [`perform_benchmark`] on `h_device`, which must have
+/// been opened with `FILE_FLAG_OVERLAPPED`. Returns the latency of each round
+/// trip, and the total number of bytes written and read.
+fn benchmark_round_trips(
+    h_device: HANDLE,
+    round_trips: usize,
+    test_length: u32,
+) -> Result<(Vec<Duration>, u64), Box<dyn Error>> {
+    if round_trips == 0 {
+        return Err("The benchmark needs at least one round trip".into());
+    }
+
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+    let read_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+
+    let write_buffer = create_pattern_buffer(test_length);
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length).unwrap()];
+    let mut latencies = Vec::with_capacity(round_trips);
+    let mut bytes_transferred: u64 = 0;
+
+    for i in 0..round_trips {
+        let round_trip_start = Instant::now();
+
+        let bytes_written = overlapped_io_with_timeout(h_device, INFINITE, |overlapped| {
+            // SAFETY:
+            // Call Win32 API FFI WriteFile to write buffer to the driver with an
+            // overlap option
+            unsafe {
+                WriteFile(
+                    h_device,
+                    write_buffer.as_ptr().cast(),
+                    test_length,
+                    std::ptr::null_mut(),
+                    overlapped,
+                )
+            }
+        })
+        .map_err(|error| format!("Benchmark: WriteFile {i} failed: {error}"))?;
+
+        let bytes_read = overlapped_io_with_timeout(h_device, INFINITE, |overlapped| {
+            // SAFETY:
+            // Call Win32 API FFI ReadFile to read data from the driver with an
+            // overlap option
+            unsafe {
+                ReadFile(
+                    h_device,
+                    read_buffer.as_mut_ptr().cast(),
+                    read_length,
+                    std::ptr::null_mut(),
+                    overlapped,
+                )
+            }
+        })
+        .map_err(|error| format!("Benchmark: ReadFile {i} failed: {error}"))?;
+
+        latencies.push(round_trip_start.elapsed());
+
+        if bytes_written != test_length || bytes_read != read_length {
+            return Err(format!(
+                "Benchmark: round trip {i} Written {bytes_written}, Read {bytes_read}, SB \
+                 {test_length} and {read_length}"
+            )
+            .into());
+        }
+        bytes_transferred += u64::from(bytes_written) + u64::from(bytes_read);
+    }
+
+    Ok((latencies, bytes_transferred))
+}
@@ -0,0 +1,253 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! Finding the path of the echo device through its device interface, for
+//! `--list`, `--instance` and `--wait-ms`.
+
+use std::{
+    error::Error,
+    iter,
+    thread,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+use windows_sys::Win32::Devices::DeviceAndDriverInstallation;
+
+use crate::GLOBAL_DATA;
+
+static DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub fn get_device_path(interface_guid: &Uuid) -> Result<(), Box<dyn Error>> {
+    let instance = GLOBAL_DATA.read()?.instance;
+    let paths = wait_for_device_paths(interface_guid, instance)?;
+
+    let mut globals = GLOBAL_DATA.write()?;
+    if paths.len() > 1 {
+        println!("Found {} echo device interfaces:", paths.len());
+        print_device_paths(&paths);
+    }
+
+    let Some(path) = paths.get(globals.instance) else {
+        return Err(format!(
+            "Error: Instance {} requested, but only {} echo device interfaces were found.",
+            globals.instance,
+            paths.len()
+        )
+        .into());
+    };
+    globals.device_path.clone_from(path);
+    drop(globals);
+
+    Ok(())
+}
+
+/// Returns the paths of the present device interfaces of class
+/// `interface_guid`. With `--wait-ms`, polls until there are more than
+/// `instance` of them or the wait is over, since the interface of a driver that
+/// is still starting appears slightly late.
+pub fn wait_for_device_paths(
+    interface_guid: &Uuid,
+    instance: usize,
+) -> Result<Vec<Vec<u16>>, Box<dyn Error>> {
+    let wait_ms = GLOBAL_DATA.read()?.wait_ms;
+    let mut paths = get_device_paths(interface_guid)?;
+
+    if let Some(wait_ms) = wait_ms {
+        let deadline = Instant::now() + Duration::from_millis(wait_ms.into());
+        while paths.len() <= instance {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                println!("Gave up waiting for echo device interface {instance} after {wait_ms} ms");
+                break;
+            }
+
+            println!(
+                "Waiting for echo device interface {instance}, {} ms left",
+                remaining.as_millis()
+            );
+            thread::sleep(DEVICE_POLL_INTERVAL.min(remaining));
+            paths = get_device_paths(interface_guid)?;
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(
+            "Error: No active device interfaces found.  Is the sample driver loaded?".into(),
+        );
+    }
+
+    Ok(paths)
+}
+
+pub fn print_device_paths(paths: &[Vec<u16>]) {
+    for (index, path) in paths.iter().enumerate() {
+        println!("    {index}: {}", display_path(path));
+    }
+}
+
+/// Printable form of `path`, a null-terminated UTF-16 device path. It is only
+/// used for messages, so unpaired surrogates are replaced rather than failing.
+pub fn display_path(path: &[u16]) -> String {
+    let length = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+    String::from_utf16_lossy(&path[..length])
+}
+
+/// Returns the path of every present device interface of class
+/// `interface_guid`, in the order they are returned by
+/// `CM_Get_Device_Interface_ListW`. The list is empty when there is none.
+///
+/// The paths are kept as null-terminated UTF-16, the form `CreateFileW` takes,
+/// so that a path that is not valid Unicode can still be opened.
+fn get_device_paths(interface_guid: &Uuid) -> Result<Vec<Vec<u16>>, Box<dyn Error>> {
+    let mut guid = windows_sys::core::GUID {
+        data1: 0,
+        data2: 0,
+        data3: 0,
+        data4: [0, 0, 0, 0, 0, 0, 0, 0],
+    };
+    let guid_data4: &[u8; 8];
+    let mut device_interface_list_length: u32 = 0;
+    let mut config_ret;
+
+    (guid.data1, guid.data2, guid.data3, guid_data4) = interface_guid.as_fields();
+    guid.data4 = *guid_data4;
+
+    // SAFETY:
+    // Call Win32 API FFI CM_Get_Device_Interface_List_SizeW to determine size of
+    // space needed for a subsequent request
+    unsafe {
+        config_ret = DeviceAndDriverInstallation::CM_Get_Device_Interface_List_SizeW(
+            &mut device_interface_list_length,
+            &guid,
+            std::ptr::null(),
+            DeviceAndDriverInstallation::CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+        );
+    }
+
+    if config_ret != DeviceAndDriverInstallation::CR_SUCCESS {
+        return Err(
+            format!("Error 0x{config_ret:08X} retrieving device interface list size.").into(),
+        );
+    }
+
+    // An empty list is a single null terminator
+    if device_interface_list_length <= 1 {
+        return Ok(Vec::new());
+    }
+
+    let mut buffer: Vec<u16> = vec![0; usize::try_from(device_interface_list_length).unwrap()];
+    let buffer_ptr = buffer.as_mut_ptr();
+
+    // SAFETY:
+    // Call Win32 API FFI CM_Get_Device_Interface_ListW to get the list of Device
+    // Interfaces that match the Interface GUID for the echo driver
+    unsafe {
+        config_ret = DeviceAndDriverInstallation::CM_Get_Device_Interface_ListW(
+            &guid,
+            std::ptr::null(),
+            buffer_ptr,
+            device_interface_list_length,
+            DeviceAndDriverInstallation::CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+        );
+    }
+
+    if config_ret != DeviceAndDriverInstallation::CR_SUCCESS {
+        return Err(format!("Error 0x{config_ret:08X} retrieving device interface list.").into());
+    }
+
+    // Terminate each path again for CreateFileW
+    let paths = MultiSz::new(&buffer)
+        .map(|path| path.iter().copied().chain(iter::once(0)).collect())
+        .collect();
+
+    Ok(paths)
+}
+
+/// Iterator over the strings of a Win32 multi-string, such as the list of
+/// `CM_Get_Device_Interface_ListW`: null-terminated UTF-16 strings one after
+/// the other, ended by an empty string, i.e. a second null terminator.
+///
+/// Each string is yielded without its null terminator, and as UTF-16 rather
+/// than a `String`, so that a device path that is not valid Unicode can still
+/// be opened. The iteration ends at the first empty string, or at the end of
+/// the buffer if it is missing its final terminator, so an empty list, a
+/// buffer of a single null, yields nothing.
+struct MultiSz<'a> {
+    remaining: &'a [u16],
+}
+
+impl<'a> MultiSz<'a> {
+    /// Iterates over the strings of the multi-string in `buffer`
+    const fn new(buffer: &'a [u16]) -> Self {
+        Self { remaining: buffer }
+    }
+}
+
+impl<'a> Iterator for MultiSz<'a> {
+    type Item = &'a [u16];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = self
+            .remaining
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.remaining.len());
+        if length == 0 {
+            // The terminating empty string, or the end of the buffer
+            self.remaining = &[];
+            return None;
+        }
+
+        let (string, rest) = self.remaining.split_at(length);
+        // Skip the null terminator of the string, if it has one
+        self.remaining = rest.get(1..).unwrap_or(&[]);
+        Some(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn multi_sz_empty_list() {
+        assert_eq!(MultiSz::new(&[0]).count(), 0);
+        assert_eq!(MultiSz::new(&[]).count(), 0);
+    }
+
+    #[test]
+    fn multi_sz_single_string() {
+        let buffer = utf16("a\0\0");
+        let strings: Vec<&[u16]> = MultiSz::new(&buffer).collect();
+        assert_eq!(strings, vec![utf16("a").as_slice()]);
+    }
+
+    #[test]
+    fn multi_sz_several_strings() {
+        let buffer = utf16("a\0bc\0\0");
+        let strings: Vec<&[u16]> = MultiSz::new(&buffer).collect();
+        assert_eq!(strings, vec![utf16("a").as_slice(), utf16("bc").as_slice()]);
+    }
+
+    #[test]
+    fn multi_sz_stops_at_double_null() {
+        // What follows the terminating empty string is not part of the list
+        let buffer = utf16("a\0\0stale\0\0");
+        let mut strings = MultiSz::new(&buffer);
+        assert_eq!(strings.next(), Some(utf16("a").as_slice()));
+        assert_eq!(strings.next(), None);
+        assert_eq!(strings.next(), None);
+    }
+
+    #[test]
+    fn multi_sz_missing_final_terminator() {
+        let buffer = utf16("a\0bc");
+        let strings: Vec<&[u16]> = MultiSz::new(&buffer).collect();
+        assert_eq!(strings, vec![utf16("a").as_slice(), utf16("bc").as_slice()]);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! Reading the state of the default queue of a debug driver built with the
+//! `queue-diagnostics` feature, for `-QueueState`.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GetLastError, FALSE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x807, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a debug driver built with the `queue-diagnostics` feature
+static IOCTL_ECHO_GET_QUEUE_STATE: u32 = 0x0022_201C;
+// RequestState of the current request reported by IOCTL_ECHO_GET_QUEUE_STATE
+// while it is idle or waiting for the timer
+pub static REQUEST_STATE_IDLE: u32 = 0;
+pub static REQUEST_STATE_PENDING: u32 = 1;
+
+/// State of the default queue returned by `IOCTL_ECHO_GET_QUEUE_STATE`, with
+/// the layout of `EchoQueueState` in the driver
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct QueueState {
+    pub pending_requests: u32,
+    /// `RequestState` of the current request: 0 idle, 1 pending, 2 completing,
+    /// 3 cancelled. Always 0 with a parallel queue.
+    pub current_request_state: u32,
+    pub ownership_count: i32,
+    pub current_status: i32,
+}
+
+/// Asks a debug driver built with the `queue-diagnostics` feature for the
+/// state of its default queue, with `IOCTL_ECHO_GET_QUEUE_STATE`, through a
+/// synchronous handle of its own.
+pub fn query_queue_state(path: &[u16]) -> Result<QueueState, Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let mut state = QueueState::default();
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to retrieve the state. state outlives
+    // the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_GET_QUEUE_STATE,
+            std::ptr::null(),
+            0,
+            std::ptr::addr_of_mut!(state).cast(),
+            u32::try_from(std::mem::size_of_val(&state))?,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    let result = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        Err(format!(
+            "QueryQueueState: DeviceIoControl failed: Error {error}. Is the driver a debug build \
+             with `queue-diagnostics`?"
+        )
+        .into())
+    } else if bytes_returned as usize != std::mem::size_of_val(&state) {
+        Err(format!("QueryQueueState: Driver returned {bytes_returned} bytes").into())
+    } else {
+        Ok(state)
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
@@ -0,0 +1,165 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-Fault`: injecting a failure in a driver built with the `fault-injection`
+//! feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle,
+        GetLastError,
+        ERROR_NO_SYSTEM_RESOURCES,
+        FALSE,
+        HANDLE,
+        INVALID_HANDLE_VALUE,
+        NTSTATUS,
+        STATUS_INSUFFICIENT_RESOURCES,
+    },
+    Storage::FileSystem::{
+        CreateFileW,
+        WriteFile,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+use crate::create_pattern_buffer;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a driver built with the `fault-injection` feature
+static IOCTL_ECHO_INJECT_FAULT: u32 = 0x0022_2000;
+
+/// Asks a driver built with the `fault-injection` feature to fail the next
+/// request with `STATUS_INSUFFICIENT_RESOURCES`, then checks that the next
+/// write fails with the matching Win32 error, and that the one after it
+/// succeeds again, since an injected fault only fails one request.
+pub fn perform_fault_injection_test(path: &[u16], test_length: u32) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result = inject_fault_and_write(h_device, &write_buffer);
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+fn inject_fault_and_write(h_device: HANDLE, write_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+    let injected_status: NTSTATUS = STATUS_INSUFFICIENT_RESOURCES;
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to send the status to inject to the
+    // driver. injected_status outlives the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_INJECT_FAULT,
+            std::ptr::addr_of!(injected_status).cast(),
+            u32::try_from(std::mem::size_of::<NTSTATUS>())?,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        return Err(
+            format!("PerformFaultInjectionTest: DeviceIoControl failed: Error {error}").into(),
+        );
+    }
+
+    println!("Injected status {injected_status:#010X}");
+
+    let test_length = u32::try_from(write_buffer.len())?;
+    for expect_fault in [true, false] {
+        let mut bytes_written: u32 = 0;
+
+        // SAFETY:
+        // Call Win32 API FFI WriteFile to write the pattern to the driver
+        let r = unsafe {
+            WriteFile(
+                h_device,
+                write_buffer.as_ptr().cast(),
+                test_length,
+                &mut bytes_written,
+                std::ptr::null_mut(),
+            )
+        };
+
+        let error = if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+            unsafe { GetLastError() }
+        } else {
+            0
+        };
+
+        match (expect_fault, r == FALSE) {
+            (true, true) if error == ERROR_NO_SYSTEM_RESOURCES => {
+                println!("Write failed with the injected status as expected");
+            }
+            (true, _) => {
+                return Err(format!(
+                    "PerformFaultInjectionTest: Write did not fail with the injected status: \
+                     Error {error}, SB {ERROR_NO_SYSTEM_RESOURCES}"
+                )
+                .into());
+            }
+            (false, true) => {
+                return Err(format!(
+                    "PerformFaultInjectionTest: Write after the fault failed: Error {error}"
+                )
+                .into());
+            }
+            (false, false) if bytes_written != test_length => {
+                return Err(format!(
+                    "Write after the fault: Written {bytes_written}, SB {test_length}"
+                )
+                .into());
+            }
+            (false, false) => {
+                println!(
+                    "Write after the fault: {bytes_written} Pattern Bytes Written successfully"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
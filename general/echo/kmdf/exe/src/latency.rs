@@ -0,0 +1,104 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `--stats`: how long requests stayed in a driver built with the
+//! `latency-stats` feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GetLastError, FALSE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a driver built with the `latency-stats` feature
+static IOCTL_ECHO_GET_LATENCY_STATS: u32 = 0x0022_2008;
+
+/// Latency statistics returned by `IOCTL_ECHO_GET_LATENCY_STATS`, with the
+/// layout of `EchoLatencyStats` in the driver
+#[repr(C)]
+#[derive(Default, Debug)]
+struct LatencyStats {
+    count: u64,
+    min_us: u64,
+    max_us: u64,
+    average_us: u64,
+}
+
+/// Asks a driver built with the `latency-stats` feature how long the requests
+/// it completed stayed in it, with `IOCTL_ECHO_GET_LATENCY_STATS`, and prints
+/// the statistics.
+pub fn print_latency_stats(path: &[u16]) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let mut stats = LatencyStats::default();
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to retrieve the statistics. stats
+    // outlives the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_GET_LATENCY_STATS,
+            std::ptr::null(),
+            0,
+            std::ptr::addr_of_mut!(stats).cast(),
+            u32::try_from(std::mem::size_of_val(&stats))?,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    let result = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        Err(format!("PrintLatencyStats: DeviceIoControl failed: Error {error}").into())
+    } else if bytes_returned as usize != std::mem::size_of_val(&stats) {
+        Err(format!("PrintLatencyStats: Driver returned {bytes_returned} bytes").into())
+    } else {
+        println!("Requests completed: {}", stats.count);
+        println!("Min latency: {} us", stats.min_us);
+        println!("Max latency: {} us", stats.max_us);
+        println!("Average latency: {} us", stats.average_us);
+        Ok(())
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
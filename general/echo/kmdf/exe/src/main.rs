@@ -27,20 +27,38 @@
 #![deny(rustdoc::unescaped_backticks)]
 #![deny(rustdoc::redundant_explicit_links)]
 
-use std::{env, error::Error, ffi::OsString, os::windows::prelude::*, sync::RwLock, thread};
+mod async_io;
+mod bench;
+mod device_path;
+mod diagnostics;
+mod fault_injection;
+mod latency;
+mod memory_pressure;
+mod method_neither;
+mod partial_reads;
+mod pending_limit;
+mod transform;
+mod unknown_ioctl;
+
+use std::{env, error::Error, iter, sync::RwLock, thread, time::Duration};
 
 use once_cell::sync::Lazy;
 use uuid::{uuid, Uuid};
 use windows_sys::Win32::{
-    Devices::DeviceAndDriverInstallation,
     Foundation::{
         CloseHandle,
         GetLastError,
         BOOL,
+        ERROR_ACCESS_DENIED,
+        ERROR_BUSY,
         ERROR_IO_PENDING,
+        ERROR_OPERATION_ABORTED,
+        ERROR_SHARING_VIOLATION,
         FALSE,
         HANDLE,
         INVALID_HANDLE_VALUE,
+        TRUE,
+        WAIT_TIMEOUT,
     },
     Storage::FileSystem::{
         CreateFileW,
@@ -54,64 +72,156 @@
         OPEN_EXISTING,
     },
     System::{
-        Threading::INFINITE,
-        IO::{CreateIoCompletionPort, GetQueuedCompletionStatus, OVERLAPPED, OVERLAPPED_0},
+        Threading::{CreateEventW, WaitForSingleObject},
+        IO::{CancelIoEx, DeviceIoControl, GetOverlappedResult, OVERLAPPED, OVERLAPPED_0},
     },
 };
 
+use crate::{
+    async_io::{async_io_work, set_console_ctrl_handler},
+    bench::{perform_benchmark, perform_stress_test},
+    device_path::{display_path, get_device_path, print_device_paths, wait_for_device_paths},
+    diagnostics::{query_queue_state, REQUEST_STATE_IDLE, REQUEST_STATE_PENDING},
+    fault_injection::perform_fault_injection_test,
+    latency::print_latency_stats,
+    memory_pressure::perform_allocation_failure_test,
+    method_neither::perform_method_neither_test,
+    partial_reads::{perform_drain_read_test, perform_oversized_read_test},
+    pending_limit::perform_pending_limit_test,
+    transform::perform_transform_round_trip_test,
+    unknown_ioctl::perform_unknown_control_code_test,
+};
+
 #[derive(Default, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 struct Globals {
-    perform_async_io: bool,
-    limited_loops: bool,
-    async_io_loops_num: usize,
-    device_path: String,
+    instance: usize,
+    timeout_ms: Option<u32>,
+    wait_ms: Option<u32>,
+    access_mode: AccessMode,
+    exclusive: bool,
+    sequence_numbers: bool,
+    last_sequence_number: Option<u64>,
+    /// Null-terminated UTF-16 path of the device, as passed to `CreateFileW`,
+    /// or empty until it is known
+    device_path: Vec<u16>,
+}
+
+/// Test run by the app, or information it prints, selected by the arguments
+/// other than the common options. Only one can be given.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Single write and read requests sent synchronously, or the access test
+    /// of `--read-only` and `--write-only`
+    #[default]
+    WriteRead,
+    /// `-Async`, forever or for the given number of reads and writes
+    AsyncIo { loops: Option<usize> },
+    /// `-Cancel`
+    Cancel,
+    /// `-QueueState`
+    QueueState,
+    /// `-Pipeline`
+    Pipeline,
+    /// `-Fault`
+    Fault,
+    /// `-MemoryPressure`
+    MemoryPressure,
+    /// `-Neither`
+    Neither,
+    /// `-Backpressure`
+    Backpressure,
+    /// `-PartialRead`
+    PartialRead,
+    /// `-Drain`
+    Drain,
+    /// `-Transform`
+    Transform,
+    /// `-BadIoctl`
+    BadIoctl,
+    /// `--bench`
+    Bench { round_trips: usize },
+    /// `--threads`
+    Stress { threads: usize },
+    /// `--version`
+    Version,
+    /// `--stats`
+    Stats,
+    /// `--list`
+    List,
+}
+
+/// Access the device handle of the synchronous test is opened with, changed by
+/// `--read-only` and `--write-only`
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+enum AccessMode {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
+impl AccessMode {
+    /// Desired access to open the device with
+    const fn desired_access(self) -> u32 {
+        match self {
+            Self::ReadWrite => FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            Self::ReadOnly => FILE_GENERIC_READ,
+            Self::WriteOnly => FILE_GENERIC_WRITE,
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::ReadWrite => "read and write",
+            Self::ReadOnly => "read-only",
+            Self::WriteOnly => "write-only",
+        }
+    }
 }
 
 static GLOBAL_DATA: Lazy<RwLock<Globals>> = Lazy::new(|| RwLock::new(Globals::default()));
 static GUID_DEVINTERFACE_ECHO: Uuid = uuid!("CDC35B6E-0BE4-4936-BF5F-5537380A7C1A");
-static READER_TYPE: u32 = 1;
-static WRITER_TYPE: u32 = 2;
-static NUM_ASYNCH_IO: usize = 100;
 static BUFFER_SIZE: usize = 40 * 1024;
-
+static CANCEL_DELAY: Duration = Duration::from_millis(500);
+static SEQUENCE_NUMBER_LENGTH: u32 = 8;
+static BENCH_ROUND_TRIPS: usize = 100;
+static BENCH_LENGTH: u32 = 4 * 1024;
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS)
+static IOCTL_ECHO_GET_WDF_VERSION: u32 = 0x0022_2004;
+// Length of the write the drain test reads back in pieces, and of each read
+static DRAIN_WRITE_LENGTH: u32 = 30 * 1024;
+static DRAIN_READ_LENGTH: u32 = 8 * 1024;
+// Each test mode adds its own branch
+#[allow(clippy::too_many_lines)]
 fn main() -> Result<(), Box<dyn Error>> {
-    let argument_vector: Vec<String> = env::args().collect();
-    let argument_count = argument_vector.len();
+    let mut argument_vector: Vec<String> = env::args().collect();
 
-    if argument_count > 1 {
-        if argument_vector[1] == "-Async" {
-            let mut globals = GLOBAL_DATA.write()?;
-            globals.perform_async_io = true;
-            if argument_count > 2 {
-                globals.async_io_loops_num = argument_vector[2].parse::<usize>()?;
-                globals.limited_loops = true;
-            } else {
-                globals.limited_loops = false;
-            }
-        } else {
-            eprintln!(
-                r"
-Usage:
-    Echoapp.exe         --- Send single write and read request synchronously
-    Echoapp.exe -Async  --- Send reads and writes asynchronously without terminating
-    Echoapp.exe -Async <number> --- Send <number> reads and writes asynchronously
-Exit the app anytime by pressing Ctrl-C
-"
-            );
-            return Err("Invalid Args".into());
-        }
+    take_common_options(&mut argument_vector)?;
+
+    let mode = parse_mode(&argument_vector)?;
+    check_mode_options(mode)?;
+
+    if mode == Mode::List {
+        let paths = wait_for_device_paths(&GUID_DEVINTERFACE_ECHO, 0)?;
+        println!("Found {} echo device interfaces:", paths.len());
+        print_device_paths(&paths);
+        return Ok(());
     }
 
-    get_device_path(&GUID_DEVINTERFACE_ECHO)?;
+    if GLOBAL_DATA.read()?.device_path.is_empty() {
+        get_device_path(&GUID_DEVINTERFACE_ECHO)?;
+    }
 
     let globals = GLOBAL_DATA.read()?;
-    println!("DevicePath: {}", globals.device_path);
-    let mut path_vec = globals.device_path.encode_utf16().collect::<Vec<_>>();
-    let perform_async_io = globals.perform_async_io;
+    println!("DevicePath: {}", display_path(&globals.device_path));
+    let path_vec = globals.device_path.clone();
+    let timeout_ms = globals.timeout_ms;
+    let access_mode = globals.access_mode;
+    let exclusive = globals.exclusive;
     drop(globals);
 
     let h_device: HANDLE;
-    path_vec.push(0);
     let path = path_vec.as_ptr();
 
     // SAFETY:
@@ -119,48 +229,121 @@ fn main() -> Result<(), Box<dyn Error>> {
     unsafe {
         h_device = CreateFileW(
             path,
-            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
-            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            access_mode.desired_access(),
+            share_mode(exclusive),
             std::ptr::null(),
             OPEN_EXISTING,
-            0,
+            // Requests can only time out when they are overlapped
+            if timeout_ms.is_some() {
+                FILE_FLAG_OVERLAPPED
+            } else {
+                0
+            },
             0,
         );
     }
 
-    // SAFETY:
-    // Call Win32 API FFI GetLastError() to check for any errors
-    unsafe {
-        if h_device == INVALID_HANDLE_VALUE {
-            return Err(format!("Failed to open device. Error {}", GetLastError()).into());
-        }
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors
+        let error = unsafe { GetLastError() };
+        return Err(format!(
+            "Failed to open device with {} access, {}. Error {error}{}",
+            access_mode.name(),
+            share_mode_name(exclusive),
+            describe_open_error(error)
+        )
+        .into());
     }
 
-    println!("Opened device successfully");
+    println!(
+        "Opened device successfully with {} access, {}",
+        access_mode.name(),
+        share_mode_name(exclusive)
+    );
 
-    if perform_async_io {
-        println!("Starting AsyncIo");
+    if exclusive {
+        report_second_open(&path_vec, access_mode);
+    }
+
+    match mode {
+        Mode::AsyncIo { loops } => {
+            set_console_ctrl_handler()?;
 
-        let h =
-            thread::spawn(|| -> Result<(), Box<dyn Error + Send + Sync>> { async_io(READER_TYPE) });
+            println!("Starting AsyncIo");
 
-        // Because async_io error requires Send + Sync but this function does not,
-        // cannot use ? operator
-        #[allow(clippy::question_mark)]
-        if let Err(e) = async_io(WRITER_TYPE) {
-            return Err(e);
+            async_io_work(loops)?;
+        }
+        Mode::Cancel => perform_cancel_read_test(&path_vec, 512, false)?,
+        Mode::QueueState => perform_cancel_read_test(&path_vec, 512, true)?,
+        Mode::Pipeline => perform_pipelined_write_test(&path_vec, 512)?,
+        Mode::Fault => perform_fault_injection_test(&path_vec, 512)?,
+        Mode::MemoryPressure => perform_allocation_failure_test(&path_vec, 512)?,
+        Mode::Neither => perform_method_neither_test(h_device, 512)?,
+        Mode::Backpressure => perform_pending_limit_test(&path_vec, 512)?,
+        Mode::PartialRead => perform_oversized_read_test(&path_vec, 512)?,
+        Mode::Drain => {
+            perform_drain_read_test(&path_vec, DRAIN_WRITE_LENGTH, DRAIN_READ_LENGTH)?;
+        }
+        Mode::Transform => perform_transform_round_trip_test(&path_vec, 512)?,
+        Mode::BadIoctl => perform_unknown_control_code_test(&path_vec)?,
+        Mode::Bench { round_trips } => perform_benchmark(&path_vec, round_trips, BENCH_LENGTH)?,
+        Mode::Stress { threads } => perform_stress_test(&path_vec, threads, 512)?,
+        Mode::Version => print_driver_version(&path_vec)?,
+        Mode::Stats => print_latency_stats(&path_vec)?,
+        Mode::WriteRead if access_mode != AccessMode::ReadWrite => {
+            perform_access_mode_test(h_device, access_mode, 512)?;
         }
+        Mode::WriteRead => {
+            perform_zero_length_write_test(h_device, timeout_ms)?;
 
-        h.join().unwrap().unwrap();
-    } else {
-        perform_write_read_test(h_device, 512)?;
+            perform_write_read_test(h_device, 512, timeout_ms)?;
 
-        perform_write_read_test(h_device, 30 * 1024)?;
+            perform_write_read_test(h_device, 30 * 1024, timeout_ms)?;
+        }
+        Mode::List => unreachable!("--list returns before opening the device"),
     }
 
     Ok(())
 }
 
+fn print_usage() {
+    eprintln!(
+        r"
+Usage:
+    Echoapp.exe         --- Send single write and read request synchronously
+    Echoapp.exe -Async  --- Send reads and writes asynchronously without terminating
+    Echoapp.exe -Async <number> --- Send <number> reads and writes asynchronously
+    Echoapp.exe -Cancel --- Send a read and cancel it before the driver completes it
+    Echoapp.exe -Pipeline --- Send two writes at once and check that both complete
+    Echoapp.exe -Fault  --- Inject a failure in a driver built with `fault-injection` and check a write fails with it
+    Echoapp.exe -MemoryPressure --- Fail an allocation in a driver built with `memory-pressure` and check it recovers
+    Echoapp.exe -Neither --- Echo a buffer through the METHOD_NEITHER control code of a driver built with `method-neither`
+    Echoapp.exe -Backpressure --- Check that a driver built with `pending-limit` fails the reads beyond its limit with ERROR_BUSY
+    Echoapp.exe -PartialRead --- Check that reads longer than the data written return exactly the data available
+    Echoapp.exe -Drain  --- Read back a large write in pieces from a driver built with `partial-reads`
+    Echoapp.exe -Transform --- Check that a driver built with `transform` transforms the data written, by undoing each transform
+    Echoapp.exe -BadIoctl --- Send a control code the driver does not handle and check it fails with ERROR_INVALID_FUNCTION
+    Echoapp.exe -QueueState --- Run -Cancel, checking the queue state of a driver built with `queue-diagnostics` before and after the cancel
+    Echoapp.exe --bench [<number>] --- Time <number> (default 100) write and read round trips and print key=value statistics
+    Echoapp.exe --threads <number> --- Run 100 write and read round trips on each of <number> threads with their own handle at once
+    Echoapp.exe --list  --- List the paths of all echo device interfaces and exit
+    Echoapp.exe --version --- Print the version string of the driver and exit
+    Echoapp.exe --stats --- Print how long requests stayed in a driver built with `latency-stats` and exit
+    Echoapp.exe ... --instance <index> --- Use the <index>th echo device instead of the first
+    Echoapp.exe ... --name <name> --- Open \\.\<name> of a driver built with `named-device`, e.g. RustEcho
+    Echoapp.exe --timeout-ms <ms> --- Fail the synchronous test if a request takes longer than <ms>
+    Echoapp.exe ... --wait-ms <ms> --- Wait up to <ms> for the echo device interface to appear, e.g. while the driver starts
+    Echoapp.exe --read-only --- Open the device for reading only, and check that writes are denied
+    Echoapp.exe --write-only --- Open the device for writing only, and check that reads are denied
+    Echoapp.exe ... --exclusive --- Open the device without sharing it, and report whether a second handle can be opened
+    Echoapp.exe ... --sequence --- Check the sequence numbers of a driver built with `sequence-numbers`
+Only one mode can be given, and the options without ... only apply to the synchronous test
+Exit the app anytime by pressing Ctrl-C
+"
+    );
+}
+
 fn create_pattern_buffer(length: u32) -> Vec<u8> {
     let mut buf = Vec::<u8>::with_capacity(usize::try_from(length).unwrap());
     let mut val: u8 = 0;
@@ -173,6 +356,34 @@ fn create_pattern_buffer(length: u32) -> Vec<u8> {
     buf
 }
 
+/// Splits the sequence number that a driver built with the `sequence-numbers`
+/// feature puts in front of the echoed data off `buf`, and checks that it is
+/// greater than the one of the previous read. Returns the echoed data.
+fn verify_sequence_number(buf: &[u8]) -> Result<&[u8], Box<dyn Error>> {
+    if buf.len() < SEQUENCE_NUMBER_LENGTH as usize {
+        return Err(format!("Read of {} bytes has no sequence number", buf.len()).into());
+    }
+    let (sequence_number, data) = buf.split_at(SEQUENCE_NUMBER_LENGTH as usize);
+    let sequence_number = u64::from_le_bytes(sequence_number.try_into()?);
+
+    let last_sequence_number = GLOBAL_DATA
+        .write()?
+        .last_sequence_number
+        .replace(sequence_number);
+    if let Some(last_sequence_number) = last_sequence_number {
+        if sequence_number <= last_sequence_number {
+            return Err(format!(
+                "Sequence number went from {last_sequence_number} to {sequence_number}"
+            )
+            .into());
+        }
+    }
+
+    println!("Sequence number {sequence_number} verified");
+
+    Ok(data)
+}
+
 fn verify_pattern_buffer(buf: &[u8]) -> Result<(), Box<dyn Error>> {
     let mut check_value: u8 = 0;
     for val in buf {
@@ -188,398 +399,628 @@ fn verify_pattern_buffer(buf: &[u8]) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn perform_write_read_test(h_device: HANDLE, test_length: u32) -> Result<(), Box<dyn Error>> {
-    let write_buffer = create_pattern_buffer(test_length);
-    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(test_length).unwrap()];
-
-    let mut r: BOOL;
-    let mut bytes_returned: u32 = 0;
+/// Writes `write_buffer` to the driver with the synchronous handle `h_device`
+fn write_pattern(h_device: HANDLE, write_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+    let test_length = u32::try_from(write_buffer.len())?;
+    let mut bytes_written: u32 = 0;
 
     // SAFETY:
-    // Call Win32 API FFI WriteFile to write buffer to the driver
-    unsafe {
-        r = WriteFile(
+    // Call Win32 API FFI WriteFile to write the pattern to the driver
+    let r = unsafe {
+        WriteFile(
             h_device,
             write_buffer.as_ptr().cast(),
-            u32::try_from(write_buffer.len()).unwrap(),
-            &mut bytes_returned,
-            std::ptr::null_mut(),
-        );
-    }
-
-    // SAFETY:
-    // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
-    unsafe {
-        if r == FALSE {
-            return Err(format!(
-                "PerformWriteReadTest: WriteFile failed: Error {}",
-                GetLastError()
-            )
-            .into());
-        }
-    }
-
-    if bytes_returned != test_length {
-        return Err(format!(
-            "bytes written is not test length! Written {bytes_returned}, SB {test_length}"
-        )
-        .into());
-    }
-
-    println!("{bytes_returned} Pattern Bytes Written successfully");
-
-    bytes_returned = 0;
-
-    // SAFETY:
-    // Call Win32 API FFI ReadFile to read data from the driver
-    unsafe {
-        r = ReadFile(
-            h_device,
-            read_buffer.as_mut_ptr().cast(),
             test_length,
-            &mut bytes_returned,
+            &mut bytes_written,
             std::ptr::null_mut(),
-        );
-    }
-
-    // SAFETY:
-    // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
-    unsafe {
-        if r == FALSE {
-            return Err(format!(
-                "PerformWriteReadTest: ReadFile failed: Error {}",
-                GetLastError()
-            )
-            .into());
-        }
-    }
+        )
+    };
 
-    // SAFETY:
-    // Call set_len on the Vec that contains the buffer used in ReadFile to tell the
-    // Vec how many bytes were actually put into the Vec
-    unsafe {
-        read_buffer.set_len(usize::try_from(bytes_returned).unwrap());
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+        let error = unsafe { GetLastError() };
+        return Err(format!("WriteFile failed: Error {error}").into());
     }
 
-    if bytes_returned != test_length {
-        return Err(format!(
-            "bytes Read is not test length! Read {bytes_returned}, SB {test_length}"
-        )
-        .into());
+    if bytes_written != test_length {
+        return Err(format!("Written {bytes_written}, SB {test_length}").into());
     }
 
-    println!("{bytes_returned} Pattern Bytes Read successfully");
-
-    verify_pattern_buffer(&read_buffer)?;
-
-    println!("Pattern Verified successfully\n");
-
+    println!("{bytes_written} Pattern Bytes Written successfully");
     Ok(())
 }
 
-fn async_io(thread_parameter: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match async_io_work(thread_parameter) {
-        Err(e) => Err(e.to_string().into()),
-        Ok(()) => Ok(()),
+/// Share mode to open the device with, none if `exclusive`
+const fn share_mode(exclusive: bool) -> u32 {
+    if exclusive {
+        0
+    } else {
+        FILE_SHARE_READ | FILE_SHARE_WRITE
     }
 }
 
-// In order to keep this function close to the original WDK app, ignoring large
-// function warning
-#[allow(clippy::too_many_lines)]
-fn async_io_work(io_type: u32) -> Result<(), Box<dyn Error>> {
-    let globals = GLOBAL_DATA.read()?;
+const fn share_mode_name(exclusive: bool) -> &'static str {
+    if exclusive {
+        "not shared"
+    } else {
+        "shared for reading and writing"
+    }
+}
 
-    let h_device: HANDLE;
-    let h_completion_port: HANDLE;
-    let mut r: BOOL;
+/// Explains the errors `CreateFileW`, `ReadFile` and `WriteFile` fail with when
+/// the access or sharing of the handle does not allow the operation. A driver
+/// built with `restricted-access` also denies opening the device to anyone but
+/// administrators and the system.
+const fn describe_open_error(error: u32) -> &'static str {
+    match error {
+        ERROR_ACCESS_DENIED => {
+            " (access denied, by the access of the handle or by the security descriptor of the \
+             device)"
+        }
+        ERROR_SHARING_VIOLATION => " (sharing violation)",
+        _ => "",
+    }
+}
 
+/// Tries to open a second handle to the device while the first one, opened
+/// with `--exclusive`, is still open, and reports the outcome. The I/O manager
+/// only enforces the share mode for file systems and exclusive devices, so
+/// whether the open is refused depends on how the driver configures its device
+/// and handles `IRP_MJ_CREATE`.
+fn report_second_open(path: &[u16], access_mode: AccessMode) {
     // SAFETY:
-    // Call Win32 API FFI CreateFileW to access driver
-    unsafe {
-        let mut path_vec = globals.device_path.encode_utf16().collect::<Vec<_>>();
-        path_vec.push(0);
-        let path = path_vec.as_ptr();
-
-        h_device = CreateFileW(
-            path,
-            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
-            FILE_SHARE_READ | FILE_SHARE_WRITE,
+    // Call Win32 API FFI CreateFileW to access driver a second time
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            access_mode.desired_access(),
+            share_mode(false),
             std::ptr::null(),
             OPEN_EXISTING,
-            FILE_FLAG_OVERLAPPED,
             0,
-        );
-    }
+            0,
+        )
+    };
 
-    // SAFETY:
-    // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
-    unsafe {
-        if h_device == INVALID_HANDLE_VALUE {
-            return Err(format!(
-                "Cannot open {} error {}",
-                globals.device_path,
-                GetLastError()
-            )
-            .into());
-        }
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        println!(
+            "Second handle refused while the first is not shared: Error {error}{}",
+            describe_open_error(error)
+        );
+        return;
     }
 
-    // SAFETY:
-    // Call Win32 API FFI CreateIoCompletionPort to get handle for completing async
-    // requests
-    unsafe {
-        h_completion_port = CreateIoCompletionPort(h_device, 0, 1, 0);
-    }
+    println!("Second handle opened although the first is not shared");
 
     // SAFETY:
-    // Call Win32 API FFI to check for CreateIoCompletionPort result from
-    // GetLastError()
+    // Call Win32 API FFI CloseHandle to close the second device handle
     unsafe {
-        // CreateIoCompletionPort returns NULL on failure, not INVALID_HANDLE_VALUE
-        if h_completion_port == 0 {
-            return Err(format!("Cannot open completion port {}", GetLastError()).into());
-        }
-    }
-
-    let mut remaining_requests_to_receive = 0;
-    let mut max_pending_requests = NUM_ASYNCH_IO;
-    let mut remaining_requests_to_send = 0;
-    if globals.limited_loops {
-        remaining_requests_to_receive = globals.async_io_loops_num;
-        if globals.async_io_loops_num > NUM_ASYNCH_IO {
-            max_pending_requests = NUM_ASYNCH_IO;
-            remaining_requests_to_send = globals.async_io_loops_num - NUM_ASYNCH_IO;
-        } else {
-            max_pending_requests = globals.async_io_loops_num;
-            remaining_requests_to_send = 0;
-        }
+        CloseHandle(h_device);
     }
+}
 
-    let mut ov_list: Vec<OVERLAPPED> = vec![
-        OVERLAPPED {
-            Internal: 0,
-            InternalHigh: 0,
-            Anonymous: OVERLAPPED_0 {
-                Pointer: std::ptr::null_mut(),
-            },
-            hEvent: 0,
-        };
-        max_pending_requests
-    ];
-    let mut buf: Vec<u8> = vec![0; max_pending_requests * BUFFER_SIZE];
-
-    for i in 0..max_pending_requests {
-        // SAFETY:
-        // Get the offset into the buffer for sending data at offset for request 'i'
-        let buffer_offset = unsafe {
-            (buf.as_mut_ptr()
-                .offset(isize::try_from(i * BUFFER_SIZE).unwrap()))
-            .cast()
-        };
+/// Sends a write and a read of `test_length` bytes on `h_device`, a synchronous
+/// handle opened with `--read-only` or `--write-only`, and checks that the
+/// request the handle has no access for fails with `ERROR_ACCESS_DENIED`, while
+/// the other one succeeds. The I/O manager checks the access of the handle
+/// before the driver sees the request. The read returns whatever data the
+/// driver has, possibly none.
+fn perform_access_mode_test(
+    h_device: HANDLE,
+    access_mode: AccessMode,
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+    let mut read_buffer: Vec<u8> = vec![0; write_buffer.len()];
 
-        // SAFETY:
-        // Get the pointer for the list of Overlapped array for ReadFile at the offset
-        // for request 'i'
-        let overlap_struct_offset =
-            unsafe { ov_list.as_mut_ptr().offset(isize::try_from(i).unwrap()) };
+    for (operation, allowed) in [
+        ("Write", access_mode != AccessMode::ReadOnly),
+        ("Read", access_mode != AccessMode::WriteOnly),
+    ] {
+        let mut bytes_transferred: u32 = 0;
 
-        if io_type == READER_TYPE {
+        let r = if operation == "Write" {
             // SAFETY:
-            // Call Win32 API FFI ReadFile to read from driver with an overlap option
+            // Call Win32 API FFI WriteFile to write the pattern to the driver
             unsafe {
-                r = ReadFile(
+                WriteFile(
                     h_device,
-                    buffer_offset,
-                    u32::try_from(BUFFER_SIZE).unwrap(),
+                    write_buffer.as_ptr().cast(),
+                    test_length,
+                    &mut bytes_transferred,
                     std::ptr::null_mut(),
-                    overlap_struct_offset,
-                );
-            }
-
-            // SAFETY:
-            // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
-            unsafe {
-                if r == FALSE {
-                    let error = GetLastError();
-                    if error != ERROR_IO_PENDING {
-                        return Err(format!("{i}th Read failed {error}").into());
-                    }
-                }
+                )
             }
         } else {
             // SAFETY:
-            // Call Win32 API FFI WriteFile to write to driver with an overlap option
+            // Call Win32 API FFI ReadFile to read whatever data the driver has
             unsafe {
-                let mut number_of_bytes_written: u32 = 0;
-
-                r = WriteFile(
+                ReadFile(
                     h_device,
-                    buffer_offset,
-                    u32::try_from(BUFFER_SIZE).unwrap(),
-                    &mut number_of_bytes_written,
-                    overlap_struct_offset,
-                );
+                    read_buffer.as_mut_ptr().cast(),
+                    test_length,
+                    &mut bytes_transferred,
+                    std::ptr::null_mut(),
+                )
             }
+        };
 
+        let error = if r == FALSE {
             // SAFETY:
-            // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
-            unsafe {
-                if r == FALSE {
-                    let error = GetLastError();
-                    if error != ERROR_IO_PENDING {
-                        return Err(format!("{i}th Write failed {error}").into());
-                    }
-                }
+            // Call Win32 API FFI GetLastError() to check for any errors from the
+            // request
+            unsafe { GetLastError() }
+        } else {
+            0
+        };
+
+        match (allowed, r == FALSE) {
+            (true, false) => {
+                println!(
+                    "{operation} on a {} handle: {bytes_transferred} bytes transferred",
+                    access_mode.name()
+                );
+            }
+            (false, true) if error == ERROR_ACCESS_DENIED => {
+                println!(
+                    "{operation} on a {} handle denied as expected",
+                    access_mode.name()
+                );
+            }
+            (true, true) => {
+                return Err(format!(
+                    "PerformAccessModeTest: {operation} on a {} handle failed: Error {error}{}",
+                    access_mode.name(),
+                    describe_open_error(error)
+                )
+                .into());
+            }
+            (false, _) => {
+                return Err(format!(
+                    "PerformAccessModeTest: {operation} on a {} handle was not denied: Error \
+                     {error}, SB {ERROR_ACCESS_DENIED}",
+                    access_mode.name()
+                )
+                .into());
             }
         }
     }
 
-    loop {
-        let mut number_of_bytes_transferred = 0;
-        let mut key = 0;
-        let mut completed_ov_ptr: *mut OVERLAPPED = std::ptr::null_mut();
-
-        // SAFETY:
-        // Call Win32 API FFI GetQueuedCompletionStatus to access the status of the
-        // completion request
-        unsafe {
-            r = GetQueuedCompletionStatus(
-                h_completion_port,
-                &mut number_of_bytes_transferred,
-                &mut key,
-                std::ptr::addr_of_mut!(completed_ov_ptr),
-                INFINITE,
-            );
-        }
+    Ok(())
+}
 
-        // SAFETY:
-        // Call Win32 API FFI GetLastError() to check for any errors from
-        // GetQueuedCompletionStatus
-        unsafe {
-            if r == FALSE {
-                return Err(format!("GetQueuedCompletionStatus failed {}", GetLastError()).into());
+/// Sends a zero-length write, which must succeed without transferring any
+/// data, whether it is completed by the framework or, with the driver's
+/// `allow-zero-length-requests` feature, by the driver itself.
+fn perform_zero_length_write_test(
+    h_device: HANDLE,
+    timeout_ms: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let bytes_returned = if let Some(timeout_ms) = timeout_ms {
+        overlapped_io_with_timeout(h_device, timeout_ms, |overlapped| {
+            // SAFETY:
+            // Call Win32 API FFI WriteFile to send an empty write to the driver with
+            // an overlap option
+            unsafe {
+                WriteFile(
+                    h_device,
+                    std::ptr::null(),
+                    0,
+                    std::ptr::null_mut(),
+                    overlapped,
+                )
             }
-        }
-
-        let i;
+        })
+        .map_err(|error| format!("PerformZeroLengthWriteTest: WriteFile failed: {error}"))?
+    } else {
+        let mut bytes_written: u32 = 0;
 
         // SAFETY:
-        // Perform pointer math to determine which index 'i' to use by determining the
-        // offset of 'completed_ov_ptr' from the start of the array given by
-        // 'ov_list'
-        unsafe {
-            i = completed_ov_ptr.offset_from(ov_list.as_ptr());
+        // Call Win32 API FFI WriteFile to send an empty write to the driver
+        let r = unsafe {
+            WriteFile(
+                h_device,
+                std::ptr::null(),
+                0,
+                &mut bytes_written,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+            let error = unsafe { GetLastError() };
+            return Err(
+                format!("PerformZeroLengthWriteTest: WriteFile failed: Error {error}").into(),
+            );
         }
 
-        if io_type == READER_TYPE {
-            println!("Number of bytes read by request number {i} is {number_of_bytes_transferred}",);
+        bytes_written
+    };
 
-            if globals.limited_loops {
-                remaining_requests_to_receive -= 1;
-                if remaining_requests_to_receive == 0 {
-                    break;
-                }
+    if bytes_returned != 0 {
+        return Err(format!("Zero-length write returned {bytes_returned} bytes written").into());
+    }
 
-                if remaining_requests_to_send == 0 {
-                    continue;
-                }
+    println!("Zero-length write completed successfully\n");
 
-                remaining_requests_to_send -= 1;
-            }
+    Ok(())
+}
 
-            let buffer_offset;
+/// Writes a pattern to the driver and reads it back. If `timeout_ms` is set,
+/// `h_device` must have been opened with `FILE_FLAG_OVERLAPPED`, and each
+/// request fails if the driver does not complete it in time. With `--sequence`,
+/// the read is made large enough for the sequence number in front of the data.
+fn perform_write_read_test(
+    h_device: HANDLE,
+    test_length: u32,
+    timeout_ms: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+    let read_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
 
-            // SAFETY:
-            // Get the offset into the buffer for reading data at offset for request 'i'
-            unsafe {
-                buffer_offset = (buf
-                    .as_mut_ptr()
-                    .offset(i * isize::try_from(BUFFER_SIZE).unwrap()))
-                .cast();
-            }
+    let write_buffer = create_pattern_buffer(test_length);
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length).unwrap()];
 
+    let mut r: BOOL;
+    let mut bytes_returned: u32 = if let Some(timeout_ms) = timeout_ms {
+        overlapped_io_with_timeout(h_device, timeout_ms, |overlapped| {
             // SAFETY:
-            // Call Win32 API FFI ReadFile to read in data from the driver
+            // Call Win32 API FFI WriteFile to write buffer to the driver with an
+            // overlap option
             unsafe {
-                r = ReadFile(
+                WriteFile(
                     h_device,
-                    buffer_offset,
-                    u32::try_from(BUFFER_SIZE).unwrap(),
+                    write_buffer.as_ptr().cast(),
+                    u32::try_from(write_buffer.len()).unwrap(),
                     std::ptr::null_mut(),
-                    completed_ov_ptr,
-                );
+                    overlapped,
+                )
             }
+        })
+        .map_err(|error| format!("PerformWriteReadTest: WriteFile failed: {error}"))?
+    } else {
+        let mut bytes_written: u32 = 0;
 
-            // SAFETY:
-            // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
-            unsafe {
-                if r == FALSE {
-                    let error = GetLastError();
-                    if error != ERROR_IO_PENDING {
-                        return Err(format!("{i}th Read failed {error}").into());
-                    }
-                }
-            }
-        } else {
-            println!(
-                "Number of bytes written by request number {i} is {number_of_bytes_transferred}",
+        // SAFETY:
+        // Call Win32 API FFI WriteFile to write buffer to the driver
+        unsafe {
+            r = WriteFile(
+                h_device,
+                write_buffer.as_ptr().cast(),
+                u32::try_from(write_buffer.len()).unwrap(),
+                &mut bytes_written,
+                std::ptr::null_mut(),
             );
+        }
 
-            if globals.limited_loops {
-                remaining_requests_to_receive -= 1;
-                if remaining_requests_to_receive == 0 {
-                    break;
-                }
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+        unsafe {
+            if r == FALSE {
+                return Err(format!(
+                    "PerformWriteReadTest: WriteFile failed: Error {}",
+                    GetLastError()
+                )
+                .into());
+            }
+        }
 
-                if remaining_requests_to_send == 0 {
-                    continue;
-                }
+        bytes_written
+    };
 
-                remaining_requests_to_send -= 1;
-            }
+    if bytes_returned != test_length {
+        return Err(format!(
+            "bytes written is not test length! Written {bytes_returned}, SB {test_length}"
+        )
+        .into());
+    }
 
-            let buffer_offset;
+    println!("{bytes_returned} Pattern Bytes Written successfully");
 
-            // SAFETY:
-            // Get the offset into the buffer for sending data at offset for request 'i'
-            unsafe {
-                buffer_offset = (buf
-                    .as_mut_ptr()
-                    .offset(i * isize::try_from(BUFFER_SIZE).unwrap()))
-                .cast();
-            }
+    bytes_returned = 0;
 
+    if let Some(timeout_ms) = timeout_ms {
+        bytes_returned = overlapped_io_with_timeout(h_device, timeout_ms, |overlapped| {
             // SAFETY:
-            // Call Win32 API FFI WriteFile to write data to the driver
+            // Call Win32 API FFI ReadFile to read data from the driver with an
+            // overlap option
             unsafe {
-                r = WriteFile(
+                ReadFile(
                     h_device,
-                    buffer_offset,
-                    u32::try_from(BUFFER_SIZE).unwrap(),
+                    read_buffer.as_mut_ptr().cast(),
+                    read_length,
                     std::ptr::null_mut(),
-                    completed_ov_ptr,
-                );
+                    overlapped,
+                )
+            }
+        })
+        .map_err(|error| format!("PerformWriteReadTest: ReadFile failed: {error}"))?;
+    } else {
+        // SAFETY:
+        // Call Win32 API FFI ReadFile to read data from the driver
+        unsafe {
+            r = ReadFile(
+                h_device,
+                read_buffer.as_mut_ptr().cast(),
+                read_length,
+                &mut bytes_returned,
+                std::ptr::null_mut(),
+            );
+        }
+
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+        unsafe {
+            if r == FALSE {
+                return Err(format!(
+                    "PerformWriteReadTest: ReadFile failed: Error {}",
+                    GetLastError()
+                )
+                .into());
             }
+        }
+    }
+
+    // SAFETY:
+    // Call set_len on the Vec that contains the buffer used in ReadFile to tell the
+    // Vec how many bytes were actually put into the Vec
+    unsafe {
+        read_buffer.set_len(usize::try_from(bytes_returned).unwrap());
+    }
 
+    if bytes_returned != read_length {
+        return Err(format!(
+            "bytes Read is not test length! Read {bytes_returned}, SB {read_length}"
+        )
+        .into());
+    }
+
+    println!("{bytes_returned} Pattern Bytes Read successfully");
+
+    verify_pattern_buffer(if sequence_numbers {
+        verify_sequence_number(&read_buffer)?
+    } else {
+        &read_buffer
+    })?;
+
+    println!("Pattern Verified successfully\n");
+
+    Ok(())
+}
+
+/// Sends a single overlapped request with `issue_request` and waits up to
+/// `timeout_ms` for it to complete. A request that does not complete in time is
+/// cancelled with `CancelIoEx`, so a driver that never completes requests
+/// cannot hang the app.
+fn overlapped_io_with_timeout(
+    h_device: HANDLE,
+    timeout_ms: u32,
+    issue_request: impl FnOnce(*mut OVERLAPPED) -> BOOL,
+) -> Result<u32, Box<dyn Error>> {
+    let h_event: HANDLE;
+
+    // SAFETY:
+    // Call Win32 API FFI CreateEventW to create a manual reset event that is
+    // signalled when the request completes
+    unsafe {
+        h_event = CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null());
+    }
+
+    // CreateEventW returns NULL on failure, not INVALID_HANDLE_VALUE
+    if h_event == 0 {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // CreateEventW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to create event. Error {error}").into());
+    }
+
+    let mut overlapped = OVERLAPPED {
+        Internal: 0,
+        InternalHigh: 0,
+        Anonymous: OVERLAPPED_0 {
+            Pointer: std::ptr::null_mut(),
+        },
+        hEvent: h_event,
+    };
+
+    let r = issue_request(&mut overlapped);
+
+    let error = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from the
+        // request
+        unsafe { GetLastError() }
+    } else {
+        0
+    };
+
+    let result = if r == FALSE && error != ERROR_IO_PENDING {
+        Err(format!("Error {error}").into())
+    } else {
+        // SAFETY:
+        // Call Win32 API FFI WaitForSingleObject to wait for the request to
+        // complete, up to the timeout
+        let wait_result = unsafe { WaitForSingleObject(h_event, timeout_ms) };
+
+        if wait_result == WAIT_TIMEOUT {
             // SAFETY:
-            // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+            // Call Win32 API FFI CancelIoEx to cancel the request that timed out
             unsafe {
-                if r == FALSE {
-                    let error = GetLastError();
-                    if error != ERROR_IO_PENDING {
-                        return Err(format!("{i}th write failed {error}").into());
-                    }
-                }
+                CancelIoEx(h_device, &overlapped);
             }
+
+            // The buffer and OVERLAPPED must stay valid until the driver has
+            // completed the cancelled request
+            let _ = wait_for_overlapped_result(h_device, &overlapped);
+
+            Err(format!("Timed out after {timeout_ms} ms").into())
+        } else {
+            wait_for_overlapped_result(h_device, &overlapped)
+                .map_err(|(error, _)| format!("Error {error}").into())
         }
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close event handle
+    unsafe {
+        CloseHandle(h_event);
     }
-    drop(globals);
+
+    result
+}
+
+fn wait_for_overlapped_result(
+    h_device: HANDLE,
+    overlapped: &OVERLAPPED,
+) -> Result<u32, (u32, u32)> {
+    let mut bytes_transferred: u32 = 0;
+    let r: BOOL;
+
+    // SAFETY:
+    // Call Win32 API FFI GetOverlappedResult to wait for the request that was
+    // issued with 'overlapped' to complete
+    unsafe {
+        r = GetOverlappedResult(h_device, overlapped, &mut bytes_transferred, TRUE);
+    }
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to get the error the request completed
+        // with
+        let error = unsafe { GetLastError() };
+        return Err((error, bytes_transferred));
+    }
+
+    Ok(bytes_transferred)
+}
+
+/// Sends a write so that the driver has data to echo, then sends a read that
+/// the driver marks cancelable and holds until its timer fires. The read is
+/// cancelled from this app with `CancelIoEx` before the timer gets a chance to
+/// complete it, which makes the driver's `echo_evt_request_cancel` complete it
+/// with `STATUS_CANCELLED` (`ERROR_OPERATION_ABORTED` in user mode).
+///
+/// With `query_state`, the state of the queue of a driver built with the
+/// `queue-diagnostics` feature is checked while the read is held, and again
+/// once it has been cancelled.
+fn perform_cancel_read_test(
+    path: &[u16],
+    test_length: u32,
+    query_state: bool,
+) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(test_length).unwrap()];
+
+    let h_device: HANDLE;
+    let h_event: HANDLE;
+    let r: BOOL;
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver
+    unsafe {
+        h_device = CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        );
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+    unsafe {
+        if h_device == INVALID_HANDLE_VALUE {
+            return Err(format!("Failed to open device. Error {}", GetLastError()).into());
+        }
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CreateEventW to create a manual reset event used to wait
+    // on the overlapped requests
+    unsafe {
+        h_event = CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null());
+    }
+
+    // CreateEventW returns NULL on failure, not INVALID_HANDLE_VALUE
+    if h_event == 0 {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // CreateEventW
+        let error = unsafe { GetLastError() };
+
+        // SAFETY:
+        // Call Win32 API FFI CloseHandle to close device handle
+        unsafe {
+            CloseHandle(h_device);
+        }
+
+        return Err(format!("Failed to create event. Error {error}").into());
+    }
+
+    let mut overlapped = OVERLAPPED {
+        Internal: 0,
+        InternalHigh: 0,
+        Anonymous: OVERLAPPED_0 {
+            Pointer: std::ptr::null_mut(),
+        },
+        hEvent: h_event,
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI WriteFile to give the driver some data to echo back
+    unsafe {
+        r = WriteFile(
+            h_device,
+            write_buffer.as_ptr().cast(),
+            test_length,
+            std::ptr::null_mut(),
+            &mut overlapped,
+        );
+    }
+
+    let error = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+        unsafe { GetLastError() }
+    } else {
+        0
+    };
+
+    let result = if r == FALSE && error != ERROR_IO_PENDING {
+        Err(format!("PerformCancelReadTest: WriteFile failed: Error {error}").into())
+    } else {
+        // The write is held by the driver until its timer fires, so this can
+        // take up to one timer period
+        match wait_for_overlapped_result(h_device, &overlapped) {
+            Ok(bytes_written) => {
+                println!("{bytes_written} Pattern Bytes Written successfully");
+                issue_and_cancel_read(
+                    h_device,
+                    &mut overlapped,
+                    &mut read_buffer,
+                    query_state.then_some(path),
+                )
+            }
+            Err((error, _)) => {
+                Err(format!("PerformCancelReadTest: Write failed: Error {error}").into())
+            }
+        }
+    };
 
     // SAFETY:
-    // Call Win32 API FFI CloseHandle to close completion port handle
+    // Call Win32 API FFI CloseHandle to close event handle
     unsafe {
-        CloseHandle(h_completion_port);
+        CloseHandle(h_event);
     }
 
     // SAFETY:
@@ -588,72 +1029,547 @@ fn async_io_work(io_type: u32) -> Result<(), Box<dyn Error>> {
         CloseHandle(h_device);
     }
 
-    Ok(())
+    result
 }
 
-fn get_device_path(interface_guid: &Uuid) -> Result<(), Box<dyn Error>> {
-    let mut guid = windows_sys::core::GUID {
-        data1: 0,
-        data2: 0,
-        data3: 0,
-        data4: [0, 0, 0, 0, 0, 0, 0, 0],
+/// Sends two overlapped writes without waiting for the first one to complete,
+/// then waits for both. The driver holds each write until its timer fires, so
+/// the second write arrives while the first is still pending, and neither may
+/// be lost or failed because of the other.
+fn perform_pipelined_write_test(path: &[u16], test_length: u32) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        )
     };
-    let guid_data4: &[u8; 8];
-    let mut device_interface_list_length: u32 = 0;
-    let mut config_ret;
 
-    (guid.data1, guid.data2, guid.data3, guid_data4) = interface_guid.as_fields();
-    guid.data4 = *guid_data4;
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let mut overlapped_list: Vec<OVERLAPPED> = Vec::with_capacity(2);
+    let mut result: Result<(), Box<dyn Error>> = Ok(());
+
+    for i in 0..2 {
+        // SAFETY:
+        // Call Win32 API FFI CreateEventW to create a manual reset event used to
+        // wait on this write
+        let h_event = unsafe { CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null()) };
+
+        // CreateEventW returns NULL on failure, not INVALID_HANDLE_VALUE
+        if h_event == 0 {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from
+            // CreateEventW
+            let error = unsafe { GetLastError() };
+            result = Err(format!("Failed to create event. Error {error}").into());
+            break;
+        }
+
+        overlapped_list.push(OVERLAPPED {
+            Internal: 0,
+            InternalHigh: 0,
+            Anonymous: OVERLAPPED_0 {
+                Pointer: std::ptr::null_mut(),
+            },
+            hEvent: h_event,
+        });
+
+        // SAFETY:
+        // Call Win32 API FFI WriteFile without waiting for the previous write. The
+        // OVERLAPPED is not moved, since overlapped_list never grows past its
+        // capacity, and outlives the request, which is waited on below
+        let r = unsafe {
+            WriteFile(
+                h_device,
+                write_buffer.as_ptr().cast(),
+                test_length,
+                std::ptr::null_mut(),
+                &mut overlapped_list[i],
+            )
+        };
+
+        if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+            let error = unsafe { GetLastError() };
+            if error != ERROR_IO_PENDING {
+                result = Err(format!(
+                    "PerformPipelinedWriteTest: WriteFile {i} failed: Error {error}"
+                )
+                .into());
+
+                // SAFETY:
+                // Call Win32 API FFI CloseHandle to close the event of the failed write
+                unsafe {
+                    CloseHandle(h_event);
+                }
+
+                // Only wait on the writes that were issued
+                overlapped_list.pop();
+                break;
+            }
+        }
+    }
+
+    // Wait for every write that was issued, even if issuing another one failed
+    let mut writes_succeeded = 0;
+    for (i, overlapped) in overlapped_list.iter().enumerate() {
+        match wait_for_overlapped_result(h_device, overlapped) {
+            Ok(bytes_written) if bytes_written == test_length => {
+                println!("Pipelined write {i}: {bytes_written} Pattern Bytes Written successfully");
+                writes_succeeded += 1;
+            }
+            Ok(bytes_written) => {
+                result = result.and(Err(format!(
+                    "Pipelined write {i}: Written {bytes_written}, SB {test_length}"
+                )
+                .into()));
+            }
+            // The driver must hold a request arriving while it is busy with
+            // another one, rather than fail it
+            Err((ERROR_BUSY, _)) => {
+                result = result.and(Err(format!(
+                    "Pipelined write {i} failed with ERROR_BUSY, the driver did not hold it"
+                )
+                .into()));
+            }
+            Err((error, _)) => {
+                result = result.and(Err(
+                    format!("Pipelined write {i} failed: Error {error}").into()
+                ));
+            }
+        }
+
+        // SAFETY:
+        // Call Win32 API FFI CloseHandle to close event handle
+        unsafe {
+            CloseHandle(overlapped.hEvent);
+        }
+    }
+
+    // Both writes must have been issued and have succeeded
+    if result.is_ok() && writes_succeeded != 2 {
+        result = Err(format!("{writes_succeeded} pipelined writes succeeded, SB 2").into());
+    }
+    if result.is_ok() {
+        println!("Both pipelined writes succeeded");
+    }
 
     // SAFETY:
-    // Call Win32 API FFI CM_Get_Device_Interface_List_SizeW to determine size of
-    // space needed for a subsequent request
+    // Call Win32 API FFI CloseHandle to close device handle
     unsafe {
-        config_ret = DeviceAndDriverInstallation::CM_Get_Device_Interface_List_SizeW(
-            &mut device_interface_list_length,
-            &guid,
-            std::ptr::null(),
-            DeviceAndDriverInstallation::CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
-        );
+        CloseHandle(h_device);
     }
 
-    if config_ret != DeviceAndDriverInstallation::CR_SUCCESS {
-        return Err(
-            format!("Error 0x{config_ret:08X} retrieving device interface list size.",).into(),
-        );
+    result
+}
+
+/// Asks the driver for its version string with `IOCTL_ECHO_GET_WDF_VERSION`
+/// and prints it.
+fn print_driver_version(path: &[u16]) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
     }
 
-    if device_interface_list_length <= 1 {
-        return Err(
-            "Error: No active device interfaces found.  Is the sample driver loaded?".into(),
+    // The string is returned in UTF-16, without a terminating null
+    let mut version_buffer = [0u16; 256];
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to retrieve the version string.
+    // version_buffer outlives the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_GET_WDF_VERSION,
+            std::ptr::null(),
+            0,
+            version_buffer.as_mut_ptr().cast(),
+            u32::try_from(std::mem::size_of_val(&version_buffer))?,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    let result = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        Err(format!("PrintDriverVersion: DeviceIoControl failed: Error {error}").into())
+    } else {
+        let length = bytes_returned as usize / std::mem::size_of::<u16>();
+        println!(
+            "Driver version: {}",
+            String::from_utf16_lossy(&version_buffer[..length])
         );
+        Ok(())
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
     }
 
-    let mut buffer: Vec<u16> = vec![0; usize::try_from(device_interface_list_length).unwrap()];
-    let buffer_ptr = buffer.as_mut_ptr();
+    result
+}
+
+fn issue_and_cancel_read(
+    h_device: HANDLE,
+    overlapped: &mut OVERLAPPED,
+    read_buffer: &mut [u8],
+    state_path: Option<&[u16]>,
+) -> Result<(), Box<dyn Error>> {
+    let r: BOOL;
 
     // SAFETY:
-    // Call Win32 API FFI CM_Get_Device_Interface_ListW to get the list of Device
-    // Interfaces that match the Interface GUID for the echo driver
+    // Call Win32 API FFI ReadFile to send a read that the driver will hold as
+    // its current cancelable request
     unsafe {
-        config_ret = DeviceAndDriverInstallation::CM_Get_Device_Interface_ListW(
-            &guid,
-            std::ptr::null(),
-            buffer_ptr,
-            device_interface_list_length,
-            DeviceAndDriverInstallation::CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+        r = ReadFile(
+            h_device,
+            read_buffer.as_mut_ptr().cast(),
+            u32::try_from(read_buffer.len()).unwrap(),
+            std::ptr::null_mut(),
+            overlapped,
         );
     }
 
-    if config_ret != DeviceAndDriverInstallation::CR_SUCCESS {
-        return Err(format!("Error 0x{config_ret:08X} retrieving device interface list.").into());
+    // SAFETY:
+    // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+    unsafe {
+        if r == FALSE {
+            let error = GetLastError();
+            if error != ERROR_IO_PENDING {
+                return Err(
+                    format!("PerformCancelReadTest: ReadFile failed: Error {error}").into(),
+                );
+            }
+        }
+    }
+
+    // Give the driver a chance to mark the request cancelable
+    thread::sleep(CANCEL_DELAY);
+
+    // The read waits for the timer, with the initial cancel completion
+    // ownership count. A parallel queue always reports the idle state
+    if let Some(path) = state_path {
+        let state = query_queue_state(path)?;
+        println!("Queue state while the read is held: {state:?}");
+        if state.pending_requests != 1 || state.ownership_count != 1 {
+            return Err(format!(
+                "{} requests held with ownership count {}, SB 1 request with count 1",
+                state.pending_requests, state.ownership_count
+            )
+            .into());
+        }
+        if state.current_request_state != REQUEST_STATE_IDLE
+            && state.current_request_state != REQUEST_STATE_PENDING
+        {
+            return Err(format!(
+                "Read held in state {}, SB pending",
+                state.current_request_state
+            )
+            .into());
+        }
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CancelIoEx to cancel the read sent above
+    if unsafe { CancelIoEx(h_device, overlapped) } == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CancelIoEx
+        println!("CancelIoEx failed: Error {}", unsafe { GetLastError() });
+    }
+
+    match wait_for_overlapped_result(h_device, overlapped) {
+        Err((ERROR_OPERATION_ABORTED, bytes_read)) => {
+            println!(
+                "Read was cancelled with ERROR_OPERATION_ABORTED after {bytes_read} bytes, the \
+                 driver's cancel routine completed it with STATUS_CANCELLED"
+            );
+            if bytes_read != 0 {
+                return Err(format!("Cancelled read returned {bytes_read} bytes, SB 0").into());
+            }
+
+            // The cancel routine cleared the current request, and no request is
+            // left to own
+            if let Some(path) = state_path {
+                let state = query_queue_state(path)?;
+                println!("Queue state once the read is cancelled: {state:?}");
+                if state.pending_requests != 0
+                    || state.current_request_state != REQUEST_STATE_IDLE
+                    || state.ownership_count != 0
+                {
+                    return Err(format!(
+                        "{} requests held in state {} with ownership count {}, SB none held, \
+                         idle, with count 0",
+                        state.pending_requests, state.current_request_state, state.ownership_count
+                    )
+                    .into());
+                }
+            }
+
+            println!("Cancel Verified successfully\n");
+            Ok(())
+        }
+        Err((error, _)) => {
+            Err(format!("PerformCancelReadTest: Read failed with unexpected Error {error}").into())
+        }
+        Ok(bytes_read) => {
+            // The driver's timer completed the read before the cancel arrived
+            println!(
+                "Read completed with {bytes_read} bytes before it could be cancelled, the \
+                 driver's cancel routine did not run"
+            );
+            Err("Read was not cancelled".into())
+        }
+    }
+}
+
+/// Returns the mode selected by the arguments left in `argument_vector` once
+/// the common options are taken, or [`Mode::WriteRead`] if there are none.
+/// Fails on an unknown argument, or when more than one mode is given.
+fn parse_mode(argument_vector: &[String]) -> Result<Mode, Box<dyn Error>> {
+    let mut selected: Option<(&str, Mode)> = None;
+    let mut arguments = argument_vector.iter().skip(1).peekable();
+
+    while let Some(argument) = arguments.next() {
+        // The number of -Async and --bench is optional, so only an argument
+        // that is not an option is taken as their value
+        let mut optional_value = || arguments.next_if(|value| !value.starts_with('-'));
+        let mode = match argument.as_str() {
+            "-Async" => Mode::AsyncIo {
+                loops: optional_value()
+                    .map(|loops| loops.parse::<usize>())
+                    .transpose()?,
+            },
+            "-Cancel" => Mode::Cancel,
+            "-QueueState" => Mode::QueueState,
+            "-Pipeline" => Mode::Pipeline,
+            "-Fault" => Mode::Fault,
+            "-MemoryPressure" => Mode::MemoryPressure,
+            "-Neither" => Mode::Neither,
+            "-Backpressure" => Mode::Backpressure,
+            "-PartialRead" => Mode::PartialRead,
+            "-Drain" => Mode::Drain,
+            "-Transform" => Mode::Transform,
+            "-BadIoctl" => Mode::BadIoctl,
+            "--bench" => Mode::Bench {
+                round_trips: match optional_value() {
+                    Some(round_trips) => round_trips.parse::<usize>()?,
+                    None => BENCH_ROUND_TRIPS,
+                },
+            },
+            "--threads" => {
+                let Some(thread_count) = arguments.next() else {
+                    print_usage();
+                    return Err("--threads requires a number of threads".into());
+                };
+                Mode::Stress {
+                    threads: thread_count.parse::<usize>()?,
+                }
+            }
+            "--version" => Mode::Version,
+            "--stats" => Mode::Stats,
+            "--list" => Mode::List,
+            _ => {
+                print_usage();
+                return Err(format!("Invalid argument {argument}").into());
+            }
+        };
+
+        if let Some((previous, _)) = selected {
+            print_usage();
+            return Err(format!("{previous} and {argument} cannot be combined").into());
+        }
+        selected = Some((argument, mode));
+    }
+
+    Ok(selected.map_or(Mode::WriteRead, |(_, mode)| mode))
+}
+
+/// Fails if common options that only apply to the synchronous test, which
+/// `--read-only` and `--write-only` also select, are given with another mode.
+fn check_mode_options(mode: Mode) -> Result<(), Box<dyn Error>> {
+    if mode == Mode::WriteRead {
+        return Ok(());
     }
 
-    let path = OsString::from_wide(buffer.as_slice());
+    let access_mode = GLOBAL_DATA.read()?.access_mode;
+    if access_mode != AccessMode::ReadWrite {
+        return Err(format!(
+            "A {} device can only be used by the synchronous test",
+            access_mode.name()
+        )
+        .into());
+    }
+    if GLOBAL_DATA.read()?.timeout_ms.is_some() {
+        return Err("--timeout-ms can only be used by the synchronous test".into());
+    }
 
-    GLOBAL_DATA.write()?.device_path = path
-        .into_string()
-        .expect("Unable to convert Device Path to String");
+    Ok(())
+}
+
+/// Removes the options that can be combined with any of the tests from
+/// `argument_vector`, and stores their values in `GLOBAL_DATA`:
+///
+/// * `--instance <index>` selects the device when several are installed
+/// * `--name <name>` opens `\\.\<name>`, the symbolic link of a driver built
+///   with the `named-device` feature, instead of looking up the device
+///   interface
+/// * `--timeout-ms <ms>` bounds how long the synchronous test waits for each
+///   request
+/// * `--wait-ms <ms>` bounds how long to wait for the device interface to
+///   appear when there is none yet
+/// * `--sequence` checks the sequence numbers added by a driver built with the
+///   `sequence-numbers` feature
+fn take_common_options(argument_vector: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = take_option_value(argument_vector, "--name")? {
+        GLOBAL_DATA.write()?.device_path = format!(r"\\.\{name}")
+            .encode_utf16()
+            .chain(iter::once(0))
+            .collect();
+    }
+    if let Some(instance) = take_option_value(argument_vector, "--instance")? {
+        GLOBAL_DATA.write()?.instance = instance.parse::<usize>()?;
+    }
+    if let Some(timeout_ms) = take_option_value(argument_vector, "--timeout-ms")? {
+        GLOBAL_DATA.write()?.timeout_ms = Some(timeout_ms.parse::<u32>()?);
+    }
+    if let Some(wait_ms) = take_option_value(argument_vector, "--wait-ms")? {
+        GLOBAL_DATA.write()?.wait_ms = Some(wait_ms.parse::<u32>()?);
+    }
+    for (name, access_mode) in [
+        ("--read-only", AccessMode::ReadOnly),
+        ("--write-only", AccessMode::WriteOnly),
+    ] {
+        if let Some(position) = argument_vector.iter().position(|arg| arg == name) {
+            argument_vector.remove(position);
+            let mut globals = GLOBAL_DATA.write()?;
+            if globals.access_mode != AccessMode::ReadWrite {
+                return Err("--read-only and --write-only are mutually exclusive".into());
+            }
+            globals.access_mode = access_mode;
+        }
+    }
+    if let Some(position) = argument_vector.iter().position(|arg| arg == "--exclusive") {
+        argument_vector.remove(position);
+        GLOBAL_DATA.write()?.exclusive = true;
+    }
+    let access_mode = GLOBAL_DATA.read()?.access_mode;
+    // The access mode test sends its requests synchronously
+    if access_mode != AccessMode::ReadWrite && GLOBAL_DATA.read()?.timeout_ms.is_some() {
+        return Err(format!(
+            "--timeout-ms cannot be used with a {} device",
+            access_mode.name()
+        )
+        .into());
+    }
+    if let Some(position) = argument_vector.iter().position(|arg| arg == "--sequence") {
+        argument_vector.remove(position);
+        GLOBAL_DATA.write()?.sequence_numbers = true;
+    }
 
     Ok(())
 }
+
+/// Removes `name` and the value following it from `argument_vector`, returning
+/// the value, or `None` if `name` is not present.
+fn take_option_value(
+    argument_vector: &mut Vec<String>,
+    name: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(position) = argument_vector.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if position + 1 >= argument_vector.len() {
+        return Err(format!("{name} requires a value").into());
+    }
+    let value = argument_vector.remove(position + 1);
+    argument_vector.remove(position);
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Mode, Box<dyn Error>> {
+        let argument_vector: Vec<String> = iter::once("echoapp")
+            .chain(arguments.iter().copied())
+            .map(String::from)
+            .collect();
+        parse_mode(&argument_vector)
+    }
+
+    #[test]
+    fn parse_mode_defaults_to_write_read() {
+        assert_eq!(parse(&[]).unwrap(), Mode::WriteRead);
+    }
+
+    #[test]
+    fn parse_mode_optional_values() {
+        assert_eq!(parse(&["-Async"]).unwrap(), Mode::AsyncIo { loops: None });
+        assert_eq!(
+            parse(&["-Async", "5"]).unwrap(),
+            Mode::AsyncIo { loops: Some(5) }
+        );
+        assert_eq!(
+            parse(&["--bench"]).unwrap(),
+            Mode::Bench {
+                round_trips: BENCH_ROUND_TRIPS
+            }
+        );
+        assert_eq!(
+            parse(&["--threads", "4"]).unwrap(),
+            Mode::Stress { threads: 4 }
+        );
+        assert!(parse(&["--threads"]).is_err());
+    }
+
+    #[test]
+    fn parse_mode_rejects_several_modes() {
+        assert!(parse(&["-Cancel", "-QueueState"]).is_err());
+        assert!(parse(&["-Async", "-Fault"]).is_err());
+        assert!(parse(&["--bench", "10", "--version"]).is_err());
+    }
+
+    #[test]
+    fn parse_mode_rejects_unknown_arguments() {
+        assert!(parse(&["-Cancel", "512"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+    }
+}
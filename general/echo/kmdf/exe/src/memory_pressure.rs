@@ -0,0 +1,142 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-MemoryPressure`: failing allocations in a driver built with the
+//! `memory-pressure` feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle,
+        GetLastError,
+        ERROR_NO_SYSTEM_RESOURCES,
+        FALSE,
+        HANDLE,
+        INVALID_HANDLE_VALUE,
+    },
+    Storage::FileSystem::{
+        CreateFileW,
+        WriteFile,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+use crate::{create_pattern_buffer, perform_write_read_test};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a driver built with the `memory-pressure` feature
+static IOCTL_ECHO_FAIL_ALLOCATIONS: u32 = 0x0022_200C;
+
+/// Asks a driver built with the `memory-pressure` feature to treat its next
+/// write buffer allocation as failed, then checks that the next write fails
+/// with `ERROR_NO_SYSTEM_RESOURCES`, and that the driver has recovered: a write
+/// and read round trip after it must echo the data as usual.
+pub fn perform_allocation_failure_test(
+    path: &[u16],
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result = fail_allocation_and_write(h_device, &write_buffer)
+        .and_then(|()| perform_write_read_test(h_device, test_length, None));
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+fn fail_allocation_and_write(h_device: HANDLE, write_buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+    let failing_allocations: u32 = 1;
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to send the number of allocations to
+    // fail to the driver. failing_allocations outlives the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_FAIL_ALLOCATIONS,
+            std::ptr::addr_of!(failing_allocations).cast(),
+            u32::try_from(std::mem::size_of::<u32>())?,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        return Err(
+            format!("PerformAllocationFailureTest: DeviceIoControl failed: Error {error}").into(),
+        );
+    }
+
+    let mut bytes_written: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI WriteFile to write the pattern to the driver
+    let r = unsafe {
+        WriteFile(
+            h_device,
+            write_buffer.as_ptr().cast(),
+            u32::try_from(write_buffer.len())?,
+            &mut bytes_written,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r != FALSE {
+        return Err(
+            "PerformAllocationFailureTest: Write succeeded despite the failed allocation".into(),
+        );
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+    let error = unsafe { GetLastError() };
+    if error != ERROR_NO_SYSTEM_RESOURCES {
+        return Err(format!(
+            "PerformAllocationFailureTest: Write did not fail with STATUS_INSUFFICIENT_RESOURCES: \
+             Error {error}, SB {ERROR_NO_SYSTEM_RESOURCES}"
+        )
+        .into());
+    }
+
+    println!("Write failed with the simulated allocation failure as expected");
+    Ok(())
+}
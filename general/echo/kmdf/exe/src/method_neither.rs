@@ -0,0 +1,97 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-Neither`: the `METHOD_NEITHER` control code of a driver built with the
+//! `method-neither` feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{GetLastError, FALSE, HANDLE},
+    System::IO::DeviceIoControl,
+};
+
+use crate::{create_pattern_buffer, verify_pattern_buffer};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x804, METHOD_NEITHER, FILE_ANY_ACCESS),
+// handled by a driver built with the `method-neither` feature
+static IOCTL_ECHO_NEITHER: u32 = 0x0022_2013;
+// Kernel-mode address, which the driver must refuse to read for the app
+static KERNEL_ADDRESS: usize = 0xFFFF_8000_0000_0000;
+
+/// Sends `IOCTL_ECHO_NEITHER` to a driver built with the `method-neither`
+/// feature, and checks that the pattern in the input buffer is copied to the
+/// output buffer. Then checks that the driver refuses a kernel-mode address as
+/// the input buffer, which it must probe since `METHOD_NEITHER` buffers are
+/// passed as they are.
+pub fn perform_method_neither_test(
+    h_device: HANDLE,
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    let input_buffer = create_pattern_buffer(test_length);
+    let mut output_buffer: Vec<u8> = vec![0; input_buffer.len()];
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to echo the pattern through the
+    // driver. Both buffers outlive the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_NEITHER,
+            input_buffer.as_ptr().cast(),
+            test_length,
+            output_buffer.as_mut_ptr().cast(),
+            test_length,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        return Err(
+            format!("PerformMethodNeitherTest: DeviceIoControl failed: Error {error}").into(),
+        );
+    }
+
+    if bytes_returned != test_length {
+        return Err(format!(
+            "PerformMethodNeitherTest: Returned {bytes_returned}, SB {test_length}"
+        )
+        .into());
+    }
+    verify_pattern_buffer(&output_buffer)?;
+    println!("METHOD_NEITHER: {bytes_returned} Pattern Bytes echoed successfully");
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl with a kernel-mode address as the
+    // input buffer. The driver must fail the request without reading it
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_NEITHER,
+            KERNEL_ADDRESS as *const std::ffi::c_void,
+            test_length,
+            output_buffer.as_mut_ptr().cast(),
+            test_length,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r != FALSE {
+        return Err("PerformMethodNeitherTest: A kernel-mode input buffer was accepted".into());
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI GetLastError() to check for any errors from
+    // DeviceIoControl
+    let error = unsafe { GetLastError() };
+    println!("METHOD_NEITHER: Kernel-mode input buffer refused as expected: Error {error}");
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-PartialRead` and `-Drain`: reads of another length than the data
+//! written, with the `read-overflow` and `partial-reads` features.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GetLastError, ERROR_MORE_DATA, FALSE, HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW,
+        ReadFile,
+        WriteFile,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+};
+
+use crate::{
+    create_pattern_buffer,
+    verify_pattern_buffer,
+    verify_sequence_number,
+    write_pattern,
+    BUFFER_SIZE,
+    GLOBAL_DATA,
+    SEQUENCE_NUMBER_LENGTH,
+};
+
+/// Checks the status and information a read completes with when it asks for
+/// more than the driver has. A read longer than the data written must return
+/// exactly the data available. A read longer than the driver can ever hold
+/// either does the same, or, with the driver's `read-overflow` feature, fails
+/// with `ERROR_MORE_DATA` and reports the longest length a read can return.
+pub fn perform_oversized_read_test(path: &[u16], test_length: u32) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let sequence_length = u32::from(GLOBAL_DATA.read()?.sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+    let max_read_length = u32::try_from(BUFFER_SIZE)? + sequence_length;
+    let result = [2 * test_length, max_read_length + 1]
+        .into_iter()
+        .try_for_each(|read_length| {
+            oversized_read(h_device, test_length, read_length, max_read_length)
+        });
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+/// Writes `test_length` bytes of pattern, then reads them back with a buffer of
+/// `read_length` bytes, see `perform_oversized_read_test`.
+fn oversized_read(
+    h_device: HANDLE,
+    test_length: u32,
+    read_length: u32,
+    max_read_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+    let available_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+
+    let write_buffer = create_pattern_buffer(test_length);
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length)?];
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI WriteFile to write the pattern to the driver
+    let r = unsafe {
+        WriteFile(
+            h_device,
+            write_buffer.as_ptr().cast(),
+            test_length,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if r == FALSE || bytes_returned != test_length {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from WriteFile
+        let error = unsafe { GetLastError() };
+        return Err(format!(
+            "PerformOversizedReadTest: WriteFile failed: Error {error}, Written {bytes_returned}"
+        )
+        .into());
+    }
+
+    bytes_returned = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI ReadFile to read data from the driver
+    let r = unsafe {
+        ReadFile(
+            h_device,
+            read_buffer.as_mut_ptr().cast(),
+            read_length,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+        let error = unsafe { GetLastError() };
+        // STATUS_BUFFER_OVERFLOW, with the longest length a read can return
+        if error == ERROR_MORE_DATA
+            && read_length > max_read_length
+            && bytes_returned == max_read_length
+        {
+            println!("Read of {read_length} bytes overflowed, {bytes_returned} bytes needed");
+            return Ok(());
+        }
+        return Err(format!(
+            "PerformOversizedReadTest: ReadFile of {read_length} bytes failed: Error {error}, \
+             Read {bytes_returned}"
+        )
+        .into());
+    }
+
+    if bytes_returned != available_length {
+        return Err(format!(
+            "Read of {read_length} bytes is not the data available! Read {bytes_returned}, SB \
+             {available_length}"
+        )
+        .into());
+    }
+
+    read_buffer.truncate(usize::try_from(bytes_returned)?);
+    verify_pattern_buffer(if sequence_numbers {
+        verify_sequence_number(&read_buffer)?
+    } else {
+        &read_buffer
+    })?;
+
+    println!("Read of {read_length} bytes returned the {bytes_returned} bytes available");
+
+    Ok(())
+}
+
+/// Writes `test_length` bytes of pattern to a driver built with the
+/// `partial-reads` feature, then reads them back `read_length` bytes at a time
+/// until all of them have been returned, and checks that the pieces put back
+/// together are the pattern written. Without the feature, every read returns
+/// the start of the data again, and the check fails on the second piece.
+pub fn perform_drain_read_test(
+    path: &[u16],
+    test_length: u32,
+    read_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result = write_pattern(h_device, &create_pattern_buffer(test_length))
+        .and_then(|()| drain_reads(h_device, test_length, read_length));
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+/// Reads the `test_length` bytes of pattern written by
+/// `perform_drain_read_test` back `read_length` bytes at a time, and verifies
+/// them.
+fn drain_reads(h_device: HANDLE, test_length: u32, read_length: u32) -> Result<(), Box<dyn Error>> {
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+    let available_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+
+    let available = usize::try_from(available_length)?;
+
+    let mut drained: Vec<u8> = Vec::with_capacity(available);
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length)?];
+    let mut read_count = 0;
+    while drained.len() < available {
+        let mut bytes_read: u32 = 0;
+
+        // SAFETY:
+        // Call Win32 API FFI ReadFile to read the next piece of the data
+        let r = unsafe {
+            ReadFile(
+                h_device,
+                read_buffer.as_mut_ptr().cast(),
+                read_length,
+                &mut bytes_read,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+            let error = unsafe { GetLastError() };
+            return Err(format!(
+                "PerformDrainTest: ReadFile failed after {} bytes: Error {error}",
+                drained.len()
+            )
+            .into());
+        }
+
+        // The driver has no data left before all of it was read
+        if bytes_read == 0 {
+            return Err(format!(
+                "PerformDrainTest: Data drained after {} bytes, SB {available_length}",
+                drained.len()
+            )
+            .into());
+        }
+
+        read_count += 1;
+        println!("Read {read_count} returned {bytes_read} bytes");
+        drained.extend_from_slice(&read_buffer[..usize::try_from(bytes_read)?]);
+    }
+
+    if drained.len() != available {
+        return Err(format!(
+            "PerformDrainTest: Read {} bytes, SB {available_length}",
+            drained.len()
+        )
+        .into());
+    }
+
+    verify_pattern_buffer(if sequence_numbers {
+        verify_sequence_number(&drained)?
+    } else {
+        &drained
+    })?;
+
+    println!("{available_length} bytes drained with {read_count} reads of {read_length} bytes");
+
+    Ok(())
+}
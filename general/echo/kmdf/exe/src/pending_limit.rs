@@ -0,0 +1,307 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-Backpressure`: the limit on the reads held by a driver built with the
+//! `pending-limit` feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle,
+        GetLastError,
+        ERROR_BUSY,
+        ERROR_IO_PENDING,
+        ERROR_OPERATION_ABORTED,
+        FALSE,
+        HANDLE,
+        INVALID_HANDLE_VALUE,
+        TRUE,
+    },
+    Storage::FileSystem::{
+        CreateFileW,
+        ReadFile,
+        FILE_FLAG_OVERLAPPED,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::{
+        Threading::CreateEventW,
+        IO::{CancelIoEx, DeviceIoControl, OVERLAPPED, OVERLAPPED_0},
+    },
+};
+
+use crate::{create_pattern_buffer, wait_for_overlapped_result, write_pattern};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a driver built with the `pending-limit` feature
+static IOCTL_ECHO_SET_MAX_PENDING: u32 = 0x0022_2014;
+// Most reads the backpressure test lets the driver hold at once
+static BACKPRESSURE_LIMIT: usize = 4;
+
+/// Limits a driver built with the `pending-limit` feature to
+/// `BACKPRESSURE_LIMIT` pending reads, writes some data for the reads to
+/// return, then sends twice as many overlapped reads at once. The driver holds
+/// each read until its timer fires, so the reads beyond the limit must fail
+/// right away with `ERROR_BUSY`. Once the held reads are cancelled, their slots
+/// must be free again: as many reads as the limit must then all be accepted.
+/// The previous limit is restored at the end, even if the test fails.
+pub fn perform_pending_limit_test(path: &[u16], test_length: u32) -> Result<(), Box<dyn Error>> {
+    let write_buffer = create_pattern_buffer(test_length);
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle,
+    // used to change the limit and to write the data
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with an overlapped handle,
+    // used to send the reads without waiting for them
+    let h_overlapped_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        )
+    };
+
+    if h_overlapped_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+
+        // SAFETY:
+        // Call Win32 API FFI CloseHandle to close device handle
+        unsafe {
+            CloseHandle(h_device);
+        }
+
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result =
+        set_max_pending(h_device, u32::try_from(BACKPRESSURE_LIMIT)?).and_then(|previous_limit| {
+            println!(
+                "Limited the pending reads to {BACKPRESSURE_LIMIT}, instead of {previous_limit}"
+            );
+            let result = write_pattern(h_device, &write_buffer)
+                .and_then(|()| check_read_backpressure(h_overlapped_device, test_length));
+            // Restored even if the test failed
+            let restored = set_max_pending(h_device, previous_limit).map(|_| ());
+            result.and(restored)
+        });
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close overlapped device handle
+    unsafe {
+        CloseHandle(h_overlapped_device);
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+/// Sends `IOCTL_ECHO_SET_MAX_PENDING` to change the most reads the driver holds
+/// at once to `max_pending`, and returns the previous limit.
+fn set_max_pending(h_device: HANDLE, max_pending: u32) -> Result<u32, Box<dyn Error>> {
+    let mut previous_limit: u32 = 0;
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to send the new limit to the driver.
+    // Both values outlive the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_SET_MAX_PENDING,
+            std::ptr::addr_of!(max_pending).cast(),
+            u32::try_from(std::mem::size_of::<u32>())?,
+            std::ptr::addr_of_mut!(previous_limit).cast(),
+            u32::try_from(std::mem::size_of::<u32>())?,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        return Err(
+            format!("PerformBackpressureTest: DeviceIoControl failed: Error {error}").into(),
+        );
+    }
+
+    Ok(previous_limit)
+}
+
+/// Sends reads past the limit of the driver, then as many reads as the limit
+/// once the first ones are cancelled, and checks how many were refused.
+/// `h_device` must have been opened with `FILE_FLAG_OVERLAPPED`.
+fn check_read_backpressure(h_device: HANDLE, test_length: u32) -> Result<(), Box<dyn Error>> {
+    let read_count = 2 * BACKPRESSURE_LIMIT;
+    let busy_count = issue_and_cancel_reads(h_device, test_length, read_count)?;
+    if busy_count < read_count - BACKPRESSURE_LIMIT {
+        return Err(format!(
+            "PerformBackpressureTest: {busy_count} of {read_count} reads failed with ERROR_BUSY, \
+             SB at least {}",
+            read_count - BACKPRESSURE_LIMIT
+        )
+        .into());
+    }
+    println!("{busy_count} of {read_count} reads failed with ERROR_BUSY as expected");
+
+    // The cancelled reads must have given back their slots
+    let busy_count = issue_and_cancel_reads(h_device, test_length, BACKPRESSURE_LIMIT)?;
+    if busy_count != 0 {
+        return Err(format!(
+            "PerformBackpressureTest: {busy_count} reads failed with ERROR_BUSY after the \
+             previous ones were cancelled"
+        )
+        .into());
+    }
+    println!("{BACKPRESSURE_LIMIT} reads accepted after the previous ones were cancelled");
+
+    Ok(())
+}
+
+/// Sends `read_count` overlapped reads of `test_length` bytes without waiting
+/// for any of them, then cancels those the driver holds with `CancelIoEx` and
+/// waits for them. Returns how many reads failed right away with `ERROR_BUSY`.
+fn issue_and_cancel_reads(
+    h_device: HANDLE,
+    test_length: u32,
+    read_count: usize,
+) -> Result<usize, Box<dyn Error>> {
+    let mut read_buffers: Vec<Vec<u8>> = vec![vec![0; usize::try_from(test_length)?]; read_count];
+    let mut overlapped_list: Vec<OVERLAPPED> = Vec::with_capacity(read_count);
+    let mut busy_count = 0;
+    let mut result: Result<(), Box<dyn Error>> = Ok(());
+
+    for (i, read_buffer) in read_buffers.iter_mut().enumerate() {
+        // SAFETY:
+        // Call Win32 API FFI CreateEventW to create a manual reset event used to
+        // wait on this read
+        let h_event = unsafe { CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null()) };
+
+        // CreateEventW returns NULL on failure, not INVALID_HANDLE_VALUE
+        if h_event == 0 {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from
+            // CreateEventW
+            let error = unsafe { GetLastError() };
+            result = Err(format!("Failed to create event. Error {error}").into());
+            break;
+        }
+
+        overlapped_list.push(OVERLAPPED {
+            Internal: 0,
+            InternalHigh: 0,
+            Anonymous: OVERLAPPED_0 {
+                Pointer: std::ptr::null_mut(),
+            },
+            hEvent: h_event,
+        });
+        let overlapped = overlapped_list.last_mut().unwrap();
+
+        // SAFETY:
+        // Call Win32 API FFI ReadFile without waiting for the previous reads. The
+        // OVERLAPPED is not moved, since overlapped_list never grows past its
+        // capacity, and both it and the buffer outlive the request, which is
+        // waited on below
+        let r = unsafe {
+            ReadFile(
+                h_device,
+                read_buffer.as_mut_ptr().cast(),
+                test_length,
+                std::ptr::null_mut(),
+                overlapped,
+            )
+        };
+
+        if r == FALSE {
+            // SAFETY:
+            // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+            let error = unsafe { GetLastError() };
+            if error != ERROR_IO_PENDING {
+                if error == ERROR_BUSY {
+                    busy_count += 1;
+                } else {
+                    result = Err(format!(
+                        "PerformBackpressureTest: ReadFile {i} failed: Error {error}"
+                    )
+                    .into());
+                }
+
+                // SAFETY:
+                // Call Win32 API FFI CloseHandle to close the event of the read,
+                // which is not pending
+                unsafe {
+                    CloseHandle(h_event);
+                }
+
+                // Only wait on the reads the driver holds
+                overlapped_list.pop();
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // SAFETY:
+    // Call Win32 API FFI CancelIoEx to cancel every read still held by the
+    // driver, which gives back their slots
+    unsafe {
+        CancelIoEx(h_device, std::ptr::null());
+    }
+
+    // Wait for every read that was issued, even if issuing another one failed
+    for overlapped in &overlapped_list {
+        match wait_for_overlapped_result(h_device, overlapped) {
+            // The timer can complete a read before it is cancelled
+            Ok(_) | Err((ERROR_OPERATION_ABORTED, _)) => {}
+            Err((error, _)) => {
+                result = result.and(Err(format!("Held read failed: Error {error}").into()));
+            }
+        }
+
+        // SAFETY:
+        // Call Win32 API FFI CloseHandle to close event handle
+        unsafe {
+            CloseHandle(overlapped.hEvent);
+        }
+    }
+
+    result.map(|()| busy_count)
+}
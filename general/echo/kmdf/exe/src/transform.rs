@@ -0,0 +1,179 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-Transform`: the transforms of a driver built with the `transform`
+//! feature.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GetLastError, FALSE, HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW,
+        ReadFile,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+use crate::{
+    create_pattern_buffer,
+    verify_pattern_buffer,
+    verify_sequence_number,
+    write_pattern,
+    GLOBAL_DATA,
+    SEQUENCE_NUMBER_LENGTH,
+};
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS),
+// handled by a driver built with the `transform` feature
+static IOCTL_ECHO_SET_TRANSFORM: u32 = 0x0022_2018;
+// Kinds of transform of IOCTL_ECHO_SET_TRANSFORM: the data is left as it is,
+// XOR-ed with the key, or has the key added to each byte
+static TRANSFORM_IDENTITY: u32 = 0;
+static TRANSFORM_XOR: u32 = 1;
+static TRANSFORM_INCREMENT: u32 = 2;
+
+/// Has a driver built with the `transform` feature XOR the data written with a
+/// key, then add a key to it, and checks each time that undoing the transform
+/// on the data read back gives the pattern written. The driver is set back to
+/// echoing the data verbatim at the end, even if the test fails.
+pub fn perform_transform_round_trip_test(
+    path: &[u16],
+    test_length: u32,
+) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with a synchronous handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result = [
+        (TRANSFORM_XOR, 0x5A),
+        (TRANSFORM_INCREMENT, 3),
+        (TRANSFORM_IDENTITY, 0),
+    ]
+    .into_iter()
+    .try_for_each(|(kind, key)| transformed_round_trip(h_device, test_length, kind, key));
+    // Restored even if the test failed
+    let restored = set_transform(h_device, TRANSFORM_IDENTITY, 0);
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result.and(restored)
+}
+
+/// Sends `IOCTL_ECHO_SET_TRANSFORM` to have the driver apply the transform
+/// `kind` with `key` to the data of the next writes.
+fn set_transform(h_device: HANDLE, kind: u32, key: u32) -> Result<(), Box<dyn Error>> {
+    let transform: [u32; 2] = [kind, key];
+    let mut bytes_returned: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to send the transform to the driver.
+    // transform outlives the synchronous call
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_SET_TRANSFORM,
+            transform.as_ptr().cast(),
+            u32::try_from(std::mem::size_of_val(&transform))?,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        let error = unsafe { GetLastError() };
+        return Err(format!("PerformTransformTest: DeviceIoControl failed: Error {error}").into());
+    }
+
+    Ok(())
+}
+
+/// Writes the pattern with the transform `kind` and `key` selected, reads it
+/// back, and checks that undoing the transform gives the pattern again.
+fn transformed_round_trip(
+    h_device: HANDLE,
+    test_length: u32,
+    kind: u32,
+    key: u32,
+) -> Result<(), Box<dyn Error>> {
+    set_transform(h_device, kind, key)?;
+    write_pattern(h_device, &create_pattern_buffer(test_length))?;
+
+    let sequence_numbers = GLOBAL_DATA.read()?.sequence_numbers;
+    let read_length = test_length + u32::from(sequence_numbers) * SEQUENCE_NUMBER_LENGTH;
+    let mut read_buffer: Vec<u8> = vec![0; usize::try_from(read_length)?];
+    let mut bytes_read: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI ReadFile to read the transformed data from the driver
+    let r = unsafe {
+        ReadFile(
+            h_device,
+            read_buffer.as_mut_ptr().cast(),
+            read_length,
+            &mut bytes_read,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from ReadFile
+        let error = unsafe { GetLastError() };
+        return Err(format!("PerformTransformTest: ReadFile failed: Error {error}").into());
+    }
+
+    if bytes_read != read_length {
+        return Err(format!("Read {bytes_read}, SB {read_length}").into());
+    }
+
+    // The sequence number is not transformed, only the data after it
+    let data_offset = read_buffer.len() - usize::try_from(test_length)?;
+    if sequence_numbers {
+        verify_sequence_number(&read_buffer)?;
+    }
+    let data = &mut read_buffer[data_offset..];
+    let key = u8::try_from(key)?;
+    for byte in data.iter_mut() {
+        if kind == TRANSFORM_XOR {
+            *byte ^= key;
+        } else if kind == TRANSFORM_INCREMENT {
+            *byte = byte.wrapping_sub(key);
+        }
+    }
+    verify_pattern_buffer(data)?;
+
+    println!("Transform {kind} with key {key:#04X} undone successfully");
+    Ok(())
+}
@@ -0,0 +1,188 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! `-BadIoctl`: a control code the driver does not handle.
+
+use std::error::Error;
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle,
+        GetLastError,
+        ERROR_INVALID_FUNCTION,
+        ERROR_IO_PENDING,
+        FALSE,
+        HANDLE,
+        INVALID_HANDLE_VALUE,
+        TRUE,
+        WAIT_TIMEOUT,
+    },
+    Storage::FileSystem::{
+        CreateFileW,
+        FILE_FLAG_OVERLAPPED,
+        FILE_GENERIC_READ,
+        FILE_GENERIC_WRITE,
+        FILE_SHARE_READ,
+        FILE_SHARE_WRITE,
+        OPEN_EXISTING,
+    },
+    System::{
+        Threading::{CreateEventW, WaitForSingleObject},
+        IO::{CancelIoEx, DeviceIoControl, OVERLAPPED, OVERLAPPED_0},
+    },
+};
+
+use crate::wait_for_overlapped_result;
+
+// CTL_CODE(FILE_DEVICE_UNKNOWN, 0xFFF, METHOD_BUFFERED, FILE_ANY_ACCESS), which
+// the driver does not handle
+static IOCTL_ECHO_UNKNOWN: u32 = 0x0022_3FFC;
+// How long the unknown control code test waits for the driver to fail it
+static UNKNOWN_IOCTL_TIMEOUT_MS: u32 = 5000;
+
+/// Sends `IOCTL_ECHO_UNKNOWN`, a control code the driver does not handle, and
+/// checks that the driver fails it with `STATUS_INVALID_DEVICE_REQUEST`, which
+/// the app sees as `ERROR_INVALID_FUNCTION`. The request is overlapped, so a
+/// driver that leaves it uncompleted fails the test after
+/// `UNKNOWN_IOCTL_TIMEOUT_MS` instead of hanging the app.
+pub fn perform_unknown_control_code_test(path: &[u16]) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateFileW to access driver with an overlapped handle
+    let h_device = unsafe {
+        CreateFileW(
+            path.as_ptr(),
+            FILE_GENERIC_READ | FILE_GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            0,
+        )
+    };
+
+    if h_device == INVALID_HANDLE_VALUE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from CreateFileW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to open device. Error {error}").into());
+    }
+
+    let result = send_unknown_control_code(h_device);
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close device handle
+    unsafe {
+        CloseHandle(h_device);
+    }
+
+    result
+}
+
+/// Sends `IOCTL_ECHO_UNKNOWN` with the overlapped handle `h_device`, see
+/// `perform_unknown_control_code_test`.
+fn send_unknown_control_code(h_device: HANDLE) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI CreateEventW to create a manual reset event that is
+    // signalled when the request completes
+    let h_event = unsafe { CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null()) };
+
+    // CreateEventW returns NULL on failure, not INVALID_HANDLE_VALUE
+    if h_event == 0 {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // CreateEventW
+        let error = unsafe { GetLastError() };
+        return Err(format!("Failed to create event. Error {error}").into());
+    }
+
+    let mut overlapped = OVERLAPPED {
+        Internal: 0,
+        InternalHigh: 0,
+        Anonymous: OVERLAPPED_0 {
+            Pointer: std::ptr::null_mut(),
+        },
+        hEvent: h_event,
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI DeviceIoControl to send the unknown control code, with
+    // no buffers. `overlapped` outlives the request, which is waited for below
+    let r = unsafe {
+        DeviceIoControl(
+            h_device,
+            IOCTL_ECHO_UNKNOWN,
+            std::ptr::null(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            &mut overlapped,
+        )
+    };
+
+    let error = if r == FALSE {
+        // SAFETY:
+        // Call Win32 API FFI GetLastError() to check for any errors from
+        // DeviceIoControl
+        unsafe { GetLastError() }
+    } else {
+        0
+    };
+
+    let result = if r != FALSE {
+        Err("DeviceIoControl of an unknown control code succeeded".into())
+    } else if error != ERROR_IO_PENDING {
+        // Failed right away, before being issued or by a driver completing it
+        // synchronously
+        check_unknown_control_code_error(error)
+    } else {
+        // SAFETY:
+        // Call Win32 API FFI WaitForSingleObject to wait for the request to
+        // complete, up to the timeout
+        let wait_result = unsafe { WaitForSingleObject(h_event, UNKNOWN_IOCTL_TIMEOUT_MS) };
+
+        if wait_result == WAIT_TIMEOUT {
+            // SAFETY:
+            // Call Win32 API FFI CancelIoEx to cancel the request the driver
+            // left uncompleted
+            unsafe {
+                CancelIoEx(h_device, &overlapped);
+            }
+
+            // The OVERLAPPED must stay valid until the request is completed
+            let _ = wait_for_overlapped_result(h_device, &overlapped);
+
+            Err(
+                format!("Unknown control code not completed after {UNKNOWN_IOCTL_TIMEOUT_MS} ms")
+                    .into(),
+            )
+        } else {
+            match wait_for_overlapped_result(h_device, &overlapped) {
+                Ok(_) => Err("Unknown control code completed successfully".into()),
+                Err((error, _)) => check_unknown_control_code_error(error),
+            }
+        }
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI CloseHandle to close event handle
+    unsafe {
+        CloseHandle(h_event);
+    }
+
+    result
+}
+
+/// Checks that `error`, the error `IOCTL_ECHO_UNKNOWN` failed with, is the
+/// `ERROR_INVALID_FUNCTION` of `STATUS_INVALID_DEVICE_REQUEST`.
+fn check_unknown_control_code_error(error: u32) -> Result<(), Box<dyn Error>> {
+    if error != ERROR_INVALID_FUNCTION {
+        return Err(format!(
+            "Unknown control code failed with Error {error}, SB {ERROR_INVALID_FUNCTION}"
+        )
+        .into());
+    }
+
+    println!("Unknown control code {IOCTL_ECHO_UNKNOWN:#010X} failed with ERROR_INVALID_FUNCTION");
+    Ok(())
+}
@@ -0,0 +1,284 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! Unit tests of the modules of the kmdf echo-2 sample driver that do not
+//! depend on WDF.
+//!
+//! The driver is a `cdylib` linked against the WDK, so its own tests cannot be
+//! built or run (see `test = false` in its manifest). This crate includes those
+//! modules by path instead, so that the tests they contain run with
+//! `cargo test` on any host, without the WDK or a test machine.
+//!
+//! The few `wdk-sys` items the modules use are stood in for by items of this
+//! crate, with the same types as in `wdk-sys` on x64, so that the modules
+//! build unchanged: the crate is its own `wdk_sys`, and pool allocations are
+//! served by the global allocator.
+#![cfg(test)]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+
+extern crate self as wdk_sys;
+
+#[path = "../../driver/DriverSync/src/cancel_protocol.rs"]
+mod cancel_protocol;
+#[path = "../../driver/DriverSync/src/chunks.rs"]
+mod chunks;
+#[path = "../../driver/DriverSync/src/request_state.rs"]
+mod request_state;
+#[path = "../../driver/DriverSync/src/ring.rs"]
+mod ring;
+#[path = "../../driver/DriverSync/src/teardown.rs"]
+mod teardown;
+#[path = "../../driver/DriverSync/src/transfer_length.rs"]
+mod transfer_length;
+#[path = "../../driver/DriverSync/src/transform.rs"]
+mod transform;
+#[path = "../../driver/DriverSync/src/unicode_string.rs"]
+mod unicode_string;
+#[path = "../../driver/DriverSync/src/wdf_io_queue_config.rs"]
+mod wdf_io_queue_config;
+// Only the layout checks of the context types are tested, the rest needs WDF
+#[allow(
+    dead_code,
+    unused_imports,
+    unused_macros,
+    reason = "the macros are only used by the driver"
+)]
+#[path = "../../driver/DriverSync/src/wdf_object_context.rs"]
+mod wdf_object_context;
+#[path = "../../driver/DriverSync/src/wdf_structure_size.rs"]
+mod wdf_structure_size;
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type NTSTATUS = i32;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type SIZE_T = u64;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type ULONG = u32;
+
+pub const POOL_FLAG_NON_PAGED: u64 = 0x0000_0000_0000_0040;
+#[allow(
+    clippy::cast_possible_wrap,
+    reason = "NTSTATUS values are defined as u32 in C"
+)]
+pub const STATUS_BUFFER_OVERFLOW: NTSTATUS = 0x8000_0005_u32 as NTSTATUS;
+#[allow(
+    clippy::cast_possible_wrap,
+    reason = "NTSTATUS values are defined as u32 in C"
+)]
+pub const STATUS_INSUFFICIENT_RESOURCES: NTSTATUS = 0xC000_009A_u32 as NTSTATUS;
+#[allow(
+    clippy::cast_possible_wrap,
+    reason = "NTSTATUS values are defined as u32 in C"
+)]
+pub const STATUS_NAME_TOO_LONG: NTSTATUS = 0xC000_0106_u32 as NTSTATUS;
+
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+// Only the field read by the modules
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct WDF_OBJECT_CONTEXT_TYPE_INFO {
+    pub UniqueType: PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+}
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PCWDF_OBJECT_CONTEXT_TYPE_INFO = *const WDF_OBJECT_CONTEXT_TYPE_INFO;
+
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UNICODE_STRING {
+    pub Length: u16,
+    pub MaximumLength: u16,
+    pub Buffer: *mut u16,
+}
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type WDFQUEUE = *mut core::ffi::c_void;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type WDFREQUEST = *mut core::ffi::c_void;
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PFN_WDF_IO_QUEUE_IO_READ =
+    Option<unsafe extern "C" fn(queue: WDFQUEUE, request: WDFREQUEST, length: usize)>;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PFN_WDF_IO_QUEUE_IO_WRITE =
+    Option<unsafe extern "C" fn(queue: WDFQUEUE, request: WDFREQUEST, length: usize)>;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL = Option<
+    unsafe extern "C" fn(
+        queue: WDFQUEUE,
+        request: WDFREQUEST,
+        output_buffer_length: usize,
+        input_buffer_length: usize,
+        io_control_code: ULONG,
+    ),
+>;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PFN_WDF_IO_QUEUE_IO_STOP =
+    Option<unsafe extern "C" fn(queue: WDFQUEUE, request: WDFREQUEST, action_flags: ULONG)>;
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type WDF_IO_QUEUE_DISPATCH_TYPE = _WDF_IO_QUEUE_DISPATCH_TYPE::Type;
+
+#[allow(
+    non_snake_case,
+    non_upper_case_globals,
+    reason = "named like the wdk-sys enumeration"
+)]
+pub mod _WDF_IO_QUEUE_DISPATCH_TYPE {
+    pub type Type = i32;
+    pub const WdfIoQueueDispatchSequential: Type = 1;
+    pub const WdfIoQueueDispatchParallel: Type = 2;
+    pub const WdfIoQueueDispatchManual: Type = 3;
+}
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type WDF_TRI_STATE = _WDF_TRI_STATE::Type;
+
+#[allow(
+    non_snake_case,
+    non_upper_case_globals,
+    reason = "named like the wdk-sys enumeration"
+)]
+pub mod _WDF_TRI_STATE {
+    pub type Type = i32;
+    pub const WdfFalse: Type = 0;
+    pub const WdfTrue: Type = 1;
+    pub const WdfUseDefault: Type = 2;
+}
+
+// Only the fields set by the modules
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct WDF_IO_QUEUE_CONFIG {
+    pub Size: ULONG,
+    pub DispatchType: WDF_IO_QUEUE_DISPATCH_TYPE,
+    pub PowerManaged: WDF_TRI_STATE,
+    pub AllowZeroLengthRequests: u8,
+    pub DefaultQueue: u8,
+    pub EvtIoRead: PFN_WDF_IO_QUEUE_IO_READ,
+    pub EvtIoWrite: PFN_WDF_IO_QUEUE_IO_WRITE,
+    pub EvtIoDeviceControl: PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL,
+    pub EvtIoStop: PFN_WDF_IO_QUEUE_IO_STOP,
+    pub Settings: WDF_IO_QUEUE_CONFIG_SETTINGS,
+}
+
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union WDF_IO_QUEUE_CONFIG_SETTINGS {
+    pub Parallel: WDF_IO_QUEUE_CONFIG_PARALLEL,
+}
+
+impl Default for WDF_IO_QUEUE_CONFIG_SETTINGS {
+    fn default() -> Self {
+        Self {
+            Parallel: WDF_IO_QUEUE_CONFIG_PARALLEL::default(),
+        }
+    }
+}
+
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct WDF_IO_QUEUE_CONFIG_PARALLEL {
+    pub NumberOfPresentedRequests: ULONG,
+}
+
+/// Pool allocation routines of `wdk_sys::ntddk`, over the global allocator
+#[allow(non_snake_case, reason = "named like the wdk-sys functions")]
+pub mod ntddk {
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+    use crate::SIZE_T;
+
+    /// Room before each allocation for its length, which `ExFreePool` needs to
+    /// rebuild its layout
+    const HEADER_SIZE: usize = 16;
+
+    /// Allocate `number_of_bytes` of zero-initialized memory, or return null
+    /// like `ExAllocatePool2` when `number_of_bytes` is too large.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be freed with [`ExFreePool`].
+    #[must_use]
+    pub unsafe fn ExAllocatePool2(
+        _flags: u64,
+        number_of_bytes: SIZE_T,
+        _tag: u32,
+    ) -> *mut core::ffi::c_void {
+        let Some(layout) = layout(number_of_bytes) else {
+            return core::ptr::null_mut();
+        };
+        // SAFETY: The layout has a nonzero size, including the header
+        let pointer = unsafe { alloc_zeroed(layout) };
+        if pointer.is_null() {
+            return pointer.cast();
+        }
+        // SAFETY: The allocation starts with HEADER_SIZE bytes aligned for a
+        // SIZE_T
+        unsafe { header(pointer).write(number_of_bytes) };
+        // SAFETY: The allocation is HEADER_SIZE bytes longer than requested
+        unsafe { pointer.add(HEADER_SIZE) }.cast()
+    }
+
+    /// Free memory allocated by [`ExAllocatePool2`].
+    ///
+    /// # Safety
+    ///
+    /// `p` must have been returned by [`ExAllocatePool2`] and not freed yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length before `p` has been overwritten.
+    pub unsafe fn ExFreePool(p: *mut core::ffi::c_void) {
+        // SAFETY: `p` is HEADER_SIZE bytes into an allocation of
+        // ExAllocatePool2, per the contract of the caller
+        let pointer = unsafe { p.cast::<u8>().sub(HEADER_SIZE) };
+        // SAFETY: ExAllocatePool2 wrote the length at the start of the
+        // allocation
+        let number_of_bytes = unsafe { header(pointer).read() };
+        let layout = layout(number_of_bytes).expect("layout of an existing allocation");
+        // SAFETY: The allocation was made with this layout
+        unsafe { dealloc(pointer, layout) };
+    }
+
+    #[allow(
+        clippy::cast_ptr_alignment,
+        reason = "allocations are aligned to HEADER_SIZE"
+    )]
+    const fn header(pointer: *mut u8) -> *mut SIZE_T {
+        pointer.cast()
+    }
+
+    fn layout(number_of_bytes: SIZE_T) -> Option<Layout> {
+        let size = usize::try_from(number_of_bytes)
+            .ok()?
+            .checked_add(HEADER_SIZE)?;
+        Layout::from_size_align(size, HEADER_SIZE).ok()
+    }
+}
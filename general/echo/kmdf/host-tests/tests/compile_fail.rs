@@ -0,0 +1,11 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! Declarations the driver modules must reject at compile time. Each file in
+//! `tests/compile_fail` must fail to build with the errors in the `.stderr`
+//! file next to it.
+
+#[test]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/compile_fail/*.rs");
+}
@@ -0,0 +1,56 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! A zero-sized type cannot be declared as a WDF object context, since WDF
+//! does not accept a zero `ContextSize`.
+//!
+//! As in the host tests, this crate stands in for the few `wdk-sys` items the
+//! context declaration uses.
+#![allow(unused, reason = "only the declaration is checked")]
+
+extern crate self as wdk_sys;
+
+#[path = "../../../driver/DriverSync/src/wdf_object_context.rs"]
+mod wdf_object_context;
+#[path = "../../../driver/DriverSync/src/wdf_structure_size.rs"]
+mod wdf_structure_size;
+
+mod wdf_object_attributes {
+    pub trait ObjectContext {
+        fn type_info() -> wdk_sys::PCWDF_OBJECT_CONTEXT_TYPE_INFO;
+    }
+}
+
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type ULONG = u32;
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type WDFOBJECT = *mut core::ffi::c_void;
+
+#[allow(
+    non_camel_case_types,
+    non_snake_case,
+    reason = "named like the wdk-sys type"
+)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WDF_OBJECT_CONTEXT_TYPE_INFO {
+    pub Size: ULONG,
+    pub ContextName: *const core::ffi::c_char,
+    pub ContextSize: usize,
+    pub UniqueType: PCWDF_OBJECT_CONTEXT_TYPE_INFO,
+    pub EvtDriverGetUniqueContextType: Option<extern "C" fn() -> PCWDF_OBJECT_CONTEXT_TYPE_INFO>,
+}
+#[allow(non_camel_case_types, reason = "named like the wdk-sys type")]
+pub type PCWDF_OBJECT_CONTEXT_TYPE_INFO = *const WDF_OBJECT_CONTEXT_TYPE_INFO;
+
+macro_rules! call_unsafe_wdf_function_binding {
+    ($($arguments:tt)*) => {
+        core::ptr::null_mut::<core::ffi::c_void>()
+    };
+}
+
+struct EmptyContext;
+
+wdf_object_context::wdf_declare_context_type_with_name!(EmptyContext, empty_context_get);
+
+fn main() {}
@@ -0,0 +1,403 @@
+// Copyright (c) Microsoft Corporation
+// License: MIT OR Apache-2.0
+
+//! End-to-end test of the KMDF echo driver, for a DUT with test signing on.
+//!
+//! Unlike `echoapp`, which expects the driver to be installed already, this
+//! installs it: it creates a root-enumerated `root\ECHO_2` device, installs
+//! the driver package on it from its INF, waits for the device to start, runs
+//! `echoapp` against it, and removes the device again, whatever the outcome.
+//! The install is what `devgen.exe` and `pnputil.exe /install` do, written
+//! with the device installation (`SetupDi*`) and configuration manager
+//! (`CM_*`) functions of `windows-sys`.
+#![deny(unsafe_op_in_unsafe_fn)]
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+#![deny(clippy::nursery)]
+#![deny(clippy::cargo)]
+#![deny(clippy::multiple_unsafe_ops_per_block)]
+#![deny(clippy::undocumented_unsafe_blocks)]
+#![deny(clippy::unnecessary_safety_doc)]
+#![deny(rustdoc::broken_intra_doc_links)]
+#![deny(rustdoc::private_intra_doc_links)]
+#![deny(rustdoc::missing_crate_level_docs)]
+#![deny(rustdoc::invalid_codeblock_attributes)]
+#![deny(rustdoc::invalid_html_tags)]
+#![deny(rustdoc::invalid_rust_codeblocks)]
+#![deny(rustdoc::bare_urls)]
+#![deny(rustdoc::unescaped_backticks)]
+#![deny(rustdoc::redundant_explicit_links)]
+
+use std::{
+    env,
+    error::Error,
+    os::windows::prelude::*,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use windows_sys::{
+    core::GUID,
+    Win32::{
+        Devices::DeviceAndDriverInstallation::{
+            CM_Get_DevNode_Status,
+            CM_Locate_DevNodeW,
+            SetupDiCallClassInstaller,
+            SetupDiCreateDeviceInfoList,
+            SetupDiCreateDeviceInfoW,
+            SetupDiDestroyDeviceInfoList,
+            SetupDiGetDeviceInstanceIdW,
+            SetupDiSetDeviceRegistryPropertyW,
+            UpdateDriverForPlugAndPlayDevicesW,
+            CM_LOCATE_DEVNODE_NORMAL,
+            CR_SUCCESS,
+            DICD_GENERATE_ID,
+            DIF_REGISTERDEVICE,
+            DIF_REMOVE,
+            DN_HAS_PROBLEM,
+            DN_STARTED,
+            HDEVINFO,
+            INSTALLFLAG_FORCE,
+            MAX_DEVICE_ID_LEN,
+            SPDRP_HARDWAREID,
+            SP_DEVINFO_DATA,
+        },
+        Foundation::{GetLastError, BOOL, FALSE, INVALID_HANDLE_VALUE},
+    },
+};
+
+// Hardware ID the INF of the echo driver matches
+static HARDWARE_ID: &str = r"root\ECHO_2";
+// Setup class of the echo device, from the `Class` and `ClassGuid` of its INF
+static CLASS_NAME: &str = "Sample";
+static CLASS_GUID: GUID = GUID::from_u128(0x78A1_C341_4539_11D3_B88D_00C0_4FAD_5171);
+static START_TIMEOUT: Duration = Duration::from_secs(10);
+static START_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut argument_vector: Vec<String> = env::args().collect();
+
+    // The arguments after `--` are passed to echoapp as they are
+    let echoapp_arguments = argument_vector
+        .iter()
+        .position(|arg| arg == "--")
+        .map_or_else(Vec::new, |position| {
+            argument_vector.split_off(position).split_off(1)
+        });
+    let echoapp_path = match take_option_value(&mut argument_vector, "--echoapp")? {
+        Some(path) => PathBuf::from(path),
+        None => env::current_exe()?.with_file_name("echoapp.exe"),
+    };
+    let [_, inf_path] = argument_vector.as_slice() else {
+        print_usage();
+        return Err("Invalid Args".into());
+    };
+    // UpdateDriverForPlugAndPlayDevicesW requires a full path
+    let inf_path = env::current_dir()?.join(inf_path);
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiCreateDeviceInfoList to create an empty device
+    // information set for the setup class of the echo device
+    let device_info_set = unsafe { SetupDiCreateDeviceInfoList(&CLASS_GUID, 0) };
+    if device_info_set == INVALID_HANDLE_VALUE {
+        return Err(last_error("SetupDiCreateDeviceInfoList"));
+    }
+
+    let result = register_device(device_info_set).and_then(|(device_info_data, instance_id)| {
+        let result = install_driver(&inf_path)
+            .and_then(|()| wait_for_device_start(device_info_data.DevInst))
+            .and_then(|()| run_echoapp(&echoapp_path, &echoapp_arguments));
+
+        // Removed even if the test failed, so that the next run starts from a
+        // machine without the device
+        let removed = remove_device(device_info_set, &device_info_data, &instance_id);
+        result.and(removed)
+    });
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiDestroyDeviceInfoList to free the device
+    // information set
+    unsafe {
+        SetupDiDestroyDeviceInfoList(device_info_set);
+    }
+
+    result?;
+    println!("Echo driver test passed");
+
+    Ok(())
+}
+
+fn print_usage() {
+    eprintln!(
+        r"
+Usage:
+    Echotest.exe <inf> --- Install the echo driver from <inf> on a new device, run echoapp and remove the device
+    Echotest.exe <inf> --echoapp <path> --- Run the echoapp at <path> instead of the one next to Echotest.exe
+    Echotest.exe <inf> -- <arguments> --- Pass <arguments> to echoapp, e.g. -Cancel
+"
+    );
+}
+
+/// Removes `name` and the value following it from `argument_vector`, returning
+/// the value, or `None` if `name` is not present.
+fn take_option_value(
+    argument_vector: &mut Vec<String>,
+    name: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(position) = argument_vector.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if position + 1 >= argument_vector.len() {
+        return Err(format!("{name} requires a value").into());
+    }
+    let value = argument_vector.remove(position + 1);
+    argument_vector.remove(position);
+    Ok(Some(value))
+}
+
+/// Error of the Win32 API function `function`, which just failed
+fn last_error(function: &str) -> Box<dyn Error> {
+    // SAFETY:
+    // Call Win32 API FFI GetLastError() to get the error of `function`
+    let error = unsafe { GetLastError() };
+    format!("{function} failed: Error {error}").into()
+}
+
+/// Encodes `string` as a null-terminated UTF-16 string
+fn to_wide(string: &str) -> Vec<u16> {
+    string.encode_utf16().chain(Some(0)).collect()
+}
+
+/// Adds a new device of the setup class of the echo device to
+/// `device_info_set`, with an instance ID generated from the class name, gives
+/// it the `root\ECHO_2` hardware ID, and registers it, which creates its
+/// devnode. Returns the device and its instance ID.
+///
+/// Until the device is registered, destroying `device_info_set` discards it.
+/// Once this function succeeds, it must be removed with `remove_device`.
+fn register_device(
+    device_info_set: HDEVINFO,
+) -> Result<(SP_DEVINFO_DATA, Vec<u16>), Box<dyn Error>> {
+    let class_name = to_wide(CLASS_NAME);
+    let mut device_info_data = SP_DEVINFO_DATA {
+        cbSize: u32::try_from(std::mem::size_of::<SP_DEVINFO_DATA>())?,
+        ClassGuid: CLASS_GUID,
+        DevInst: 0,
+        Reserved: 0,
+    };
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiCreateDeviceInfoW to add the device to the set.
+    // class_name outlives the call
+    let r = unsafe {
+        SetupDiCreateDeviceInfoW(
+            device_info_set,
+            class_name.as_ptr(),
+            &CLASS_GUID,
+            std::ptr::null(),
+            0,
+            DICD_GENERATE_ID,
+            &mut device_info_data,
+        )
+    };
+    if r == FALSE {
+        return Err(last_error("SetupDiCreateDeviceInfoW"));
+    }
+
+    // The hardware IDs are a REG_MULTI_SZ: null-terminated strings followed by
+    // an empty one
+    let mut hardware_ids = to_wide(HARDWARE_ID);
+    hardware_ids.push(0);
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiSetDeviceRegistryPropertyW to set the hardware
+    // IDs of the device. hardware_ids outlives the call
+    let r = unsafe {
+        SetupDiSetDeviceRegistryPropertyW(
+            device_info_set,
+            &mut device_info_data,
+            SPDRP_HARDWAREID,
+            hardware_ids.as_ptr().cast(),
+            u32::try_from(hardware_ids.len() * std::mem::size_of::<u16>())?,
+        )
+    };
+    if r == FALSE {
+        return Err(last_error("SetupDiSetDeviceRegistryPropertyW"));
+    }
+
+    // The instance ID is generated when the device is added to the set, so it
+    // is known before the device is registered
+    let mut instance_id: Vec<u16> = vec![0; usize::try_from(MAX_DEVICE_ID_LEN)? + 1];
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiGetDeviceInstanceIdW to get the instance ID of
+    // the device. instance_id is long enough for any instance ID and its null
+    let r = unsafe {
+        SetupDiGetDeviceInstanceIdW(
+            device_info_set,
+            &device_info_data,
+            instance_id.as_mut_ptr(),
+            u32::try_from(instance_id.len())?,
+            std::ptr::null_mut(),
+        )
+    };
+    if r == FALSE {
+        return Err(last_error("SetupDiGetDeviceInstanceIdW"));
+    }
+
+    let length = instance_id
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(instance_id.len());
+    instance_id.truncate(length + 1);
+
+    // SAFETY:
+    // Call Win32 API FFI SetupDiCallClassInstaller to register the device, which
+    // creates its devnode under the root enumerator
+    let r = unsafe {
+        SetupDiCallClassInstaller(DIF_REGISTERDEVICE, device_info_set, &device_info_data)
+    };
+    if r == FALSE {
+        return Err(last_error("SetupDiCallClassInstaller(DIF_REGISTERDEVICE)"));
+    }
+
+    println!(
+        "Registered device {}",
+        String::from_utf16_lossy(&instance_id[..instance_id.len() - 1])
+    );
+
+    Ok((device_info_data, instance_id))
+}
+
+/// Installs the driver package of `inf_path` on every present device with the
+/// `root\ECHO_2` hardware ID, which includes the one just registered. Fails if
+/// the install needs a reboot, since the device would not be started until
+/// then.
+fn install_driver(inf_path: &Path) -> Result<(), Box<dyn Error>> {
+    let hardware_id = to_wide(HARDWARE_ID);
+    let inf_path_wide: Vec<u16> = inf_path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut reboot_required: BOOL = FALSE;
+
+    println!("Installing {}", inf_path.display());
+
+    // SAFETY:
+    // Call Win32 API FFI UpdateDriverForPlugAndPlayDevicesW to install the
+    // driver, even if a better matching one is already installed. Both strings
+    // outlive the call
+    let r = unsafe {
+        UpdateDriverForPlugAndPlayDevicesW(
+            0,
+            hardware_id.as_ptr(),
+            inf_path_wide.as_ptr(),
+            INSTALLFLAG_FORCE,
+            &mut reboot_required,
+        )
+    };
+    if r == FALSE {
+        return Err(last_error("UpdateDriverForPlugAndPlayDevicesW"));
+    }
+
+    if reboot_required != FALSE {
+        return Err("Installing the driver requires a reboot".into());
+    }
+
+    println!("Driver installed");
+    Ok(())
+}
+
+/// Waits up to `START_TIMEOUT` for the devnode `dev_inst` to be started. Fails
+/// right away if the configuration manager reports a problem with the device
+/// instead, e.g. `CM_PROB_FAILED_START` (10) when `DriverEntry` or
+/// `EvtDriverDeviceAdd` fails, or `CM_PROB_UNSIGNED_DRIVER` (52) without test
+/// signing.
+fn wait_for_device_start(dev_inst: u32) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + START_TIMEOUT;
+
+    loop {
+        let mut status: u32 = 0;
+        let mut problem: u32 = 0;
+
+        // SAFETY:
+        // Call Win32 API FFI CM_Get_DevNode_Status to get the status of the
+        // device and its problem code, if it has one
+        let config_ret = unsafe { CM_Get_DevNode_Status(&mut status, &mut problem, dev_inst, 0) };
+        if config_ret != CR_SUCCESS {
+            return Err(format!("Error 0x{config_ret:08X} getting the device status").into());
+        }
+
+        if status & DN_HAS_PROBLEM != 0 {
+            return Err(format!("Device failed to start: problem code {problem}").into());
+        }
+
+        if status & DN_STARTED != 0 {
+            println!("Device started");
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "Device not started after {} s, status 0x{status:08X}",
+                START_TIMEOUT.as_secs()
+            )
+            .into());
+        }
+
+        thread::sleep(START_POLL_INTERVAL);
+    }
+}
+
+/// Runs the `echoapp` at `echoapp_path` with `arguments`, showing its output,
+/// and fails unless it exits successfully.
+fn run_echoapp(echoapp_path: &Path, arguments: &[String]) -> Result<(), Box<dyn Error>> {
+    println!("Running {} {}", echoapp_path.display(), arguments.join(" "));
+
+    let status = Command::new(echoapp_path)
+        .args(arguments)
+        .status()
+        .map_err(|error| format!("Failed to run {}: {error}", echoapp_path.display()))?;
+
+    if !status.success() {
+        return Err(format!("echoapp failed: {status}").into());
+    }
+
+    println!("echoapp succeeded");
+    Ok(())
+}
+
+/// Removes the device registered by `register_device`, which unloads the
+/// driver once no other device uses it, then checks with `CM_Locate_DevNodeW`
+/// that the devnode of `instance_id`, a null-terminated instance ID, is gone.
+fn remove_device(
+    device_info_set: HDEVINFO,
+    device_info_data: &SP_DEVINFO_DATA,
+    instance_id: &[u16],
+) -> Result<(), Box<dyn Error>> {
+    // SAFETY:
+    // Call Win32 API FFI SetupDiCallClassInstaller to remove the device
+    let r = unsafe { SetupDiCallClassInstaller(DIF_REMOVE, device_info_set, device_info_data) };
+    if r == FALSE {
+        return Err(last_error("SetupDiCallClassInstaller(DIF_REMOVE)"));
+    }
+
+    let mut dev_inst: u32 = 0;
+
+    // SAFETY:
+    // Call Win32 API FFI CM_Locate_DevNodeW to look for the devnode of the
+    // removed device among the present ones. instance_id is null-terminated and
+    // outlives the call
+    let config_ret = unsafe {
+        CM_Locate_DevNodeW(
+            &mut dev_inst,
+            instance_id.as_ptr(),
+            CM_LOCATE_DEVNODE_NORMAL,
+        )
+    };
+    if config_ret == CR_SUCCESS {
+        return Err("Device still present after being removed".into());
+    }
+
+    println!("Device removed");
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::{nt_success, println};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    PWDFDEVICE_INIT,
+    WDFDRIVER,
+    WDF_DRIVER_CONFIG,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::{device, WDF_DRIVER_CONFIG_SIZE};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into the host process. In UMDF this is an opaque handle that is only
+///   passed on to `WdfDriverCreate`.
+/// * `registry_path` - represents the driver specific path in the Registry. The
+///   function driver can use the path to store driver related data between
+///   reboots. The path does not store hardware instance specific data.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[export_name = "DriverEntry"] // WDF expects a symbol with the name DriverEntry
+extern "system" fn driver_entry(
+    driver: PDRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    let mut driver_config = WDF_DRIVER_CONFIG {
+        Size: WDF_DRIVER_CONFIG_SIZE,
+        EvtDriverDeviceAdd: Some(echo_evt_device_add),
+        ..WDF_DRIVER_CONFIG::default()
+    };
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    nt_status
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+extern "C" fn echo_evt_device_add(_driver: WDFDRIVER, device_init: PWDFDEVICE_INIT) -> NTSTATUS {
+    println!("Enter  EchoEvtDeviceAdd");
+
+    let device_init =
+        // SAFETY: WDF should always be providing a pointer that is properly aligned, dereferencable per https://doc.rust-lang.org/std/ptr/index.html#safety, and initialized. For the lifetime of the resulting reference, the pointed-to memory is never accessed through any other pointer.
+        unsafe {
+        device_init
+            .as_mut()
+            .expect("WDF should never provide a null pointer for device_init")
+    };
+    device::echo_device_create(device_init)
+}
@@ -0,0 +1,191 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//!    This driver is a UMDF 2 port of the KMDF echo sample. It demonstrates
+//!    use of a default I/O Queue, its request start events, cancellation
+//!    event, and a timer callback, all running in a user-mode host process.
+//!
+//!    To demonstrate asynchronous operation, the I/O requests are not completed
+//!    immediately, but stored in the drivers private data structure, and a
+//!    timer callback will complete it next time it runs.
+//!
+//!    During the time the request is waiting for the timer to run, it is
+//!    made cancellable by the call `WdfRequestMarkCancelableEx`. This
+//!    allows the test program to cancel the request and exit instantly.
+//!
+//!    The framework API surface is the same one the KMDF driver uses, so the
+//!    queue, cancel and timer logic is unchanged. The differences are the ones
+//!    that come with running in user mode:
+//!
+//!    * The driver is a DLL loaded by `WUDFHost.exe` on top of the reflector
+//!      (`WUDFRd.sys`), which forwards the I/O requests from the kernel.
+//!    * Memory comes from the process heap through the standard library, so the
+//!      write buffer is a `Vec<u8>` instead of an `ExAllocatePool2` allocation,
+//!      and no global allocator or panic handler is provided.
+//!    * There are no IRQLs or pageable code sections, so callbacks are not
+//!      placed in the `PAGE` section and don't use `paged_code!`.
+
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+
+mod device;
+mod driver;
+mod queue;
+
+use wdk::wdf;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    GUID,
+    NTSTATUS,
+    ULONG,
+    WDFOBJECT,
+    WDFREQUEST,
+    WDF_DRIVER_CONFIG,
+    WDF_IO_QUEUE_CONFIG,
+    WDF_OBJECT_ATTRIBUTES,
+    WDF_OBJECT_CONTEXT_TYPE_INFO,
+    WDF_PNPPOWER_EVENT_CALLBACKS,
+    WDF_TIMER_CONFIG,
+};
+mod wdf_object_context;
+use core::sync::atomic::AtomicI32;
+
+use wdf_object_context::{wdf_declare_context_type, wdf_declare_context_type_with_name};
+
+// {CDC35B6E-0BE4-4936-BF5F-5537380A7C1A}
+const GUID_DEVINTERFACE_ECHO: GUID = GUID {
+    Data1: 0xCDC3_5B6Eu32,
+    Data2: 0x0BE4u16,
+    Data3: 0x4936u16,
+    Data4: [
+        0xBFu8, 0x5Fu8, 0x55u8, 0x37u8, 0x38u8, 0x0Au8, 0x7Cu8, 0x1Au8,
+    ],
+};
+
+// Declare queue context.
+//
+// ====== CONTEXT SETUP ========//
+
+// The device context performs the same job as
+// a WDM device extension in the driver frameworks
+pub struct DeviceContext {
+    private_device_data: ULONG, // just a placeholder
+}
+wdf_declare_context_type!(DeviceContext);
+
+pub struct QueueContext {
+    /// Data from the last write. Context memory is zeroed by the framework
+    /// rather than constructed, so this is initialized in
+    /// `echo_queue_initialize` and dropped in the queue's destroy callback.
+    buffer: Vec<u8>,
+    timer: wdf::Timer,
+    current_request: WDFREQUEST,
+    current_status: NTSTATUS,
+    spin_lock: wdf::SpinLock,
+}
+wdf_declare_context_type_with_name!(QueueContext, queue_get_context);
+
+pub struct RequestContext {
+    cancel_completion_ownership_count: AtomicI32,
+}
+wdf_declare_context_type_with_name!(RequestContext, request_get_context);
+
+// None of the below SIZE constants should be needed after an equivalent `WDF_STRUCTURE_SIZE` macro is added to `wdk-sys`: https://github.com/microsoft/windows-drivers-rs/issues/242
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_DRIVER_CONFIG>() is known to fit in ULONG due to below const assert"
+)]
+const WDF_DRIVER_CONFIG_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_DRIVER_CONFIG>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_DRIVER_CONFIG>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_IO_QUEUE_CONFIG>() is known to fit in ULONG due to below const assert"
+)]
+const WDF_IO_QUEUE_CONFIG_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_IO_QUEUE_CONFIG>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_IO_QUEUE_CONFIG>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_OBJECT_ATTRIBUTES>() is known to fit in ULONG due to below const \
+              assert"
+)]
+const WDF_OBJECT_ATTRIBUTES_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_OBJECT_ATTRIBUTES>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_OBJECT_ATTRIBUTES>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>() is known to fit in ULONG due to below \
+              const assert"
+)]
+const WDF_OBJECT_CONTEXT_TYPE_INFO_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_OBJECT_CONTEXT_TYPE_INFO>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>() is known to fit in ULONG due to below \
+              const assert"
+)]
+const WDF_PNPPOWER_EVENT_CALLBACKS_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_PNPPOWER_EVENT_CALLBACKS>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<WDF_TIMER_CONFIG>() is known to fit in ULONG due to below const assert"
+)]
+const WDF_TIMER_CONFIG_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<WDF_TIMER_CONFIG>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<WDF_TIMER_CONFIG>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
@@ -0,0 +1,479 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::{nt_success, println};
+use wdk_sys::{
+    ntddk::{
+        ExAllocatePool2,
+        ExFreePool,
+        IoDeleteDevice,
+        IoDeleteSymbolicLink,
+        IoDetachDevice,
+        IoForwardIrpSynchronously,
+        IoSetDeviceInterfaceState,
+        IofCallDriver,
+        IofCompleteRequest,
+        KeAcquireSpinLockRaiseToDpc,
+        KeReleaseSpinLock,
+        PoCallDriver,
+        RtlFreeUnicodeString,
+        RtlInitUnicodeString,
+    },
+    IO_NO_INCREMENT,
+    IRP_MN_CANCEL_REMOVE_DEVICE,
+    IRP_MN_CANCEL_STOP_DEVICE,
+    IRP_MN_QUERY_REMOVE_DEVICE,
+    IRP_MN_QUERY_STOP_DEVICE,
+    IRP_MN_REMOVE_DEVICE,
+    IRP_MN_START_DEVICE,
+    IRP_MN_STOP_DEVICE,
+    IRP_MN_SURPRISE_REMOVAL,
+    NTSTATUS,
+    PDEVICE_OBJECT,
+    PIO_STACK_LOCATION,
+    PIRP,
+    POOL_FLAG_NON_PAGED,
+    SIZE_T,
+    STATUS_BUFFER_OVERFLOW,
+    STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_SUCCESS,
+    STATUS_UNSUCCESSFUL,
+    UNICODE_STRING,
+};
+
+use crate::{
+    remove_lock::RemoveLockGuard,
+    DeviceExtension,
+    ECHO_POOL_TAG,
+    MAX_WRITE_LENGTH,
+    SYMBOLIC_LINK_NAME,
+};
+
+/// Port of the inline `IoGetCurrentIrpStackLocation` from `wdm.h`.
+///
+/// # Safety
+///
+/// `irp` must point to a valid IRP that is currently owned by this driver.
+unsafe fn io_get_current_irp_stack_location(irp: PIRP) -> PIO_STACK_LOCATION {
+    // SAFETY: The caller guarantees `irp` is valid and owned by this driver
+    unsafe {
+        (*irp)
+            .Tail
+            .Overlay
+            .__bindgen_anon_2
+            .__bindgen_anon_1
+            .CurrentStackLocation
+    }
+}
+
+/// Port of the inline `IoSkipCurrentIrpStackLocation` from `wdm.h`. It lets
+/// the next lower driver reuse this driver's stack location when the IRP is
+/// passed down without a completion routine.
+///
+/// # Safety
+///
+/// `irp` must point to a valid IRP that is currently owned by this driver.
+unsafe fn io_skip_current_irp_stack_location(irp: PIRP) {
+    // SAFETY: The caller guarantees `irp` is valid and owned by this driver
+    unsafe {
+        let overlay = &mut (*irp).Tail.Overlay.__bindgen_anon_2.__bindgen_anon_1;
+        (*irp).CurrentLocation += 1;
+        overlay.CurrentStackLocation = overlay.CurrentStackLocation.add(1);
+    }
+}
+
+/// Returns the device extension that `IoCreateDevice` allocated for
+/// `device_object`.
+///
+/// # Safety
+///
+/// `device_object` must be a device object created by this driver.
+unsafe fn get_device_extension<'a>(device_object: PDEVICE_OBJECT) -> &'a mut DeviceExtension {
+    // SAFETY: The caller guarantees that `device_object` was created by this
+    // driver, whose device extension is always a `DeviceExtension`
+    unsafe { &mut *(*device_object).DeviceExtension.cast::<DeviceExtension>() }
+}
+
+/// Sets the final status and information of `irp` and completes it.
+///
+/// # Safety
+///
+/// `irp` must point to a valid IRP that is currently owned by this driver. The
+/// IRP must not be accessed after this call.
+unsafe fn complete_request(irp: PIRP, status: NTSTATUS, information: u64) -> NTSTATUS {
+    // SAFETY: The caller guarantees `irp` is valid and owned by this driver
+    unsafe {
+        (*irp).IoStatus.__bindgen_anon_1.Status = status;
+        (*irp).IoStatus.Information = information;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as i8);
+    }
+    status
+}
+
+/// Acquires the remove lock of the device for `irp`, so that the device is not
+/// deleted before the returned guard is dropped. Once the device is being
+/// removed, `irp` is completed with `STATUS_DELETE_PENDING` instead.
+///
+/// # Safety
+///
+/// `irp` must point to a valid IRP that is currently owned by this driver. On
+/// error, the IRP has been completed and must not be accessed anymore.
+///
+/// # Errors
+///
+/// This function will return the status `irp` was completed with if the remove
+/// lock cannot be acquired.
+unsafe fn acquire_remove_lock(
+    device_extension: &mut DeviceExtension,
+    irp: PIRP,
+) -> Result<RemoveLockGuard, NTSTATUS> {
+    // SAFETY: The remove lock was initialized in AddDevice, and the device
+    // extension is not freed before every acquisition has been released
+    match unsafe { RemoveLockGuard::acquire(&mut device_extension.remove_lock, irp.cast()) } {
+        Ok(remove_lock) => Ok(remove_lock),
+        Err(nt_status) => {
+            println!("Device is being removed, failing irp {irp:?} {nt_status:#010X}");
+            // SAFETY: The caller guarantees `irp` is valid and owned by this driver
+            Err(unsafe { complete_request(irp, nt_status, 0) })
+        }
+    }
+}
+
+/// Dispatch routine for `IRP_MJ_CREATE` and `IRP_MJ_CLOSE`. The echo device
+/// keeps no per-handle state, so both are completed successfully.
+///
+/// # Arguments:
+///
+/// * `device_object` - Pointer to the device object of this driver
+/// * `irp` - Pointer to the create or close IRP
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS`, or `STATUS_DELETE_PENDING` if the device is being
+///   removed
+pub extern "C" fn echo_dispatch_create_close(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    println!("echo_dispatch_create_close called! irp {irp:?}");
+
+    // SAFETY: The I/O manager passes a device object created by this driver
+    let device_extension = unsafe { get_device_extension(device_object) };
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let _remove_lock = match unsafe { acquire_remove_lock(device_extension, irp) } {
+        Ok(remove_lock) => remove_lock,
+        Err(nt_status) => return nt_status,
+    };
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    unsafe { complete_request(irp, STATUS_SUCCESS, 0) }
+}
+
+/// Dispatch routine for `IRP_MJ_READ`. It copies as much of the data stored
+/// by the last write as fits into the caller's buffer. If no write was
+/// received yet, the read succeeds with zero bytes.
+///
+/// # Arguments:
+///
+/// * `device_object` - Pointer to the device object of this driver
+/// * `irp` - Pointer to the read IRP
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+pub extern "C" fn echo_dispatch_read(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let stack = unsafe { io_get_current_irp_stack_location(irp) };
+    // SAFETY: The I/O manager passes a device object created by this driver
+    let device_extension = unsafe { get_device_extension(device_object) };
+
+    // SAFETY: The current stack location of a read IRP holds read parameters, and
+    // the device uses buffered I/O so the system buffer is valid non-paged memory
+    let (length, system_buffer) = unsafe {
+        (
+            (*stack).Parameters.Read.Length as usize,
+            (*irp).AssociatedIrp.SystemBuffer,
+        )
+    };
+
+    println!("echo_dispatch_read called! irp {irp:?}, length {length:?}");
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let _remove_lock = match unsafe { acquire_remove_lock(device_extension, irp) } {
+        Ok(remove_lock) => remove_lock,
+        Err(nt_status) => return nt_status,
+    };
+
+    // SAFETY: The lock was initialized in AddDevice and is released below
+    let old_irql = unsafe { KeAcquireSpinLockRaiseToDpc(&mut device_extension.lock) };
+
+    // Read what we have
+    let bytes_read = length.min(device_extension.length);
+    if bytes_read > 0 {
+        // SAFETY: The stored buffer holds `device_extension.length` bytes and the
+        // system buffer holds `length` bytes, both at least `bytes_read` long
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                device_extension.buffer.cast::<u8>(),
+                system_buffer.cast::<u8>(),
+                bytes_read,
+            );
+        }
+    }
+
+    // SAFETY: The lock was acquired above at `old_irql`
+    unsafe { KeReleaseSpinLock(&mut device_extension.lock, old_irql) };
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    unsafe { complete_request(irp, STATUS_SUCCESS, bytes_read as u64) }
+}
+
+/// Dispatch routine for `IRP_MJ_WRITE`. It allocates a buffer, copies the
+/// caller's data into it and stores it in the device extension, replacing the
+/// data of any previous write.
+///
+/// # Arguments:
+///
+/// * `device_object` - Pointer to the device object of this driver
+/// * `irp` - Pointer to the write IRP
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+pub extern "C" fn echo_dispatch_write(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let stack = unsafe { io_get_current_irp_stack_location(irp) };
+    // SAFETY: The I/O manager passes a device object created by this driver
+    let device_extension = unsafe { get_device_extension(device_object) };
+
+    // SAFETY: The current stack location of a write IRP holds write parameters, and
+    // the device uses buffered I/O so the system buffer is valid non-paged memory
+    let (length, system_buffer) = unsafe {
+        (
+            (*stack).Parameters.Write.Length as usize,
+            (*irp).AssociatedIrp.SystemBuffer,
+        )
+    };
+
+    println!("echo_dispatch_write called! irp {irp:?}, length {length:?}");
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let _remove_lock = match unsafe { acquire_remove_lock(device_extension, irp) } {
+        Ok(remove_lock) => remove_lock,
+        Err(nt_status) => return nt_status,
+    };
+
+    if length > MAX_WRITE_LENGTH {
+        println!(
+            "echo_dispatch_write Buffer Length to big {length:?}, Max is {MAX_WRITE_LENGTH:?}"
+        );
+        // SAFETY: The I/O manager passes a valid IRP that this driver owns
+        return unsafe { complete_request(irp, STATUS_BUFFER_OVERFLOW, 0) };
+    }
+
+    // The KMDF queue completes zero length requests on the driver's behalf, but
+    // in WDM they reach the dispatch routine
+    if length == 0 {
+        // SAFETY: The I/O manager passes a valid IRP that this driver owns
+        return unsafe { complete_request(irp, STATUS_SUCCESS, 0) };
+    }
+
+    // SAFETY: Allocating non-paged pool is allowed at the IRQL write IRPs are
+    // dispatched at
+    let buffer = unsafe { ExAllocatePool2(POOL_FLAG_NON_PAGED, length as SIZE_T, ECHO_POOL_TAG) };
+    if buffer.is_null() {
+        println!("echo_dispatch_write Could not allocate {length:?} byte buffer");
+        // SAFETY: The I/O manager passes a valid IRP that this driver owns
+        return unsafe { complete_request(irp, STATUS_INSUFFICIENT_RESOURCES, 0) };
+    }
+
+    // SAFETY: Both buffers are at least `length` bytes long and don't overlap
+    unsafe {
+        core::ptr::copy_nonoverlapping(system_buffer.cast::<u8>(), buffer.cast::<u8>(), length);
+    }
+
+    // Swap the new buffer in under the lock, and release the previous one once the
+    // lock is dropped
+    // SAFETY: The lock was initialized in AddDevice and is released below
+    let old_irql = unsafe { KeAcquireSpinLockRaiseToDpc(&mut device_extension.lock) };
+    let previous_buffer = core::mem::replace(&mut device_extension.buffer, buffer);
+    device_extension.length = length;
+    // SAFETY: The lock was acquired above at `old_irql`
+    unsafe { KeReleaseSpinLock(&mut device_extension.lock, old_irql) };
+
+    if !previous_buffer.is_null() {
+        // SAFETY: `previous_buffer` was allocated with ExAllocatePool2 by a previous
+        // write and is no longer reachable from the device extension
+        unsafe { ExFreePool(previous_buffer) };
+    }
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    unsafe { complete_request(irp, STATUS_SUCCESS, length as u64) }
+}
+
+/// Dispatch routine for `IRP_MJ_PNP`. Device start enables the device
+/// interface once the lower drivers have started, and device removal tears
+/// down everything `AddDevice` set up. All other `PnP` IRPs are passed down
+/// the device stack.
+///
+/// # Arguments:
+///
+/// * `device_object` - Pointer to the device object of this driver
+/// * `irp` - Pointer to the `PnP` IRP
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+pub extern "C" fn echo_dispatch_pnp(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let stack = unsafe { io_get_current_irp_stack_location(irp) };
+    // SAFETY: The I/O manager passes a device object created by this driver
+    let device_extension = unsafe { get_device_extension(device_object) };
+    // SAFETY: `stack` is the current stack location of a valid IRP
+    let minor_function = u32::from(unsafe { (*stack).MinorFunction });
+
+    println!("echo_dispatch_pnp called! minor function {minor_function:#04X}");
+
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let remove_lock = match unsafe { acquire_remove_lock(device_extension, irp) } {
+        Ok(remove_lock) => remove_lock,
+        Err(nt_status) => return nt_status,
+    };
+
+    match minor_function {
+        IRP_MN_START_DEVICE => {
+            // The lower drivers must finish starting the device before this driver
+            // does anything with it
+            // SAFETY: The lower device object stays attached until the device is
+            // removed, and `irp` is owned by this driver
+            let mut nt_status =
+                if unsafe { IoForwardIrpSynchronously(device_extension.lower_device_object, irp) }
+                    == 0
+                {
+                    STATUS_UNSUCCESSFUL
+                } else {
+                    // SAFETY: The IRP was completed by the lower drivers and ownership
+                    // returned to this driver
+                    unsafe { (*irp).IoStatus.__bindgen_anon_1.Status }
+                };
+
+            if nt_success(nt_status) {
+                // SAFETY: The interface was registered in AddDevice
+                nt_status = unsafe {
+                    IoSetDeviceInterfaceState(&mut device_extension.interface_name, u8::from(true))
+                };
+            }
+
+            // SAFETY: The IRP is owned by this driver again after it was forwarded
+            // synchronously
+            unsafe {
+                (*irp).IoStatus.__bindgen_anon_1.Status = nt_status;
+                IofCompleteRequest(irp, IO_NO_INCREMENT as i8);
+            }
+            nt_status
+        }
+
+        IRP_MN_REMOVE_DEVICE => {
+            // Wait for the dispatch routines still handling an IRP, which use the
+            // device extension and the lower device object. New IRPs fail from now
+            // on.
+            // SAFETY: PnP IRPs are dispatched at PASSIVE_LEVEL, and the device is
+            // only removed once
+            unsafe { remove_lock.release_and_wait() };
+
+            // SAFETY: The interface was registered in AddDevice. Disabling an interface
+            // that was already disabled on surprise removal is harmless.
+            unsafe {
+                IoSetDeviceInterfaceState(&mut device_extension.interface_name, u8::from(false));
+            }
+
+            // SAFETY: `irp` is owned by this driver and the lower device object stays
+            // attached until IoDetachDevice below
+            let nt_status = unsafe {
+                (*irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS;
+                io_skip_current_irp_stack_location(irp);
+                IofCallDriver(device_extension.lower_device_object, irp)
+            };
+
+            let mut symbolic_link_name = UNICODE_STRING::default();
+            // SAFETY: All of the resources below were created in AddDevice or by a
+            // write, and no dispatch routine uses them anymore since the remove lock
+            // has been released and waited for
+            unsafe {
+                IoDetachDevice(device_extension.lower_device_object);
+                RtlFreeUnicodeString(&mut device_extension.interface_name);
+                RtlInitUnicodeString(&mut symbolic_link_name, SYMBOLIC_LINK_NAME.as_ptr());
+                IoDeleteSymbolicLink(&mut symbolic_link_name);
+                if !device_extension.buffer.is_null() {
+                    ExFreePool(device_extension.buffer);
+                    device_extension.buffer = core::ptr::null_mut();
+                    device_extension.length = 0;
+                }
+                IoDeleteDevice(device_object);
+            }
+
+            nt_status
+        }
+
+        IRP_MN_SURPRISE_REMOVAL
+        | IRP_MN_QUERY_REMOVE_DEVICE
+        | IRP_MN_CANCEL_REMOVE_DEVICE
+        | IRP_MN_QUERY_STOP_DEVICE
+        | IRP_MN_CANCEL_STOP_DEVICE
+        | IRP_MN_STOP_DEVICE => {
+            if minor_function == IRP_MN_SURPRISE_REMOVAL {
+                // SAFETY: The interface was registered in AddDevice
+                unsafe {
+                    IoSetDeviceInterfaceState(
+                        &mut device_extension.interface_name,
+                        u8::from(false),
+                    );
+                }
+            }
+
+            // A function driver must report success for these before passing them
+            // down the stack
+            // SAFETY: `irp` is owned by this driver and the lower device object stays
+            // attached until the device is removed
+            unsafe {
+                (*irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS;
+                io_skip_current_irp_stack_location(irp);
+                IofCallDriver(device_extension.lower_device_object, irp)
+            }
+        }
+
+        _ => {
+            // SAFETY: `irp` is owned by this driver and the lower device object stays
+            // attached until the device is removed
+            unsafe {
+                io_skip_current_irp_stack_location(irp);
+                IofCallDriver(device_extension.lower_device_object, irp)
+            }
+        }
+    }
+}
+
+/// Dispatch routine for `IRP_MJ_POWER`. The echo device has no hardware state
+/// to save or restore, so every power IRP is passed down the device stack.
+///
+/// # Arguments:
+///
+/// * `device_object` - Pointer to the device object of this driver
+/// * `irp` - Pointer to the power IRP
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+pub extern "C" fn echo_dispatch_power(device_object: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    // SAFETY: The I/O manager passes a device object created by this driver
+    let device_extension = unsafe { get_device_extension(device_object) };
+    // SAFETY: The I/O manager passes a valid IRP that this driver owns
+    let _remove_lock = match unsafe { acquire_remove_lock(device_extension, irp) } {
+        Ok(remove_lock) => remove_lock,
+        Err(nt_status) => return nt_status,
+    };
+
+    // SAFETY: `irp` is owned by this driver and the lower device object stays
+    // attached until the device is removed
+    unsafe {
+        io_skip_current_irp_stack_location(irp);
+        PoCallDriver(device_extension.lower_device_object, irp)
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::{nt_success, paged_code, println};
+use wdk_sys::{
+    ntddk::{
+        IoAttachDeviceToDeviceStack,
+        IoCreateDevice,
+        IoCreateSymbolicLink,
+        IoDeleteDevice,
+        IoDeleteSymbolicLink,
+        IoRegisterDeviceInterface,
+        KeGetCurrentIrql,
+        RtlFreeUnicodeString,
+        RtlInitUnicodeString,
+    },
+    APC_LEVEL,
+    DO_BUFFERED_IO,
+    DO_DEVICE_INITIALIZING,
+    DRIVER_OBJECT,
+    FILE_DEVICE_SECURE_OPEN,
+    FILE_DEVICE_UNKNOWN,
+    IRP_MJ_CLOSE,
+    IRP_MJ_CREATE,
+    IRP_MJ_PNP,
+    IRP_MJ_POWER,
+    IRP_MJ_READ,
+    IRP_MJ_WRITE,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDEVICE_OBJECT,
+    PDRIVER_OBJECT,
+    STATUS_NO_SUCH_DEVICE,
+    STATUS_SUCCESS,
+    ULONG,
+    UNICODE_STRING,
+};
+
+use crate::{
+    dispatch::{
+        echo_dispatch_create_close,
+        echo_dispatch_pnp,
+        echo_dispatch_power,
+        echo_dispatch_read,
+        echo_dispatch_write,
+    },
+    remove_lock::initialize_remove_lock,
+    DeviceExtension,
+    DEVICE_NAME,
+    GUID_DEVINTERFACE_ECHO,
+    SYMBOLIC_LINK_NAME,
+};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<DeviceExtension>() is known to fit in ULONG due to below const assert"
+)]
+const DEVICE_EXTENSION_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<DeviceExtension>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<DeviceExtension>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. A WDM driver fills in the dispatch table
+/// of its `DRIVER_OBJECT` with the routines that handle each major function,
+/// along with its `AddDevice` and `DriverUnload` routines.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverEntry` must initialize members of `DriverObject`
+///   before it returns to the caller. `DriverObject` is allocated by the system
+///   before the driver is loaded, and it is released by the system after the
+///   system unloads the function driver from memory.
+/// * `_registry_path` - represents the driver specific path in the Registry.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS`
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"] // The I/O manager expects a symbol with the name DriverEntry
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    _registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    println!("Enter: driver_entry");
+
+    driver.MajorFunction[IRP_MJ_CREATE as usize] = Some(echo_dispatch_create_close);
+    driver.MajorFunction[IRP_MJ_CLOSE as usize] = Some(echo_dispatch_create_close);
+    driver.MajorFunction[IRP_MJ_READ as usize] = Some(echo_dispatch_read);
+    driver.MajorFunction[IRP_MJ_WRITE as usize] = Some(echo_dispatch_write);
+    driver.MajorFunction[IRP_MJ_PNP as usize] = Some(echo_dispatch_pnp);
+    driver.MajorFunction[IRP_MJ_POWER as usize] = Some(echo_dispatch_power);
+    driver.DriverUnload = Some(echo_driver_unload);
+
+    // SAFETY: The I/O manager always allocates the driver extension along with the
+    // driver object, and nothing else accesses it during DriverEntry
+    unsafe {
+        (*driver.DriverExtension).AddDevice = Some(echo_add_device);
+    }
+
+    println!("Exit: driver_entry");
+
+    STATUS_SUCCESS
+}
+
+/// `AddDevice` is called by the `PnP` manager when a device this driver is
+/// installed for is enumerated. It creates the functional device object (FDO),
+/// attaches it to the device stack, and makes it reachable from user mode
+/// through a symbolic link and a device interface.
+///
+/// # Arguments:
+///
+/// * `driver` - Pointer to this driver's `DRIVER_OBJECT`
+/// * `physical_device_object` - Pointer to the physical device object (PDO)
+///   created by the bus driver that enumerated the device
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn echo_add_device(
+    driver: PDRIVER_OBJECT,
+    physical_device_object: PDEVICE_OBJECT,
+) -> NTSTATUS {
+    paged_code!();
+
+    println!("Enter: echo_add_device");
+
+    let mut device_name = UNICODE_STRING::default();
+    let mut symbolic_link_name = UNICODE_STRING::default();
+    // SAFETY: Both buffers are null-terminated UTF-16 strings with static lifetime
+    unsafe {
+        RtlInitUnicodeString(&mut device_name, DEVICE_NAME.as_ptr());
+        RtlInitUnicodeString(&mut symbolic_link_name, SYMBOLIC_LINK_NAME.as_ptr());
+    }
+
+    let mut device_object: PDEVICE_OBJECT = core::ptr::null_mut();
+    // SAFETY: `driver` is the driver object the PnP manager passed to AddDevice and
+    // all other arguments are valid for the duration of the call
+    let mut nt_status = unsafe {
+        IoCreateDevice(
+            driver,
+            DEVICE_EXTENSION_SIZE,
+            &mut device_name,
+            FILE_DEVICE_UNKNOWN,
+            FILE_DEVICE_SECURE_OPEN,
+            u8::from(false),
+            &mut device_object,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: IoCreateDevice failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    // SAFETY: IoCreateDevice succeeded, so `device_object` points to a valid device
+    // object whose extension is DEVICE_EXTENSION_SIZE bytes of zeroed memory
+    let device_extension =
+        unsafe { &mut *(*device_object).DeviceExtension.cast::<DeviceExtension>() };
+    device_extension.buffer = core::ptr::null_mut();
+    device_extension.length = 0;
+    // KeInitializeSpinLock is an inline function that zeroes the lock
+    device_extension.lock = 0;
+    // SAFETY: The remove lock is in the device extension, which is nonpaged, and
+    // no IRP can reach the device before AddDevice returns
+    unsafe { initialize_remove_lock(&mut device_extension.remove_lock) };
+
+    // SAFETY: Both strings were initialized above and reference static buffers
+    nt_status = unsafe { IoCreateSymbolicLink(&mut symbolic_link_name, &mut device_name) };
+    if !nt_success(nt_status) {
+        println!("Error: IoCreateSymbolicLink failed {nt_status:#010X}");
+        // SAFETY: The device object was created above and is not attached yet
+        unsafe { IoDeleteDevice(device_object) };
+        return nt_status;
+    }
+
+    // Register the same device interface the KMDF sample exposes so that echoapp
+    // finds this device. The interface is enabled once the device is started.
+    // SAFETY: `physical_device_object` is the PDO passed in by the PnP manager and
+    // `interface_name` receives a string allocated by the I/O manager
+    nt_status = unsafe {
+        IoRegisterDeviceInterface(
+            physical_device_object,
+            &GUID_DEVINTERFACE_ECHO,
+            core::ptr::null_mut(),
+            &mut device_extension.interface_name,
+        )
+    };
+    if !nt_success(nt_status) {
+        println!("Error: IoRegisterDeviceInterface failed {nt_status:#010X}");
+        // SAFETY: The symbolic link and the device object were created above and are
+        // not used by anything else yet
+        unsafe {
+            IoDeleteSymbolicLink(&mut symbolic_link_name);
+            IoDeleteDevice(device_object);
+        }
+        return nt_status;
+    }
+
+    // SAFETY: Both device objects are valid, and the returned device object is the
+    // top of the stack that this device is now attached to
+    device_extension.lower_device_object =
+        unsafe { IoAttachDeviceToDeviceStack(device_object, physical_device_object) };
+    if device_extension.lower_device_object.is_null() {
+        println!("Error: IoAttachDeviceToDeviceStack failed");
+        // SAFETY: The interface name, the symbolic link and the device object were
+        // created above and are not used by anything else yet
+        unsafe {
+            RtlFreeUnicodeString(&mut device_extension.interface_name);
+            IoDeleteSymbolicLink(&mut symbolic_link_name);
+            IoDeleteDevice(device_object);
+        }
+        return STATUS_NO_SUCH_DEVICE;
+    }
+
+    // SAFETY: `device_object` is valid and not yet visible to the I/O manager since
+    // DO_DEVICE_INITIALIZING is still set
+    unsafe {
+        (*device_object).Flags |= DO_BUFFERED_IO;
+        (*device_object).Flags &= !DO_DEVICE_INITIALIZING;
+    }
+
+    println!("Exit: echo_add_device");
+
+    STATUS_SUCCESS
+}
+
+/// `DriverUnload` is called once all device objects created by the driver have
+/// been deleted. Everything was already released during
+/// `IRP_MN_REMOVE_DEVICE`, so there is nothing left to clean up.
+///
+/// # Arguments:
+///
+/// * `_driver` - Pointer to this driver's `DRIVER_OBJECT`
+#[link_section = "PAGE"]
+extern "C" fn echo_driver_unload(_driver: PDRIVER_OBJECT) {
+    paged_code!();
+
+    println!("Enter: echo_driver_unload");
+}
@@ -0,0 +1,115 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//!    This driver is a WDM port of the KMDF echo sample. Instead of relying on
+//!    the framework, it fills in the `MajorFunction` dispatch table of its
+//!    `DRIVER_OBJECT` and handles the raw `IRP_MJ_CREATE`, `IRP_MJ_CLOSE`,
+//!    `IRP_MJ_READ` and `IRP_MJ_WRITE` requests itself.
+//!
+//!    A write stores a copy of the caller's buffer in the device extension and
+//!    a subsequent read copies it back, which is the same contract the KMDF
+//!    echo driver implements, so the existing echoapp pattern-buffer test can
+//!    be run against this driver unmodified.
+//!
+//!    The device object is created with `IoCreateDevice` under a name in the
+//!    `\Device` namespace, and `IoCreateSymbolicLink` makes it reachable from
+//!    user mode as `\\.\EchoWdm`. The `GUID_DEVINTERFACE_ECHO` device interface
+//!    is registered as well so applications that enumerate interfaces, like
+//!    echoapp, can find it.
+//!
+//!    Unlike the KMDF sample, requests are completed synchronously in the
+//!    dispatch routines, and the device extension is protected with an
+//!    explicit `KSPIN_LOCK` since there is no framework synchronization. `PnP`
+//!    and power IRPs that the driver doesn't care about are passed down the
+//!    device stack, which KMDF otherwise does on the driver's behalf.
+//!
+//!    KMDF also keeps the device from being deleted while a callback is still
+//!    running. Here, every dispatch routine holds the `IO_REMOVE_LOCK` of the
+//!    device extension while it handles an IRP, and `IRP_MN_REMOVE_DEVICE`
+//!    waits for all of them to release it with `IoReleaseRemoveLockAndWait`
+//!    before it detaches and deletes the device. IRPs that arrive after that
+//!    fail with `STATUS_DELETE_PENDING`.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+
+mod dispatch;
+mod driver;
+mod remove_lock;
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+use wdk_sys::{GUID, IO_REMOVE_LOCK, KSPIN_LOCK, PDEVICE_OBJECT, PVOID, UNICODE_STRING};
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+// {CDC35B6E-0BE4-4936-BF5F-5537380A7C1A}
+const GUID_DEVINTERFACE_ECHO: GUID = GUID {
+    Data1: 0xCDC3_5B6Eu32,
+    Data2: 0x0BE4u16,
+    Data3: 0x4936u16,
+    Data4: [
+        0xBFu8, 0x5Fu8, 0x55u8, 0x37u8, 0x38u8, 0x0Au8, 0x7Cu8, 0x1Au8,
+    ],
+};
+
+/// Name of the device object in the object manager namespace
+const DEVICE_NAME: [u16; 16] = ascii_to_utf16(r"\Device\EchoWdm");
+
+/// Name of the symbolic link that makes the device reachable as `\\.\EchoWdm`
+const SYMBOLIC_LINK_NAME: [u16; 20] = ascii_to_utf16(r"\DosDevices\EchoWdm");
+
+/// Pool tag used for the echo buffer allocations
+const ECHO_POOL_TAG: u32 = u32::from_le_bytes(*b"Echo");
+
+/// Set max write length for testing
+const MAX_WRITE_LENGTH: usize = 1024 * 40;
+
+/// The device extension is the WDM equivalent of a KMDF device context. Its
+/// memory is allocated by `IoCreateDevice` right after the `DEVICE_OBJECT`.
+pub struct DeviceExtension {
+    /// Device object this device is attached on top of
+    lower_device_object: PDEVICE_OBJECT,
+    /// Name of the registered `GUID_DEVINTERFACE_ECHO` interface instance
+    interface_name: UNICODE_STRING,
+    /// Held by each dispatch routine while it handles an IRP, so that
+    /// `IRP_MN_REMOVE_DEVICE` does not delete the device under it
+    remove_lock: IO_REMOVE_LOCK,
+    /// Protects `buffer` and `length`
+    lock: KSPIN_LOCK,
+    buffer: PVOID,
+    length: usize,
+}
+
+/// Converts a nul-free ASCII string into a null-terminated UTF-16 array at
+/// compile time. `N` must be exactly one more than the length of `string`.
+const fn ascii_to_utf16<const N: usize>(string: &str) -> [u16; N] {
+    let bytes = string.as_bytes();
+    assert!(
+        bytes.len() + 1 == N,
+        "N should leave room for the terminating null"
+    );
+
+    let mut utf16 = [0u16; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii() && bytes[i] != 0,
+            "string should be ASCII"
+        );
+        utf16[i] = bytes[i] as u16;
+        i += 1;
+    }
+    utf16
+}
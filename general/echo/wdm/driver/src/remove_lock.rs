@@ -0,0 +1,128 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! Ports of the `IoInitializeRemoveLock`, `IoAcquireRemoveLock`,
+//! `IoReleaseRemoveLock` and `IoReleaseRemoveLockAndWait` macros from `wdm.h`.
+//!
+//! KMDF keeps a device from being deleted while its callbacks run. In WDM, a
+//! dispatch routine can still be using the device extension when
+//! `IRP_MN_REMOVE_DEVICE` arrives, so each one holds the remove lock of the
+//! device while it handles an IRP, and the removal waits for all of them to
+//! release it before deleting the device.
+
+use wdk::nt_success;
+use wdk_sys::{
+    ntddk::{
+        IoAcquireRemoveLockEx,
+        IoInitializeRemoveLockEx,
+        IoReleaseRemoveLockAndWaitEx,
+        IoReleaseRemoveLockEx,
+    },
+    IO_REMOVE_LOCK,
+    NTSTATUS,
+    PVOID,
+    ULONG,
+};
+
+use crate::ECHO_POOL_TAG;
+
+/// Path of this file, null-terminated, which the I/O manager records along
+/// with the line of each acquisition when it tracks remove lock acquisitions
+const FILE: &str = concat!(file!(), "\0");
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "size_of::<IO_REMOVE_LOCK>() is known to fit in ULONG due to below const assert"
+)]
+const REMOVE_LOCK_SIZE: ULONG = {
+    const S: usize = core::mem::size_of::<IO_REMOVE_LOCK>();
+    const {
+        assert!(
+            S <= ULONG::MAX as usize,
+            "size_of::<IO_REMOVE_LOCK>() should fit in ULONG"
+        );
+    };
+    S as ULONG
+};
+
+/// Initializes `remove_lock`, like `IoInitializeRemoveLock`.
+///
+/// # Safety
+///
+/// `remove_lock` must point to a remove lock in nonpaged memory, e.g. in a
+/// device extension, that nothing acquires yet.
+pub unsafe fn initialize_remove_lock(remove_lock: *mut IO_REMOVE_LOCK) {
+    // SAFETY: `remove_lock` is valid and unused per the contract of the caller
+    unsafe { IoInitializeRemoveLockEx(remove_lock, ECHO_POOL_TAG, 0, 0, REMOVE_LOCK_SIZE) };
+}
+
+/// Acquisition of a remove lock, released with `IoReleaseRemoveLock` when
+/// dropped.
+///
+/// ```rust,ignore
+/// let remove_lock =
+///     unsafe { RemoveLockGuard::acquire(&mut device_extension.remove_lock, irp.cast()) }?;
+/// // The device is not deleted until `remove_lock` is dropped
+/// ```
+#[must_use = "the remove lock is released as soon as the guard is dropped"]
+pub struct RemoveLockGuard {
+    remove_lock: *mut IO_REMOVE_LOCK,
+    tag: PVOID,
+}
+
+impl RemoveLockGuard {
+    /// Acquires `remove_lock` for `tag`, like `IoAcquireRemoveLock`. Dispatch
+    /// routines use the IRP they handle as the tag.
+    ///
+    /// # Safety
+    ///
+    /// `remove_lock` must have been initialized with
+    /// [`initialize_remove_lock`], and stay valid until the guard is
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `STATUS_DELETE_PENDING` once
+    /// [`RemoveLockGuard::release_and_wait`] has been called for the device,
+    /// which the caller completes the IRP with.
+    pub unsafe fn acquire(remove_lock: *mut IO_REMOVE_LOCK, tag: PVOID) -> Result<Self, NTSTATUS> {
+        // SAFETY: `remove_lock` is initialized per the contract of the caller,
+        // and `FILE` is null-terminated
+        let nt_status = unsafe {
+            IoAcquireRemoveLockEx(
+                remove_lock,
+                tag,
+                FILE.as_ptr().cast(),
+                line!(),
+                REMOVE_LOCK_SIZE,
+            )
+        };
+        if !nt_success(nt_status) {
+            return Err(nt_status);
+        }
+        Ok(Self { remove_lock, tag })
+    }
+
+    /// Releases the remove lock and waits until every other acquisition of it
+    /// has been released, like `IoReleaseRemoveLockAndWait`. Further attempts
+    /// to acquire it fail, so this is called once, while handling
+    /// `IRP_MN_REMOVE_DEVICE`, before the device is deleted.
+    ///
+    /// # Safety
+    ///
+    /// Must be called at `PASSIVE_LEVEL`, at most once per remove lock.
+    pub unsafe fn release_and_wait(self) {
+        let this = core::mem::ManuallyDrop::new(self);
+        // SAFETY: The lock was acquired for `tag` by `acquire`, and is not
+        // released again since `this` is not dropped
+        unsafe { IoReleaseRemoveLockAndWaitEx(this.remove_lock, this.tag, REMOVE_LOCK_SIZE) };
+    }
+}
+
+impl Drop for RemoveLockGuard {
+    fn drop(&mut self) {
+        // SAFETY: The lock was acquired for `tag` by `acquire`, and is still
+        // valid per its contract
+        unsafe { IoReleaseRemoveLockEx(self.remove_lock, self.tag, REMOVE_LOCK_SIZE) };
+    }
+}
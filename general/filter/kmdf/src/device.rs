@@ -0,0 +1,162 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    KEY_QUERY_VALUE,
+    NTSTATUS,
+    PLUGPLAY_REGKEY_DEVICE,
+    STATUS_INVALID_DEVICE_STATE,
+    ULONG,
+    UNICODE_STRING,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFKEY,
+    WDFOBJECT,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    paged_code::paged_code_checked,
+    queue::filter_queue_initialize,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_object_get_device_context,
+    DeviceContext,
+};
+
+/// Name of the value of the device hardware key that selects whether the
+/// completion of forwarded requests is logged
+const SNOOP_COMPLETIONS_VALUE_NAME: &str = "SnoopCompletions";
+
+/// Worker routine called to create the filter device and attach it to the
+/// device stack being built.
+///
+/// # Arguments:
+///
+/// * `device_init` - Pointer to an opaque init structure. Memory for this
+///   structure will be freed by the framework when the `WdfDeviceCreate`
+///   succeeds. So don't access the structure after that point.
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+#[link_section = "PAGE"]
+pub fn filter_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
+    paged_code_checked!();
+
+    // Tell the framework that this is a filter driver. The framework then
+    // forwards the requests the driver has no callback for to the next driver,
+    // and does not take the power policy ownership of the device stack. The
+    // device type and characteristics are inherited from the device below.
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfFdoInitSetFilter, device_init);
+    };
+
+    // The hardware key can only be opened through the init structure before the
+    // device is created.
+    let snoop_completions = filter_query_snoop_completions(device_init);
+
+    let mut attributes = ObjectAttributes::new().context::<DeviceContext>().build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            (core::ptr::addr_of_mut!(device_init)).cast(),
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return STATUS_INVALID_DEVICE_STATE;
+    };
+    // The default I/O target of a filter device is the next device in the stack
+    let target = unsafe { call_unsafe_wdf_function_binding!(WdfDeviceGetIoTarget, device) };
+    unsafe {
+        (*device_context).target = target;
+        (*device_context).snoop_completions = snoop_completions;
+    };
+
+    log_info!("Filtering device {device:?}, snoop completions {snoop_completions}");
+
+    // Initialize the I/O Package and any Queues
+    unsafe { filter_queue_initialize(device) }
+}
+
+/// Read the `SnoopCompletions` value of the device hardware key, which the INF
+/// sets in its `.HW` section.
+///
+/// # Arguments:
+///
+/// * `device_init` - Pointer to the init structure of the device, before it is
+///   created.
+///
+/// # Return value:
+///
+/// * Whether the value is set and not 0. A missing key or value is logged and
+///   treated as 0.
+#[link_section = "PAGE"]
+fn filter_query_snoop_completions(device_init: &mut WDFDEVICE_INIT) -> bool {
+    paged_code_checked!();
+
+    let mut key: WDFKEY = core::ptr::null_mut();
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfFdoInitOpenRegistryKey,
+            device_init,
+            PLUGPLAY_REGKEY_DEVICE,
+            KEY_QUERY_VALUE,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut key,
+        )
+    };
+    if !nt_success(nt_status) {
+        log_error!("WdfFdoInitOpenRegistryKey failed {nt_status:#010X}");
+        return false;
+    }
+
+    let mut value_name: Vec<u16> = SNOOP_COMPLETIONS_VALUE_NAME.encode_utf16().collect();
+    #[allow(
+        clippy::cast_possible_truncation,
+        reason = "the value name is a short constant"
+    )]
+    let length = (value_name.len() * core::mem::size_of::<u16>()) as u16;
+    let unicode_string = UNICODE_STRING {
+        Length: length,
+        MaximumLength: length,
+        Buffer: value_name.as_mut_ptr(),
+    };
+
+    let mut value: ULONG = 0;
+    // SAFETY: `key` was opened above, and `unicode_string` points into
+    // `value_name`, which outlives the call.
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(WdfRegistryQueryULong, key, &unicode_string, &mut value)
+    };
+    if !nt_success(nt_status) {
+        log_info!("{SNOOP_COMPLETIONS_VALUE_NAME} not read {nt_status:#010X}, defaulting to 0");
+        value = 0;
+    }
+
+    // SAFETY: `key` was opened above and is not used after being closed
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfRegistryClose, key);
+    };
+
+    value != 0
+}
@@ -0,0 +1,80 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//!    This driver demonstrates a KMDF upper filter driver. It is installed
+//!    on top of the ECHO device of the echo sample, but does not depend on
+//!    it: it can filter any device stack its INF attaches it to.
+//!
+//!    The device it creates is marked as a filter with `WdfFdoInitSetFilter`,
+//!    so the framework passes the requests it has no callback for, such as
+//!    device I/O control requests, down to the next driver in the stack
+//!    without involving it.
+//!
+//!    Read and write requests go through its default queue, where their
+//!    size is logged before they are forwarded to the next driver. When the
+//!    `SnoopCompletions` value of the device hardware key, opened with
+//!    `WdfFdoInitOpenRegistryKey`, is not 0, the requests are forwarded with a
+//!    completion routine that logs how many bytes the lower driver
+//!    transferred. Otherwise, they are sent and forgotten, which is cheaper.
+//!
+//!    The logging and object context macros are the ones of the echo
+//!    sample, whose modules are built into this driver as well.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+
+mod device;
+mod driver;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/log.rs"]
+mod log;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/paged_code.rs"]
+mod paged_code;
+mod queue;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_driver_config.rs"]
+mod wdf_driver_config;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_structure_size.rs"]
+mod wdf_structure_size;
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    WDFIOTARGET,
+    WDFOBJECT,
+    WDF_OBJECT_CONTEXT_TYPE_INFO,
+};
+// The filter only declares a device context, so some of the macros and builder
+// methods of the echo sample are unused
+#[allow(dead_code)]
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_object_attributes.rs"]
+mod wdf_object_attributes;
+#[allow(unused_imports, unused_macros)]
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_object_context.rs"]
+mod wdf_object_context;
+
+use wdf_object_context::wdf_declare_context_type;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+// ====== CONTEXT SETUP ========//
+
+// The device context of the filter device object
+pub struct DeviceContext {
+    // Next driver in the stack, which every request is forwarded to
+    target: WDFIOTARGET,
+    // Whether requests are forwarded with a completion routine that logs their
+    // result, from the `SnoopCompletions` value of the device hardware key
+    snoop_completions: bool,
+}
+wdf_declare_context_type!(DeviceContext);
@@ -0,0 +1,229 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    PWDF_REQUEST_COMPLETION_PARAMS,
+    STATUS_INVALID_DEVICE_STATE,
+    WDFCONTEXT,
+    WDFDEVICE,
+    WDFIOTARGET,
+    WDFOBJECT,
+    WDFQUEUE,
+    WDFREQUEST,
+    WDF_IO_QUEUE_CONFIG,
+    WDF_NO_CONTEXT,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+    WDF_NO_SEND_OPTIONS,
+    WDF_REQUEST_SEND_OPTIONS,
+    _WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_REQUEST_SEND_OPTIONS_FLAGS,
+    _WDF_REQUEST_TYPE,
+    _WDF_TRI_STATE,
+};
+
+use crate::{
+    log::{log_error, log_info},
+    paged_code::paged_code_checked,
+    wdf_object_get_device_context,
+    wdf_structure_size::wdf_structure_size,
+    DeviceContext,
+};
+
+/// Create the default queue of the filter device, which receives the read and
+/// write requests sent to the device stack.
+///
+/// The queue is parallel, since the filter keeps no state about the requests
+/// and the driver below does its own synchronization. It does not register
+/// `EvtIoDeviceControl`, so the framework forwards device I/O control requests
+/// to the next driver without presenting them to the filter.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to a framework device object.
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+#[link_section = "PAGE"]
+pub unsafe fn filter_queue_initialize(device: WDFDEVICE) -> NTSTATUS {
+    paged_code_checked!();
+
+    let mut queue = WDF_NO_HANDLE as WDFQUEUE;
+
+    // The queue of a filter device is not power managed by default: the filter
+    // is not the power policy owner, and the requests are only passed through,
+    // so they do not need to wait for the device to be in D0.
+    let mut queue_config = WDF_IO_QUEUE_CONFIG {
+        Size: wdf_structure_size!(WDF_IO_QUEUE_CONFIG),
+        PowerManaged: _WDF_TRI_STATE::WdfUseDefault,
+        DefaultQueue: u8::from(true),
+        DispatchType: _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchParallel,
+        EvtIoRead: Some(filter_evt_io_read),
+        EvtIoWrite: Some(filter_evt_io_write),
+        ..WDF_IO_QUEUE_CONFIG::default()
+    };
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfIoQueueCreate,
+            device,
+            &mut queue_config,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut queue
+        )
+    };
+
+    if !nt_success(nt_status) {
+        log_error!("WdfIoQueueCreate failed {nt_status:#010X}");
+    }
+
+    nt_status
+}
+
+/// This event is invoked when the framework receives `IRP_MJ_READ` request.
+/// The size of the read is logged and the request is forwarded to the next
+/// driver.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `length` - number of bytes to be read.
+extern "C" fn filter_evt_io_read(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    log_info!("Read of {length} bytes, request {request:?}");
+
+    unsafe { filter_forward_request(queue, request) };
+}
+
+/// This event is invoked when the framework receives `IRP_MJ_WRITE` request.
+/// The size of the write is logged and the request is forwarded to the next
+/// driver.
+///
+/// # Arguments:
+///
+/// * `queue` - Handle to the framework queue object that is associated with the
+///   I/O request.
+/// * `request` - Handle to a framework request object.
+/// * `length` - number of bytes to be written.
+extern "C" fn filter_evt_io_write(queue: WDFQUEUE, request: WDFREQUEST, length: usize) {
+    log_info!("Write of {length} bytes, request {request:?}");
+
+    unsafe { filter_forward_request(queue, request) };
+}
+
+/// Pass `request` down to the next driver in the stack.
+///
+/// With `SnoopCompletions`, the request is formatted for the next driver and a
+/// completion routine is set, so the filter gets it back once it is
+/// completed. Otherwise, it is sent with
+/// `WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET`, which lets the framework skip the
+/// formatting and never returns it to the filter.
+///
+/// # Safety
+///
+/// `request` must be owned by the driver. It is always completed or handed to
+/// the next driver when this function returns.
+unsafe fn filter_forward_request(queue: WDFQUEUE, request: WDFREQUEST) {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfIoQueueGetDevice, queue) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestComplete,
+                request,
+                STATUS_INVALID_DEVICE_STATE
+            );
+        };
+        return;
+    };
+    let DeviceContext {
+        target,
+        snoop_completions,
+    } = unsafe { &*device_context };
+
+    let sent = if *snoop_completions {
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRequestFormatRequestUsingCurrentType, request);
+            call_unsafe_wdf_function_binding!(
+                WdfRequestSetCompletionRoutine,
+                request,
+                Some(filter_evt_request_completion),
+                WDF_NO_CONTEXT
+            );
+            call_unsafe_wdf_function_binding!(
+                WdfRequestSend,
+                request,
+                *target,
+                WDF_NO_SEND_OPTIONS.cast()
+            )
+        }
+    } else {
+        #[allow(
+            clippy::cast_sign_loss,
+            reason = "WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET is a small positive flag"
+        )]
+        let mut send_options = WDF_REQUEST_SEND_OPTIONS {
+            Size: wdf_structure_size!(WDF_REQUEST_SEND_OPTIONS),
+            Flags: _WDF_REQUEST_SEND_OPTIONS_FLAGS::WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET as u32,
+            ..WDF_REQUEST_SEND_OPTIONS::default()
+        };
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRequestSend, request, *target, &mut send_options)
+        }
+    };
+
+    // The request was not sent, so it is still owned by the filter, which
+    // completes it with the status the framework set
+    if sent == 0 {
+        let nt_status = unsafe { call_unsafe_wdf_function_binding!(WdfRequestGetStatus, request) };
+        log_error!("WdfRequestSend failed {nt_status:#010X}");
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, nt_status);
+        };
+    }
+}
+
+/// Completion routine of the requests forwarded with `SnoopCompletions`. It
+/// logs the result of the request, and completes it with the status the next
+/// driver completed it with.
+///
+/// # Arguments:
+///
+/// * `request` - Handle to the request, which is owned by the filter again.
+/// * `_target` - Handle to the I/O target the request was sent to.
+/// * `params` - Completion parameters, including the type of the request.
+/// * `_context` - Unused, `WDF_NO_CONTEXT` is set with the routine.
+extern "C" fn filter_evt_request_completion(
+    request: WDFREQUEST,
+    _target: WDFIOTARGET,
+    params: PWDF_REQUEST_COMPLETION_PARAMS,
+    _context: WDFCONTEXT,
+) {
+    let (nt_status, information) = unsafe {
+        (
+            call_unsafe_wdf_function_binding!(WdfRequestGetStatus, request),
+            call_unsafe_wdf_function_binding!(WdfRequestGetInformation, request),
+        )
+    };
+
+    // SAFETY: The framework passes valid completion parameters for the duration
+    // of the call
+    let request_type = unsafe { (*params).Type };
+    let operation = match request_type {
+        _WDF_REQUEST_TYPE::WdfRequestTypeRead => "Read",
+        _WDF_REQUEST_TYPE::WdfRequestTypeWrite => "Write",
+        _ => "Request",
+    };
+    log_info!("{operation} completed {nt_status:#010X}, {information} bytes, request {request:?}");
+
+    // The information value set by the next driver is kept
+    unsafe {
+        call_unsafe_wdf_function_binding!(WdfRequestComplete, request, nt_status);
+    };
+}
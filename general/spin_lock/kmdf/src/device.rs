@@ -0,0 +1,250 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::{nt_success, wdf};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    NTSTATUS,
+    STATUS_INVALID_DEVICE_STATE,
+    STATUS_SUCCESS,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFOBJECT,
+    WDFTIMER,
+    WDF_NO_HANDLE,
+    WDF_PNPPOWER_EVENT_CALLBACKS,
+    WDF_POWER_DEVICE_STATE,
+    WDF_TIMER_CONFIG,
+};
+
+use crate::{
+    k_spin_lock::KSpinLock,
+    log::{log_error, log_info},
+    paged_code::paged_code_checked,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_object_get_device_context,
+    wdf_spin_lock::SpinLockExt,
+    wdf_structure_size::wdf_structure_size,
+    DeviceContext,
+};
+
+/// Period of the timer incrementing the counts, in milliseconds
+const TIMER_PERIOD: u32 = 1000;
+
+/// Worker routine called to create a device and its software resources.
+///
+/// # Arguments:
+///
+/// * `device_init` - Pointer to an opaque init structure. Memory for this
+///   structure will be freed by the framework when the `WdfDeviceCreate`
+///   succeeds. So don't access the structure after that point.
+///
+/// # Return value:
+///
+/// * `NTSTATUS`
+#[link_section = "PAGE"]
+pub fn spin_lock_device_create(mut device_init: &mut WDFDEVICE_INIT) -> NTSTATUS {
+    paged_code_checked!();
+
+    let mut pnp_power_callbacks = WDF_PNPPOWER_EVENT_CALLBACKS {
+        Size: wdf_structure_size!(WDF_PNPPOWER_EVENT_CALLBACKS),
+        EvtDeviceD0Entry: Some(spin_lock_evt_device_d0_entry),
+        EvtDeviceD0Exit: Some(spin_lock_evt_device_d0_exit),
+        ..WDF_PNPPOWER_EVENT_CALLBACKS::default()
+    };
+
+    unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitSetPnpPowerEventCallbacks,
+            device_init,
+            &mut pnp_power_callbacks
+        );
+    };
+
+    let mut attributes = ObjectAttributes::new().context::<DeviceContext>().build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            (core::ptr::addr_of_mut!(device_init)).cast(),
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        log_error!("WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return STATUS_INVALID_DEVICE_STATE;
+    };
+
+    // The framework spin lock is an object of its own, parented to the device
+    // so that it is deleted with it. Creating it can fail.
+    let mut attributes = ObjectAttributes::new().parent(device as WDFOBJECT).build();
+    let spin_lock = match wdf::SpinLock::create(&mut attributes) {
+        Ok(spin_lock) => spin_lock,
+        Err(nt_status) => {
+            log_error!("WdfSpinLockCreate failed {nt_status:#010X}");
+            return nt_status;
+        }
+    };
+
+    // By not using AutomaticSerialization, the timer function is not serialized
+    // with any other callback of the device, so it has to acquire the locks
+    // itself.
+    let mut timer_config = WDF_TIMER_CONFIG {
+        Size: wdf_structure_size!(WDF_TIMER_CONFIG),
+        EvtTimerFunc: Some(spin_lock_evt_timer_func),
+        Period: TIMER_PERIOD,
+        AutomaticSerialization: u8::from(false),
+        TolerableDelay: 0,
+        ..WDF_TIMER_CONFIG::default()
+    };
+
+    let timer = match wdf::Timer::create(&mut timer_config, &mut attributes) {
+        Ok(timer) => timer,
+        Err(nt_status) => {
+            log_error!("WdfTimerCreate failed {nt_status:#010X}");
+            return nt_status;
+        }
+    };
+
+    unsafe {
+        (*device_context).wdf_spin_lock = spin_lock;
+        (*device_context).wdf_ticks = 0;
+        // The context is zero-initialized, which already is an initialized
+        // KSPIN_LOCK, but the raw spin lock is initialized explicitly like the
+        // framework one is created.
+        (*device_context).k_ticks = KSpinLock::new(0);
+        (*device_context).timer = timer;
+    };
+
+    STATUS_SUCCESS
+}
+
+/// `EvtDeviceD0Entry` is called by the framework when the device enters D0,
+/// e.g. once it has been started. Both counts are reset under their lock, and
+/// the timer is started.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to the framework device object.
+/// * `_previous_state` - Device power state the device is coming from.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - Failures will result in the device stack being torn down.
+extern "C" fn spin_lock_evt_device_d0_entry(
+    device: WDFDEVICE,
+    _previous_state: WDF_POWER_DEVICE_STATE,
+) -> NTSTATUS {
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return STATUS_INVALID_DEVICE_STATE;
+    };
+
+    // Both locks are acquired at PASSIVE_LEVEL here, and raise the IRQL to
+    // DISPATCH_LEVEL while they are held
+    {
+        let _guard = unsafe { (*device_context).wdf_spin_lock.lock() };
+        unsafe { (*device_context).wdf_ticks = 0 };
+    }
+    {
+        let mut k_ticks = unsafe { (*device_context).k_ticks.lock() };
+        *k_ticks = 0;
+    }
+
+    let due_time = -i64::from(TIMER_PERIOD) * 10000;
+    let _ = unsafe { (*device_context).timer.start(due_time) };
+
+    log_info!("Counting ticks every {TIMER_PERIOD} ms");
+
+    STATUS_SUCCESS
+}
+
+/// `EvtDeviceD0Exit` is called by the framework when the device leaves D0,
+/// e.g. when it is disabled. The timer is stopped, and both counts are
+/// reported.
+///
+/// # Arguments:
+///
+/// * `device` - Handle to the framework device object.
+/// * `_target_state` - Device power state the device is going to.
+///
+/// # Return value:
+///
+/// * `NTSTATUS` - The driver is not allowed to fail this function. If it does,
+///   the device stack will be torn down.
+#[link_section = "PAGE"]
+extern "C" fn spin_lock_evt_device_d0_exit(
+    device: WDFDEVICE,
+    _target_state: WDF_POWER_DEVICE_STATE,
+) -> NTSTATUS {
+    paged_code_checked!();
+
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device as WDFOBJECT) })
+    else {
+        // This callback is not allowed to fail
+        log_error!("Device {device:?} has no DeviceContext");
+        return STATUS_SUCCESS;
+    };
+
+    // Wait for a timer function already running to return, so that the counts
+    // are final
+    let _ = unsafe { (*device_context).timer.stop(true) };
+
+    let wdf_ticks = {
+        let _guard = unsafe { (*device_context).wdf_spin_lock.lock() };
+        unsafe { (*device_context).wdf_ticks }
+    };
+    let k_ticks = {
+        let k_ticks = unsafe { (*device_context).k_ticks.lock() };
+        *k_ticks
+    };
+
+    log_info!("Counted {wdf_ticks} ticks under the WDFSPINLOCK, {k_ticks} under the KSPIN_LOCK");
+
+    STATUS_SUCCESS
+}
+
+/// `EvtTimerFunc` of the device timer, called at `DISPATCH_LEVEL` every
+/// [`TIMER_PERIOD`] while the device is in D0. Both counts are incremented
+/// under their lock.
+///
+/// # Arguments:
+///
+/// * `timer` - Handle to the framework timer object, parented to the device.
+extern "C" fn spin_lock_evt_timer_func(timer: WDFTIMER) {
+    let device = unsafe { call_unsafe_wdf_function_binding!(WdfTimerGetParentObject, timer) };
+    let Some(device_context) = (unsafe { wdf_object_get_device_context(device) }) else {
+        log_error!("Device {device:?} has no DeviceContext");
+        return;
+    };
+
+    // Acquiring the framework spin lock is the same call at any IRQL. The
+    // framework records the IRQL it was acquired at to restore it on release.
+    let wdf_ticks = {
+        let _guard = unsafe { (*device_context).wdf_spin_lock.lock() };
+        unsafe {
+            (*device_context).wdf_ticks += 1;
+            (*device_context).wdf_ticks
+        }
+    };
+
+    // The raw spin lock is acquired with KeAcquireSpinLockAtDpcLevel, since the
+    // timer function already runs at DISPATCH_LEVEL
+    let k_ticks = {
+        let mut k_ticks = unsafe { (*device_context).k_ticks.lock() };
+        *k_ticks += 1;
+        *k_ticks
+    };
+
+    log_info!("Tick {wdf_ticks} under the WDFSPINLOCK, {k_ticks} under the KSPIN_LOCK");
+}
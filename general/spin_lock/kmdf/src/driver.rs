@@ -0,0 +1,118 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::nt_success;
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    DRIVER_OBJECT,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    PWDFDEVICE_INIT,
+    WDFDRIVER,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::{
+    device,
+    log::{log_error, log_info},
+    paged_code::paged_code_checked,
+    wdf_driver_config::DriverConfig,
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverObject` is allocated by the system before the driver
+///   is loaded, and it is released by the system after the system unloads the
+///   function driver from memory.
+/// * `registry_path` - represents the driver specific path in the Registry.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"] // WDF expects a symbol with the name DriverEntry
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    #[cfg(feature = "log-etw")]
+    crate::log::initialize();
+
+    let mut driver_config = DriverConfig::new(Some(spin_lock_evt_device_add))
+        .unload(Some(spin_lock_evt_driver_unload))
+        .build();
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver as PDRIVER_OBJECT,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        log_error!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        #[cfg(feature = "log-etw")]
+        crate::log::uninitialize();
+    }
+
+    nt_status
+}
+
+/// `EvtDriverUnload` is called by the framework before the driver is unloaded.
+/// With the `log-etw` logging backend, it unregisters the ETW provider.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+#[link_section = "PAGE"]
+extern "C" fn spin_lock_evt_driver_unload(_driver: WDFDRIVER) {
+    paged_code_checked!();
+
+    #[cfg(feature = "log-etw")]
+    crate::log::uninitialize();
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn spin_lock_evt_device_add(
+    _driver: WDFDRIVER,
+    device_init: PWDFDEVICE_INIT,
+) -> NTSTATUS {
+    paged_code_checked!();
+
+    log_info!("Enter  SpinLockEvtDeviceAdd");
+
+    let device_init =
+        // SAFETY: WDF should always be providing a pointer that is properly aligned, dereferencable per https://doc.rust-lang.org/std/ptr/index.html#safety, and initialized. For the lifetime of the resulting reference, the pointed-to memory is never accessed through any other pointer.
+        unsafe {
+        device_init
+            .as_mut()
+            .expect("WDF should never provide a null pointer for device_init")
+    };
+    device::spin_lock_device_create(device_init)
+}
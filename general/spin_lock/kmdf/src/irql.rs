@@ -0,0 +1,44 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! IRQL bookkeeping of [`KSpinLock`](crate::k_spin_lock::KSpinLock).
+//!
+//! Deciding how a `KSPIN_LOCK` is acquired and released only depends on the
+//! IRQL it is acquired at, so it is kept apart from the kernel calls that
+//! acquire and release it, and can be reasoned about on its own.
+
+use wdk_sys::{DISPATCH_LEVEL, KIRQL};
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "DISPATCH_LEVEL is 2, which fits in KIRQL"
+)]
+const DISPATCH_LEVEL_IRQL: KIRQL = DISPATCH_LEVEL as KIRQL;
+
+/// How a spin lock was acquired, which selects how it must be released
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acquisition {
+    /// Acquired below `DISPATCH_LEVEL` with `KeAcquireSpinLockRaiseToDpc`,
+    /// which raised the IRQL to `DISPATCH_LEVEL`. `KeReleaseSpinLock` must
+    /// lower it back to `old_irql`.
+    Raised { old_irql: KIRQL },
+    /// Acquired at `DISPATCH_LEVEL` with `KeAcquireSpinLockAtDpcLevel`, which
+    /// leaves the IRQL unchanged. It must be released with
+    /// `KeReleaseSpinLockFromDpcLevel`, which does not lower it either.
+    AtDpcLevel,
+}
+
+impl Acquisition {
+    /// How a spin lock must be acquired at `irql`, or `None` above
+    /// `DISPATCH_LEVEL`, where acquiring it would lower the IRQL when it is
+    /// released and a `KSPIN_LOCK` must never be used.
+    pub const fn at(irql: KIRQL) -> Option<Self> {
+        if irql < DISPATCH_LEVEL_IRQL {
+            Some(Self::Raised { old_irql: irql })
+        } else if irql == DISPATCH_LEVEL_IRQL {
+            Some(Self::AtDpcLevel)
+        } else {
+            None
+        }
+    }
+}
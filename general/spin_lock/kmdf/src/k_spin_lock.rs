@@ -0,0 +1,133 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+};
+
+use wdk_sys::{
+    ntddk::{
+        KeAcquireSpinLockAtDpcLevel,
+        KeAcquireSpinLockRaiseToDpc,
+        KeGetCurrentIrql,
+        KeReleaseSpinLock,
+        KeReleaseSpinLockFromDpcLevel,
+    },
+    KSPIN_LOCK,
+};
+
+use crate::irql::Acquisition;
+
+/// `KSPIN_LOCK` owning the data it protects.
+///
+/// Unlike a framework spin lock, a `KSPIN_LOCK` is not an object: it is a
+/// pointer-sized value that `KeInitializeSpinLock` sets to 0, so creating one
+/// cannot fail and it can be embedded in any nonpaged memory, including a
+/// zero-initialized object context. The data is only reachable through the
+/// [`KSpinLockGuard`] returned by [`KSpinLock::lock`], which releases the lock
+/// and restores the IRQL when it goes out of scope.
+///
+/// ```rust,ignore
+/// let ticks = {
+///     let mut ticks = unsafe { (*device_context).k_ticks.lock() };
+///     *ticks += 1;
+///     *ticks
+/// }; // The lock is released here
+/// ```
+pub struct KSpinLock<T> {
+    spin_lock: UnsafeCell<KSPIN_LOCK>,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The data is only accessed while the spin lock is held, so it can be
+// shared between processors as long as it can be sent to them.
+unsafe impl<T: Send> Sync for KSpinLock<T> {}
+
+impl<T> KSpinLock<T> {
+    /// Spin lock protecting `data`, like `KeInitializeSpinLock` in C
+    pub const fn new(data: T) -> Self {
+        Self {
+            // KeInitializeSpinLock is an inline function that zeroes the lock
+            spin_lock: UnsafeCell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquire the spin lock, returning a guard that gives access to the data
+    /// and releases it on drop. It must be called at or below
+    /// `DISPATCH_LEVEL`, and the IRQL is `DISPATCH_LEVEL` until the guard is
+    /// dropped.
+    ///
+    /// Below `DISPATCH_LEVEL`, the lock is acquired with
+    /// `KeAcquireSpinLockRaiseToDpc`, which `KeAcquireSpinLock` expands to. At
+    /// `DISPATCH_LEVEL`, e.g. in a timer or DPC callback, it is acquired with
+    /// the cheaper `KeAcquireSpinLockAtDpcLevel`, which leaves the IRQL alone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called above `DISPATCH_LEVEL`.
+    pub fn lock(&self) -> KSpinLockGuard<'_, T> {
+        // SAFETY: KeGetCurrentIrql can be called at any IRQL
+        let irql = unsafe { KeGetCurrentIrql() };
+        let acquisition =
+            Acquisition::at(irql).expect("a KSPIN_LOCK cannot be acquired above DISPATCH_LEVEL");
+
+        match acquisition {
+            Acquisition::Raised { old_irql } => {
+                // SAFETY: The lock was initialized by `new`, and the IRQL is below
+                // DISPATCH_LEVEL
+                let raised_from = unsafe { KeAcquireSpinLockRaiseToDpc(self.spin_lock.get()) };
+                debug_assert_eq!(raised_from, old_irql);
+            }
+            // SAFETY: The lock was initialized by `new`, and the IRQL is
+            // DISPATCH_LEVEL
+            Acquisition::AtDpcLevel => unsafe { KeAcquireSpinLockAtDpcLevel(self.spin_lock.get()) },
+        }
+
+        KSpinLockGuard {
+            spin_lock: self,
+            acquisition,
+        }
+    }
+}
+
+/// Holds a [`KSpinLock`] acquired with [`KSpinLock::lock`], gives access to its
+/// data, and releases it when dropped.
+#[must_use = "the spinlock is released as soon as the guard is dropped"]
+pub struct KSpinLockGuard<'a, T> {
+    spin_lock: &'a KSpinLock<T>,
+    acquisition: Acquisition,
+}
+
+impl<T> Deref for KSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The lock is held for the lifetime of the guard, so nothing
+        // else accesses the data
+        unsafe { &*self.spin_lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for KSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The lock is held for the lifetime of the guard, so nothing
+        // else accesses the data
+        unsafe { &mut *self.spin_lock.data.get() }
+    }
+}
+
+impl<T> Drop for KSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let spin_lock = self.spin_lock.spin_lock.get();
+        match self.acquisition {
+            // SAFETY: The lock was acquired by `KSpinLock::lock`, which raised
+            // the IRQL from `old_irql`
+            Acquisition::Raised { old_irql } => unsafe { KeReleaseSpinLock(spin_lock, old_irql) },
+            // SAFETY: The lock was acquired by `KSpinLock::lock` at
+            // DISPATCH_LEVEL, which the IRQL still is
+            Acquisition::AtDpcLevel => unsafe { KeReleaseSpinLockFromDpcLevel(spin_lock) },
+        }
+    }
+}
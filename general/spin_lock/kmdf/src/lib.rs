@@ -0,0 +1,92 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//!    This driver compares the two kinds of spin locks a KMDF driver can use:
+//!    a framework spin lock, `WDFSPINLOCK`, wrapped by [`wdk::wdf::SpinLock`],
+//!    and a raw `KSPIN_LOCK` acquired with `KeAcquireSpinLock` like in a WDM
+//!    driver.
+//!
+//!    The device counts the ticks of a periodic timer twice, once under each
+//!    lock. The timer function runs at `DISPATCH_LEVEL` and increments both
+//!    counts, while `EvtDeviceD0Entry` and `EvtDeviceD0Exit` run at
+//!    `PASSIVE_LEVEL` and reset and report them, so both kinds of lock are
+//!    acquired from both IRQLs.
+//!
+//!    * The framework spin lock is a WDF object: creating it can fail, it is
+//!      deleted with its parent, and Driver Verifier and the KMDF verifier
+//!      track its use. It does not own the data it protects, so the count is
+//!      accessed through the device context, and only the code reading it shows
+//!      that the lock is held.
+//!    * The `KSPIN_LOCK` is a pointer-sized value that needs no allocation, and
+//!      is wrapped in [`k_spin_lock::KSpinLock`], which owns the count and only
+//!      hands it out through the guard returned by acquiring the lock. The
+//!      guard restores the IRQL the lock was acquired at when it is dropped,
+//!      which is the bookkeeping the framework otherwise does, and is kept in
+//!      [`irql`], away from the kernel calls.
+//!
+//!    The logging, paged code and object context modules are the ones of the
+//!    echo sample, like the scoped locking of framework spin locks.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+
+mod device;
+mod driver;
+mod irql;
+mod k_spin_lock;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/log.rs"]
+mod log;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/paged_code.rs"]
+mod paged_code;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_driver_config.rs"]
+mod wdf_driver_config;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_spin_lock.rs"]
+mod wdf_spin_lock;
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_structure_size.rs"]
+mod wdf_structure_size;
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+use k_spin_lock::KSpinLock;
+use wdk::wdf;
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+use wdk_sys::{call_unsafe_wdf_function_binding, WDFOBJECT, WDF_OBJECT_CONTEXT_TYPE_INFO};
+// The driver only declares a device context, so some of the macros and builder
+// methods of the echo sample are unused
+#[allow(dead_code)]
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_object_attributes.rs"]
+mod wdf_object_attributes;
+#[allow(unused_imports, unused_macros)]
+#[path = "../../../echo/kmdf/driver/DriverSync/src/wdf_object_context.rs"]
+mod wdf_object_context;
+
+use wdf_object_context::wdf_declare_context_type;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+// ====== CONTEXT SETUP ========//
+
+// The device context of the spin lock device
+pub struct DeviceContext {
+    // Framework spin lock protecting `wdf_ticks`
+    wdf_spin_lock: wdf::SpinLock,
+    // Ticks of `timer` since the device entered D0. Only accessed while
+    // `wdf_spin_lock` is held.
+    wdf_ticks: u64,
+    // Ticks of `timer` since the device entered D0, owned by the raw spin lock
+    // protecting it
+    k_ticks: KSpinLock<u64>,
+    // Periodic timer incrementing both counts while the device is in D0
+    timer: wdf::Timer,
+}
+wdf_declare_context_type!(DeviceContext);
@@ -0,0 +1,235 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::sync::atomic::AtomicU64;
+
+use wdk::{nt_success, paged_code, println};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ntddk::{KeAcquireSpinLockRaiseToDpc, KeReleaseSpinLock},
+    DRIVER_OBJECT,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    STATUS_SUCCESS,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFDRIVER,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+    WDF_PNPPOWER_EVENT_CALLBACKS,
+    WDF_POWER_DEVICE_STATE,
+};
+
+use crate::{
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    wdf_structure_size::wdf_structure_size,
+    GUID_DEVINTERFACE,
+    LOCK_A,
+    LOCK_B,
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverEntry` must initialize members of `DriverObject`
+///   before it returns to the caller. `DriverObject` is allocated by the system
+///   before the driver is loaded, and it is released by the system after the
+///   system unloads the function driver from memory.
+/// * `registry_path` - represents the driver specific path in the Registry. The
+///   function driver can use the path to store driver related data between
+///   reboots. The path does not store hardware instance specific data.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"]
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    println!("Enter: driver_entry");
+
+    let mut driver_config = DriverConfig::new(Some(evt_driver_device_add))
+        .unload(Some(evt_driver_unload))
+        .build();
+
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver as PDRIVER_OBJECT,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: driver_entry");
+
+    nt_status
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn evt_driver_device_add(
+    _driver: WDFDRIVER,
+    mut device_init: *mut WDFDEVICE_INIT,
+) -> NTSTATUS {
+    paged_code!();
+
+    println!("Enter: evt_driver_device_add");
+
+    // Register the D0 entry callback, which takes the locks in the opposite
+    // order once the device is started
+    let mut pnp_power_callbacks = WDF_PNPPOWER_EVENT_CALLBACKS {
+        Size: wdf_structure_size!(WDF_PNPPOWER_EVENT_CALLBACKS),
+        EvtDeviceD0Entry: Some(evt_device_d0_entry),
+        ..WDF_PNPPOWER_EVENT_CALLBACKS::default()
+    };
+
+    unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitSetPnpPowerEventCallbacks,
+            device_init,
+            &mut pnp_power_callbacks
+        );
+    };
+
+    let mut attributes = ObjectAttributes::new().build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            &mut device_init,
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateDeviceInterface,
+            device,
+            &GUID_DEVINTERFACE,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreateDeviceInterface failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    // Take LOCK_A, then LOCK_B. This order is fine on its own: it is only
+    // wrong because evt_device_d0_entry uses the opposite one.
+    unsafe { acquire_and_release(&LOCK_A, &LOCK_B) };
+
+    println!("Exit: evt_driver_device_add");
+
+    nt_status
+}
+
+/// `EvtDeviceD0Entry` is called by the framework when the device enters D0,
+/// e.g. once it has been started.
+///
+/// # Arguments:
+///
+/// * `_device` - Handle to the framework device object.
+/// * `_previous_state` - Device power state the device is coming from.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS`
+extern "C" fn evt_device_d0_entry(
+    _device: WDFDEVICE,
+    _previous_state: WDF_POWER_DEVICE_STATE,
+) -> NTSTATUS {
+    println!("Enter: evt_device_d0_entry");
+
+    // Take LOCK_B, then LOCK_A, the opposite of evt_driver_device_add. Ideally,
+    // every code path should acquire the locks in the same order. But to
+    // demonstrate the Driver Verifier's ability to catch potential deadlocks,
+    // the order is deliberately inconsistent.
+    unsafe { acquire_and_release(&LOCK_B, &LOCK_A) };
+
+    println!("Exit: evt_device_d0_entry");
+
+    STATUS_SUCCESS
+}
+
+/// Acquire `first` and then `second`, and release them in the reverse order.
+///
+/// # Arguments:
+///
+/// * `first` - Spin lock acquired first.
+/// * `second` - Spin lock acquired while holding `first`.
+///
+/// # Safety
+///
+/// Both locks must be initialized `KSPIN_LOCK`s, and the caller must be at or
+/// below `DISPATCH_LEVEL`.
+unsafe fn acquire_and_release(first: &AtomicU64, second: &AtomicU64) {
+    // SAFETY: `first` is an initialized spin lock per the contract of the
+    // caller. KeAcquireSpinLock is a macro expanding to this function.
+    let first_irql = unsafe { KeAcquireSpinLockRaiseToDpc(first.as_ptr()) };
+    // SAFETY: `second` is an initialized spin lock per the contract of the
+    // caller
+    let second_irql = unsafe { KeAcquireSpinLockRaiseToDpc(second.as_ptr()) };
+
+    // SAFETY: `second` was acquired above, at `second_irql`
+    unsafe { KeReleaseSpinLock(second.as_ptr(), second_irql) };
+    // SAFETY: `first` was acquired above, at `first_irql`
+    unsafe { KeReleaseSpinLock(first.as_ptr(), first_irql) };
+}
+
+/// This event callback function is called before the driver is unloaded
+///
+/// The EvtDriverUnload callback function must deallocate any
+/// non-device-specific system resources that the driver's DriverEntry routine
+/// allocated.
+///
+/// # Argument:
+///
+/// * `driver` - Handle to the framework driver object
+///
+/// # Return Value:
+///
+/// None
+extern "C" fn evt_driver_unload(_driver: WDFDRIVER) {
+    println!("Enter: evt_driver_unload");
+
+    println!("Exit: evt_driver_unload");
+}
@@ -0,0 +1,63 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!
+//! The driver holds two spin locks, LOCK_A and LOCK_B. Its EvtDeviceAdd
+//! callback acquires LOCK_A and then LOCK_B, while its EvtDeviceD0Entry
+//! callback acquires them in the opposite order. The two callbacks never run
+//! at the same time here, so the driver does not actually hang, but two threads
+//! taking the locks in these orders at the same time would each wait forever
+//! for the lock the other one holds.
+//!
+//! By enabling the deadlock detection of Driver Verifier on this driver, the
+//! inconsistent lock order is caught as soon as the second callback acquires
+//! the locks, without the deadlock ever having to happen, and with an active
+//! KDNET session, the bug can be analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+use core::sync::atomic::AtomicU64;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use wdk_sys::GUID;
+
+// {FFDE493F-9CA0-4362-A90F-CA6B322E6554}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0xFFDE_493Fu32,
+    Data2: 0x9CA0u16,
+    Data3: 0x4362u16,
+    Data4: [
+        0xA9u8, 0x0Fu8, 0xCAu8, 0x6Bu8, 0x32u8, 0x2Eu8, 0x65u8, 0x54u8,
+    ],
+};
+
+// The two KSPIN_LOCKs of the driver. A KSPIN_LOCK is a pointer-sized value
+// that KeInitializeSpinLock sets to 0, so they are atomics initialized to 0
+// rather than `static mut`s, and handed to the kernel through `as_ptr`.
+static LOCK_A: AtomicU64 = AtomicU64::new(0);
+static LOCK_B: AtomicU64 = AtomicU64::new(0);
+
+mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;
@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{WDF_OBJECT_ATTRIBUTES, _WDF_EXECUTION_LEVEL, _WDF_SYNCHRONIZATION_SCOPE};
+
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Builder of `WDF_OBJECT_ATTRIBUTES`, like `WDF_OBJECT_ATTRIBUTES_INIT` in C.
+///
+/// The attributes are correctly sized, and inherit the execution level and
+/// synchronization scope of the parent object.
+///
+/// ```rust,ignore
+/// let mut attributes = ObjectAttributes::new().build();
+/// ```
+#[must_use]
+pub struct ObjectAttributes {
+    attributes: WDF_OBJECT_ATTRIBUTES,
+}
+
+impl ObjectAttributes {
+    /// Attributes with no context, no callbacks and the default parent
+    pub fn new() -> Self {
+        Self {
+            attributes: WDF_OBJECT_ATTRIBUTES {
+                Size: wdf_structure_size!(WDF_OBJECT_ATTRIBUTES),
+                ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
+                SynchronizationScope:
+                    _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
+                ..WDF_OBJECT_ATTRIBUTES::default()
+            },
+        }
+    }
+
+    /// The attributes, to pass by pointer to the function creating the object
+    pub const fn build(self) -> WDF_OBJECT_ATTRIBUTES {
+        self.attributes
+    }
+}
+
+impl Default for ObjectAttributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::sync::atomic::Ordering;
+
+use wdk::{nt_success, paged_code, println};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ntddk::{ExAllocatePool2, ExFreePool, KeGetCurrentIrql},
+    APC_LEVEL,
+    DRIVER_OBJECT,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    POOL_FLAG_NON_PAGED,
+    SIZE_T,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFDRIVER,
+    WDFOBJECT,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::{
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    GLOBAL_BUFFER,
+    GUID_DEVINTERFACE,
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverEntry` must initialize members of `DriverObject`
+///   before it returns to the caller. `DriverObject` is allocated by the system
+///   before the driver is loaded, and it is released by the system after the
+///   system unloads the function driver from memory.
+/// * `registry_path` - represents the driver specific path in the Registry. The
+///   function driver can use the path to store driver related data between
+///   reboots. The path does not store hardware instance specific data.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"]
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    println!("Enter: driver_entry");
+
+    let mut driver_config = DriverConfig::new(Some(evt_driver_device_add))
+        .unload(Some(evt_driver_unload))
+        .build();
+
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver as PDRIVER_OBJECT,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: driver_entry");
+
+    nt_status
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn evt_driver_device_add(
+    _driver: WDFDRIVER,
+    mut device_init: *mut WDFDEVICE_INIT,
+) -> NTSTATUS {
+    paged_code!();
+
+    println!("Enter: evt_driver_device_add");
+
+    let mut attributes = ObjectAttributes::new()
+        .cleanup(Some(evt_device_cleanup))
+        .build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            &mut device_init,
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    // Allocate non-paged memory pool of 64 bytes (arbitrarily chosen) for the
+    // Global buffer. This pool of memory is freed in evt_device_cleanup and then
+    // intentionally freed again in evt_driver_unload.
+    unsafe {
+        const LENGTH: usize = 64;
+        GLOBAL_BUFFER.store(
+            ExAllocatePool2(POOL_FLAG_NON_PAGED, LENGTH as SIZE_T, 's' as u32),
+            Ordering::SeqCst,
+        );
+    }
+
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateDeviceInterface,
+            device,
+            &GUID_DEVINTERFACE,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreateDeviceInterface failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: evt_driver_device_add");
+
+    nt_status
+}
+
+/// This event callback function is called when the device object is being
+/// deleted, after the device has been removed
+///
+/// The EvtCleanupCallback callback function releases the resources that were
+/// allocated for the device, so the Global buffer is freed here.
+///
+/// # Argument:
+///
+/// * `_device` - Handle to the framework device object
+///
+/// # Return Value:
+///
+/// None
+extern "C" fn evt_device_cleanup(_device: WDFOBJECT) {
+    println!("Enter: evt_device_cleanup");
+
+    unsafe { ExFreePool(GLOBAL_BUFFER.load(Ordering::SeqCst)) };
+
+    println!("Exit: evt_device_cleanup");
+}
+
+/// This event callback function is called before the driver is unloaded
+///
+/// The EvtDriverUnload callback function must deallocate any
+/// non-device-specific system resources that the driver's DriverEntry routine
+/// allocated.
+///
+/// # Argument:
+///
+/// * `driver` - Handle to the framework driver object
+///
+/// # Return Value:
+///
+/// None
+extern "C" fn evt_driver_unload(_driver: WDFDRIVER) {
+    println!("Enter: evt_driver_unload");
+
+    // The Global buffer was already freed in evt_device_cleanup, and should not
+    // be touched here. But to demonstrate the Driver Verifier's ability to catch
+    // double frees, the buffer is deliberately freed a second time.
+    unsafe { ExFreePool(GLOBAL_BUFFER.load(Ordering::SeqCst)) };
+
+    println!("Exit: evt_driver_unload");
+}
@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!     
+//! The driver is designed to allocate memory using ExAllocatePool2 to its
+//! Device Context buffer when a device is added by the PnP manager. The buffer
+//! is freed with ExFreePool in the device's cleanup callback when the device is
+//! removed, and then freed a second time in the driver unload function.
+//!
+//! By enabling Driver Verifier with the Special Pool option on this driver,
+//! the double free is caught when the driver is unloaded: the first free makes
+//! the special pool page inaccessible, so the second ExFreePool faults on it
+//! and the system bugchecks with DRIVER_PAGE_FAULT_IN_FREED_SPECIAL_POOL. With
+//! an active KDNET session, the bug can be analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use core::{ffi::c_void, sync::atomic::AtomicPtr};
+
+use wdk_sys::GUID;
+
+// {5E3C8B1A-7D24-4F6B-9A0E-2C1D8F4B6A73}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0x5E3C_8B1Au32,
+    Data2: 0x7D24u16,
+    Data3: 0x4F6Bu16,
+    Data4: [
+        0x9Au8, 0x0Eu8, 0x2Cu8, 0x1Du8, 0x8Fu8, 0x4Bu8, 0x6Au8, 0x73u8,
+    ],
+};
+
+// Global Buffer for the driver. It is an atomic rather than a `static mut`, so
+// that devices added concurrently do not race on it.
+static GLOBAL_BUFFER: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;
@@ -0,0 +1,207 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk::{nt_success, paged_code, println};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ntddk::KeGetCurrentIrql,
+    APC_LEVEL,
+    DISPATCH_LEVEL,
+    DRIVER_OBJECT,
+    KIRQL,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFDRIVER,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+};
+
+use crate::{
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    GUID_DEVINTERFACE,
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverEntry` must initialize members of `DriverObject`
+///   before it returns to the caller. `DriverObject` is allocated by the system
+///   before the driver is loaded, and it is released by the system after the
+///   system unloads the function driver from memory.
+/// * `registry_path` - represents the driver specific path in the Registry. The
+///   function driver can use the path to store driver related data between
+///   reboots. The path does not store hardware instance specific data.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"]
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    println!("Enter: driver_entry");
+
+    let mut driver_config = DriverConfig::new(Some(evt_driver_device_add))
+        .unload(Some(evt_driver_unload))
+        .build();
+
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver as PDRIVER_OBJECT,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: driver_entry");
+
+    nt_status
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn evt_driver_device_add(
+    _driver: WDFDRIVER,
+    mut device_init: *mut WDFDEVICE_INIT,
+) -> NTSTATUS {
+    paged_code!();
+
+    println!("Enter: evt_driver_device_add");
+
+    let mut attributes = ObjectAttributes::new().build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            &mut device_init,
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateDeviceInterface,
+            device,
+            &GUID_DEVINTERFACE,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreateDeviceInterface failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    // Raise the IRQL to DISPATCH_LEVEL. Ideally, the previous IRQL should be
+    // restored by calling KeLowerIrql before returning. But to demonstrate the
+    // Driver Verifier's ability to catch IRQL leaks, the callback deliberately
+    // returns to the framework at DISPATCH_LEVEL.
+    #[allow(clippy::cast_possible_truncation)]
+    let _old_irql = unsafe { ke_raise_irql(DISPATCH_LEVEL as KIRQL) };
+
+    println!("Exit: evt_driver_device_add");
+
+    nt_status
+}
+
+/// Port of the `KeRaiseIrql` macro, which expands to `KfRaiseIrql`. On x64,
+/// `KfRaiseIrql` is an inline function that writes the new IRQL to CR8, so it
+/// is not part of the generated bindings.
+///
+/// # Arguments:
+///
+/// * `new_irql` - IRQL to raise to. It must be greater than or equal to the
+///   current IRQL.
+///
+/// # Return value:
+///
+/// * The IRQL before it was raised
+#[cfg(target_arch = "x86_64")]
+unsafe fn ke_raise_irql(new_irql: KIRQL) -> KIRQL {
+    let old_irql = unsafe { KeGetCurrentIrql() };
+    // SAFETY: CR8 holds the current IRQL on x64, and raising it is how
+    // KfRaiseIrql is implemented in wdm.h
+    unsafe {
+        core::arch::asm!(
+            "mov cr8, {}",
+            in(reg) u64::from(new_irql),
+            options(nostack, preserves_flags)
+        );
+    }
+    old_irql
+}
+
+/// Port of the `KeRaiseIrql` macro, which expands to `KfRaiseIrql`. On
+/// `ARM64`, `KfRaiseIrql` is exported by the kernel.
+///
+/// # Arguments:
+///
+/// * `new_irql` - IRQL to raise to. It must be greater than or equal to the
+///   current IRQL.
+///
+/// # Return value:
+///
+/// * The IRQL before it was raised
+#[cfg(target_arch = "aarch64")]
+unsafe fn ke_raise_irql(new_irql: KIRQL) -> KIRQL {
+    unsafe { wdk_sys::ntddk::KfRaiseIrql(new_irql) }
+}
+
+/// This event callback function is called before the driver is unloaded
+///
+/// The EvtDriverUnload callback function must deallocate any
+/// non-device-specific system resources that the driver's DriverEntry routine
+/// allocated.
+///
+/// # Argument:
+///
+/// * `driver` - Handle to the framework driver object
+///
+/// # Return Value:
+///
+/// None
+extern "C" fn evt_driver_unload(_driver: WDFDRIVER) {
+    println!("Enter: evt_driver_unload");
+
+    println!("Exit: evt_driver_unload");
+}
@@ -0,0 +1,53 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!     
+//! The driver is designed to raise the IRQL to DISPATCH_LEVEL with
+//! KeRaiseIrql in its EvtDeviceAdd callback when a device is added by the PnP
+//! manager. However, the callback returns to the framework without calling
+//! KeLowerIrql to restore the IRQL it was called at.
+//!
+//! By enabling Driver Verifier on this driver, the IRQL leak is caught as soon
+//! as the callback returns, since the caller finds the IRQL different from the
+//! one it made the call at, and with an active KDNET session, the bug can be
+//! analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use wdk_sys::GUID;
+
+// {3F9A6C21-8E47-4B05-A1D3-7C5E2B9F0846}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0x3F9A_6C21u32,
+    Data2: 0x8E47u16,
+    Data3: 0x4B05u16,
+    Data4: [
+        0xA1u8, 0xD3u8, 0x7Cu8, 0x5Eu8, 0x2Bu8, 0x9Fu8, 0x08u8, 0x46u8,
+    ],
+};
+
+mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;
@@ -0,0 +1,57 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!
+//! The driver allocates a page with MmAllocatePagesForMdlEx when a device is
+//! added by the PnP manager, and maps it into system space through its MDL
+//! with MmGetSystemAddressForMdlSafe. The mapping is wrapped in a MappedMdl,
+//! which unmaps it with MmUnmapLockedPages when it is dropped, and only hands
+//! out the buffer as a slice borrowed from it, so that safe code cannot use the
+//! buffer once it is unmapped. The driver deliberately keeps a raw pointer to
+//! the buffer instead, and writes through it after the mapping is dropped.
+//!
+//! By enabling Driver Verifier on this driver, the write to the unmapped
+//! address is caught as it happens, and with an active KDNET session, the bug
+//! can be analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use wdk_sys::GUID;
+
+// {EE38E92B-4D01-42F0-8822-5FFBAFD04372}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0xEE38_E92Bu32,
+    Data2: 0x4D01u16,
+    Data3: 0x42F0u16,
+    Data4: [
+        0x88u8, 0x22u8, 0x5Fu8, 0xFBu8, 0xAFu8, 0xD0u8, 0x43u8, 0x72u8,
+    ],
+};
+
+mod driver;
+mod mapped_mdl;
+mod mdl;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;
@@ -0,0 +1,98 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{
+    ntddk::{ExFreePool, MmAllocatePagesForMdlEx, MmFreePagesFromMdl, MmUnmapLockedPages},
+    PHYSICAL_ADDRESS,
+    PMDL,
+    SIZE_T,
+    _MEMORY_CACHING_TYPE,
+};
+
+use crate::mdl::get_system_address_for_mdl_safe;
+
+/// Pages allocated with `MmAllocatePagesForMdlEx`, described by an MDL and
+/// mapped into system space.
+///
+/// Unlike the MDL of a direct I/O request, which the I/O manager unmaps when
+/// the request is completed, the driver owns both the pages and their mapping:
+/// dropping a [`MappedMdl`] unmaps the pages with `MmUnmapLockedPages` and
+/// frees them. The buffer is only handed out as a slice borrowed from the
+/// [`MappedMdl`], so safe code cannot touch it once it is unmapped.
+pub struct MappedMdl {
+    mdl: PMDL,
+    buffer: *mut u8,
+    length: usize,
+}
+
+impl MappedMdl {
+    /// Allocate `length` bytes of pages anywhere in physical memory, and map
+    /// them into system space.
+    ///
+    /// # Return value:
+    ///
+    /// * The mapped pages, or `None` if the system is too low on memory or on
+    ///   system address space.
+    pub fn allocate(length: usize) -> Option<Self> {
+        let low_address = PHYSICAL_ADDRESS { QuadPart: 0 };
+        let high_address = PHYSICAL_ADDRESS { QuadPart: -1 };
+        let skip_bytes = PHYSICAL_ADDRESS { QuadPart: 0 };
+
+        // SAFETY: The returned MDL describes locked pages that this driver owns
+        // until they are freed in `drop`
+        let mdl = unsafe {
+            MmAllocatePagesForMdlEx(
+                low_address,
+                high_address,
+                skip_bytes,
+                length as SIZE_T,
+                _MEMORY_CACHING_TYPE::MmCached,
+                0,
+            )
+        };
+        if mdl.is_null() {
+            return None;
+        }
+
+        let mut mapped_mdl = Self {
+            mdl,
+            buffer: core::ptr::null_mut(),
+            length,
+        };
+
+        // SAFETY: The MDL was built by MmAllocatePagesForMdlEx, so it describes
+        // locked pages. It may describe fewer bytes than requested, which
+        // `length` must account for.
+        unsafe {
+            mapped_mdl.length = (*mdl).ByteCount as usize;
+            mapped_mdl.buffer = get_system_address_for_mdl_safe(mdl).cast();
+        }
+        if mapped_mdl.buffer.is_null() || mapped_mdl.length < length {
+            return None;
+        }
+
+        Some(mapped_mdl)
+    }
+
+    /// The mapped buffer, which cannot outlive the mapping
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: The buffer is a mapping of `length` bytes of pages owned by
+        // `self`, which is not dropped while the slice is borrowed
+        unsafe { core::slice::from_raw_parts_mut(self.buffer, self.length) }
+    }
+}
+
+impl Drop for MappedMdl {
+    fn drop(&mut self) {
+        if !self.buffer.is_null() {
+            // SAFETY: `buffer` is the mapping of `mdl` made in `allocate`
+            unsafe { MmUnmapLockedPages(self.buffer.cast(), self.mdl) };
+        }
+        // SAFETY: The pages of `mdl` were allocated by MmAllocatePagesForMdlEx,
+        // and are no longer mapped
+        unsafe { MmFreePagesFromMdl(self.mdl) };
+        // SAFETY: MmFreePagesFromMdl does not free the MDL itself, which was
+        // allocated from pool by MmAllocatePagesForMdlEx
+        unsafe { ExFreePool(self.mdl.cast()) };
+    }
+}
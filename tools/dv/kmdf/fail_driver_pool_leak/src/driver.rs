@@ -1,6 +1,9 @@
 // Copyright (c) Microsoft Corporation.
 // License: MIT OR Apache-2.0
 
+#[cfg(not(feature = "drop-on-cleanup"))]
+use core::sync::atomic::Ordering;
+
 use wdk::{nt_success, paged_code, println};
 use wdk_sys::{
     call_unsafe_wdf_function_binding,
@@ -12,19 +15,52 @@
     PDRIVER_OBJECT,
     POOL_FLAG_NON_PAGED,
     SIZE_T,
-    ULONG,
     WDFDEVICE,
     WDFDEVICE_INIT,
     WDFDRIVER,
-    WDF_DRIVER_CONFIG,
     WDF_NO_HANDLE,
     WDF_NO_OBJECT_ATTRIBUTES,
-    WDF_OBJECT_ATTRIBUTES,
-    _WDF_EXECUTION_LEVEL,
-    _WDF_SYNCHRONIZATION_SCOPE,
+};
+#[cfg(feature = "drop-on-cleanup")]
+use wdk_sys::{ntddk::ExFreePool, PVOID, WDFOBJECT, WDF_OBJECT_CONTEXT_TYPE_INFO};
+
+#[cfg(feature = "drop-on-cleanup")]
+use crate::wdf_object_context::wdf_declare_context_type_with_name_and_drop;
+#[cfg(not(feature = "drop-on-cleanup"))]
+use crate::GLOBAL_BUFFER;
+use crate::{
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    GUID_DEVINTERFACE,
 };
 
-use crate::{GLOBAL_BUFFER, GUID_DEVINTERFACE};
+/// Pool allocation freed when it is dropped, with the `drop-on-cleanup`
+/// feature. It is null, and not freed, in a context the framework has just
+/// zero-initialized.
+#[cfg(feature = "drop-on-cleanup")]
+pub struct PoolAllocation(PVOID);
+
+#[cfg(feature = "drop-on-cleanup")]
+impl Drop for PoolAllocation {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // SAFETY: The pointer was returned by ExAllocatePool2 and is only
+            // freed here
+            unsafe { ExFreePool(self.0) };
+        }
+    }
+}
+
+/// Context of the device with the `drop-on-cleanup` feature, owning the buffer
+/// allocated when the device is added. `device_context_evt_cleanup` drops it
+/// when the device is deleted, which frees the buffer.
+#[cfg(feature = "drop-on-cleanup")]
+pub struct DeviceContext {
+    buffer: PoolAllocation,
+}
+
+#[cfg(feature = "drop-on-cleanup")]
+wdf_declare_context_type_with_name_and_drop!(DeviceContext, device_get_context);
 
 /// `DriverEntry` initializes the driver and is the first routine called by the
 /// system after the driver is loaded. `DriverEntry` specifies the other entry
@@ -53,28 +89,9 @@ extern "system" fn driver_entry(
 ) -> NTSTATUS {
     println!("Enter: driver_entry");
 
-    let mut driver_config = {
-        let wdf_driver_config_size: ULONG;
-
-        // clippy::cast_possible_truncation cannot currently check compile-time constants: https://github.com/rust-lang/rust-clippy/issues/9613
-        #[allow(clippy::cast_possible_truncation)]
-        {
-            const WDF_DRIVER_CONFIG_SIZE: usize = core::mem::size_of::<WDF_DRIVER_CONFIG>();
-
-            // Manually assert there is not truncation since clippy doesn't work for
-            // compile-time constants
-            const { assert!(WDF_DRIVER_CONFIG_SIZE <= ULONG::MAX as usize) }
-
-            wdf_driver_config_size = WDF_DRIVER_CONFIG_SIZE as ULONG;
-        }
-
-        WDF_DRIVER_CONFIG {
-            Size: wdf_driver_config_size,
-            EvtDriverDeviceAdd: Some(evt_driver_device_add),
-            EvtDriverUnload: Some(evt_driver_unload),
-            ..WDF_DRIVER_CONFIG::default()
-        }
-    };
+    let mut driver_config = DriverConfig::new(Some(evt_driver_device_add))
+        .unload(Some(evt_driver_unload))
+        .build();
 
     let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
 
@@ -121,13 +138,13 @@ extern "C" fn evt_driver_device_add(
 
     println!("Enter: evt_driver_device_add");
 
-    #[allow(clippy::cast_possible_truncation)]
-    let mut attributes = WDF_OBJECT_ATTRIBUTES {
-        Size: core::mem::size_of::<WDF_OBJECT_ATTRIBUTES>() as ULONG,
-        ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
-        SynchronizationScope: _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
-        ..WDF_OBJECT_ATTRIBUTES::default()
-    };
+    #[cfg(not(feature = "drop-on-cleanup"))]
+    let mut attributes = ObjectAttributes::new().build();
+    #[cfg(feature = "drop-on-cleanup")]
+    let mut attributes = ObjectAttributes::new()
+        .context::<DeviceContext>()
+        .cleanup(Some(device_context_evt_cleanup))
+        .build();
 
     let mut device = WDF_NO_HANDLE as WDFDEVICE;
     let mut nt_status = unsafe {
@@ -147,9 +164,27 @@ extern "C" fn evt_driver_device_add(
     // Allocate non-paged memory pool of 64 bytes (arbitrarily chosen) for the
     // Global buffer. This pool of memory is intentionally not freed by
     // the driver.
+    #[cfg(not(feature = "drop-on-cleanup"))]
     unsafe {
         const LENGTH: usize = 64;
-        GLOBAL_BUFFER = ExAllocatePool2(POOL_FLAG_NON_PAGED, LENGTH as SIZE_T, 's' as u32);
+        GLOBAL_BUFFER.store(
+            ExAllocatePool2(POOL_FLAG_NON_PAGED, LENGTH as SIZE_T, 's' as u32),
+            Ordering::SeqCst,
+        );
+    }
+
+    // With the `drop-on-cleanup` feature, the buffer is owned by the context of
+    // the device instead, and freed when the device is deleted
+    #[cfg(feature = "drop-on-cleanup")]
+    unsafe {
+        const LENGTH: usize = 64;
+        if let Some(context) = device_get_context(device as WDFOBJECT) {
+            (*context).buffer = PoolAllocation(ExAllocatePool2(
+                POOL_FLAG_NON_PAGED,
+                LENGTH as SIZE_T,
+                's' as u32,
+            ));
+        }
     }
 
     nt_status = unsafe {
@@ -187,11 +222,13 @@ extern "C" fn evt_driver_device_add(
 extern "C" fn evt_driver_unload(_driver: WDFDRIVER) {
     println!("Enter: evt_driver_unload");
 
-    // Ideally, the memory allocated to the Global buffer in lib.rs L51 should
-    // be freed here by calling the ExFreePool API. But to demonstrate the Driver
-    // Verifier's ability to catch pool leaks, the buffer is deliberately not freed.
+    // Ideally, the memory allocated to the Global buffer in evt_driver_device_add
+    // should be freed here by calling the ExFreePool API. But to demonstrate
+    // the Driver Verifier's ability to catch pool leaks, the buffer is
+    // deliberately not freed. With the `drop-on-cleanup` feature, there is no
+    // global buffer: each buffer was freed when its device was deleted.
 
-    // unsafe { wdk_sys::ntddk::ExFreePool(GLOBAL_BUFFER) };
+    // unsafe { wdk_sys::ntddk::ExFreePool(GLOBAL_BUFFER.load(Ordering::SeqCst)) };
 
     println!("Exit: evt_driver_unload");
 }
@@ -15,6 +15,10 @@
 //! By enabling Driver Verifier on this driver, the pool leak
 //! violation can be caught when the driver is unloaded and with an active KDNET
 //! session, the bug can be analyzed further.
+//!
+//! With the `drop-on-cleanup` feature, the driver is fixed instead: the buffer
+//! is owned by the context of the device, and freed by its `Drop`
+//! implementation when the device is deleted.
 
 #![no_std]
 #![deny(clippy::all)]
@@ -34,7 +38,10 @@
 #[global_allocator]
 static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
 
-use wdk_sys::{GUID, PVOID};
+#[cfg(not(feature = "drop-on-cleanup"))]
+use core::{ffi::c_void, sync::atomic::AtomicPtr};
+
+use wdk_sys::GUID;
 
 // {A1B2C3D4-E5F6-7890-1234-56789ABCDEF0}
 const GUID_DEVINTERFACE: GUID = GUID {
@@ -46,7 +53,14 @@
     ],
 };
 
-// Global Buffer for the driver
-static mut GLOBAL_BUFFER: PVOID = core::ptr::null_mut();
+// Global Buffer for the driver. It is an atomic rather than a `static mut`, so
+// that devices added concurrently do not race on it.
+#[cfg(not(feature = "drop-on-cleanup"))]
+static GLOBAL_BUFFER: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
 
 mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+#[cfg(feature = "drop-on-cleanup")]
+mod wdf_object_context;
+mod wdf_structure_size;
@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{PFN_WDF_DRIVER_DEVICE_ADD, PFN_WDF_DRIVER_UNLOAD, WDF_DRIVER_CONFIG};
+
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Builder of `WDF_DRIVER_CONFIG`, like `WDF_DRIVER_CONFIG_INIT` in C.
+///
+/// The configuration is correctly sized, so `DriverEntry` does not have to
+/// assert that the size of the structure fits in its `Size` field.
+///
+/// ```rust,ignore
+/// let mut driver_config = DriverConfig::new(Some(echo_evt_device_add))
+///     .unload(Some(echo_evt_driver_unload))
+///     .build();
+/// ```
+#[must_use]
+pub struct DriverConfig {
+    config: WDF_DRIVER_CONFIG,
+}
+
+impl DriverConfig {
+    /// Configuration calling `device_add` when the `PnP` manager adds a device
+    /// the driver is installed for
+    pub fn new(device_add: PFN_WDF_DRIVER_DEVICE_ADD) -> Self {
+        Self {
+            config: WDF_DRIVER_CONFIG {
+                Size: wdf_structure_size!(WDF_DRIVER_CONFIG),
+                EvtDriverDeviceAdd: device_add,
+                ..WDF_DRIVER_CONFIG::default()
+            },
+        }
+    }
+
+    /// Set the `EvtDriverUnload` callback, called before the driver is unloaded
+    pub const fn unload(mut self, callback: PFN_WDF_DRIVER_UNLOAD) -> Self {
+        self.config.EvtDriverUnload = callback;
+        self
+    }
+
+    /// The configuration, to pass by pointer to `WdfDriverCreate`
+    pub const fn build(self) -> WDF_DRIVER_CONFIG {
+        self.config
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use wdk_sys::{WDF_OBJECT_ATTRIBUTES, _WDF_EXECUTION_LEVEL, _WDF_SYNCHRONIZATION_SCOPE};
+#[cfg(feature = "drop-on-cleanup")]
+use wdk_sys::{PCWDF_OBJECT_CONTEXT_TYPE_INFO, PFN_WDF_OBJECT_CONTEXT_CLEANUP};
+
+use crate::wdf_structure_size::wdf_structure_size;
+
+/// Type that can be used as the context of a WDF object, with the
+/// `drop-on-cleanup` feature. It is implemented by the
+/// `wdf_declare_context_type*!` macros, so that [`ObjectAttributes::context`]
+/// can find the type info of a context from its type.
+#[cfg(feature = "drop-on-cleanup")]
+pub trait ObjectContext {
+    /// Type info that `WDF_OBJECT_ATTRIBUTES.ContextTypeInfo` must point to
+    fn type_info() -> PCWDF_OBJECT_CONTEXT_TYPE_INFO;
+}
+
+/// Builder of `WDF_OBJECT_ATTRIBUTES`, like `WDF_OBJECT_ATTRIBUTES_INIT` and
+/// `WDF_OBJECT_ATTRIBUTES_INIT_CONTEXT_TYPE` in C.
+///
+/// The attributes are correctly sized, and inherit the execution level and
+/// synchronization scope of the parent object.
+///
+/// ```rust,ignore
+/// let mut attributes = ObjectAttributes::new()
+///     .context::<DeviceContext>()
+///     .cleanup(Some(device_context_evt_cleanup))
+///     .build();
+/// ```
+#[must_use]
+pub struct ObjectAttributes {
+    attributes: WDF_OBJECT_ATTRIBUTES,
+}
+
+impl ObjectAttributes {
+    /// Attributes with no context, no callbacks and the default parent
+    pub fn new() -> Self {
+        Self {
+            attributes: WDF_OBJECT_ATTRIBUTES {
+                Size: wdf_structure_size!(WDF_OBJECT_ATTRIBUTES),
+                ExecutionLevel: _WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent,
+                SynchronizationScope:
+                    _WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent,
+                ..WDF_OBJECT_ATTRIBUTES::default()
+            },
+        }
+    }
+
+    /// Allocate a context of type `T` with the object
+    #[cfg(feature = "drop-on-cleanup")]
+    pub fn context<T: ObjectContext>(mut self) -> Self {
+        self.attributes.ContextTypeInfo = T::type_info();
+        self
+    }
+
+    /// Set the `EvtCleanupCallback` of the object, e.g. the
+    /// `<context_type>_evt_cleanup` generated by
+    /// `wdf_declare_context_type_with_name_and_drop!`
+    #[cfg(feature = "drop-on-cleanup")]
+    pub const fn cleanup(mut self, callback: PFN_WDF_OBJECT_CONTEXT_CLEANUP) -> Self {
+        self.attributes.EvtCleanupCallback = callback;
+        self
+    }
+
+    /// The attributes, to pass by pointer to the function creating the object
+    pub const fn build(self) -> WDF_OBJECT_ATTRIBUTES {
+        self.attributes
+    }
+}
+
+impl Default for ObjectAttributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+use core::sync::atomic::Ordering;
+
+use wdk::{nt_success, paged_code, println};
+use wdk_sys::{
+    call_unsafe_wdf_function_binding,
+    ntddk::KeGetCurrentIrql,
+    APC_LEVEL,
+    DRIVER_OBJECT,
+    NTSTATUS,
+    PCUNICODE_STRING,
+    PDRIVER_OBJECT,
+    ULONG,
+    WDFDEVICE,
+    WDFDEVICE_INIT,
+    WDFDRIVER,
+    WDFQUEUE,
+    WDFREQUEST,
+    WDF_IO_QUEUE_CONFIG,
+    WDF_NO_HANDLE,
+    WDF_NO_OBJECT_ATTRIBUTES,
+    _WDF_IO_QUEUE_DISPATCH_TYPE,
+    _WDF_TRI_STATE,
+};
+
+use crate::{
+    wdf_driver_config::DriverConfig,
+    wdf_object_attributes::ObjectAttributes,
+    GUID_DEVINTERFACE,
+    LEAKED_REQUEST,
+};
+
+/// `DriverEntry` initializes the driver and is the first routine called by the
+/// system after the driver is loaded. `DriverEntry` specifies the other entry
+/// points in the function driver, such as `EvtDevice` and `DriverUnload`.
+///
+/// # Arguments
+///
+/// * `driver` - represents the instance of the function driver that is loaded
+///   into memory. `DriverEntry` must initialize members of `DriverObject`
+///   before it returns to the caller. `DriverObject` is allocated by the system
+///   before the driver is loaded, and it is released by the system after the
+///   system unloads the function driver from memory.
+/// * `registry_path` - represents the driver specific path in the Registry. The
+///   function driver can use the path to store driver related data between
+///   reboots. The path does not store hardware instance specific data.
+///
+/// # Return value:
+///
+/// * `STATUS_SUCCESS` - if successful,
+/// * `STATUS_UNSUCCESSFUL` - otherwise.
+#[link_section = "INIT"]
+#[export_name = "DriverEntry"]
+extern "system" fn driver_entry(
+    driver: &mut DRIVER_OBJECT,
+    registry_path: PCUNICODE_STRING,
+) -> NTSTATUS {
+    println!("Enter: driver_entry");
+
+    let mut driver_config = DriverConfig::new(Some(evt_driver_device_add))
+        .unload(Some(evt_driver_unload))
+        .build();
+
+    let driver_handle_output = WDF_NO_HANDLE.cast::<WDFDRIVER>();
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDriverCreate,
+            driver as PDRIVER_OBJECT,
+            registry_path,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut driver_config,
+            driver_handle_output,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: driver_entry");
+
+    nt_status
+}
+
+/// `EvtDeviceAdd` is called by the framework in response to `AddDevice`
+/// call from the `PnP` manager. We create and initialize a device object to
+/// represent a new instance of the device.
+///
+/// # Arguments:
+///
+/// * `_driver` - Handle to a framework driver object created in `DriverEntry`
+/// * `device_init` - Pointer to a framework-allocated `WDFDEVICE_INIT`
+///   structure.
+///
+/// # Return value:
+///
+///   * `NTSTATUS`
+#[link_section = "PAGE"]
+extern "C" fn evt_driver_device_add(
+    _driver: WDFDRIVER,
+    mut device_init: *mut WDFDEVICE_INIT,
+) -> NTSTATUS {
+    paged_code!();
+
+    println!("Enter: evt_driver_device_add");
+
+    let mut attributes = ObjectAttributes::new().build();
+
+    let mut device = WDF_NO_HANDLE as WDFDEVICE;
+    let mut nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreate,
+            &mut device_init,
+            &mut attributes,
+            &mut device,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    // Configure a default sequential queue, like the echo sample does, so that
+    // every request sent to the device is dispatched to evt_io_default.
+    #[allow(clippy::cast_possible_truncation)]
+    let mut queue_config = WDF_IO_QUEUE_CONFIG {
+        Size: core::mem::size_of::<WDF_IO_QUEUE_CONFIG>() as ULONG,
+        PowerManaged: _WDF_TRI_STATE::WdfUseDefault,
+        DefaultQueue: u8::from(true),
+        DispatchType: _WDF_IO_QUEUE_DISPATCH_TYPE::WdfIoQueueDispatchSequential,
+        EvtIoDefault: Some(evt_io_default),
+        ..WDF_IO_QUEUE_CONFIG::default()
+    };
+
+    let mut queue = WDF_NO_HANDLE as WDFQUEUE;
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfIoQueueCreate,
+            device,
+            &mut queue_config,
+            WDF_NO_OBJECT_ATTRIBUTES,
+            &mut queue,
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfIoQueueCreate failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceCreateDeviceInterface,
+            device,
+            &GUID_DEVINTERFACE,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if !nt_success(nt_status) {
+        println!("Error: WdfDeviceCreateDeviceInterface failed {nt_status:#010X}");
+        return nt_status;
+    }
+
+    println!("Exit: evt_driver_device_add");
+
+    nt_status
+}
+
+/// This event callback function is called when the framework dispatches a
+/// request from the default queue to the driver.
+///
+/// # Arguments:
+///
+/// * `_queue` - Handle to the framework queue object that is associated with
+///   the I/O request.
+/// * `request` - Handle to a framework request object.
+///
+/// # Return value:
+///
+/// None
+extern "C" fn evt_io_default(_queue: WDFQUEUE, request: WDFREQUEST) {
+    println!("Enter: evt_io_default");
+
+    let nt_status = unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfRequestMarkCancelableEx,
+            request,
+            Some(evt_request_cancel)
+        )
+    };
+
+    if !nt_success(nt_status) {
+        // The request was already cancelled, so the driver still owns it and must
+        // complete it here.
+        unsafe {
+            call_unsafe_wdf_function_binding!(WdfRequestComplete, request, nt_status);
+        }
+        return;
+    }
+
+    // Ideally, the request should be completed with WdfRequestComplete once it
+    // has been processed. But to demonstrate the verifiers' ability to catch
+    // request leaks, the request is stored and deliberately never completed.
+    LEAKED_REQUEST.store(request.cast(), Ordering::SeqCst);
+
+    println!("Exit: evt_io_default");
+}
+
+/// This event callback function is called when a request that the driver
+/// marked cancelable is cancelled, e.g. when the application that sent it
+/// exits or the device is removed.
+///
+/// # Arguments:
+///
+/// * `request` - Handle to the framework request object being cancelled.
+///
+/// # Return value:
+///
+/// None
+extern "C" fn evt_request_cancel(request: WDFREQUEST) {
+    println!("Enter: evt_request_cancel {request:?}");
+
+    // Ideally, the cancel routine should complete the request with
+    // STATUS_CANCELLED. But to demonstrate the verifiers' ability to catch
+    // request leaks, the request is deliberately not completed here either.
+
+    println!("Exit: evt_request_cancel");
+}
+
+/// This event callback function is called before the driver is unloaded
+///
+/// The EvtDriverUnload callback function must deallocate any
+/// non-device-specific system resources that the driver's DriverEntry routine
+/// allocated.
+///
+/// # Argument:
+///
+/// * `driver` - Handle to the framework driver object
+///
+/// # Return Value:
+///
+/// None
+extern "C" fn evt_driver_unload(_driver: WDFDRIVER) {
+    println!("Enter: evt_driver_unload");
+
+    println!("Exit: evt_driver_unload");
+}
@@ -0,0 +1,64 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!     
+//! The driver is designed to receive I/O requests on a default queue, in the
+//! same way as the echo sample, and mark each request cancelable before
+//! storing it. However, the request is never completed with
+//! WdfRequestComplete, neither after it is processed nor when it is cancelled.
+//!
+//! Requests that a driver receives from a queue are owned by the driver until
+//! it completes them, so the framework cannot finish removing the device while
+//! one is outstanding. By enabling Driver Verifier and the KMDF Verifier on
+//! this driver, the leaked request is reported when the device is removed or
+//! the driver is unloaded and with an active KDNET session, the bug can be
+//! analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use core::{ffi::c_void, sync::atomic::AtomicPtr};
+
+use wdk_sys::GUID;
+
+// The echo sample's device interface, so that echoapp can send requests to
+// this driver
+// {CDC35B6E-0BE4-4936-BF5F-5537380A7C1A}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0xCDC3_5B6Eu32,
+    Data2: 0x0BE4u16,
+    Data3: 0x4936u16,
+    Data4: [
+        0xBFu8, 0x5Fu8, 0x55u8, 0x37u8, 0x38u8, 0x0Au8, 0x7Cu8, 0x1Au8,
+    ],
+};
+
+// Request that the driver received and never completes. It is an atomic rather
+// than a `static mut`, so that requests presented concurrently do not race on
+// it. WDFREQUEST handles are stored as untyped pointers.
+static LEAKED_REQUEST: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;
@@ -0,0 +1,54 @@
+// Copyright (c) Microsoft Corporation.
+// License: MIT OR Apache-2.0
+
+//! # Abstract
+//!
+//! This KMDF sample contains an intentional error that is designed to
+//! demonstrate the capabilities and features of Driver Verifier and the Device
+//! Fundamental tests.
+//!     
+//! The driver is designed to create a WDFMEMORY object, parented to its
+//! device, when a device is added by the PnP manager. It then deletes the
+//! memory object with WdfObjectDelete and keeps using the stale handle by
+//! calling WdfMemoryGetBuffer on it.
+//!
+//! By enabling the KMDF Verifier's handle verification on this driver, along
+//! with Driver Verifier, the framework validates every handle passed to it and
+//! the use after free is caught as soon as the device is added. The system
+//! bugchecks with WDF_VIOLATION and with an active KDNET session, the bug can
+//! be analyzed further.
+
+#![no_std]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::missing_safety_doc)]
+#![allow(clippy::doc_markdown)]
+
+#[cfg(not(test))]
+extern crate wdk_panic;
+
+#[cfg(not(test))]
+use wdk_alloc::WdkAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
+
+use wdk_sys::GUID;
+
+// {9B7F2D46-31C8-4E0A-8D5B-6F14A2C7E903}
+const GUID_DEVINTERFACE: GUID = GUID {
+    Data1: 0x9B7F_2D46u32,
+    Data2: 0x31C8u16,
+    Data3: 0x4E0Au16,
+    Data4: [
+        0x8Du8, 0x5Bu8, 0x6Fu8, 0x14u8, 0xA2u8, 0xC7u8, 0xE9u8, 0x03u8,
+    ],
+};
+
+mod driver;
+mod wdf_driver_config;
+mod wdf_object_attributes;
+mod wdf_structure_size;